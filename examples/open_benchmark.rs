@@ -0,0 +1,103 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Measures how long opening VFIO devices takes when their regions are set up right away, as
+//! [`VfioPciDevice::open_in_container`] does, and when they are set up lazily, as
+//! [`VfioPciDevice::open_in_container_lazy`] does.
+//!
+//! Run it with the sysfs paths of devices bound to vfio-pci, _e.g._, all the VFs of an SR-IOV
+//! device:
+//!
+//! ```text
+//! cargo run --release --example open_benchmark -- /sys/bus/pci/devices/0000:3b:02.*
+//! ```
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use pci_driver::backends::vfio::{VfioContainer, VfioPciDevice};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+const ITERATIONS: u32 = 10;
+
+fn main() -> io::Result<()> {
+    let paths: Vec<String> = env::args().skip(1).collect();
+
+    if paths.is_empty() {
+        eprintln!("Usage: open_benchmark <device sysfs path>...");
+        process::exit(2);
+    }
+
+    let mut groups = paths
+        .iter()
+        .map(|path| group_number(path))
+        .collect::<io::Result<Vec<u32>>>()?;
+    groups.sort_unstable();
+    groups.dedup();
+
+    let container = Arc::new(VfioContainer::new(&groups)?);
+
+    let eager = measure(&paths, |path| {
+        VfioPciDevice::open_in_container(path, Arc::clone(&container))
+    })?;
+
+    let lazy = measure(&paths, |path| {
+        VfioPciDevice::open_in_container_lazy(path, Arc::clone(&container))
+    })?;
+
+    println!(
+        "Opening {} devices, averaged over {} runs:",
+        paths.len(),
+        ITERATIONS
+    );
+    println!("  open_in_container:      {:?}", eager);
+    println!("  open_in_container_lazy: {:?}", lazy);
+
+    Ok(())
+}
+
+/// Returns the average time it takes to open all the devices with `open`.
+fn measure<F>(paths: &[String], open: F) -> io::Result<Duration>
+where
+    F: Fn(&str) -> io::Result<VfioPciDevice>,
+{
+    let mut total = Duration::ZERO;
+
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        let devices = paths
+            .iter()
+            .map(|path| open(path))
+            .collect::<io::Result<Vec<_>>>()?;
+        total += start.elapsed();
+
+        // closing the devices isn't part of opening them
+        drop(devices);
+    }
+
+    Ok(total / ITERATIONS)
+}
+
+fn group_number(device_sysfs_path: &str) -> io::Result<u32> {
+    let group_path = fs::read_link(Path::new(device_sysfs_path).join("iommu_group"))?;
+
+    group_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Can't find the IOMMU group of {}", device_sysfs_path),
+            )
+        })
+}
+
+/* ---------------------------------------------------------------------------------------------- */
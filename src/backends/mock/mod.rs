@@ -0,0 +1,873 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::fmt::{self, Debug};
+use std::io::{self, ErrorKind};
+use std::os::unix::io::{OwnedFd, RawFd};
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::PciConfig;
+use crate::device::{
+    PciAddress, PciDevice, PciDeviceInfo, PciDeviceInternal, PciResetCapabilities, Sealed,
+};
+use crate::interrupts::{PciInterruptKind, PciInterrupts};
+use crate::iommu::PciIommu;
+use crate::regions::{
+    BackedByPciSubregion, Cacheability, OwningPciRegion, PciMemoryRegion, PciRegion, Permissions,
+    RegionIdentifier,
+};
+
+#[cfg(test)]
+mod nvme;
+
+#[cfg(test)]
+pub(crate) use self::nvme::MockPciDevice;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+const CONFIG_SPACE_SIZE: usize = 4096;
+
+/// Where [`MockDeviceBuilder`] places BARs and the Expansion ROM in the fake physical address space.
+const FIRST_BAR_ADDRESS: u64 = 0xc000_0000;
+
+const NUM_INTERRUPT_KINDS: usize = 5;
+
+/// Builds a [`MockDevice`] with the given config space contents, capabilities, BARs, and interrupt
+/// vectors.
+///
+/// Config space starts out as a type 0 header with the given Vendor and Device IDs and everything
+/// else zeroed. Methods that take offsets or indices panic if these are out of range.
+///
+/// ```
+/// use pci_driver::backends::mock::MockDeviceBuilder;
+/// use pci_driver::device::PciDevice;
+/// use pci_driver::interrupts::PciInterruptKind;
+/// use pci_driver::regions::PciRegion;
+///
+/// let device = MockDeviceBuilder::new(0x1af4, 0x1042)
+///     .config_bytes(0x08, &[0x01, 0x00, 0x80, 0x01]) // revision and class code
+///     .capability(0x09, &[0x04, 0x00]) // a Vendor Specific Capability
+///     .bar(0, vec![0; 0x1000])
+///     .interrupts(PciInterruptKind::MsiX, 4)
+///     .build();
+///
+/// assert_eq!(device.info().vendor_id, 0x1af4);
+///
+/// let bar = device.bar(0).unwrap();
+/// bar.write_le_u32(0x10, 0x1234)?;
+/// assert_eq!(bar.read_le_u32(0x10)?, 0x1234);
+/// # std::io::Result::Ok(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct MockDeviceBuilder {
+    config: Vec<u8>,
+    /// Offset of the next Capability, or of the Capabilities Pointer while there are none.
+    next_capability: usize,
+    /// Offset of the `next` field of the last Capability, or of the Capabilities Pointer.
+    last_capability_link: usize,
+    next_extended_capability: usize,
+    /// Offset of the header of the last Extended Capability, if any.
+    last_extended_capability: Option<usize>,
+    bars: [Option<Vec<u8>>; 6],
+    rom: Option<Vec<u8>>,
+    /// Where 32-bit BARs and the Expansion ROM start being placed.
+    first_bar_address: u64,
+    max_interrupts: [usize; NUM_INTERRUPT_KINDS],
+    address: Option<PciAddress>,
+}
+
+impl MockDeviceBuilder {
+    /// Starts building a device with the given Vendor and Device IDs.
+    pub fn new(vendor_id: u16, device_id: u16) -> MockDeviceBuilder {
+        let mut config = vec![0; CONFIG_SPACE_SIZE];
+        config[0x00..0x02].copy_from_slice(&vendor_id.to_le_bytes());
+        config[0x02..0x04].copy_from_slice(&device_id.to_le_bytes());
+
+        MockDeviceBuilder {
+            config,
+            next_capability: 0x40,
+            last_capability_link: 0x34,
+            next_extended_capability: 0x100,
+            last_extended_capability: None,
+            bars: Default::default(),
+            rom: None,
+            first_bar_address: FIRST_BAR_ADDRESS,
+            max_interrupts: [0; NUM_INTERRUPT_KINDS],
+            address: None,
+        }
+    }
+
+    /// Overwrites config space at the given offset with the given bytes.
+    ///
+    /// This can set any register, _e.g._, the Class Code or the Interrupt Pin. Capabilities are best
+    /// added with [`MockDeviceBuilder::capability`] and [`MockDeviceBuilder::extended_capability`],
+    /// which link them into the respective lists.
+    pub fn config_bytes(mut self, offset: usize, bytes: &[u8]) -> MockDeviceBuilder {
+        self.config[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self
+    }
+
+    /// Appends a Capability with the given ID to the Capabilities List, and sets the Status
+    /// register's Capabilities List bit.
+    ///
+    /// `body` is what follows the Capability ID and Next Capability Pointer, _e.g._, the Message
+    /// Control register onwards for an MSI Capability. Capabilities are placed one after the other
+    /// from offset 0x40 on, so this panics if they don't all fit below offset 0x100.
+    pub fn capability(mut self, id: u8, body: &[u8]) -> MockDeviceBuilder {
+        let offset = self.next_capability;
+        let end = offset + 2 + body.len();
+
+        assert!(end <= 0x100, "Capabilities don't fit in config space");
+
+        self.config[offset] = id;
+        self.config[offset + 1] = 0;
+        self.config[offset + 2..end].copy_from_slice(body);
+
+        self.config[self.last_capability_link] = offset as u8;
+        self.config[0x06] |= 1 << 4;
+
+        self.last_capability_link = offset + 1;
+        self.next_capability = (end + 3) & !3;
+
+        self
+    }
+
+    /// Appends an Extended Capability with the given ID and version to the Extended Capabilities
+    /// list.
+    ///
+    /// `body` is what follows the Extended Capability header. Extended Capabilities are placed one
+    /// after the other from offset 0x100 on, so this panics if they don't all fit in config space.
+    ///
+    /// Like on real devices, Extended Capabilities are only looked for if there is a PCI Express
+    /// Capability.
+    pub fn extended_capability(mut self, id: u16, version: u8, body: &[u8]) -> MockDeviceBuilder {
+        let offset = self.next_extended_capability;
+        let end = offset + 4 + body.len();
+
+        assert!(
+            version < 0x10,
+            "Extended Capability versions are 4 bits wide"
+        );
+        assert!(
+            end <= CONFIG_SPACE_SIZE,
+            "Extended Capabilities don't fit in config space"
+        );
+
+        let header = u32::from(id) | u32::from(version) << 16;
+        self.config[offset..offset + 4].copy_from_slice(&header.to_le_bytes());
+        self.config[offset + 4..end].copy_from_slice(body);
+
+        if let Some(last) = self.last_extended_capability {
+            let mut header = [0; 4];
+            header.copy_from_slice(&self.config[last..last + 4]);
+            let header = u32::from_le_bytes(header) | (offset as u32) << 20;
+            self.config[last..last + 4].copy_from_slice(&header.to_le_bytes());
+        }
+
+        self.last_extended_capability = Some(offset);
+        self.next_extended_capability = (end + 3) & !3;
+
+        self
+    }
+
+    /// Gives the device a BAR with the given initial contents, whose length is the BAR's size.
+    ///
+    /// The BAR is a non-prefetchable memory BAR. Its register in config space is set to an address
+    /// that doesn't overlap with the other BARs and the Expansion ROM. The BAR can be memory-mapped
+    /// in its entirety.
+    ///
+    /// The BARs and the Expansion ROM are placed from 3 GiB upwards, each aligned to its size
+    /// rounded up to a power of two. BARs that don't fit below 4 GiB are made 64-bit and placed
+    /// from 4 GiB upwards instead, which requires the next BAR to be unused, since a 64-bit BAR
+    /// takes up its register as well. [`MockDeviceBuilder::build`] panics if that isn't the case,
+    /// or if the Expansion ROM doesn't fit below 4 GiB.
+    pub fn bar(mut self, index: usize, contents: Vec<u8>) -> MockDeviceBuilder {
+        assert!(index < 6, "Invalid BAR index {}", index);
+        self.bars[index] = Some(contents);
+        self
+    }
+
+    /// Gives the device an Expansion ROM with the given contents, whose length is its size.
+    ///
+    /// The Expansion ROM BAR is set like the BARs are, see [`MockDeviceBuilder::bar`], but with
+    /// the ROM left disabled.
+    pub fn rom(mut self, contents: Vec<u8>) -> MockDeviceBuilder {
+        self.rom = Some(contents);
+        self
+    }
+
+    /// Sets how many vectors of the given interrupt mechanism the device supports. All mechanisms
+    /// support none by default.
+    ///
+    /// Enabling MSI vectors also requires an MSI Capability that supports enough of them, see
+    /// [`MockDeviceBuilder::capability`].
+    pub fn interrupts(mut self, kind: PciInterruptKind, max: usize) -> MockDeviceBuilder {
+        self.max_interrupts[kind as usize] = max;
+        self
+    }
+
+    /// Sets the address that [`PciDevice::address`] returns. By default it returns `None`.
+    pub fn address(mut self, address: PciAddress) -> MockDeviceBuilder {
+        self.address = Some(address);
+        self
+    }
+
+    /// Builds the device. This panics if the BARs and the Expansion ROM can't be placed, see
+    /// [`MockDeviceBuilder::bar`].
+    pub fn build(mut self) -> MockDevice {
+        let mut next_address_32 = self.first_bar_address;
+        let mut next_address_64 = 1 << 32;
+
+        for index in 0..6 {
+            let length = match &self.bars[index] {
+                Some(contents) => contents.len(),
+                None => continue,
+            };
+
+            let register = 0x10 + index * 4;
+
+            if let Some(address) = place(&mut next_address_32, length, 1 << 32) {
+                self.config[register..register + 4]
+                    .copy_from_slice(&(address as u32).to_le_bytes());
+            } else {
+                assert!(
+                    index < 5 && self.bars[index + 1].is_none(),
+                    "BAR {} doesn't fit below 4 GiB and can't be made 64-bit",
+                    index
+                );
+
+                let address = place(&mut next_address_64, length, u64::MAX)
+                    .expect("BARs don't fit in the 64-bit address space");
+
+                // memory BAR of type 64-bit
+                self.config[register..register + 8].copy_from_slice(&(address | 0x4).to_le_bytes());
+            }
+        }
+
+        if let Some(contents) = &self.rom {
+            let address = place(&mut next_address_32, contents.len(), 1 << 32)
+                .expect("Expansion ROM doesn't fit below 4 GiB");
+
+            self.config[0x30..0x34].copy_from_slice(&(address as u32).to_le_bytes());
+        }
+
+        let config = MockRegion::new(&self.config);
+        let info = PciDeviceInfo::read(&PciConfig::backed_by(&config as &dyn PciRegion))
+            .expect("config space reads can't fail");
+
+        let bars = self
+            .bars
+            .iter()
+            .map(|bar| {
+                bar.as_ref()
+                    .map(|contents| Arc::new(MockRegion::new(contents)))
+            })
+            .collect();
+
+        MockDevice {
+            inner: Arc::new(MockDeviceInner {
+                initial_config: self.config.into_boxed_slice(),
+                config,
+                info,
+                address: self.address,
+                bars,
+                rom: self
+                    .rom
+                    .map(|contents| Arc::new(MockRegion::new(&contents))),
+                max_interrupts: self.max_interrupts,
+                interrupt_triggers: Mutex::new(Default::default()),
+                masked: Mutex::new([false; NUM_INTERRUPT_KINDS]),
+                resets: AtomicUsize::new(0),
+            }),
+        }
+    }
+}
+
+/// Returns the address at which a region of the given size is placed, aligned to its size rounded up
+/// to a power of two, at or after `next_address`, and advances `next_address` past it. Returns
+/// `None` if the region wouldn't end at or below `limit`.
+fn place(next_address: &mut u64, size: usize, limit: u64) -> Option<u64> {
+    let size = (size.max(16) as u64).checked_next_power_of_two()?;
+    let address = next_address.checked_add(size - 1)? & !(size - 1);
+    let end = address.checked_add(size)?;
+
+    if end > limit {
+        return None;
+    }
+
+    *next_address = end;
+    Some(address)
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// A fake PCI function backed by memory, for unit-testing drivers without real hardware. Build one
+/// with [`MockDeviceBuilder`].
+///
+/// Config space, the BARs, and the Expansion ROM behave like plain memory: writes are stored as
+/// is, with no read-only bits or side effects, and BAR sizing isn't emulated. Resetting the device
+/// restores config space to its initial contents and disables all interrupt vectors, but leaves
+/// the BARs' contents alone.
+///
+/// Enabling interrupt vectors with eventfds works as with real devices, and
+/// [`PciInterruptMechanism::trigger`](crate::interrupts::PciInterruptMechanism::trigger)
+/// signals them, so drivers' interrupt handling can be exercised. Only INTx can be masked, and
+/// triggering a masked mechanism does nothing. The device has no IOMMU.
+#[derive(Debug)]
+pub struct MockDevice {
+    inner: Arc<MockDeviceInner>,
+}
+
+impl MockDevice {
+    /// How many times the device was reset through [`PciDevice::reset`] or any method that calls
+    /// it.
+    pub fn reset_count(&self) -> usize {
+        self.inner.resets.load(Ordering::Relaxed)
+    }
+}
+
+impl Sealed for MockDevice {}
+impl PciDevice for MockDevice {
+    fn config(&self) -> PciConfig<'_> {
+        self.inner.config_space()
+    }
+
+    fn info(&self) -> PciDeviceInfo {
+        self.inner.info
+    }
+
+    fn address(&self) -> Option<PciAddress> {
+        self.inner.address
+    }
+
+    fn numa_node(&self) -> io::Result<Option<u32>> {
+        Ok(None)
+    }
+
+    fn bar(&self, index: usize) -> Option<OwningPciRegion> {
+        let bar = self.inner.bars.get(index)?.as_ref()?;
+        Some(self.owning_region(bar, RegionIdentifier::Bar(index)))
+    }
+
+    fn bar_region(&self, index: usize) -> Option<Box<dyn PciRegion>> {
+        let bar: Option<OwningPciRegion> = self.bar(index);
+        bar.map(|b| Box::new(b) as Box<dyn PciRegion>)
+    }
+
+    fn rom(&self) -> Option<OwningPciRegion> {
+        let rom = self.inner.rom.as_ref()?;
+        Some(self.owning_region(rom, RegionIdentifier::Rom))
+    }
+
+    fn iommu(&self) -> Option<PciIommu<'_>> {
+        None
+    }
+
+    fn interrupts(&self) -> PciInterrupts<'_> {
+        PciInterrupts {
+            device: &*self.inner,
+        }
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        for &kind in &INTERRUPT_KINDS {
+            self.inner.interrupts_disable(kind)?;
+        }
+
+        let config = &self.inner.config;
+        for (offset, &byte) in (0..).zip(self.inner.initial_config.iter()) {
+            config.write_u8(offset, byte)?;
+        }
+
+        self.inner.resets.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn reset_capabilities(&self) -> io::Result<PciResetCapabilities> {
+        let mut capabilities = PciResetCapabilities::from_config(&self.config())?;
+        capabilities.function_reset = true;
+        Ok(capabilities)
+    }
+}
+
+impl MockDevice {
+    fn owning_region(
+        &self,
+        region: &Arc<MockRegion>,
+        identifier: RegionIdentifier,
+    ) -> OwningPciRegion {
+        let whole_region = 0..region.len();
+
+        OwningPciRegion::new(
+            Arc::<MockDeviceInner>::clone(&self.inner),
+            Arc::<MockRegion>::clone(region),
+            identifier,
+            slice::from_ref(&whole_region),
+        )
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+const INTERRUPT_KINDS: [PciInterruptKind; NUM_INTERRUPT_KINDS] = [
+    PciInterruptKind::Intx,
+    PciInterruptKind::Msi,
+    PciInterruptKind::MsiX,
+    PciInterruptKind::Error,
+    PciInterruptKind::Request,
+];
+
+#[derive(Debug)]
+struct MockDeviceInner {
+    config: MockRegion,
+    initial_config: Box<[u8]>,
+    info: PciDeviceInfo,
+    address: Option<PciAddress>,
+
+    bars: Box<[Option<Arc<MockRegion>>]>,
+    rom: Option<Arc<MockRegion>>,
+
+    max_interrupts: [usize; NUM_INTERRUPT_KINDS],
+    interrupt_triggers: Mutex<[MockInterruptTriggers; NUM_INTERRUPT_KINDS]>,
+    masked: Mutex<[bool; NUM_INTERRUPT_KINDS]>,
+
+    resets: AtomicUsize,
+}
+
+/// The eventfds set as triggers of an interrupt mechanism's vectors.
+#[derive(Debug, Default)]
+struct MockInterruptTriggers {
+    /// -1 for vectors without one.
+    eventfds: Vec<RawFd>,
+    /// The eventfds that were handed over to us.
+    owned: Vec<Option<OwnedFd>>,
+}
+
+impl PciDeviceInternal for MockDeviceInner {
+    // Config space
+
+    fn config_space(&self) -> PciConfig<'_> {
+        PciConfig::backed_by(&self.config as &dyn PciRegion)
+    }
+
+    // BARs / ROM
+
+    fn region_map(
+        &self,
+        identifier: RegionIdentifier,
+        offset: u64,
+        len: usize,
+        _permissions: Permissions,
+        _cacheability: Cacheability,
+    ) -> io::Result<*mut u8> {
+        let region = match identifier {
+            RegionIdentifier::Bar(index) => self.bars.get(index).and_then(Option::as_ref),
+            RegionIdentifier::Rom => self.rom.as_ref(),
+            _ => None,
+        };
+
+        let region = region
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "No such region to map"))?;
+
+        if offset > region.len() || len as u64 > region.len() - offset {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Mapping falls outside region",
+            ));
+        }
+
+        // the "mapping" is the region's memory itself
+        Ok(unsafe { region.as_mut_ptr().unwrap().add(offset as usize) })
+    }
+
+    unsafe fn region_unmap(
+        &self,
+        _identifier: RegionIdentifier,
+        _address: *mut u8,
+        _length: usize,
+    ) {
+    }
+
+    // Interrupts
+
+    fn interrupts_max(&self, kind: PciInterruptKind) -> usize {
+        self.max_interrupts[kind as usize]
+    }
+
+    fn interrupts_enable(
+        &self,
+        kind: PciInterruptKind,
+        start: usize,
+        eventfds: &[RawFd],
+        owned: Option<Vec<OwnedFd>>,
+    ) -> io::Result<()> {
+        let max = self.max_interrupts[kind as usize];
+
+        if start > max || eventfds.len() > max - start {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Vectors [{}, {}) exceed the maximum of {}",
+                    start,
+                    start.saturating_add(eventfds.len()),
+                    max
+                ),
+            ));
+        }
+
+        let mut triggers = self.interrupt_triggers.lock().unwrap();
+        let triggers = &mut triggers[kind as usize];
+        let end = start + eventfds.len();
+
+        if triggers.eventfds.len() < end {
+            triggers.eventfds.resize(end, -1);
+            triggers.owned.resize_with(end, || None);
+        }
+        triggers.eventfds[start..end].copy_from_slice(eventfds);
+
+        // this closes the eventfds we owned for these vectors, if any
+        match owned {
+            Some(owned) => {
+                for (slot, fd) in triggers.owned[start..end].iter_mut().zip(owned) {
+                    *slot = Some(fd);
+                }
+            }
+            None => triggers.owned[start..end]
+                .iter_mut()
+                .for_each(|slot| *slot = None),
+        }
+
+        Ok(())
+    }
+
+    fn interrupts_eventfds(&self, kind: PciInterruptKind) -> Vec<RawFd> {
+        self.interrupt_triggers.lock().unwrap()[kind as usize]
+            .eventfds
+            .clone()
+    }
+
+    fn interrupts_owned_eventfds(
+        &self,
+        kind: PciInterruptKind,
+    ) -> io::Result<Vec<Option<OwnedFd>>> {
+        self.interrupt_triggers.lock().unwrap()[kind as usize]
+            .owned
+            .iter()
+            .map(|fd| fd.as_ref().map(OwnedFd::try_clone).transpose())
+            .collect()
+    }
+
+    fn interrupts_is_maskable(&self, kind: PciInterruptKind) -> bool {
+        kind == PciInterruptKind::Intx
+    }
+
+    fn interrupts_is_automasked(&self, _kind: PciInterruptKind) -> bool {
+        false
+    }
+
+    fn interrupts_is_resizable(&self, _kind: PciInterruptKind) -> bool {
+        true
+    }
+
+    fn interrupts_set_masked(&self, kind: PciInterruptKind, masked: bool) -> io::Result<()> {
+        if !self.interrupts_is_maskable(kind) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} interrupts can't be masked", kind),
+            ));
+        }
+
+        self.masked.lock().unwrap()[kind as usize] = masked;
+
+        Ok(())
+    }
+
+    fn interrupts_set_unmask_eventfd(
+        &self,
+        _kind: PciInterruptKind,
+        _eventfd: RawFd,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "Mock devices don't support unmask eventfds",
+        ))
+    }
+
+    fn interrupts_trigger(&self, kind: PciInterruptKind, vector: usize) -> io::Result<()> {
+        let eventfd = match self.interrupt_triggers.lock().unwrap()[kind as usize]
+            .eventfds
+            .get(vector)
+        {
+            Some(&eventfd) => eventfd,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Vector {} is not enabled", vector),
+                ))
+            }
+        };
+
+        if eventfd < 0 || self.masked.lock().unwrap()[kind as usize] {
+            return Ok(());
+        }
+
+        let value = 1_u64.to_ne_bytes();
+        let written = unsafe { libc::write(eventfd, value.as_ptr().cast(), value.len()) };
+
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn interrupts_disable(&self, kind: PciInterruptKind) -> io::Result<()> {
+        self.interrupt_triggers.lock().unwrap()[kind as usize] = MockInterruptTriggers::default();
+        self.masked.lock().unwrap()[kind as usize] = false;
+        Ok(())
+    }
+
+    fn interrupts_irq_name(&self, _kind: PciInterruptKind, _vector: usize) -> Option<String> {
+        None
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Memory standing in for config space, a BAR, or the Expansion ROM.
+struct MockRegion {
+    /// The layout of the allocation backing `region`, which is suitably aligned for accesses of any
+    /// width. This is a raw allocation rather than, _e.g._, a `Box`, so that `region` and pointers
+    /// obtained from it remain valid when the `MockRegion` is moved.
+    layout: Layout,
+    region: PciMemoryRegion<'static>,
+}
+
+impl MockRegion {
+    fn new(contents: &[u8]) -> MockRegion {
+        let layout = Layout::from_size_align(contents.len().max(1), 8).unwrap();
+
+        let buffer = unsafe { alloc_zeroed(layout) };
+        if buffer.is_null() {
+            handle_alloc_error(layout);
+        }
+
+        let region =
+            unsafe { PciMemoryRegion::new_raw(buffer, contents.len(), Permissions::ReadWrite) };
+
+        for (offset, &byte) in (0..).zip(contents) {
+            region.write_u8(offset, byte).unwrap();
+        }
+
+        MockRegion { layout, region }
+    }
+}
+
+impl Drop for MockRegion {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.region.as_mut_ptr().unwrap(), self.layout) };
+    }
+}
+
+impl Debug for MockRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockRegion")
+            .field("len", &self.region.len())
+            .finish()
+    }
+}
+
+impl crate::regions::Sealed for MockRegion {}
+impl PciRegion for MockRegion {
+    fn len(&self) -> u64 {
+        self.region.len()
+    }
+
+    fn permissions(&self) -> Permissions {
+        self.region.permissions()
+    }
+
+    fn as_ptr(&self) -> Option<*const u8> {
+        self.region.as_ptr()
+    }
+
+    fn as_mut_ptr(&self) -> Option<*mut u8> {
+        self.region.as_mut_ptr()
+    }
+
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.region.read_bytes(offset, buffer)
+    }
+
+    fn read_u8(&self, offset: u64) -> io::Result<u8> {
+        self.region.read_u8(offset)
+    }
+
+    fn write_u8(&self, offset: u64, value: u8) -> io::Result<()> {
+        self.region.write_u8(offset, value)
+    }
+
+    fn read_le_u16(&self, offset: u64) -> io::Result<u16> {
+        self.region.read_le_u16(offset)
+    }
+
+    fn write_le_u16(&self, offset: u64, value: u16) -> io::Result<()> {
+        self.region.write_le_u16(offset, value)
+    }
+
+    fn read_le_u32(&self, offset: u64) -> io::Result<u32> {
+        self.region.read_le_u32(offset)
+    }
+
+    fn write_le_u32(&self, offset: u64, value: u32) -> io::Result<()> {
+        self.region.write_le_u32(offset, value)
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::backends::mock::MockDeviceBuilder;
+    use crate::config::caps::{Capability, MsiCapability};
+    use crate::config::ext_caps::ExtendedCapability;
+    use crate::device::PciDevice;
+    use crate::interrupts::PciInterruptKind;
+    use crate::regions::{PciRegion, Permissions};
+
+    #[test]
+    fn test_capabilities() {
+        let device = MockDeviceBuilder::new(0x1234, 0x5678)
+            .capability(0x09, &[0x03])
+            .capability(0x05, &[0x80, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .capability(0x10, &[0x02; 0x3a])
+            .extended_capability(0x000b, 1, &[0; 4])
+            .extended_capability(0x0003, 1, &[0; 8])
+            .build();
+
+        let config = device.config();
+
+        let cap_ids: Vec<_> = config
+            .capabilities()
+            .unwrap()
+            .iter()
+            .map(|cap| cap.header().capability_id().read().unwrap())
+            .collect();
+        assert_eq!(cap_ids, [0x09, 0x05, 0x10]);
+
+        let msi = config
+            .capabilities()
+            .unwrap()
+            .of_type::<MsiCapability>()
+            .unwrap()
+            .next()
+            .unwrap();
+        assert!(msi
+            .message_control()
+            .bit_64_address_capable()
+            .read()
+            .unwrap());
+
+        let ext_cap_ids: Vec<_> = config
+            .extended_capabilities()
+            .unwrap()
+            .iter()
+            .map(|cap| cap.header().capability_id().read().unwrap())
+            .collect();
+        assert_eq!(ext_cap_ids, [0x000b, 0x0003]);
+    }
+
+    #[test]
+    fn test_bars_and_reset() {
+        let device = MockDeviceBuilder::new(0x1234, 0x5678)
+            .bar(0, vec![0xaa; 0x100])
+            .bar(2, vec![0; 0x4000])
+            .rom(vec![0x55, 0xaa])
+            .build();
+
+        let config = device.config();
+        assert_eq!(config.read_le_u32(0x10).unwrap(), 0xc000_0000);
+        assert_eq!(config.read_le_u32(0x18).unwrap(), 0xc000_4000);
+        assert_eq!(config.read_le_u32(0x30).unwrap(), 0xc000_8000);
+        assert!(device.bar(1).is_none());
+
+        let bar = device.bar(2).unwrap();
+        assert_eq!(bar.len(), 0x4000);
+
+        let mapping = bar.map(0..0x1000, Permissions::ReadWrite).unwrap();
+        mapping.write_le_u32(0x20, 0xdead_beef).unwrap();
+        assert_eq!(bar.read_le_u32(0x20).unwrap(), 0xdead_beef);
+
+        assert_eq!(device.bar(0).unwrap().read_u8(0xff).unwrap(), 0xaa);
+        assert_eq!(device.rom().unwrap().read_le_u16(0).unwrap(), 0xaa55);
+
+        config.write_le_u16(0x04, 0x0006).unwrap();
+        device.reset().unwrap();
+
+        assert_eq!(config.read_le_u16(0x04).unwrap(), 0);
+        assert_eq!(bar.read_le_u32(0x20).unwrap(), 0xdead_beef);
+        assert_eq!(device.reset_count(), 1);
+    }
+
+    #[test]
+    fn test_bars_beyond_4_gib() {
+        let mut builder = MockDeviceBuilder::new(0x1234, 0x5678)
+            .bar(0, vec![0; 0x2000])
+            .bar(2, vec![0; 0x4000])
+            .bar(4, vec![0; 0x10])
+            .rom(vec![0; 0x800]);
+
+        // leave only 16 KiB below 4 GiB, so as not to allocate GiBs of memory
+        builder.first_bar_address = 0xffff_c000;
+
+        let device = builder.build();
+        let config = device.config();
+
+        assert_eq!(config.read_le_u32(0x10).unwrap(), 0xffff_c000);
+        assert_eq!(config.read_le_u32(0x18).unwrap(), 0x0000_0004);
+        assert_eq!(config.read_le_u32(0x1c).unwrap(), 0x0000_0001);
+        assert_eq!(config.read_le_u32(0x20).unwrap(), 0xffff_e000);
+        assert_eq!(config.read_le_u32(0x30).unwrap(), 0xffff_e800);
+        assert_eq!(device.bar(2).unwrap().len(), 0x4000);
+        assert!(device.bar(3).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "BAR 5 doesn't fit below 4 GiB")]
+    fn test_bars_beyond_4_gib_without_room() {
+        let mut builder = MockDeviceBuilder::new(0x1234, 0x5678).bar(5, vec![0; 0x2000]);
+        builder.first_bar_address = 0xffff_f000;
+        builder.build();
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let device = MockDeviceBuilder::new(0x1234, 0x5678)
+            .interrupts(PciInterruptKind::MsiX, 2)
+            .build();
+
+        let interrupts = device.interrupts();
+        let msi_x = interrupts.msi_x();
+        assert_eq!(msi_x.max(), 2);
+        assert!(msi_x.enable_count(3, false).is_err());
+
+        let _eventfds = msi_x.enable_count(2, false).unwrap();
+        msi_x.trigger(1).unwrap();
+
+        assert_eq!(msi_x.wait(1, Some(Duration::from_secs(1))).unwrap(), 1);
+        assert!(msi_x.trigger(2).is_err());
+        assert!(msi_x.mask().is_err());
+
+        device.reset().unwrap();
+        assert!(!msi_x.is_enabled());
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -5,14 +5,16 @@
 use byte_strings::concat_bytes;
 use std::io::{self, ErrorKind};
 use std::ops::Range;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{OwnedFd, RawFd};
 
 use crate::config::PciConfig;
-use crate::device::{PciDevice, PciDeviceInternal, Sealed};
+use crate::device::{
+    PciAddress, PciDevice, PciDeviceInfo, PciDeviceInternal, PciResetCapabilities, Sealed,
+};
 use crate::interrupts::{PciInterruptKind, PciInterrupts};
 use crate::iommu::{PciIommu, PciIommuInternal};
 use crate::regions::BackedByPciSubregion;
-use crate::regions::{OwningPciRegion, PciRegion, Permissions, RegionIdentifier};
+use crate::regions::{Cacheability, OwningPciRegion, PciRegion, Permissions, RegionIdentifier};
 
 /* ---------------------------------------------------------------------------------------------- */
 
@@ -22,7 +24,7 @@ pub(crate) struct MockPciDevice;
 impl Sealed for MockPciDevice {}
 
 impl PciDevice for MockPciDevice {
-    fn config(&self) -> PciConfig {
+    fn config(&self) -> PciConfig<'_> {
         PciConfig::backed_by(&MockConfigSpace as &dyn PciRegion)
     }
 
@@ -38,26 +40,47 @@ impl PciDevice for MockPciDevice {
         todo!()
     }
 
-    fn iommu(&self) -> Option<PciIommu> {
+    fn iommu(&self) -> Option<PciIommu<'_>> {
         Some(PciIommu { internal: self })
     }
 
-    fn interrupts(&self) -> PciInterrupts {
+    fn interrupts(&self) -> PciInterrupts<'_> {
         PciInterrupts { device: self }
     }
 
+    fn info(&self) -> PciDeviceInfo {
+        PciDeviceInfo::read(&self.config()).unwrap()
+    }
+
+    fn address(&self) -> Option<PciAddress> {
+        None
+    }
+
+    fn numa_node(&self) -> io::Result<Option<u32>> {
+        Ok(None)
+    }
+
     fn reset(&self) -> io::Result<()> {
         todo!()
     }
+
+    fn reset_capabilities(&self) -> io::Result<PciResetCapabilities> {
+        todo!()
+    }
 }
 
 impl PciDeviceInternal for MockPciDevice {
+    fn config_space(&self) -> PciConfig<'_> {
+        PciDevice::config(self)
+    }
+
     fn region_map(
         &self,
         _identifier: RegionIdentifier,
         _offset: u64,
         _len: usize,
         _permissions: Permissions,
+        _cacheability: Cacheability,
     ) -> io::Result<*mut u8> {
         todo!()
     }
@@ -75,13 +98,62 @@ impl PciDeviceInternal for MockPciDevice {
         todo!()
     }
 
-    fn interrupts_enable(&self, _kind: PciInterruptKind, _eventfds: &[RawFd]) -> io::Result<()> {
+    fn interrupts_enable(
+        &self,
+        _kind: PciInterruptKind,
+        _start: usize,
+        _eventfds: &[RawFd],
+        _owned: Option<Vec<OwnedFd>>,
+    ) -> io::Result<()> {
+        todo!()
+    }
+
+    fn interrupts_eventfds(&self, _kind: PciInterruptKind) -> Vec<RawFd> {
+        todo!()
+    }
+
+    fn interrupts_owned_eventfds(
+        &self,
+        _kind: PciInterruptKind,
+    ) -> io::Result<Vec<Option<OwnedFd>>> {
+        todo!()
+    }
+
+    fn interrupts_is_maskable(&self, _kind: PciInterruptKind) -> bool {
+        todo!()
+    }
+
+    fn interrupts_is_automasked(&self, _kind: PciInterruptKind) -> bool {
+        todo!()
+    }
+
+    fn interrupts_is_resizable(&self, _kind: PciInterruptKind) -> bool {
+        todo!()
+    }
+
+    fn interrupts_set_masked(&self, _kind: PciInterruptKind, _masked: bool) -> io::Result<()> {
+        todo!()
+    }
+
+    fn interrupts_set_unmask_eventfd(
+        &self,
+        _kind: PciInterruptKind,
+        _eventfd: RawFd,
+    ) -> io::Result<()> {
+        todo!()
+    }
+
+    fn interrupts_trigger(&self, _kind: PciInterruptKind, _vector: usize) -> io::Result<()> {
         todo!()
     }
 
     fn interrupts_disable(&self, _kind: PciInterruptKind) -> io::Result<()> {
         todo!()
     }
+
+    fn interrupts_irq_name(&self, _kind: PciInterruptKind, _vector: usize) -> Option<String> {
+        todo!()
+    }
 }
 
 impl PciIommuInternal for MockPciDevice {
@@ -97,6 +169,10 @@ impl PciIommuInternal for MockPciDevice {
         todo!()
     }
 
+    fn num_mappings(&self) -> io::Result<u32> {
+        todo!()
+    }
+
     unsafe fn map(
         &self,
         _iova: u64,
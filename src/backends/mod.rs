@@ -5,7 +5,7 @@
 #[cfg(feature = "vfio")]
 pub mod vfio;
 
-#[cfg(test)]
-pub(crate) mod mock;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 
 /* ---------------------------------------------------------------------------------------------- */
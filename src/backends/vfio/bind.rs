@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::EBUSY;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::backends::vfio::bindings::VFIO_GROUP_FLAGS_VIABLE;
+use crate::backends::vfio::containers::get_group_status_flags;
+use crate::enumerate::{PciDeviceEntry, PciDeviceFilter};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+const SYSFS_PCI_BUS: &str = "/sys/bus/pci";
+const VFIO_PCI_DRIVER: &str = "vfio-pci";
+
+/// Binds a PCI function to the vfio-pci driver, so that it can be opened with
+/// [`VfioPciDevice::open`](crate::backends::vfio::VfioPciDevice::open), and returns the name of the
+/// driver it was previously bound to, if any.
+///
+/// `sysfs_path` must correspond to the function's sysfs directory, *e.g.*,
+/// `/sys/bus/pci/devices/0000:00:01.0`. The function is unbound from its current driver, and its
+/// `driver_override` is set so that only vfio-pci may bind to it, until
+/// [`unbind_from_vfio_pci`] is called. Nothing is done if the function is already bound to
+/// vfio-pci.
+///
+/// This requires root privileges, and the vfio-pci module to be loaded. Note that VFIO requires
+/// all functions in the same IOMMU group to be bound to vfio-pci (or to no driver) before any of
+/// them can be opened.
+pub fn bind_to_vfio_pci<P: AsRef<Path>>(sysfs_path: P) -> io::Result<Option<String>> {
+    let (sysfs_path, address) = canonicalize(sysfs_path.as_ref())?;
+
+    let previous_driver = current_driver(&sysfs_path);
+
+    if previous_driver.as_deref() == Some(VFIO_PCI_DRIVER) {
+        return Ok(previous_driver);
+    }
+
+    if !Path::new(SYSFS_PCI_BUS)
+        .join("drivers")
+        .join(VFIO_PCI_DRIVER)
+        .exists()
+    {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            "The vfio-pci driver isn't loaded, try `modprobe vfio-pci`",
+        ));
+    }
+
+    fs::write(sysfs_path.join("driver_override"), VFIO_PCI_DRIVER)?;
+
+    if previous_driver.is_some() {
+        fs::write(sysfs_path.join("driver").join("unbind"), &address)?;
+    }
+
+    // probing picks the driver given in driver_override
+    fs::write(Path::new(SYSFS_PCI_BUS).join("drivers_probe"), &address)?;
+
+    if current_driver(&sysfs_path).as_deref() != Some(VFIO_PCI_DRIVER) {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("Failed to bind {} to vfio-pci", address),
+        ));
+    }
+
+    Ok(previous_driver)
+}
+
+/// Unbinds a PCI function from its current driver, which is usually vfio-pci, clears its
+/// `driver_override`, and binds it to the given driver, or if `None`, to whatever driver the kernel
+/// picks for it.
+///
+/// This is the reverse of [`bind_to_vfio_pci`], to which you can pass the driver it returned.
+///
+/// This requires root privileges.
+pub fn unbind_from_vfio_pci<P: AsRef<Path>>(sysfs_path: P, driver: Option<&str>) -> io::Result<()> {
+    let (sysfs_path, address) = canonicalize(sysfs_path.as_ref())?;
+
+    if current_driver(&sysfs_path).is_some() {
+        fs::write(sysfs_path.join("driver").join("unbind"), &address)?;
+    }
+
+    // writing an empty line clears the override
+    fs::write(sysfs_path.join("driver_override"), "\n")?;
+
+    match driver {
+        Some(driver) => fs::write(
+            Path::new(SYSFS_PCI_BUS)
+                .join("drivers")
+                .join(driver)
+                .join("bind"),
+            &address,
+        ),
+        None => fs::write(Path::new(SYSFS_PCI_BUS).join("drivers_probe"), &address),
+    }
+}
+
+/// The drivers that VFIO tolerates the devices in a group being bound to, besides vfio-pci variant
+/// drivers. `pcieport` only manages the port itself, not DMA by devices below it.
+const VFIO_ALLOWED_DRIVERS: &[&str] = &[VFIO_PCI_DRIVER, "pci-stub", "pcieport"];
+
+/// Whether VFIO tolerates a device in a group being bound to the given driver.
+///
+/// The kernel also accepts any driver that manages DMA through VFIO itself, which in practice means
+/// the vfio-pci variant drivers, _e.g._, `mlx5_vfio_pci`.
+fn vfio_allows_driver(driver: &str) -> bool {
+    VFIO_ALLOWED_DRIVERS.contains(&driver) || driver.ends_with("_vfio_pci")
+}
+
+/// Returns the PCI functions in the given IOMMU group that prevent VFIO from using the group,
+/// because they are bound to drivers other than vfio-pci and its variant drivers, pci-stub, or
+/// pcieport.
+///
+/// The group is _viable_, _i.e._, can be added to a
+/// [`VfioContainer`](crate::backends::vfio::VfioContainer), if this returns no functions. You can
+/// check this before opening anything, and _e.g._ tell the user which sibling functions must also
+/// be unbound, or call [`bind_to_vfio_pci`] on each of them.
+///
+/// Only PCI functions are considered, but IOMMU groups can in principle also contain other kinds
+/// of devices.
+pub fn vfio_group_blockers(group_number: u32) -> io::Result<Vec<PciDeviceEntry>> {
+    let mut devices = PciDeviceFilter::new().iommu_group(group_number).devices()?;
+
+    devices.retain(|device| match &device.driver {
+        Some(driver) => !vfio_allows_driver(driver),
+        None => false,
+    });
+
+    Ok(devices)
+}
+
+/// The status of a VFIO group. See [`vfio_group_status`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct VfioGroupStatus {
+    /// Whether the group is a no-IOMMU group, _i.e._, `/dev/vfio/noiommu-<group>`, which can only
+    /// be used through
+    /// [`VfioContainer::new_noiommu`](crate::backends::vfio::VfioContainer::new_noiommu).
+    pub noiommu: bool,
+    /// Whether the group is viable, _i.e._, can be added to a
+    /// [`VfioContainer`](crate::backends::vfio::VfioContainer).
+    ///
+    /// If the group is [in use](VfioGroupStatus::in_use), VFIO can't be asked, so this is instead
+    /// whether there are no [blockers](VfioGroupStatus::blockers).
+    pub viable: bool,
+    /// Whether the group is currently open elsewhere, _e.g._, by another process or an existing
+    /// [`VfioContainer`](crate::backends::vfio::VfioContainer), and so can't be opened until it
+    /// is closed there.
+    pub in_use: bool,
+    /// The PCI functions that keep the group from being viable, as returned by
+    /// [`vfio_group_blockers`].
+    pub blockers: Vec<PciDeviceEntry>,
+}
+
+/// Queries the status of the given VFIO group without adding it to a container, so that the
+/// prerequisites for opening its devices can be checked and reported beforehand.
+///
+/// Fails with [`ErrorKind::NotFound`] if VFIO doesn't know about the group, _e.g._, because none
+/// of its devices is bound to vfio-pci.
+pub fn vfio_group_status(group_number: u32) -> io::Result<VfioGroupStatus> {
+    let (file, noiommu) = match open_unless_busy(format!("/dev/vfio/{}", group_number)) {
+        Ok(file) => (file, false),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            match open_unless_busy(format!("/dev/vfio/noiommu-{}", group_number)) {
+                Ok(file) => (file, true),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    return Err(io::Error::new(
+                        ErrorKind::NotFound,
+                        format!(
+                            "VFIO group {} doesn't exist; is any device in it bound to vfio-pci?",
+                            group_number
+                        ),
+                    ))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(e) => return Err(e),
+    };
+
+    let blockers = vfio_group_blockers(group_number)?;
+
+    let viable = match &file {
+        Some(file) => get_group_status_flags(file)? & VFIO_GROUP_FLAGS_VIABLE != 0,
+        None => blockers.is_empty(),
+    };
+
+    Ok(VfioGroupStatus {
+        noiommu,
+        viable,
+        in_use: file.is_none(),
+        blockers,
+    })
+}
+
+/// Opens a VFIO group file, or returns `None` if it is already open elsewhere, since VFIO groups
+/// can only be open once at a time.
+fn open_unless_busy(path: String) -> io::Result<Option<File>> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(EBUSY) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Returns the canonical sysfs path of a PCI function and its address.
+fn canonicalize(sysfs_path: &Path) -> io::Result<(PathBuf, String)> {
+    let sysfs_path = sysfs_path.canonicalize()?;
+
+    let address = sysfs_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} isn't a PCI device's sysfs path", sysfs_path.display()),
+            )
+        })?
+        .to_string();
+
+    Ok((sysfs_path, address))
+}
+
+/// Returns the name of the driver the PCI function is bound to, if any.
+fn current_driver(sysfs_path: &Path) -> Option<String> {
+    let target = fs::read_link(sysfs_path.join("driver")).ok()?;
+    Some(target.file_name()?.to_str()?.to_string())
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use super::vfio_allows_driver;
+
+    #[test]
+    fn test_vfio_allows_driver() {
+        assert!(vfio_allows_driver("vfio-pci"));
+        assert!(vfio_allows_driver("pci-stub"));
+        assert!(vfio_allows_driver("pcieport"));
+        assert!(vfio_allows_driver("mlx5_vfio_pci"));
+        assert!(!vfio_allows_driver("nvme"));
+        assert!(!vfio_allows_driver("vfio"));
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
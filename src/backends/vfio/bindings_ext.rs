@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: GPL-2.0 WITH Linux-syscall-note
+
+// The parts of include/uapi/linux/vfio.h (Linux 6.6, plus PASID attachment from Linux 6.14) that we
+// need but that are newer than bindings.rs, transcribed by hand.
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct vfio_device_bind_iommufd {
+    pub argsz: u32,
+    pub flags: u32,
+    pub iommufd: i32,
+    pub out_devid: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct vfio_device_attach_iommufd_pt {
+    pub argsz: u32,
+    pub flags: u32,
+    pub pt_id: u32,
+    pub pasid: u32,
+}
+
+pub const VFIO_DEVICE_ATTACH_PASID: u32 = 1 << 0;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct vfio_device_detach_iommufd_pt {
+    pub argsz: u32,
+    pub flags: u32,
+    pub pasid: u32,
+}
+
+pub const VFIO_DEVICE_DETACH_PASID: u32 = 1 << 0;
+
+pub const VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY: u32 = 3;
+pub const VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY_WITH_WAKEUP: u32 = 4;
+pub const VFIO_DEVICE_FEATURE_LOW_POWER_EXIT: u32 = 5;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct vfio_device_low_power_entry_with_wakeup {
+    pub wakeup_eventfd: i32,
+    pub reserved: u32,
+}
+
+pub const VFIO_MIGRATION_PRE_COPY: u32 = 1 << 2;
+
+pub const vfio_device_mig_state_VFIO_DEVICE_STATE_PRE_COPY: u32 = 6;
+pub const vfio_device_mig_state_VFIO_DEVICE_STATE_PRE_COPY_P2P: u32 = 7;
+
+pub const VFIO_DEVICE_FEATURE_MIG_DATA_SIZE: u32 = 9;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct vfio_device_feature_mig_data_size {
+    pub stop_copy_length: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct vfio_precopy_info {
+    pub argsz: u32,
+    pub flags: u32,
+    pub initial_bytes: u64,
+    pub dirty_bytes: u64,
+}
+
+/* ---------------------------------------------------------------------------------------------- */
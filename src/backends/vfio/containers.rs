@@ -3,30 +3,48 @@
 /* ---------------------------------------------------------------------------------------------- */
 
 use std::alloc::{self, Layout};
-use std::collections::{BTreeSet, HashMap};
-use std::fmt::Debug;
-use std::fs::{File, OpenOptions};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt::{self, Debug};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, ErrorKind};
 use std::iter::FromIterator;
 use std::mem;
 use std::ops::Range;
-use std::os::unix::io::AsRawFd;
-use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd};
 use std::os::unix::prelude::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
 
 use crate::backends::vfio::bindings::{
-    vfio_group_status, vfio_info_cap_header, vfio_iommu_type1_dma_map, vfio_iommu_type1_dma_unmap,
-    vfio_iommu_type1_info, VFIO_TYPE1v2_IOMMU, __IncompleteArrayField,
-    vfio_iommu_type1_info_cap_iova_range, vfio_iommu_type1_info_dma_avail, VFIO_API_VERSION,
-    VFIO_DMA_MAP_FLAG_READ, VFIO_DMA_MAP_FLAG_WRITE, VFIO_GROUP_FLAGS_VIABLE,
-    VFIO_IOMMU_INFO_PGSIZES, VFIO_IOMMU_TYPE1_INFO_CAP_IOVA_RANGE, VFIO_IOMMU_TYPE1_INFO_DMA_AVAIL,
-    VFIO_NOIOMMU_IOMMU,
+    __IncompleteArrayField, vfio_group_status, vfio_info_cap_header, vfio_iommu_type1_dma_map,
+    vfio_iommu_type1_dma_unmap, vfio_iommu_type1_info, vfio_iommu_type1_info_cap_iova_range,
+    vfio_iommu_type1_info_dma_avail, VFIO_TYPE1v2_IOMMU, VFIO_API_VERSION, VFIO_DMA_MAP_FLAG_READ,
+    VFIO_DMA_MAP_FLAG_VADDR, VFIO_DMA_MAP_FLAG_WRITE, VFIO_DMA_UNMAP_FLAG_ALL,
+    VFIO_DMA_UNMAP_FLAG_VADDR, VFIO_GROUP_FLAGS_VIABLE, VFIO_IOMMU_INFO_PGSIZES,
+    VFIO_IOMMU_TYPE1_INFO_CAP_IOVA_RANGE, VFIO_IOMMU_TYPE1_INFO_DMA_AVAIL, VFIO_NOIOMMU_IOMMU,
+    VFIO_TYPE1_NESTING_IOMMU, VFIO_UNMAP_ALL, VFIO_UPDATE_VADDR,
+};
+use crate::backends::vfio::bindings_ext::{
+    vfio_device_attach_iommufd_pt, vfio_device_bind_iommufd,
 };
 use crate::backends::vfio::ioctl::{
-    vfio_check_extension, vfio_get_api_version, vfio_group_get_status, vfio_group_set_container,
-    vfio_iommu_get_info, vfio_iommu_map_dma, vfio_iommu_unmap_dma, vfio_set_iommu,
+    iommufd_ioas_alloc, iommufd_ioas_iova_ranges, iommufd_ioas_map, iommufd_ioas_unmap,
+    iommufd_vfio_ioas, vfio_check_extension, vfio_device_attach_iommufd_pt,
+    vfio_device_bind_iommufd, vfio_get_api_version, vfio_group_get_device_fd,
+    vfio_group_get_status, vfio_group_set_container, vfio_iommu_get_info, vfio_iommu_map_dma,
+    vfio_iommu_unmap_dma, vfio_set_iommu,
+};
+use crate::backends::vfio::iommufd::{
+    iommu_ioas_alloc, iommu_ioas_iova_ranges, iommu_ioas_map, iommu_ioas_unmap, iommu_iova_range,
+    iommu_vfio_ioas, IOMMU_IOAS_MAP_FIXED_IOVA, IOMMU_IOAS_MAP_READABLE, IOMMU_IOAS_MAP_WRITEABLE,
+    IOMMU_VFIO_IOAS_SET,
 };
-use crate::iommu::{PciIommu, PciIommuInternal};
+use crate::backends::vfio::reset::{get_hot_reset_devices, get_hot_reset_group_fds, pci_hot_reset};
+use crate::backends::vfio::vfio_group_blockers;
+use crate::enumerate::PciDeviceFilter;
+use crate::iommu::{IommuMapping, PciIommu, PciIommuInternal};
 use crate::regions::Permissions;
 
 /* ---------------------------------------------------------------------------------------------- */
@@ -42,17 +60,27 @@ fn open_group(group_number: u32, noiommu: bool) -> io::Result<File> {
 
     // check if group is viable
 
-    let mut group_status = vfio_group_status {
-        argsz: mem::size_of::<vfio_group_status>() as u32,
-        flags: 0,
-    };
-
-    unsafe { vfio_group_get_status(file.as_raw_fd(), &mut group_status)? };
+    if get_group_status_flags(&file)? & VFIO_GROUP_FLAGS_VIABLE == 0 {
+        let blockers: Vec<String> = vfio_group_blockers(group_number)
+            .unwrap_or_default()
+            .iter()
+            .map(|device| format!("{} ({})", device.address, device.driver.as_ref().unwrap()))
+            .collect();
 
-    if group_status.flags & VFIO_GROUP_FLAGS_VIABLE == 0 {
         return Err(io::Error::new(
             ErrorKind::Other,
-            "Group is not viable; are all devices in the group bound to vfio or unbound?",
+            if blockers.is_empty() {
+                format!(
+                    "Group {} is not viable; are all devices in the group bound to vfio or unbound?",
+                    group_number
+                )
+            } else {
+                format!(
+                    "Group {} is not viable; these devices must be bound to vfio-pci or unbound: {}",
+                    group_number,
+                    blockers.join(", ")
+                )
+            },
         ));
     }
 
@@ -61,8 +89,21 @@ fn open_group(group_number: u32, noiommu: bool) -> io::Result<File> {
     Ok(file)
 }
 
+/// Returns the `VFIO_GROUP_FLAGS_*` of the group with the given file.
+pub(crate) fn get_group_status_flags(group_file: &File) -> io::Result<u32> {
+    let mut group_status = vfio_group_status {
+        argsz: mem::size_of::<vfio_group_status>() as u32,
+        flags: 0,
+    };
+
+    unsafe { vfio_group_get_status(group_file.as_raw_fd(), &mut group_status)? };
+
+    Ok(group_status.flags)
+}
+
 struct IommuInfo {
     iova_alignment: usize,
+    page_sizes: u64,
     max_num_mappings: u32,
     valid_iova_ranges: Box<[Range<u64>]>,
 }
@@ -146,6 +187,7 @@ fn get_iommu_info(device_fd: RawFd) -> io::Result<IommuInfo> {
 
     Ok(IommuInfo {
         iova_alignment,
+        page_sizes: iommu_info.iova_pgsizes,
         max_num_mappings,
         valid_iova_ranges,
     })
@@ -190,18 +232,179 @@ fn get_iommu_dma_avail(info: *const vfio_iommu_type1_info) -> io::Result<u32> {
     Ok(unsafe { (*cap).avail })
 }
 
+fn get_ioas_info(iommufd: RawFd, ioas_id: u32, groups: &[u32]) -> io::Result<IommuInfo> {
+    let mut iova_ranges = iommu_ioas_iova_ranges {
+        size: mem::size_of::<iommu_ioas_iova_ranges>() as u32,
+        ioas_id,
+        ..Default::default()
+    };
+
+    // first call reports how many ranges there are
+
+    let mut ranges: Vec<iommu_iova_range> = Vec::new();
+
+    loop {
+        iova_ranges.num_iovas = ranges.len() as u32;
+        iova_ranges.allowed_iovas = ranges.as_mut_ptr() as u64;
+
+        match unsafe { iommufd_ioas_iova_ranges(iommufd, &mut iova_ranges) } {
+            Ok(_) => break,
+            Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => {
+                ranges.resize(iova_ranges.num_iovas as usize, Default::default());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    ranges.truncate(iova_ranges.num_iovas as usize);
+
+    let iova_alignment = (iova_ranges.out_iova_alignment as usize).max(page_size());
+
+    let mut valid_iova_ranges: Vec<Range<u64>> = ranges
+        .iter()
+        .filter(|r| r.start <= r.last)
+        .map(|r| r.start..r.last.saturating_add(1))
+        .collect();
+
+    // Devices are only attached to the IOAS when they are opened, and only then will their reserved
+    // regions be excluded from the IOAS' ranges, so exclude them ourselves already. Also drop the
+    // first page, as for Type1.
+
+    for r in &mut valid_iova_ranges {
+        r.start = r.start.max(iova_alignment as u64);
+    }
+
+    let valid_iova_ranges = exclude_reserved_regions(valid_iova_ranges, iova_alignment, groups)?;
+
+    Ok(IommuInfo {
+        iova_alignment,
+        // iommufd picks page sizes itself, so any suitably aligned mapping may benefit from bigger
+        // pages
+        page_sizes: !(iova_alignment as u64 - 1),
+        // iommufd imposes no limit on the number of mappings
+        max_num_mappings: u32::MAX,
+        valid_iova_ranges,
+    })
+}
+
+fn alloc_ioas(iommufd: RawFd) -> io::Result<u32> {
+    let mut ioas_alloc = iommu_ioas_alloc {
+        size: mem::size_of::<iommu_ioas_alloc>() as u32,
+        ..Default::default()
+    };
+
+    unsafe { iommufd_ioas_alloc(iommufd, &mut ioas_alloc)? };
+
+    Ok(ioas_alloc.out_ioas_id)
+}
+
+/// Removes the reserved regions of all the given groups from `ranges`, and shrinks the resulting
+/// ranges to multiples of `alignment`.
+///
+/// The kernel doesn't always exclude these from the valid IOVA ranges it reports (_e.g._, the x86
+/// MSI window when using iommufd, or on kernels older than 5.4 when using Type1), and mapping
+/// something there fails in mysterious ways.
+fn exclude_reserved_regions(
+    mut ranges: Vec<Range<u64>>,
+    alignment: usize,
+    groups: &[u32],
+) -> io::Result<Box<[Range<u64>]>> {
+    for &group in groups {
+        for reserved in get_group_reserved_regions(group)? {
+            ranges = ranges
+                .into_iter()
+                .flat_map(|r| {
+                    vec![
+                        r.start..r.end.min(reserved.start),
+                        r.start.max(reserved.end)..r.end,
+                    ]
+                })
+                .filter(|r| r.start < r.end)
+                .collect();
+        }
+    }
+
+    let alignment = alignment as u64;
+
+    let mut ranges: Vec<Range<u64>> = ranges
+        .into_iter()
+        .filter_map(|r| {
+            let start = r.start.checked_add(alignment - 1)? & !(alignment - 1);
+            let end = r.end & !(alignment - 1);
+            if start < end {
+                Some(start..end)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    ranges.sort_by_key(|r| r.start);
+
+    Ok(ranges.into_boxed_slice())
+}
+
+/// Parses `/sys/kernel/iommu_groups/<group>/reserved_regions`, which has lines of the form
+/// `0x00000000fee00000 0x00000000feefffff msi`, with inclusive end addresses.
+fn get_group_reserved_regions(group: u32) -> io::Result<Vec<Range<u64>>> {
+    let path = format!("/sys/kernel/iommu_groups/{}/reserved_regions", group);
+
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        // kernels before 4.11 don't expose reserved regions
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let parse = |s: Option<&str>| {
+        s.and_then(|s| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Failed to parse IOMMU group reserved regions",
+                )
+            })
+    };
+
+    contents
+        .lines()
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let start = parse(fields.next())?;
+            let last = parse(fields.next())?;
+            Ok(start..last.saturating_add(1))
+        })
+        .collect()
+}
+
+pub(crate) fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
 /* ---------------------------------------------------------------------------------------------- */
 
 /// A VFIO container representing an IOMMU context that may contain zero or more VFIO groups.
 #[derive(Debug)]
 pub struct VfioContainer {
-    file: File,
+    pub(crate) file: File,
     group_numbers: Box<[u32]>,
     pub(crate) groups: HashMap<u32, File>,
     iommu_iova_alignment: usize,
+    iommu_page_sizes: u64,
     iommu_max_num_mappings: u32,
     iommu_valid_iova_ranges: Box<[Range<u64>]>,
-    noiommu: bool,
+    backend: VfioIommuBackend,
+    /// Only meaningful with [`VfioIommuBackend::Iommufd`].
+    pub(crate) ioas_id: u32,
+    /// Whether devices were bound through their VFIO cdevs instead of being opened through groups,
+    /// in which case `groups` is empty.
+    device_cdevs: bool,
+    pinned_bytes: AtomicU64,
+    /// Whether `mappings` is `Some`, so that mapping and unmapping don't need to lock it when
+    /// mapping tracking is disabled.
+    mapping_tracking: AtomicBool,
+    /// Mappings currently in effect, keyed by IOVA, or `None` if mapping tracking is disabled.
+    mappings: Mutex<Option<BTreeMap<u64, IommuMapping>>>,
 }
 
 impl VfioContainer {
@@ -213,7 +416,53 @@ impl VfioContainer {
     ///
     /// This fails if any of the groups is already open elsewhere, for instance if another
     /// [`VfioContainer`] containing one of the groups already currently exists.
-    pub fn new(groups: &[u32], noiommu: bool) -> io::Result<VfioContainer> {
+    ///
+    /// This uses the [`VfioIommuBackend::Type1`] backend. See also
+    /// [`VfioContainer::with_iommu_backend`] and [`VfioContainer::new_noiommu`].
+    pub fn new(groups: &[u32]) -> io::Result<VfioContainer> {
+        VfioContainer::create(groups, VfioIommuBackend::Type1)
+    }
+
+    /// Creates a new, empty [`VfioContainer`] in VFIO's no-IOMMU mode, _i.e._, using the
+    /// [`VfioIommuBackend::NoIommu`] backend. The groups must be no-IOMMU groups, _e.g._,
+    /// `/dev/vfio/noiommu-0`, which requires the kernel to be booted with
+    /// `vfio.enable_unsafe_noiommu_mode=1`.
+    ///
+    /// The same requirements as for [`VfioContainer::new`] apply. The resulting container has no
+    /// [`PciIommu`], and its devices can do DMA to any physical address.
+    ///
+    /// # Safety
+    ///
+    /// Nothing isolates the host from the devices in the container. The caller is responsible for
+    /// ensuring that the devices only do DMA to memory that is meant to be accessed by them, which
+    /// requires knowing the physical addresses of that memory and keeping it pinned, and that they
+    /// aren't otherwise made to corrupt memory (_e.g._, by a malicious guest they are assigned to).
+    pub unsafe fn new_noiommu(groups: &[u32]) -> io::Result<VfioContainer> {
+        VfioContainer::create(groups, VfioIommuBackend::NoIommu)
+    }
+
+    /// Creates a new, empty [`VfioContainer`] that uses the given IOMMU backend.
+    ///
+    /// The same requirements as for [`VfioContainer::new`] apply. Fails with
+    /// [`ErrorKind::InvalidInput`] for [`VfioIommuBackend::NoIommu`], for which
+    /// [`VfioContainer::new_noiommu`] must be used instead.
+    pub fn with_iommu_backend(
+        groups: &[u32],
+        backend: VfioIommuBackend,
+    ) -> io::Result<VfioContainer> {
+        if backend == VfioIommuBackend::NoIommu {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "No-IOMMU containers must be created with VfioContainer::new_noiommu()",
+            ));
+        }
+
+        VfioContainer::create(groups, backend)
+    }
+
+    fn create(groups: &[u32], backend: VfioIommuBackend) -> io::Result<VfioContainer> {
+        let noiommu = backend == VfioIommuBackend::NoIommu;
+
         // open groups
 
         let group_numbers = Vec::from(groups)
@@ -227,6 +476,10 @@ impl VfioContainer {
             .map(|&n| Ok((n, open_group(n, noiommu)?)))
             .collect::<io::Result<_>>()?;
 
+        if backend == VfioIommuBackend::Iommufd {
+            return VfioContainer::with_iommufd(group_numbers, groups);
+        }
+
         // create container
 
         let file = OpenOptions::new()
@@ -247,10 +500,10 @@ impl VfioContainer {
 
         // check extension
 
-        let iommu_type = if noiommu {
-            VFIO_NOIOMMU_IOMMU
-        } else {
-            VFIO_TYPE1v2_IOMMU
+        let iommu_type = match backend {
+            VfioIommuBackend::Type1Nesting => VFIO_TYPE1_NESTING_IOMMU,
+            VfioIommuBackend::NoIommu => VFIO_NOIOMMU_IOMMU,
+            _ => VFIO_TYPE1v2_IOMMU,
         };
         if unsafe { vfio_check_extension(fd, iommu_type as usize)? } != 1 {
             return Err(io::Error::new(ErrorKind::InvalidInput, "TODO"));
@@ -270,14 +523,75 @@ impl VfioContainer {
 
         let mut iommu_info = IommuInfo {
             iova_alignment: 0_usize,
+            page_sizes: 0,
             max_num_mappings: 0,
             valid_iova_ranges: Vec::new().into(),
         };
 
-        if iommu_type == VFIO_TYPE1v2_IOMMU {
+        if !noiommu {
             iommu_info = get_iommu_info(fd)?;
+            iommu_info.valid_iova_ranges = exclude_reserved_regions(
+                iommu_info.valid_iova_ranges.into_vec(),
+                iommu_info.iova_alignment,
+                &group_numbers,
+            )?;
+        }
+
+        // success
+
+        Ok(VfioContainer {
+            file,
+            group_numbers,
+            groups,
+            iommu_iova_alignment: iommu_info.iova_alignment,
+            iommu_page_sizes: iommu_info.page_sizes,
+            iommu_max_num_mappings: iommu_info.max_num_mappings,
+            iommu_valid_iova_ranges: iommu_info.valid_iova_ranges,
+            backend,
+            ioas_id: 0,
+            device_cdevs: false,
+            pinned_bytes: AtomicU64::new(0),
+            mapping_tracking: AtomicBool::new(false),
+            mappings: Mutex::new(None),
+        })
+    }
+
+    fn with_iommufd(
+        group_numbers: Box<[u32]>,
+        groups: HashMap<u32, File>,
+    ) -> io::Result<VfioContainer> {
+        // open iommufd
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/iommu")?;
+
+        let fd = file.as_raw_fd();
+
+        // allocate an IOAS and make it the one that devices opened through groups get attached to
+
+        let ioas_id = alloc_ioas(fd)?;
+
+        let mut vfio_ioas = iommu_vfio_ioas {
+            size: mem::size_of::<iommu_vfio_ioas>() as u32,
+            ioas_id,
+            op: IOMMU_VFIO_IOAS_SET,
+            __reserved: 0,
+        };
+
+        unsafe { iommufd_vfio_ioas(fd, &mut vfio_ioas)? };
+
+        // add groups to iommufd
+
+        for group_file in groups.values() {
+            unsafe { vfio_group_set_container(group_file.as_raw_fd(), &fd)? };
         }
 
+        // get IOMMU info
+
+        let iommu_info = get_ioas_info(fd, ioas_id, &group_numbers)?;
+
         // success
 
         Ok(VfioContainer {
@@ -285,28 +599,158 @@ impl VfioContainer {
             group_numbers,
             groups,
             iommu_iova_alignment: iommu_info.iova_alignment,
+            iommu_page_sizes: iommu_info.page_sizes,
+            iommu_max_num_mappings: iommu_info.max_num_mappings,
+            iommu_valid_iova_ranges: iommu_info.valid_iova_ranges,
+            backend: VfioIommuBackend::Iommufd,
+            ioas_id,
+            device_cdevs: false,
+            pinned_bytes: AtomicU64::new(0),
+            mapping_tracking: AtomicBool::new(false),
+            mappings: Mutex::new(None),
+        })
+    }
+
+    /// Creates an iommufd-backed container and binds the given VFIO device cdevs to it, attaching
+    /// them to its IOAS. `group_numbers` are the groups of those devices, whose reserved regions are
+    /// excluded from the valid IOVA ranges.
+    ///
+    /// Devices must be bound before the IOMMU info is queried, since attaching them may restrict the
+    /// usable IOVA ranges, so they can't be added afterwards.
+    pub(crate) fn with_device_cdevs(
+        group_numbers: &[u32],
+        device_files: &[&File],
+    ) -> io::Result<VfioContainer> {
+        let group_numbers = Vec::from(group_numbers)
+            .into_iter()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Box<[_]>>();
+
+        // open iommufd
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/iommu")?;
+
+        let fd = file.as_raw_fd();
+
+        let ioas_id = alloc_ioas(fd)?;
+
+        // bind devices to iommufd and attach them to the IOAS
+
+        for device_file in device_files {
+            let mut bind = vfio_device_bind_iommufd {
+                argsz: mem::size_of::<vfio_device_bind_iommufd>() as u32,
+                flags: 0,
+                iommufd: fd,
+                out_devid: 0,
+            };
+
+            unsafe { vfio_device_bind_iommufd(device_file.as_raw_fd(), &mut bind)? };
+
+            let mut attach = vfio_device_attach_iommufd_pt {
+                argsz: mem::size_of::<vfio_device_attach_iommufd_pt>() as u32,
+                flags: 0,
+                pt_id: ioas_id,
+                pasid: 0,
+            };
+
+            unsafe { vfio_device_attach_iommufd_pt(device_file.as_raw_fd(), &mut attach)? };
+        }
+
+        // get IOMMU info
+
+        let iommu_info = get_ioas_info(fd, ioas_id, &group_numbers)?;
+
+        // success
+
+        Ok(VfioContainer {
+            file,
+            group_numbers,
+            groups: HashMap::new(),
+            iommu_iova_alignment: iommu_info.iova_alignment,
+            iommu_page_sizes: iommu_info.page_sizes,
             iommu_max_num_mappings: iommu_info.max_num_mappings,
             iommu_valid_iova_ranges: iommu_info.valid_iova_ranges,
-            noiommu,
+            backend: VfioIommuBackend::Iommufd,
+            ioas_id,
+            device_cdevs: true,
+            pinned_bytes: AtomicU64::new(0),
+            mapping_tracking: AtomicBool::new(false),
+            mappings: Mutex::new(None),
         })
     }
 
-    /// Creates a new [`VfioContainer`] using already opened vfio file descriptors.
-    pub fn from_raw_fds(
+    /// Creates a new [`VfioContainer`] using already opened vfio file descriptors, of a container
+    /// that uses the [`VfioIommuBackend::Type1`] backend and contains the given group.
+    ///
+    /// # Safety
+    ///
+    /// `container_fd` and `group_fd` must be open file descriptors that nothing else owns. The
+    /// returned container takes ownership of them, and they are closed if this fails.
+    pub unsafe fn from_raw_fds(
         container_fd: i32,
         group: u32,
         group_fd: i32,
-        noiommu: bool,
     ) -> io::Result<VfioContainer> {
-        // open groups
+        unsafe {
+            VfioContainer::from_raw_fds_with_backend(
+                container_fd,
+                group,
+                group_fd,
+                VfioIommuBackend::Type1,
+            )
+        }
+    }
+
+    /// Like [`VfioContainer::from_raw_fds`], but for a container in VFIO's no-IOMMU mode, _i.e._,
+    /// using the [`VfioIommuBackend::NoIommu`] backend.
+    ///
+    /// # Safety
+    ///
+    /// The same requirements as for [`VfioContainer::from_raw_fds`] apply, and so do the
+    /// obligations of [`VfioContainer::new_noiommu`].
+    pub unsafe fn from_raw_fds_noiommu(
+        container_fd: i32,
+        group: u32,
+        group_fd: i32,
+    ) -> io::Result<VfioContainer> {
+        unsafe {
+            VfioContainer::from_raw_fds_with_backend(
+                container_fd,
+                group,
+                group_fd,
+                VfioIommuBackend::NoIommu,
+            )
+        }
+    }
 
+    unsafe fn from_raw_fds_with_backend(
+        container_fd: i32,
+        group: u32,
+        group_fd: i32,
+        backend: VfioIommuBackend,
+    ) -> io::Result<VfioContainer> {
         // TODO: add support for multiple groups, if needed
-        let group_numbers = Box::new([group]);
         let groups = unsafe { HashMap::from_iter(vec![(group, File::from_raw_fd(group_fd))]) };
+        let file = unsafe { File::from_raw_fd(container_fd) };
 
-        // open container
+        VfioContainer::from_files(file, groups, backend)
+    }
 
-        let file = unsafe { File::from_raw_fd(container_fd) };
+    /// Creates a new [`VfioContainer`] from an already set up container file and the files of the
+    /// groups in it, which must use one of the Type1 backends or [`VfioIommuBackend::NoIommu`].
+    pub(crate) fn from_files(
+        file: File,
+        groups: HashMap<u32, File>,
+        backend: VfioIommuBackend,
+    ) -> io::Result<VfioContainer> {
+        let container_fd = file.as_raw_fd();
+
+        let mut group_numbers: Box<[u32]> = groups.keys().copied().collect();
+        group_numbers.sort_unstable();
 
         // check API version
 
@@ -319,10 +763,16 @@ impl VfioContainer {
 
         // check extension
 
-        let iommu_type = if noiommu {
-            VFIO_NOIOMMU_IOMMU
-        } else {
-            VFIO_TYPE1v2_IOMMU
+        let iommu_type = match backend {
+            VfioIommuBackend::Type1 => VFIO_TYPE1v2_IOMMU,
+            VfioIommuBackend::Type1Nesting => VFIO_TYPE1_NESTING_IOMMU,
+            VfioIommuBackend::NoIommu => VFIO_NOIOMMU_IOMMU,
+            VfioIommuBackend::Iommufd => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "iommufd-backed containers can't be created from raw fds",
+                ))
+            }
         };
         if unsafe { vfio_check_extension(container_fd, iommu_type as usize)? } != 1 {
             return Err(io::Error::new(ErrorKind::InvalidInput, "TODO"));
@@ -332,12 +782,18 @@ impl VfioContainer {
 
         let mut iommu_info = IommuInfo {
             iova_alignment: 0_usize,
+            page_sizes: 0,
             max_num_mappings: 0,
             valid_iova_ranges: Vec::new().into(),
         };
 
-        if iommu_type == VFIO_TYPE1v2_IOMMU {
+        if backend != VfioIommuBackend::NoIommu {
             iommu_info = get_iommu_info(container_fd)?;
+            iommu_info.valid_iova_ranges = exclude_reserved_regions(
+                iommu_info.valid_iova_ranges.into_vec(),
+                iommu_info.iova_alignment,
+                &group_numbers[..],
+            )?;
         }
 
         Ok(VfioContainer {
@@ -345,77 +801,517 @@ impl VfioContainer {
             group_numbers,
             groups,
             iommu_iova_alignment: iommu_info.iova_alignment,
+            iommu_page_sizes: iommu_info.page_sizes,
             iommu_max_num_mappings: iommu_info.max_num_mappings,
             iommu_valid_iova_ranges: iommu_info.valid_iova_ranges,
-            noiommu,
+            backend,
+            ioas_id: 0,
+            device_cdevs: false,
+            pinned_bytes: AtomicU64::new(0),
+            mapping_tracking: AtomicBool::new(false),
+            mappings: Mutex::new(None),
         })
     }
 
     /// The group numbers of the groups this container contains.
     ///
-    /// In ascending order, without duplicates.
+    /// In ascending order, without duplicates. For containers created by
+    /// [`VfioPciDevice::open_cdev`](crate::backends::vfio::VfioPciDevice::open_cdev), these are the
+    /// groups of the devices that were bound to it, even though the groups themselves were never
+    /// opened.
     pub fn groups(&self) -> &[u32] {
         &self.group_numbers
     }
 
     /// Returns a mapping from group number to file that belongs to this group.
+    ///
+    /// This is empty for containers created by
+    /// [`VfioPciDevice::open_cdev`](crate::backends::vfio::VfioPciDevice::open_cdev).
     pub fn group_files(&self) -> &HashMap<u32, File> {
         &self.groups
     }
 
+    /// Returns the file of the group with the given number, or `None` if the container doesn't
+    /// contain it or was created by
+    /// [`VfioPciDevice::open_cdev`](crate::backends::vfio::VfioPciDevice::open_cdev).
+    pub fn group_file(&self, group_number: u32) -> Option<&File> {
+        self.groups.get(&group_number)
+    }
+
+    /// Whether this container is in VFIO's no-IOMMU mode, in which case nothing isolates the host
+    /// from its devices' DMA. See [`VfioContainer::new_noiommu`].
+    pub fn is_noiommu(&self) -> bool {
+        self.backend == VfioIommuBackend::NoIommu
+    }
+
+    /// Whether devices were bound to this container through their VFIO cdevs rather than opened
+    /// through its groups.
+    pub fn uses_device_cdevs(&self) -> bool {
+        self.device_cdevs
+    }
+
     /// Returns a thing that lets you manage IOMMU mappings for DMA for all devices in all groups
     /// that belong to this container.
-    pub fn iommu(&self) -> Option<PciIommu> {
-        if self.noiommu {
+    pub fn iommu(&self) -> Option<PciIommu<'_>> {
+        if self.backend == VfioIommuBackend::NoIommu {
             None
         } else {
             Some(PciIommu { internal: self })
         }
     }
 
-    /// Tries to reset all the PCI functions in all the VFIO groups that `self` refers to.
+    /// Enables or disables tracking of the IOMMU mappings created through this container.
+    ///
+    /// While enabled, every mapping added or removed through [`VfioContainer::iommu`] (or through
+    /// the IOMMU of any device in this container) is recorded, which makes it possible to use
+    /// [`VfioContainer::list_mappings`], [`VfioContainer::find_mapping`],
+    /// [`VfioContainer::lookup`], [`VfioContainer::va_to_iova`], [`PciIommu::remap`], and
+    /// [`PciIommu::protect`], as well as [`VfioContainer::unmap_all`] on kernels that can't remove
+    /// all mappings at once. Tracking is disabled by default.
+    ///
+    /// Mappings that already exist when tracking is enabled are _not_ recorded, so you probably
+    /// want to enable it before creating any. Disabling tracking forgets all recorded mappings
+    /// without removing them.
+    pub fn set_mapping_tracking(&self, enabled: bool) {
+        let mut mappings = self.mappings.lock().unwrap();
+
+        if enabled != mappings.is_some() {
+            *mappings = if enabled { Some(BTreeMap::new()) } else { None };
+            self.mapping_tracking.store(enabled, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether mapping tracking is enabled. See [`VfioContainer::set_mapping_tracking`].
+    pub fn is_mapping_tracking_enabled(&self) -> bool {
+        self.mapping_tracking.load(Ordering::Relaxed)
+    }
+
+    /// Calls `f` on the tracked mappings and returns its result, or returns `None` without locking
+    /// anything if mapping tracking is disabled.
+    ///
+    /// The lock is never held across map and unmap ioctls, so that concurrent mapping operations
+    /// don't serialize on it.
+    fn with_tracked_mappings<T>(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<u64, IommuMapping>) -> T,
+    ) -> Option<T> {
+        if !self.mapping_tracking.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        self.mappings.lock().unwrap().as_mut().map(f)
+    }
+
+    /// Returns all tracked mappings currently in effect, sorted by IOVA.
+    ///
+    /// This is always empty if mapping tracking is disabled.
+    pub fn list_mappings(&self) -> Vec<IommuMapping> {
+        match &*self.mappings.lock().unwrap() {
+            Some(mappings) => mappings.values().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the tracked mapping that contains the given IOVA, if any.
+    ///
+    /// This always returns `None` if mapping tracking is disabled.
+    pub fn find_mapping(&self, iova: u64) -> Option<IommuMapping> {
+        let mappings = self.mappings.lock().unwrap();
+        let (_, mapping) = mappings.as_ref()?.range(..=iova).next_back()?;
+
+        if iova - mapping.iova < mapping.length as u64 {
+            Some(*mapping)
+        } else {
+            None
+        }
+    }
+
+    /// Translates an IOVA into the process address it is mapped to, according to the tracked
+    /// mappings.
+    ///
+    /// Returns that address, the number of bytes from it to the end of the mapping, and the
+    /// device's permissions on the mapping, or `None` if the IOVA isn't in any tracked mapping.
+    /// This always returns `None` if mapping tracking is disabled.
+    pub fn lookup(&self, iova: u64) -> Option<(*const u8, usize, Permissions)> {
+        let mapping = self.find_mapping(iova)?;
+        let offset = (iova - mapping.iova) as usize;
+
+        Some((
+            mapping.address.wrapping_add(offset),
+            mapping.length - offset,
+            mapping.device_permissions,
+        ))
+    }
+
+    /// Translates a process address into the IOVA it is mapped at, according to the tracked
+    /// mappings.
+    ///
+    /// If the address is mapped at several IOVAs, the lowest one is returned. Returns `None` if
+    /// the address isn't in any tracked mapping, or if mapping tracking is disabled.
+    ///
+    /// Unlike [`VfioContainer::lookup`], this takes time linear in the number of mappings.
+    pub fn va_to_iova(&self, address: *const u8) -> Option<u64> {
+        let mappings = self.mappings.lock().unwrap();
+
+        mappings.as_ref()?.values().find_map(|mapping| {
+            let offset = (address as usize).wrapping_sub(mapping.address as usize);
+            if offset < mapping.length {
+                Some(mapping.iova + offset as u64)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Whether the kernel supports [`VfioContainer::invalidate_vaddr`] and
+    /// [`VfioContainer::update_vaddr`], which requires Linux 5.12 or later and the
+    /// [`VfioIommuBackend::Type1`] backend.
+    pub fn supports_vaddr_update(&self) -> io::Result<bool> {
+        if self.backend != VfioIommuBackend::Type1 {
+            return Ok(false);
+        }
+
+        let fd = self.file.as_raw_fd();
+        Ok(unsafe { vfio_check_extension(fd, VFIO_UPDATE_VADDR as usize)? } == 1)
+    }
+
+    /// Tells the kernel that the process memory backing the mappings in the given IOVA range is
+    /// about to go away, without removing the mappings.
+    ///
+    /// This is meant for live update: a process can invalidate the process addresses of its
+    /// mappings, exec a new version of itself (passing it the container file descriptor, see
+    /// [`VfioContainer::from_raw_fds`]), and have that register the new addresses of the same
+    /// memory with [`VfioContainer::update_vaddr`]. The device can keep doing DMA throughout.
+    ///
+    /// In the meantime, operations that need to access the process memory, like adding mappings
+    /// that need pinning, block until the addresses are updated. The range must not cover only
+    /// part of a mapping.
+    pub fn invalidate_vaddr(&self, iova: u64, size: usize) -> io::Result<()> {
+        self.require_vaddr_update()?;
+
+        let mut dma_unmap = vfio_iommu_type1_dma_unmap {
+            argsz: mem::size_of::<vfio_iommu_type1_dma_unmap>() as u32,
+            flags: VFIO_DMA_UNMAP_FLAG_VADDR,
+            iova,
+            size: size as u64,
+            data: __IncompleteArrayField::new(),
+        };
+
+        unsafe { vfio_iommu_unmap_dma(self.file.as_raw_fd(), &mut dma_unmap)? };
+
+        Ok(())
+    }
+
+    /// Registers a new process address for a mapping whose process address was invalidated with
+    /// [`VfioContainer::invalidate_vaddr`].
     ///
-    /// This requires that the user has "ownership" over all the affected functions / permissions to
-    /// do it.
+    /// `iova` and `size` must match exactly those of a single mapping. If mapping tracking is
+    /// enabled, the tracked mapping is updated too.
     ///
-    /// TODO: Reset granularity might not match container granularity. Will probably need to expose
-    /// reset topology properly eventually.
+    /// # Safety
     ///
-    /// TODO: Should probably advertise whether this granularity of reset is supported, so the user
-    /// doesn't have to try resetting to find out.
+    /// `new_address` must refer to the same memory that was previously mapped at `iova`, _e.g._,
+    /// the same shared memory file mapped again in the new process, and otherwise satisfy the
+    /// requirements of [`PciIommu::map`].
+    pub unsafe fn update_vaddr(
+        &self,
+        iova: u64,
+        size: usize,
+        new_address: *const u8,
+    ) -> io::Result<()> {
+        self.require_vaddr_update()?;
+
+        let dma_map = vfio_iommu_type1_dma_map {
+            argsz: mem::size_of::<vfio_iommu_type1_dma_map>() as u32,
+            flags: VFIO_DMA_MAP_FLAG_VADDR,
+            vaddr: new_address as u64,
+            iova,
+            size: size as u64,
+        };
+
+        unsafe { vfio_iommu_map_dma(self.file.as_raw_fd(), &dma_map)? };
+
+        self.with_tracked_mappings(|mappings| {
+            if let Some(mapping) = mappings.get_mut(&iova) {
+                mapping.address = new_address;
+            }
+        });
+
+        Ok(())
+    }
+
+    fn require_vaddr_update(&self) -> io::Result<()> {
+        if self.supports_vaddr_update()? {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                ErrorKind::Other,
+                "Updating process addresses of mappings requires the Type1 IOMMU backend and \
+                 Linux 5.12 or later",
+            ))
+        }
+    }
+
+    /// Whether the kernel can remove all of the container's mappings in a single operation, which
+    /// is the case with the [`VfioIommuBackend::Iommufd`] backend, and with the Type1 backends on
+    /// Linux 5.12 or later. See [`VfioContainer::unmap_all`].
+    pub fn supports_unmap_all(&self) -> io::Result<bool> {
+        match self.backend {
+            VfioIommuBackend::Iommufd => Ok(true),
+            VfioIommuBackend::NoIommu => Ok(false),
+            _ => {
+                let fd = self.file.as_raw_fd();
+                Ok(unsafe { vfio_check_extension(fd, VFIO_UNMAP_ALL as usize)? } == 1)
+            }
+        }
+    }
+
+    /// Removes all mappings from the IOMMU.
+    ///
+    /// If [`VfioContainer::supports_unmap_all`], this is done in a single operation that also
+    /// removes mappings that aren't tracked, and leaves the container as if it had just been
+    /// created, except for the mapping tracking setting.
+    ///
+    /// Otherwise, this falls back to removing the tracked mappings one by one, and so fails if
+    /// mapping tracking is disabled. If removing some mapping fails, this stops and returns the
+    /// error, and that mapping and the ones not yet removed remain tracked.
+    pub fn unmap_all(&self) -> io::Result<()> {
+        if self.supports_unmap_all()? {
+            if self.backend == VfioIommuBackend::Iommufd {
+                // iommufd treats this range as meaning all mappings
+                self.ioas_unmap(0, u64::MAX)?;
+            } else {
+                self.type1_unmap_all()?;
+            }
+
+            self.pinned_bytes.store(0, Ordering::Relaxed);
+            self.with_tracked_mappings(BTreeMap::clear);
+
+            return Ok(());
+        }
+
+        let tracked: Vec<(u64, usize)> = self
+            .with_tracked_mappings(|mappings| {
+                mappings.values().map(|m| (m.iova, m.length)).collect()
+            })
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidInput, "Mapping tracking is not enabled")
+            })?;
+
+        for (iova, length) in tracked {
+            PciIommuInternal::unmap(self, iova, length)?;
+        }
+
+        Ok(())
+    }
+
+    /// Tries to reset all the PCI functions in all the VFIO groups that `self` refers to, through
+    /// secondary bus resets or slot resets.
+    ///
+    /// Each such reset may also affect functions in other groups. All of those groups must be in
+    /// this container, and this fails with [`ErrorKind::PermissionDenied`] otherwise, naming the
+    /// groups that are missing. Functions that aren't bound to a VFIO driver, _e.g._, bridges, are
+    /// skipped.
+    ///
+    /// Use [`PciDevice::reset_capabilities`](crate::device::PciDevice::reset_capabilities) on the
+    /// functions to find out whether they support being reset this way. This isn't supported for
+    /// containers created by
+    /// [`VfioPciDevice::open_cdev`](crate::backends::vfio::VfioPciDevice::open_cdev), whose
+    /// functions can instead be reset individually with
+    /// [`VfioPciDevice::hot_reset`](crate::backends::vfio::VfioPciDevice::hot_reset).
     pub fn reset(&self) -> io::Result<()> {
-        // TODO: Implement.
-        Err(io::Error::new(ErrorKind::Other, "not yet implemented"))
+        if self.device_cdevs {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Containers with devices bound through their VFIO cdevs can't be reset as a whole",
+            ));
+        }
+
+        let mut already_reset = HashSet::new();
+
+        for group_number in self.group_numbers.iter() {
+            let group_file = &self.groups[group_number];
+
+            for entry in PciDeviceFilter::new()
+                .iommu_group(*group_number)
+                .devices()?
+            {
+                if already_reset.contains(&entry.address) {
+                    continue;
+                }
+
+                let address = CString::new(entry.address.to_string()).unwrap();
+
+                let device_file = match unsafe {
+                    vfio_group_get_device_fd(group_file.as_raw_fd(), address.as_ptr())
+                } {
+                    Ok(fd) => unsafe { File::from_raw_fd(fd) },
+                    // not bound to a VFIO driver
+                    Err(e) if e.raw_os_error() == Some(libc::ENODEV) => continue,
+                    Err(e) => return Err(e),
+                };
+
+                let devices = get_hot_reset_devices(device_file.as_raw_fd())?;
+                let group_fds = get_hot_reset_group_fds(&self.groups, &devices)?;
+
+                pci_hot_reset(device_file.as_raw_fd(), &group_fds)?;
+
+                already_reset.extend(devices.iter().map(|device| device.address));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The IOMMU backend this container uses.
+    pub fn iommu_backend(&self) -> VfioIommuBackend {
+        self.backend
+    }
+
+    /// The number of bytes of process memory currently mapped through this container, and thus
+    /// pinned by the kernel.
+    ///
+    /// Pinned memory counts against the `RLIMIT_MEMLOCK` resource limit, unless the process has
+    /// the `CAP_IPC_LOCK` capability. If adding a mapping fails because of that limit, the
+    /// returned error wraps a [`MemlockLimitExceeded`] value.
+    pub fn pinned_bytes(&self) -> u64 {
+        self.pinned_bytes.load(Ordering::Relaxed)
     }
 
     /// Returns the raw file descriptor of the container.
+    ///
+    /// With [`VfioIommuBackend::Iommufd`], this is the `/dev/iommu` file descriptor.
     pub fn as_raw_fd(&self) -> RawFd {
         self.file.as_raw_fd()
     }
-}
 
-impl PciIommuInternal for VfioContainer {
-    fn alignment(&self) -> usize {
-        self.iommu_iova_alignment
+    unsafe fn map_untracked(
+        &self,
+        iova: u64,
+        size: usize,
+        address: *const u8,
+        device_permissions: Permissions,
+    ) -> io::Result<()> {
+        let result = if self.backend == VfioIommuBackend::Iommufd {
+            unsafe { self.ioas_map(iova, size, address, device_permissions) }
+        } else {
+            unsafe { self.type1_map(iova, size, address, device_permissions) }
+        };
+
+        if let Err(e) = result {
+            // The kernel reports running into the locked memory limit as ENOMEM, which isn't very
+            // helpful, so give a better explanation if that is likely what happened. Other
+            // containers and mlock() also count against the limit, so look at the whole process.
+
+            if e.raw_os_error() == Some(libc::ENOMEM) {
+                let pinned = process_locked_bytes().unwrap_or_else(|| self.pinned_bytes());
+
+                if let Some(limit) = memlock_limit() {
+                    if pinned.saturating_add(size as u64) > limit {
+                        return Err(io::Error::new(
+                            ErrorKind::Other,
+                            MemlockLimitExceeded {
+                                limit,
+                                pinned,
+                                requested: size as u64,
+                            },
+                        ));
+                    }
+                }
+            }
+
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                format!(
+                    "Failed to set up IOMMU mapping process memory [{:#x}, {:#x}) to device \
+                    memory [{:#x}, {:#x}): {}",
+                    address as usize,
+                    address as usize + size,
+                    iova,
+                    iova + size as u64,
+                    e
+                ),
+            ));
+        }
+
+        self.pinned_bytes.fetch_add(size as u64, Ordering::Relaxed);
+
+        Ok(())
     }
 
-    fn valid_iova_ranges(&self) -> &[Range<u64>] {
-        &self.iommu_valid_iova_ranges
+    /// Returns the number of bytes that were actually unmapped.
+    fn unmap_untracked(&self, iova: u64, size: usize) -> io::Result<u64> {
+        let unmapped_size = if self.backend == VfioIommuBackend::Iommufd {
+            self.ioas_unmap(iova, size as u64)?
+        } else {
+            self.type1_unmap(iova, size)?
+        };
+
+        let _ = self
+            .pinned_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pinned| {
+                Some(pinned.saturating_sub(unmapped_size))
+            });
+
+        Ok(unmapped_size)
     }
 
-    fn max_num_mappings(&self) -> u32 {
-        self.iommu_max_num_mappings
+    /// Replaces the tracked mapping at exactly `[iova, iova + length)` with the one that `replace`
+    /// returns, trying to restore the old mapping if adding the new one fails.
+    unsafe fn replace_tracked(
+        &self,
+        iova: u64,
+        length: usize,
+        replace: impl FnOnce(IommuMapping) -> IommuMapping,
+    ) -> io::Result<()> {
+        let old = self
+            .with_tracked_mappings(|mappings| mappings.get(&iova).copied())
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidInput, "Mapping tracking is not enabled")
+            })?;
+
+        let old = match old {
+            Some(old) if old.length == length => old,
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "No tracked mapping of device memory [{:#x}, {:#x})",
+                        iova,
+                        iova + length as u64
+                    ),
+                ))
+            }
+        };
+
+        let new = replace(old);
+
+        self.unmap_untracked(iova, length)?;
+
+        if let Err(e) =
+            unsafe { self.map_untracked(iova, length, new.address, new.device_permissions) }
+        {
+            // try to put the old mapping back, and stop tracking it if that fails too
+            if unsafe { self.map_untracked(iova, length, old.address, old.device_permissions) }
+                .is_err()
+            {
+                self.with_tracked_mappings(|mappings| mappings.remove(&iova));
+            }
+            return Err(e);
+        }
+
+        self.with_tracked_mappings(|mappings| mappings.insert(iova, new));
+
+        Ok(())
     }
 
-    unsafe fn map(
+    unsafe fn type1_map(
         &self,
         iova: u64,
         size: usize,
         address: *const u8,
         device_permissions: Permissions,
     ) -> io::Result<()> {
-        // map region
-
         let flags = match device_permissions {
             Permissions::Read => VFIO_DMA_MAP_FLAG_READ,
             Permissions::Write => VFIO_DMA_MAP_FLAG_WRITE,
@@ -430,27 +1326,12 @@ impl PciIommuInternal for VfioContainer {
             size: size as u64,
         };
 
-        unsafe { vfio_iommu_map_dma(self.file.as_raw_fd(), &dma_map) }.map_err(|e| {
-            io::Error::new(
-                ErrorKind::Other,
-                format!(
-                    "Failed to set up IOMMU mapping process memory [{:#x}, {:#x}) to device \
-                    memory [{:#x}, {:#x}): {}",
-                    address as usize,
-                    address as usize + size,
-                    iova,
-                    iova + size as u64,
-                    e
-                ),
-            )
-        })?;
-
-        // success
+        unsafe { vfio_iommu_map_dma(self.file.as_raw_fd(), &dma_map)? };
 
         Ok(())
     }
 
-    fn unmap(&self, iova: u64, size: usize) -> io::Result<()> {
+    fn type1_unmap(&self, iova: u64, size: usize) -> io::Result<u64> {
         let mut dma_unmap = vfio_iommu_type1_dma_unmap {
             argsz: mem::size_of::<vfio_iommu_type1_dma_unmap>() as u32,
             flags: 0,
@@ -461,8 +1342,323 @@ impl PciIommuInternal for VfioContainer {
 
         unsafe { vfio_iommu_unmap_dma(self.file.as_raw_fd(), &mut dma_unmap)? };
 
+        // the kernel sets this to how much was actually unmapped
+        Ok(dma_unmap.size)
+    }
+
+    fn type1_unmap_all(&self) -> io::Result<()> {
+        let mut dma_unmap = vfio_iommu_type1_dma_unmap {
+            argsz: mem::size_of::<vfio_iommu_type1_dma_unmap>() as u32,
+            flags: VFIO_DMA_UNMAP_FLAG_ALL,
+            iova: 0,
+            size: 0,
+            data: __IncompleteArrayField::new(),
+        };
+
+        unsafe { vfio_iommu_unmap_dma(self.file.as_raw_fd(), &mut dma_unmap)? };
+
+        Ok(())
+    }
+
+    unsafe fn ioas_map(
+        &self,
+        iova: u64,
+        size: usize,
+        address: *const u8,
+        device_permissions: Permissions,
+    ) -> io::Result<()> {
+        let flags = match device_permissions {
+            Permissions::Read => IOMMU_IOAS_MAP_READABLE,
+            Permissions::Write => IOMMU_IOAS_MAP_WRITEABLE,
+            Permissions::ReadWrite => IOMMU_IOAS_MAP_READABLE | IOMMU_IOAS_MAP_WRITEABLE,
+        };
+
+        let mut ioas_map = iommu_ioas_map {
+            size: mem::size_of::<iommu_ioas_map>() as u32,
+            flags: flags | IOMMU_IOAS_MAP_FIXED_IOVA,
+            ioas_id: self.ioas_id,
+            __reserved: 0,
+            user_va: address as u64,
+            length: size as u64,
+            iova,
+        };
+
+        unsafe { iommufd_ioas_map(self.file.as_raw_fd(), &mut ioas_map)? };
+
+        Ok(())
+    }
+
+    fn ioas_unmap(&self, iova: u64, length: u64) -> io::Result<u64> {
+        let mut ioas_unmap = iommu_ioas_unmap {
+            size: mem::size_of::<iommu_ioas_unmap>() as u32,
+            ioas_id: self.ioas_id,
+            iova,
+            length,
+        };
+
+        unsafe { iommufd_ioas_unmap(self.file.as_raw_fd(), &mut ioas_unmap)? };
+
+        // the kernel sets this to how much was actually unmapped
+        Ok(ioas_unmap.length)
+    }
+}
+
+/// The container's file descriptor, which is a VFIO container, or an iommufd with the
+/// [`VfioIommuBackend::Iommufd`] backend.
+impl AsFd for VfioContainer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl AsRawFd for VfioContainer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// The error wrapped by the [`io::Error`] returned when adding an IOMMU mapping fails in a way that
+/// suggests it would exceed the process' locked memory limit (`RLIMIT_MEMLOCK`).
+///
+/// The kernel doesn't say why mappings fail, so this is only a diagnosis: the failure was `ENOMEM`,
+/// and the memory the process already had locked or pinned plus the mapping exceed the limit.
+/// Depending on the IOMMU backend and kernel version, the limit may actually be applied to all of
+/// the user's processes.
+///
+/// You can get at it by calling [`io::Error::get_ref`] and then `downcast_ref` on the result.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MemlockLimitExceeded {
+    /// The current `RLIMIT_MEMLOCK` soft limit, in bytes.
+    pub limit: u64,
+    /// The number of bytes the process already had locked or pinned, according to the `VmLck` and
+    /// `VmPin` fields of `/proc/self/status`, or, if those can't be read, the number pinned by the
+    /// container (see [`VfioContainer::pinned_bytes`]).
+    pub pinned: u64,
+    /// The number of bytes that the failed mapping would have pinned.
+    pub requested: u64,
+}
+
+impl fmt::Display for MemlockLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Mapping {:#x} bytes failed, likely because with {:#x} bytes already locked by the \
+            process it would exceed the locked memory limit (RLIMIT_MEMLOCK) of {:#x} bytes; if so, \
+            raise it (e.g., using `ulimit -l`) or grant the process CAP_IPC_LOCK",
+            self.requested, self.pinned, self.limit
+        )
+    }
+}
+
+impl Error for MemlockLimitExceeded {}
+
+/// Returns the current `RLIMIT_MEMLOCK` soft limit, or `None` if there is none.
+fn memlock_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        None
+    } else {
+        #[allow(clippy::unnecessary_cast)] // rlim_t is 32 bits wide on some targets
+        Some(limit.rlim_cur as u64)
+    }
+}
+
+/// Returns the number of bytes of memory that the current process has locked or pinned, or `None` if
+/// that can't be determined.
+fn process_locked_bytes() -> Option<u64> {
+    parse_locked_bytes(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+/// Adds up the `VmLck` (_e.g._, Type1 mappings and `mlock()`) and `VmPin` (_e.g._, iommufd
+/// mappings) fields of the contents of a `/proc/<pid>/status` file.
+fn parse_locked_bytes(status: &str) -> Option<u64> {
+    let mut total = None;
+
+    for line in status.lines() {
+        let value = match line.split_once(':') {
+            Some(("VmLck", value)) | Some(("VmPin", value)) => value,
+            _ => continue,
+        };
+
+        let kib: u64 = value.trim().strip_suffix("kB")?.trim().parse().ok()?;
+        total = Some(total.unwrap_or(0) + kib * 1024);
+    }
+
+    total
+}
+
+/// The ways in which a [`VfioContainer`] can manage the IOMMU.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VfioIommuBackend {
+    /// The legacy VFIO Type1 IOMMU, driven through a `/dev/vfio/vfio` container.
+    Type1,
+    /// Like [`VfioIommuBackend::Type1`], but with the IOMMU set up for nested translation
+    /// (`VFIO_TYPE1_NESTING_IOMMU`).
+    ///
+    /// Mappings added through [`PciIommu`] then make up the second translation stage (_e.g._,
+    /// guest physical to host physical addresses), on top of which a first stage (_e.g._, guest
+    /// IOVA to guest physical addresses) can be configured by a paravirtual or emulated IOMMU.
+    ///
+    /// Only some IOMMUs support this, notably the Arm SMMUv3, and recent Linux versions have dropped
+    /// it in favor of iommufd.
+    ///
+    /// This only selects the nesting IOMMU type; this crate provides no way of configuring the first
+    /// stage. Mainline Linux never had a VFIO interface for that, so there is nothing portable to
+    /// wrap. If your kernel has such an interface, you can drive it by issuing its ioctls on the
+    /// container with [`vfio_ioctl`](crate::backends::vfio::vfio_ioctl).
+    Type1Nesting,
+    /// An I/O address space (IOAS) of the iommufd subsystem, driven through `/dev/iommu`.
+    ///
+    /// Requires Linux 6.2 or later.
+    Iommufd,
+    /// VFIO's no-IOMMU mode, which lets devices do DMA to arbitrary memory. No
+    /// [`PciIommu`] is available in this mode.
+    ///
+    /// Containers can only be put in this mode through the unsafe [`VfioContainer::new_noiommu`],
+    /// or be created in it by the unsafe [`VfioContainer::from_raw_fds_noiommu`] and
+    /// [`VfioContainer::receive_noiommu_from`].
+    NoIommu,
+}
+
+impl PciIommuInternal for VfioContainer {
+    fn alignment(&self) -> usize {
+        self.iommu_iova_alignment
+    }
+
+    fn page_sizes(&self) -> u64 {
+        self.iommu_page_sizes
+    }
+
+    fn valid_iova_ranges(&self) -> &[Range<u64>] {
+        &self.iommu_valid_iova_ranges
+    }
+
+    fn max_num_mappings(&self) -> u32 {
+        self.iommu_max_num_mappings
+    }
+
+    fn num_mappings(&self) -> io::Result<u32> {
+        match self.backend {
+            VfioIommuBackend::Type1 | VfioIommuBackend::Type1Nesting => {
+                // the kernel reports how many more mappings can be added
+                let available = get_iommu_info(self.file.as_raw_fd())?.max_num_mappings;
+                Ok(self.iommu_max_num_mappings.saturating_sub(available))
+            }
+            _ => match &*self.mappings.lock().unwrap() {
+                Some(mappings) => Ok(mappings.len() as u32),
+                None => Err(io::Error::new(
+                    ErrorKind::Other,
+                    "Mapping tracking must be enabled to count mappings",
+                )),
+            },
+        }
+    }
+
+    unsafe fn map(
+        &self,
+        iova: u64,
+        size: usize,
+        address: *const u8,
+        device_permissions: Permissions,
+    ) -> io::Result<()> {
+        unsafe { self.map_untracked(iova, size, address, device_permissions)? };
+
+        self.with_tracked_mappings(|mappings| {
+            let mapping = IommuMapping {
+                iova,
+                length: size,
+                address,
+                device_permissions,
+            };
+            mappings.insert(iova, mapping);
+        });
+
+        Ok(())
+    }
+
+    fn unmap(&self, iova: u64, size: usize) -> io::Result<()> {
+        self.unmap_range(iova, size)?;
         Ok(())
     }
+
+    fn unmap_all(&self) -> io::Result<()> {
+        VfioContainer::unmap_all(self)
+    }
+
+    fn unmap_range(&self, iova: u64, size: usize) -> io::Result<u64> {
+        let unmapped_size = self.unmap_untracked(iova, size)?;
+
+        self.with_tracked_mappings(|mappings| {
+            let end = iova.saturating_add(size as u64);
+            let unmapped: Vec<u64> = mappings.range(iova..end).map(|(&iova, _)| iova).collect();
+            for iova in unmapped {
+                mappings.remove(&iova);
+            }
+        });
+
+        Ok(unmapped_size)
+    }
+
+    fn attach_pasid(&self, _pasid: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "PASIDs must be attached through the IOMMU of a device, not of its container",
+        ))
+    }
+
+    fn detach_pasid(&self, _pasid: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "PASIDs must be detached through the IOMMU of a device, not of its container",
+        ))
+    }
+
+    unsafe fn remap(&self, iova: u64, length: usize, new_address: *const u8) -> io::Result<()> {
+        unsafe {
+            self.replace_tracked(iova, length, |old| IommuMapping {
+                address: new_address,
+                ..old
+            })
+        }
+    }
+
+    unsafe fn protect(
+        &self,
+        iova: u64,
+        length: usize,
+        device_permissions: Permissions,
+    ) -> io::Result<()> {
+        unsafe {
+            self.replace_tracked(iova, length, |old| IommuMapping {
+                device_permissions,
+                ..old
+            })
+        }
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use super::parse_locked_bytes;
+
+    #[test]
+    fn test_parse_locked_bytes() {
+        let status = "Name:\tcat\nVmPeak:\t    8192 kB\nVmLck:\t      16 kB\nVmPin:\t    1024 kB\n\
+                      VmHWM:\t    1024 kB\n";
+        assert_eq!(parse_locked_bytes(status), Some(1040 * 1024));
+
+        assert_eq!(parse_locked_bytes("VmLck:\t       4 kB\n"), Some(4096));
+        assert_eq!(parse_locked_bytes("Name:\tcat\n"), None);
+        assert_eq!(parse_locked_bytes("VmLck:\t   junk kB\n"), None);
+    }
 }
 
 /* ---------------------------------------------------------------------------------------------- */
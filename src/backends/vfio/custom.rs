@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::{c_ulong, ioctl};
+use std::convert::TryInto;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::slice;
+
+use crate::backends::vfio::ioctl::{ioctl_cmd, ioctl_return_to_result};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Returns the request number of the VFIO ioctl with the given index, _i.e._,
+/// `_IO(VFIO_TYPE, VFIO_BASE + index)`.
+///
+/// For instance, `VFIO_DEVICE_GET_INFO` has index 7.
+pub const fn vfio_ioctl_request(index: u32) -> c_ulong {
+    ioctl_cmd(index as c_ulong)
+}
+
+/// Issues a VFIO ioctl that this crate doesn't wrap on the given file descriptor, which can be
+/// obtained from a [`VfioPciDevice`](crate::backends::vfio::VfioPciDevice) or
+/// [`VfioContainer`](crate::backends::vfio::VfioContainer) through their [`AsFd`] implementations.
+///
+/// Returns the ioctl's non-negative return value.
+///
+/// # Safety
+///
+/// The ioctl must expect a pointer to an argument laid out as `arg` is, and must not do anything
+/// that breaks the guarantees this crate relies on, _e.g._, mapping memory for DMA that may be
+/// freed while still mapped.
+///
+/// [`AsFd`]: std::os::unix::io::AsFd
+pub unsafe fn vfio_ioctl(
+    fd: BorrowedFd<'_>,
+    request: c_ulong,
+    arg: &mut VfioIoctlArg,
+) -> io::Result<i32> {
+    let ret = unsafe { ioctl(fd.as_raw_fd(), request, arg.as_mut_ptr()) };
+    ioctl_return_to_result(ret)
+}
+
+/// A variable-size argument for VFIO ioctls, which start with an `argsz` field that gives their
+/// total size, as most do.
+///
+/// The buffer is 8-byte aligned, so [`VfioIoctlArg::as_mut_ptr`] can be cast to a pointer to the
+/// ioctl's argument struct.
+#[derive(Clone, Debug)]
+pub struct VfioIoctlArg {
+    buffer: Vec<u64>,
+    size: usize,
+}
+
+impl VfioIoctlArg {
+    /// Creates a zeroed argument of the given size, with `argsz` set to it.
+    ///
+    /// Panics if `size` is less than 4 or doesn't fit in a `u32`.
+    pub fn new(size: usize) -> VfioIoctlArg {
+        let argsz: u32 = size.try_into().expect("argument too large");
+        assert!(size >= 4, "argument too small to hold argsz");
+
+        let mut arg = VfioIoctlArg {
+            buffer: vec![0; (size + 7) / 8],
+            size,
+        };
+        arg.set_argsz(argsz);
+        arg
+    }
+
+    /// Creates an argument holding a copy of `bytes`, with `argsz` set to their length.
+    ///
+    /// Panics under the same conditions as [`VfioIoctlArg::new`].
+    pub fn from_bytes(bytes: &[u8]) -> VfioIoctlArg {
+        let mut arg = VfioIoctlArg::new(bytes.len());
+        arg.bytes_mut()[4..].copy_from_slice(&bytes[4..]);
+        arg
+    }
+
+    /// The size of the argument, in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The current value of the `argsz` field, which some ioctls update to tell how large the
+    /// argument must be to hold all they have to return.
+    pub fn argsz(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes()[..4].try_into().unwrap())
+    }
+
+    /// If `argsz` is larger than the argument, grows the argument to that size, zeroing everything
+    /// past the existing contents, and returns `true`. Returns `false` otherwise.
+    ///
+    /// Useful for ioctls like `VFIO_DEVICE_GET_REGION_INFO`, which are issued once to learn how
+    /// large the argument must be, and then again with an argument of that size.
+    pub fn grow_to_argsz(&mut self) -> bool {
+        let argsz = self.argsz() as usize;
+
+        if argsz <= self.size {
+            return false;
+        }
+
+        let old_size = self.size;
+
+        self.buffer.resize((argsz + 7) / 8, 0);
+        self.bytes_mut_unchecked(argsz)[old_size..].fill(0);
+        self.size = argsz;
+
+        true
+    }
+
+    /// The contents of the argument.
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.buffer.as_ptr() as *const u8, self.size) }
+    }
+
+    /// The contents of the argument.
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        self.bytes_mut_unchecked(self.size)
+    }
+
+    /// A pointer to the start of the argument, suitable for passing to `ioctl()`.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.buffer.as_mut_ptr() as *mut u8
+    }
+
+    fn set_argsz(&mut self, argsz: u32) {
+        self.bytes_mut()[..4].copy_from_slice(&argsz.to_ne_bytes());
+    }
+
+    fn bytes_mut_unchecked(&mut self, size: usize) -> &mut [u8] {
+        assert!(size <= self.buffer.len() * mem::size_of::<u64>());
+        unsafe { slice::from_raw_parts_mut(self.buffer.as_mut_ptr() as *mut u8, size) }
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use super::{vfio_ioctl_request, VfioIoctlArg};
+
+    #[test]
+    fn test_vfio_ioctl_request() {
+        // VFIO_DEVICE_GET_INFO
+        assert_eq!(vfio_ioctl_request(7), 0x3b6b);
+    }
+
+    #[test]
+    fn test_grow_to_argsz() {
+        let mut arg = VfioIoctlArg::from_bytes(&[0, 0, 0, 0, 1, 2, 3, 4, 5]);
+
+        assert_eq!(arg.argsz(), 9);
+        assert_eq!(arg.bytes()[4..], [1, 2, 3, 4, 5]);
+        assert!(!arg.grow_to_argsz());
+
+        // the kernel tells us it needs more room
+        arg.bytes_mut()[..4].copy_from_slice(&20_u32.to_ne_bytes());
+
+        assert!(arg.grow_to_argsz());
+        assert_eq!(arg.size(), 20);
+        assert_eq!(arg.bytes()[4..9], [1, 2, 3, 4, 5]);
+        assert!(arg.bytes()[9..].iter().all(|&b| b == 0));
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
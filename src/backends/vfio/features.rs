@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::{EINVAL, ENOTTY};
+use std::io::{self, ErrorKind};
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+
+use crate::backends::vfio::bindings::{
+    VFIO_DEVICE_FEATURE_GET, VFIO_DEVICE_FEATURE_MASK, VFIO_DEVICE_FEATURE_PROBE,
+    VFIO_DEVICE_FEATURE_SET,
+};
+use crate::backends::vfio::bindings_ext::{
+    vfio_device_low_power_entry_with_wakeup, VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY,
+    VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY_WITH_WAKEUP, VFIO_DEVICE_FEATURE_LOW_POWER_EXIT,
+};
+use crate::backends::vfio::VfioPciDevice;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Which operations a device supports on a `VFIO_DEVICE_FEATURE` feature. See
+/// [`VfioPciDevice::probe_feature`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct VfioFeatureSupport {
+    /// Whether the feature can be read with [`VfioPciDevice::get_feature`].
+    pub get: bool,
+    /// Whether the feature can be written with [`VfioPciDevice::set_feature`].
+    pub set: bool,
+}
+
+impl VfioFeatureSupport {
+    /// Whether the device supports the feature at all.
+    pub fn is_supported(&self) -> bool {
+        self.get || self.set
+    }
+}
+
+impl VfioPciDevice {
+    /// Checks whether the device supports getting and setting the given `VFIO_DEVICE_FEATURE`
+    /// feature, _e.g._, `VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY`.
+    ///
+    /// Features the kernel doesn't know about, including all features on kernels that predate the
+    /// `VFIO_DEVICE_FEATURE` ioctl (Linux 5.18), are reported as unsupported.
+    pub fn probe_feature(&self, feature: u32) -> io::Result<VfioFeatureSupport> {
+        check_feature(feature)?;
+
+        let probe = |op: u32| match self
+            .inner
+            .device_feature(VFIO_DEVICE_FEATURE_PROBE | op | feature, &mut ())
+        {
+            Ok(()) => Ok(true),
+            Err(e) if matches!(e.raw_os_error(), Some(ENOTTY) | Some(EINVAL)) => Ok(false),
+            Err(e) => Err(e),
+        };
+
+        Ok(VfioFeatureSupport {
+            get: probe(VFIO_DEVICE_FEATURE_GET)?,
+            set: probe(VFIO_DEVICE_FEATURE_SET)?,
+        })
+    }
+
+    /// Reads the given `VFIO_DEVICE_FEATURE` feature into `data`, whose layout and length are
+    /// specific to the feature.
+    pub fn get_feature(&self, feature: u32, data: &mut [u8]) -> io::Result<()> {
+        check_feature(feature)?;
+        self.inner
+            .device_feature_bytes(VFIO_DEVICE_FEATURE_GET | feature, data)
+    }
+
+    /// Writes the given `VFIO_DEVICE_FEATURE` feature from `data`, whose layout and length are
+    /// specific to the feature.
+    ///
+    /// Some features return information when set (_e.g._, a file descriptor), which is written
+    /// back into `data`.
+    pub fn set_feature(&self, feature: u32, data: &mut [u8]) -> io::Result<()> {
+        check_feature(feature)?;
+        self.inner
+            .device_feature_bytes(VFIO_DEVICE_FEATURE_SET | feature, data)
+    }
+
+    /// Puts the device into a low power state (D3cold if the platform supports it, D3hot
+    /// otherwise) until [`VfioPciDevice::exit_low_power`] is called.
+    ///
+    /// Accessing the device's config space or regions in the meantime brings it back to full
+    /// power temporarily. If `wakeup_eventfd` is given, it is signalled when the device itself
+    /// requests to be woken up (_e.g._, through a PME), after which the low power state should be
+    /// exited.
+    ///
+    /// This requires Linux 6.0 or later.
+    pub fn enter_low_power(&self, wakeup_eventfd: Option<BorrowedFd<'_>>) -> io::Result<()> {
+        match low_power_entry(wakeup_eventfd) {
+            (feature, Some(mut entry)) => self
+                .inner
+                .device_feature(VFIO_DEVICE_FEATURE_SET | feature, &mut entry),
+            (feature, None) => self
+                .inner
+                .device_feature(VFIO_DEVICE_FEATURE_SET | feature, &mut ()),
+        }
+    }
+
+    /// Brings the device back from the low power state entered with
+    /// [`VfioPciDevice::enter_low_power`].
+    ///
+    /// This requires Linux 6.0 or later.
+    pub fn exit_low_power(&self) -> io::Result<()> {
+        self.inner.device_feature(
+            VFIO_DEVICE_FEATURE_SET | VFIO_DEVICE_FEATURE_LOW_POWER_EXIT,
+            &mut (),
+        )
+    }
+}
+
+/// Returns the feature to set to enter the low power state, and its argument, if any.
+fn low_power_entry(
+    wakeup_eventfd: Option<BorrowedFd<'_>>,
+) -> (u32, Option<vfio_device_low_power_entry_with_wakeup>) {
+    match wakeup_eventfd {
+        Some(eventfd) => (
+            VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY_WITH_WAKEUP,
+            Some(vfio_device_low_power_entry_with_wakeup {
+                wakeup_eventfd: eventfd.as_raw_fd(),
+                reserved: 0,
+            }),
+        ),
+        None => (VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY, None),
+    }
+}
+
+fn check_feature(feature: u32) -> io::Result<()> {
+    if feature & !VFIO_DEVICE_FEATURE_MASK != 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid VFIO device feature {}", feature),
+        ));
+    }
+
+    Ok(())
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::ErrorKind;
+    use std::os::unix::io::{AsFd, AsRawFd};
+
+    use crate::backends::vfio::bindings::VFIO_DEVICE_FEATURE_GET;
+    use crate::backends::vfio::bindings_ext::{
+        VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY, VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY_WITH_WAKEUP,
+    };
+    use crate::backends::vfio::features::{check_feature, low_power_entry};
+
+    #[test]
+    fn test_low_power_entry() {
+        let (feature, entry) = low_power_entry(None);
+        assert_eq!(feature, VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY);
+        assert!(entry.is_none());
+
+        let file = File::open("/dev/null").unwrap();
+        let (feature, entry) = low_power_entry(Some(file.as_fd()));
+        assert_eq!(feature, VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY_WITH_WAKEUP);
+        assert_eq!(entry.unwrap().wakeup_eventfd, file.as_raw_fd());
+        assert_eq!(entry.unwrap().reserved, 0);
+    }
+
+    #[test]
+    fn test_check_feature() {
+        check_feature(VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY).unwrap();
+        assert_eq!(
+            check_feature(VFIO_DEVICE_FEATURE_GET | VFIO_DEVICE_FEATURE_LOW_POWER_ENTRY)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::mem;
+
+use vfio_bindings::bindings::vfio::{
+    vfio_device_info, vfio_info_cap_header, vfio_pci_dependent_device, vfio_precopy_info,
+    vfio_region_info_cap_type, VFIO_REGION_INFO_CAP_TYPE,
+};
+
+use crate::backends::vfio::{VfioDeviceInfo, VfioHotResetDevice, VfioPrecopyInfo, VfioRegionType};
+use crate::device::PciAddress;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+impl From<vfio_device_info> for VfioDeviceInfo {
+    fn from(info: vfio_device_info) -> Self {
+        VfioDeviceInfo {
+            flags: info.flags,
+            num_regions: info.num_regions,
+            num_irqs: info.num_irqs,
+        }
+    }
+}
+
+impl From<VfioDeviceInfo> for vfio_device_info {
+    fn from(info: VfioDeviceInfo) -> Self {
+        vfio_device_info {
+            argsz: mem::size_of::<vfio_device_info>() as u32,
+            flags: info.flags,
+            num_regions: info.num_regions,
+            num_irqs: info.num_irqs,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<vfio_region_info_cap_type> for VfioRegionType {
+    fn from(cap: vfio_region_info_cap_type) -> Self {
+        VfioRegionType {
+            region_type: cap.type_,
+            subtype: cap.subtype,
+        }
+    }
+}
+
+impl From<VfioRegionType> for vfio_region_info_cap_type {
+    fn from(region_type: VfioRegionType) -> Self {
+        vfio_region_info_cap_type {
+            header: vfio_info_cap_header {
+                id: VFIO_REGION_INFO_CAP_TYPE as u16,
+                version: 1,
+                next: 0,
+            },
+            type_: region_type.region_type,
+            subtype: region_type.subtype,
+        }
+    }
+}
+
+/// The group number is taken from `group_id`, so this isn't meaningful for devices reported as
+/// dependent on a device opened through its cdev, for which the kernel reports a `devid` instead.
+impl From<vfio_pci_dependent_device> for VfioHotResetDevice {
+    fn from(device: vfio_pci_dependent_device) -> Self {
+        VfioHotResetDevice {
+            address: PciAddress {
+                domain: device.segment.into(),
+                bus: device.bus,
+                device: device.devfn >> 3,
+                function: device.devfn & 0x7,
+            },
+            // both union fields are u32s, so reading either is fine
+            group: unsafe { device.__bindgen_anon_1.group_id },
+        }
+    }
+}
+
+impl From<vfio_precopy_info> for VfioPrecopyInfo {
+    fn from(info: vfio_precopy_info) -> Self {
+        VfioPrecopyInfo {
+            initial_bytes: info.initial_bytes,
+            dirty_bytes: info.dirty_bytes,
+        }
+    }
+}
+
+impl From<VfioPrecopyInfo> for vfio_precopy_info {
+    fn from(info: VfioPrecopyInfo) -> Self {
+        vfio_precopy_info {
+            argsz: mem::size_of::<vfio_precopy_info>() as u32,
+            flags: 0,
+            initial_bytes: info.initial_bytes,
+            dirty_bytes: info.dirty_bytes,
+        }
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use vfio_bindings::bindings::vfio::{
+        vfio_pci_dependent_device, vfio_pci_dependent_device__bindgen_ty_1,
+    };
+
+    use crate::backends::vfio::VfioHotResetDevice;
+    use crate::device::PciAddress;
+
+    #[test]
+    fn test_hot_reset_device_from_raw() {
+        let raw = vfio_pci_dependent_device {
+            __bindgen_anon_1: vfio_pci_dependent_device__bindgen_ty_1 { group_id: 42 },
+            segment: 0x0001,
+            bus: 0x02,
+            devfn: 0xfd,
+        };
+
+        let expected = VfioHotResetDevice {
+            address: PciAddress {
+                domain: 0x0001,
+                bus: 0x02,
+                device: 0x1f,
+                function: 0x5,
+            },
+            group: 42,
+        };
+
+        assert_eq!(VfioHotResetDevice::from(raw), expected);
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -8,13 +8,30 @@ use std::os::unix::io::RawFd;
 use libc::{c_char, c_ulong, ioctl};
 
 use crate::backends::vfio::bindings::{
-    vfio_device_info, vfio_group_status, vfio_iommu_type1_dma_map, vfio_iommu_type1_dma_unmap,
-    vfio_iommu_type1_info, vfio_irq_info, vfio_irq_set, vfio_region_info, VFIO_BASE, VFIO_TYPE,
+    vfio_device_feature, vfio_device_info, vfio_group_status, vfio_iommu_type1_dma_map,
+    vfio_iommu_type1_dma_unmap, vfio_iommu_type1_info, vfio_irq_info, vfio_irq_set,
+    vfio_pci_hot_reset, vfio_pci_hot_reset_info, vfio_region_info, VFIO_BASE, VFIO_TYPE,
+};
+use crate::backends::vfio::bindings_ext::{
+    vfio_device_attach_iommufd_pt, vfio_device_bind_iommufd, vfio_device_detach_iommufd_pt,
+    vfio_precopy_info,
+};
+use crate::backends::vfio::iommufd::{
+    iommu_ioas_alloc, iommu_ioas_iova_ranges, iommu_ioas_map, iommu_ioas_unmap, iommu_vfio_ioas,
+    IOMMUFD_CMD_IOAS_ALLOC, IOMMUFD_CMD_IOAS_IOVA_RANGES, IOMMUFD_CMD_IOAS_MAP,
+    IOMMUFD_CMD_IOAS_UNMAP, IOMMUFD_CMD_VFIO_IOAS, IOMMUFD_TYPE,
 };
 
 /* ---------------------------------------------------------------------------------------------- */
 
 macro_rules! define_ioctl {
+    ($name:ident, iommufd $nr:ident, $arg_name:ident: $arg_type:ty) => {
+        pub unsafe fn $name(fd: RawFd, $arg_name: $arg_type) -> io::Result<i32> {
+            const CMD: c_ulong = ioctl_cmd_raw(IOMMUFD_TYPE, $nr as c_ulong);
+            let ret = unsafe { ioctl(fd, CMD, $arg_name as *const _) };
+            ioctl_return_to_result(ret)
+        }
+    };
     ($name:ident, $index:literal) => {
         pub unsafe fn $name(fd: RawFd) -> io::Result<i32> {
             const CMD: c_ulong = ioctl_cmd($index);
@@ -38,7 +55,11 @@ macro_rules! define_ioctl {
     };
 }
 
-const fn ioctl_cmd(index: c_ulong) -> c_ulong {
+pub(crate) const fn ioctl_cmd(index: c_ulong) -> c_ulong {
+    ioctl_cmd_raw(VFIO_TYPE, VFIO_BASE as c_ulong + index)
+}
+
+const fn ioctl_cmd_raw(type_: u8, nr: c_ulong) -> c_ulong {
     const IOC_NRBITS: c_ulong = 8;
     const IOC_TYPEBITS: c_ulong = 8;
     const IOC_SIZEBITS: c_ulong = 14;
@@ -51,12 +72,12 @@ const fn ioctl_cmd(index: c_ulong) -> c_ulong {
     const IOC_NONE: c_ulong = 0;
 
     (IOC_NONE << IOC_DIRSHIFT)
-        | ((VFIO_TYPE as c_ulong) << IOC_TYPESHIFT)
-        | ((VFIO_BASE as c_ulong + index) << IOC_NRSHIFT)
+        | ((type_ as c_ulong) << IOC_TYPESHIFT)
+        | (nr << IOC_NRSHIFT)
         | (0 << IOC_SIZESHIFT)
 }
 
-fn ioctl_return_to_result(ret: i32) -> io::Result<i32> {
+pub(crate) fn ioctl_return_to_result(ret: i32) -> io::Result<i32> {
     if ret >= 0 {
         Ok(ret)
     } else {
@@ -79,6 +100,34 @@ define_ioctl!(vfio_device_get_region_info, 8, info: *mut vfio_region_info);
 define_ioctl!(vfio_device_get_irq_info, 9, info: *mut vfio_irq_info);
 define_ioctl!(vfio_device_set_irqs, 10, set: *const vfio_irq_set);
 define_ioctl!(vfio_device_reset, 11);
+define_ioctl!(
+    vfio_device_get_pci_hot_reset_info,
+    12,
+    info: *mut vfio_pci_hot_reset_info
+);
+define_ioctl!(
+    vfio_device_pci_hot_reset,
+    13,
+    reset: *const vfio_pci_hot_reset
+);
+define_ioctl!(vfio_device_feature, 17, feature: *mut vfio_device_feature);
+define_ioctl!(
+    vfio_device_bind_iommufd,
+    18,
+    bind: *mut vfio_device_bind_iommufd
+);
+define_ioctl!(
+    vfio_device_attach_iommufd_pt,
+    19,
+    attach: *mut vfio_device_attach_iommufd_pt
+);
+define_ioctl!(
+    vfio_device_detach_iommufd_pt,
+    20,
+    detach: *mut vfio_device_detach_iommufd_pt
+);
+
+define_ioctl!(vfio_mig_get_precopy_info, 21, info: *mut vfio_precopy_info);
 
 define_ioctl!(vfio_iommu_get_info, 12, info: *mut vfio_iommu_type1_info);
 define_ioctl!(
@@ -93,3 +142,15 @@ define_ioctl!(
 );
 
 /* ---------------------------------------------------------------------------------------------- */
+
+define_ioctl!(iommufd_ioas_alloc, iommufd IOMMUFD_CMD_IOAS_ALLOC, cmd: *mut iommu_ioas_alloc);
+define_ioctl!(
+    iommufd_ioas_iova_ranges,
+    iommufd IOMMUFD_CMD_IOAS_IOVA_RANGES,
+    cmd: *mut iommu_ioas_iova_ranges
+);
+define_ioctl!(iommufd_ioas_map, iommufd IOMMUFD_CMD_IOAS_MAP, cmd: *mut iommu_ioas_map);
+define_ioctl!(iommufd_ioas_unmap, iommufd IOMMUFD_CMD_IOAS_UNMAP, cmd: *mut iommu_ioas_unmap);
+define_ioctl!(iommufd_vfio_ioas, iommufd IOMMUFD_CMD_VFIO_IOAS, cmd: *mut iommu_vfio_ioas);
+
+/* ---------------------------------------------------------------------------------------------- */
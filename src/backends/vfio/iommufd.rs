@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: GPL-2.0 WITH Linux-syscall-note
+
+// The subset of include/uapi/linux/iommufd.h (Linux 6.2) that we need, transcribed by hand.
+
+/* ---------------------------------------------------------------------------------------------- */
+
+pub const IOMMUFD_TYPE: u8 = b';';
+pub const IOMMUFD_CMD_BASE: u32 = 0x80;
+
+pub const IOMMUFD_CMD_IOAS_ALLOC: u32 = 0x81;
+pub const IOMMUFD_CMD_IOAS_IOVA_RANGES: u32 = 0x84;
+pub const IOMMUFD_CMD_IOAS_MAP: u32 = 0x85;
+pub const IOMMUFD_CMD_IOAS_UNMAP: u32 = 0x86;
+pub const IOMMUFD_CMD_VFIO_IOAS: u32 = 0x88;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct iommu_ioas_alloc {
+    pub size: u32,
+    pub flags: u32,
+    pub out_ioas_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct iommu_iova_range {
+    pub start: u64,
+    pub last: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct iommu_ioas_iova_ranges {
+    pub size: u32,
+    pub ioas_id: u32,
+    pub num_iovas: u32,
+    pub __reserved: u32,
+    pub allowed_iovas: u64,
+    pub out_iova_alignment: u64,
+}
+
+pub const IOMMU_IOAS_MAP_FIXED_IOVA: u32 = 1 << 0;
+pub const IOMMU_IOAS_MAP_WRITEABLE: u32 = 1 << 1;
+pub const IOMMU_IOAS_MAP_READABLE: u32 = 1 << 2;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct iommu_ioas_map {
+    pub size: u32,
+    pub flags: u32,
+    pub ioas_id: u32,
+    pub __reserved: u32,
+    pub user_va: u64,
+    pub length: u64,
+    pub iova: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct iommu_ioas_unmap {
+    pub size: u32,
+    pub ioas_id: u32,
+    pub iova: u64,
+    pub length: u64,
+}
+
+pub const IOMMU_VFIO_IOAS_GET: u16 = 0;
+pub const IOMMU_VFIO_IOAS_SET: u16 = 1;
+pub const IOMMU_VFIO_IOAS_CLEAR: u16 = 2;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct iommu_vfio_ioas {
+    pub size: u32,
+    pub ioas_id: u32,
+    pub op: u16,
+    pub __reserved: u16,
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::{c_ulong, ioctl};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+
+use crate::backends::vfio::{get_device_group_number, VfioContainer, VfioPciDevice};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+// The subset of include/uapi/linux/kvm.h (Linux 6.6) that we need, transcribed by hand.
+
+const KVMIO: c_ulong = 0xae;
+
+const KVM_DEV_TYPE_VFIO: u32 = 4;
+
+const KVM_DEV_VFIO_FILE: u32 = 1;
+const KVM_DEV_VFIO_FILE_ADD: u64 = 1;
+const KVM_DEV_VFIO_FILE_DEL: u64 = 2;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct kvm_create_device {
+    type_: u32,
+    fd: u32,
+    flags: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct kvm_device_attr {
+    flags: u32,
+    group: u32,
+    attr: u64,
+    addr: u64,
+}
+
+/// `_IOWR(KVMIO, 0xe0, struct kvm_create_device)`
+const KVM_CREATE_DEVICE: c_ulong = ioc(3, 0xe0, mem::size_of::<kvm_create_device>());
+
+/// `_IOW(KVMIO, 0xe1, struct kvm_device_attr)`
+const KVM_SET_DEVICE_ATTR: c_ulong = ioc(1, 0xe1, mem::size_of::<kvm_device_attr>());
+
+/// Unlike VFIO's, KVM's ioctl numbers encode their direction and argument size.
+const fn ioc(dir: c_ulong, nr: c_ulong, size: usize) -> c_ulong {
+    (dir << 30) | ((size as c_ulong) << 16) | (KVMIO << 8) | nr
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// A KVM VM's VFIO pseudo-device, through which KVM is told about the VFIO groups or devices
+/// assigned to the VM.
+///
+/// VMMs that drive devices with this crate on behalf of a KVM guest must register them here, so
+/// that KVM can, _e.g._, handle non-coherent DMA correctly and let interrupts be delivered to the
+/// guest directly.
+#[derive(Debug)]
+pub struct VfioKvmDevice {
+    file: File,
+}
+
+impl VfioKvmDevice {
+    /// Creates the VFIO pseudo-device of the KVM VM with the given file descriptor, _i.e._, the
+    /// one returned by `KVM_CREATE_VM`.
+    pub fn new(vm_fd: impl AsFd) -> io::Result<VfioKvmDevice> {
+        let mut create = kvm_create_device {
+            type_: KVM_DEV_TYPE_VFIO,
+            ..Default::default()
+        };
+
+        if unsafe { ioctl(vm_fd.as_fd().as_raw_fd(), KVM_CREATE_DEVICE, &mut create) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(VfioKvmDevice {
+            file: unsafe { File::from_raw_fd(create.fd as RawFd) },
+        })
+    }
+
+    /// Registers all the groups of the given container with KVM.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] for containers whose devices were bound through their
+    /// VFIO cdevs, which have no group files. Use [`VfioKvmDevice::add_device`] on each of their
+    /// devices instead.
+    pub fn add_container(&self, container: &VfioContainer) -> io::Result<()> {
+        require_groups(container)?;
+
+        for group_file in container.group_files().values() {
+            self.set_file(KVM_DEV_VFIO_FILE_ADD, group_file.as_raw_fd())?;
+        }
+        Ok(())
+    }
+
+    /// Unregisters all the groups of the given container from KVM.
+    ///
+    /// Fails like [`VfioKvmDevice::add_container`] does, in which case use
+    /// [`VfioKvmDevice::remove_device`] on each device instead.
+    pub fn remove_container(&self, container: &VfioContainer) -> io::Result<()> {
+        require_groups(container)?;
+
+        for group_file in container.group_files().values() {
+            self.set_file(KVM_DEV_VFIO_FILE_DEL, group_file.as_raw_fd())?;
+        }
+        Ok(())
+    }
+
+    /// Registers the given device with KVM: its VFIO cdev if it was opened through one, which
+    /// requires Linux 6.6 or later, or its group otherwise.
+    pub fn add_device(&self, device: &VfioPciDevice) -> io::Result<()> {
+        self.set_file(KVM_DEV_VFIO_FILE_ADD, device_kvm_fd(device)?)
+    }
+
+    /// Unregisters the given device from KVM. See [`VfioKvmDevice::add_device`].
+    pub fn remove_device(&self, device: &VfioPciDevice) -> io::Result<()> {
+        self.set_file(KVM_DEV_VFIO_FILE_DEL, device_kvm_fd(device)?)
+    }
+
+    fn set_file(&self, attr: u64, fd: RawFd) -> io::Result<()> {
+        let device_attr = kvm_device_attr {
+            flags: 0,
+            group: KVM_DEV_VFIO_FILE,
+            attr,
+            addr: &fd as *const RawFd as u64,
+        };
+
+        if unsafe { ioctl(self.file.as_raw_fd(), KVM_SET_DEVICE_ATTR, &device_attr) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl AsFd for VfioKvmDevice {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl AsRawFd for VfioKvmDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+fn require_groups(container: &VfioContainer) -> io::Result<()> {
+    if container.uses_device_cdevs() {
+        Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Containers with devices bound through their VFIO cdevs have no groups to register \
+             with KVM; register each device with VfioKvmDevice::add_device() instead",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the fd that identifies the device to KVM.
+fn device_kvm_fd(device: &VfioPciDevice) -> io::Result<RawFd> {
+    let container = device.container();
+
+    if container.uses_device_cdevs() {
+        return Ok(device.inner.file.as_raw_fd());
+    }
+
+    let group_number = get_device_group_number(&device.inner.sysfs_path)?;
+
+    container
+        .group_files()
+        .get(&group_number)
+        .map(|file| file.as_raw_fd())
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "The device's group isn't in its container",
+            )
+        })
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use crate::backends::vfio::kvm::{VfioKvmDevice, KVM_CREATE_DEVICE, KVM_SET_DEVICE_ATTR};
+
+    #[test]
+    fn test_ioctl_numbers() {
+        assert_eq!(KVM_CREATE_DEVICE, 0xc00c_aee0);
+        assert_eq!(KVM_SET_DEVICE_ATTR, 0x4018_aee1);
+    }
+
+    #[test]
+    fn test_new_requires_vm_fd() {
+        let file = File::open("/dev/null").unwrap();
+        assert_eq!(
+            VfioKvmDevice::new(&file).unwrap_err().raw_os_error(),
+            Some(libc::ENOTTY)
+        );
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
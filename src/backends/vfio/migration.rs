@@ -0,0 +1,389 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::ENOTTY;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+
+use crate::backends::vfio::bindings::{
+    vfio_device_feature_mig_state, vfio_device_feature_migration,
+    vfio_device_mig_state_VFIO_DEVICE_STATE_ERROR,
+    vfio_device_mig_state_VFIO_DEVICE_STATE_RESUMING,
+    vfio_device_mig_state_VFIO_DEVICE_STATE_RUNNING,
+    vfio_device_mig_state_VFIO_DEVICE_STATE_RUNNING_P2P,
+    vfio_device_mig_state_VFIO_DEVICE_STATE_STOP,
+    vfio_device_mig_state_VFIO_DEVICE_STATE_STOP_COPY, VFIO_DEVICE_FEATURE_GET,
+    VFIO_DEVICE_FEATURE_MIGRATION, VFIO_DEVICE_FEATURE_MIG_DEVICE_STATE, VFIO_DEVICE_FEATURE_SET,
+    VFIO_MIGRATION_P2P, VFIO_MIGRATION_STOP_COPY,
+};
+use crate::backends::vfio::bindings_ext::{
+    vfio_device_feature_mig_data_size, vfio_device_mig_state_VFIO_DEVICE_STATE_PRE_COPY,
+    vfio_device_mig_state_VFIO_DEVICE_STATE_PRE_COPY_P2P, vfio_precopy_info,
+    VFIO_DEVICE_FEATURE_MIG_DATA_SIZE, VFIO_MIGRATION_PRE_COPY,
+};
+use crate::backends::vfio::ioctl::vfio_mig_get_precopy_info;
+use crate::backends::vfio::VfioPciDevice;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Which parts of the VFIO migration protocol a device supports. See
+/// [`VfioPciDevice::migration_support`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct VfioMigrationSupport {
+    /// Whether the device supports the [`VfioMigrationState::Stop`],
+    /// [`VfioMigrationState::StopCopy`], and [`VfioMigrationState::Resuming`] states. This is
+    /// always the case for devices that support migration at all.
+    pub stop_copy: bool,
+    /// Whether the device supports the [`VfioMigrationState::RunningP2p`] state, and the
+    /// [`VfioMigrationState::PreCopyP2p`] state if it also supports pre-copy.
+    pub p2p: bool,
+    /// Whether the device supports the [`VfioMigrationState::PreCopy`] state.
+    pub pre_copy: bool,
+}
+
+impl VfioMigrationSupport {
+    fn from_flags(flags: u64) -> VfioMigrationSupport {
+        VfioMigrationSupport {
+            stop_copy: flags & u64::from(VFIO_MIGRATION_STOP_COPY) != 0,
+            p2p: flags & u64::from(VFIO_MIGRATION_P2P) != 0,
+            pre_copy: flags & u64::from(VFIO_MIGRATION_PRE_COPY) != 0,
+        }
+    }
+}
+
+/// How much migration data a device in [`VfioMigrationState::PreCopy`] or
+/// [`VfioMigrationState::PreCopyP2p`] has available. See [`VfioMigrationData::precopy_info`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct VfioPrecopyInfo {
+    /// How many bytes of the initial data, which must be transferred before the device is stopped
+    /// for the migration to be fast, remain to be read.
+    pub initial_bytes: u64,
+    /// An estimate of how many bytes of data that changed since it was last read remain to be
+    /// read. These may be read now, or left for [`VfioMigrationState::StopCopy`].
+    pub dirty_bytes: u64,
+}
+
+/// A state of a device's VFIO migration state machine.
+///
+/// The kernel only accepts transitions along certain arcs, _e.g._, from
+/// [`VfioMigrationState::Running`] to [`VfioMigrationState::Stop`] and from there to
+/// [`VfioMigrationState::StopCopy`], but not directly from [`VfioMigrationState::Running`] to
+/// [`VfioMigrationState::StopCopy`]. See `include/uapi/linux/vfio.h` in the Linux source tree for
+/// the full state machine.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum VfioMigrationState {
+    /// A transition failed and the device is in an unknown state. The only way out is resetting
+    /// the device, after which it is in [`VfioMigrationState::Running`].
+    Error,
+    /// The device is stopped and its internal state doesn't change.
+    Stop,
+    /// The device is operating normally. This is the state after the device is opened or reset.
+    Running,
+    /// The device is stopped and its internal state can be read from the migration data.
+    StopCopy,
+    /// The device is stopped and its internal state is being loaded from the migration data.
+    Resuming,
+    /// The device is running but doesn't initiate peer-to-peer DMA or interrupts, so that other
+    /// devices of the same VM can be quiesced before any of them is stopped.
+    RunningP2p,
+    /// The device is running and part of its internal state can already be read from the migration
+    /// data, so that less remains to be transferred once it is stopped.
+    PreCopy,
+    /// Like [`VfioMigrationState::PreCopy`], but the device doesn't initiate peer-to-peer DMA or
+    /// interrupts, as in [`VfioMigrationState::RunningP2p`].
+    PreCopyP2p,
+}
+
+impl VfioMigrationState {
+    fn to_raw(self) -> u32 {
+        match self {
+            VfioMigrationState::Error => vfio_device_mig_state_VFIO_DEVICE_STATE_ERROR,
+            VfioMigrationState::Stop => vfio_device_mig_state_VFIO_DEVICE_STATE_STOP,
+            VfioMigrationState::Running => vfio_device_mig_state_VFIO_DEVICE_STATE_RUNNING,
+            VfioMigrationState::StopCopy => vfio_device_mig_state_VFIO_DEVICE_STATE_STOP_COPY,
+            VfioMigrationState::Resuming => vfio_device_mig_state_VFIO_DEVICE_STATE_RESUMING,
+            VfioMigrationState::RunningP2p => vfio_device_mig_state_VFIO_DEVICE_STATE_RUNNING_P2P,
+            VfioMigrationState::PreCopy => vfio_device_mig_state_VFIO_DEVICE_STATE_PRE_COPY,
+            VfioMigrationState::PreCopyP2p => vfio_device_mig_state_VFIO_DEVICE_STATE_PRE_COPY_P2P,
+        }
+    }
+
+    fn from_raw(raw: u32) -> io::Result<VfioMigrationState> {
+        #[allow(non_upper_case_globals)]
+        match raw {
+            vfio_device_mig_state_VFIO_DEVICE_STATE_ERROR => Ok(VfioMigrationState::Error),
+            vfio_device_mig_state_VFIO_DEVICE_STATE_STOP => Ok(VfioMigrationState::Stop),
+            vfio_device_mig_state_VFIO_DEVICE_STATE_RUNNING => Ok(VfioMigrationState::Running),
+            vfio_device_mig_state_VFIO_DEVICE_STATE_STOP_COPY => Ok(VfioMigrationState::StopCopy),
+            vfio_device_mig_state_VFIO_DEVICE_STATE_RESUMING => Ok(VfioMigrationState::Resuming),
+            vfio_device_mig_state_VFIO_DEVICE_STATE_RUNNING_P2P => {
+                Ok(VfioMigrationState::RunningP2p)
+            }
+            vfio_device_mig_state_VFIO_DEVICE_STATE_PRE_COPY => Ok(VfioMigrationState::PreCopy),
+            vfio_device_mig_state_VFIO_DEVICE_STATE_PRE_COPY_P2P => {
+                Ok(VfioMigrationState::PreCopyP2p)
+            }
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown VFIO migration state {}", raw),
+            )),
+        }
+    }
+}
+
+/// The stream through which a device's internal state is saved or restored, returned by
+/// [`VfioPciDevice::set_migration_state`].
+///
+/// In [`VfioMigrationState::StopCopy`], read it until end of file to get the device's state. In
+/// [`VfioMigrationState::Resuming`], write that state to it, then transition the device out of
+/// [`VfioMigrationState::Resuming`] for it to be loaded. The stream is specific to the state in
+/// which it was returned and can't be used after the device leaves that state.
+///
+/// The stream returned when entering [`VfioMigrationState::PreCopy`] remains valid through
+/// [`VfioMigrationState::StopCopy`], where it provides the rest of the state. While the device is
+/// still running, reaching end of file only means that no more data is available for now; see
+/// [`VfioMigrationData::precopy_info`].
+#[derive(Debug)]
+pub struct VfioMigrationData {
+    file: File,
+}
+
+impl VfioMigrationData {
+    /// Returns how much data is available to be read while the device is in
+    /// [`VfioMigrationState::PreCopy`] or [`VfioMigrationState::PreCopyP2p`].
+    ///
+    /// Fails if the device is in any other state, and with [`ErrorKind::Unsupported`] if it doesn't
+    /// support pre-copy.
+    pub fn precopy_info(&self) -> io::Result<VfioPrecopyInfo> {
+        let mut info = vfio_precopy_info {
+            argsz: mem::size_of::<vfio_precopy_info>() as u32,
+            ..Default::default()
+        };
+
+        unsafe { vfio_mig_get_precopy_info(self.file.as_raw_fd(), &mut info) }
+            .map_err(|e| unsupported_if_enotty(e, "The device doesn't support pre-copy"))?;
+
+        Ok(VfioPrecopyInfo {
+            initial_bytes: info.initial_bytes,
+            dirty_bytes: info.dirty_bytes,
+        })
+    }
+}
+
+impl Read for VfioMigrationData {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for VfioMigrationData {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl AsFd for VfioMigrationData {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl AsRawFd for VfioMigrationData {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl VfioPciDevice {
+    /// Returns which parts of the VFIO migration protocol the device supports, or `None` if it
+    /// doesn't support migration, or the kernel predates the v2 migration interface (Linux 5.18).
+    pub fn migration_support(&self) -> io::Result<Option<VfioMigrationSupport>> {
+        let mut migration = vfio_device_feature_migration::default();
+
+        let result = self.inner.device_feature(
+            VFIO_DEVICE_FEATURE_GET | VFIO_DEVICE_FEATURE_MIGRATION,
+            &mut migration,
+        );
+
+        match result {
+            Ok(()) => Ok(Some(VfioMigrationSupport::from_flags(migration.flags))),
+            Err(e) if e.raw_os_error() == Some(ENOTTY) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the current state of the device's migration state machine.
+    ///
+    /// Fails with [`ErrorKind::Unsupported`] if the device doesn't support migration.
+    pub fn migration_state(&self) -> io::Result<VfioMigrationState> {
+        let mut state = vfio_device_feature_mig_state::default();
+
+        self.inner
+            .device_feature(
+                VFIO_DEVICE_FEATURE_GET | VFIO_DEVICE_FEATURE_MIG_DEVICE_STATE,
+                &mut state,
+            )
+            .map_err(|e| unsupported_if_enotty(e, MIGRATION_UNSUPPORTED))?;
+
+        VfioMigrationState::from_raw(state.device_state)
+    }
+
+    /// Returns an estimate of how many bytes of migration data would have to be read in
+    /// [`VfioMigrationState::StopCopy`] if the device were stopped now.
+    ///
+    /// This requires Linux 6.3 or later, and fails with [`ErrorKind::Unsupported`] if the device
+    /// doesn't support migration.
+    pub fn migration_data_size(&self) -> io::Result<u64> {
+        let mut data_size = vfio_device_feature_mig_data_size::default();
+
+        self.inner
+            .device_feature(
+                VFIO_DEVICE_FEATURE_GET | VFIO_DEVICE_FEATURE_MIG_DATA_SIZE,
+                &mut data_size,
+            )
+            .map_err(|e| unsupported_if_enotty(e, MIGRATION_UNSUPPORTED))?;
+
+        Ok(data_size.stop_copy_length)
+    }
+
+    /// Transitions the device's migration state machine to the given state, which must be reachable
+    /// through a single arc from the current state (see [`VfioMigrationState`]).
+    ///
+    /// Returns the stream through which to save or restore the device's internal state when
+    /// entering a state that has one, like [`VfioMigrationState::StopCopy`] or
+    /// [`VfioMigrationState::Resuming`].
+    ///
+    /// If this fails, the device may be left in [`VfioMigrationState::Error`]; check with
+    /// [`VfioPciDevice::migration_state`]. Fails with [`ErrorKind::Unsupported`] if the device
+    /// doesn't support migration.
+    pub fn set_migration_state(
+        &self,
+        state: VfioMigrationState,
+    ) -> io::Result<Option<VfioMigrationData>> {
+        let mut mig_state = vfio_device_feature_mig_state {
+            device_state: state.to_raw(),
+            data_fd: -1,
+        };
+
+        self.inner
+            .device_feature(
+                VFIO_DEVICE_FEATURE_SET | VFIO_DEVICE_FEATURE_MIG_DEVICE_STATE,
+                &mut mig_state,
+            )
+            .map_err(|e| unsupported_if_enotty(e, MIGRATION_UNSUPPORTED))?;
+
+        if mig_state.data_fd < 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(VfioMigrationData {
+            file: unsafe { File::from_raw_fd(mig_state.data_fd) },
+        }))
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+const MIGRATION_UNSUPPORTED: &str =
+    "The device doesn't support migration, or the kernel doesn't support this operation";
+
+/// The kernel reports unsupported features and ioctls with `ENOTTY`, which isn't very telling.
+fn unsupported_if_enotty(error: io::Error, message: &str) -> io::Error {
+    if error.raw_os_error() == Some(ENOTTY) {
+        io::Error::new(ErrorKind::Unsupported, message)
+    } else {
+        error
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use libc::{EINVAL, ENOTTY};
+    use std::fs::File;
+    use std::io::{self, ErrorKind, Read};
+
+    use crate::backends::vfio::migration::{
+        unsupported_if_enotty, VfioMigrationData, VfioMigrationState, VfioMigrationSupport,
+    };
+
+    #[test]
+    fn test_migration_state_raw() {
+        let states = [
+            (VfioMigrationState::Error, 0),
+            (VfioMigrationState::Stop, 1),
+            (VfioMigrationState::Running, 2),
+            (VfioMigrationState::StopCopy, 3),
+            (VfioMigrationState::Resuming, 4),
+            (VfioMigrationState::RunningP2p, 5),
+            (VfioMigrationState::PreCopy, 6),
+            (VfioMigrationState::PreCopyP2p, 7),
+        ];
+
+        for (state, raw) in states {
+            assert_eq!(state.to_raw(), raw);
+            assert_eq!(VfioMigrationState::from_raw(raw).unwrap(), state);
+        }
+
+        assert_eq!(
+            VfioMigrationState::from_raw(8).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_migration_support_from_flags() {
+        assert_eq!(
+            VfioMigrationSupport::from_flags(0b001),
+            VfioMigrationSupport {
+                stop_copy: true,
+                p2p: false,
+                pre_copy: false
+            }
+        );
+        assert_eq!(
+            VfioMigrationSupport::from_flags(0b111),
+            VfioMigrationSupport {
+                stop_copy: true,
+                p2p: true,
+                pre_copy: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_unsupported_if_enotty() {
+        let e = unsupported_if_enotty(io::Error::from_raw_os_error(ENOTTY), "nope");
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+        assert_eq!(e.to_string(), "nope");
+
+        let e = unsupported_if_enotty(io::Error::from_raw_os_error(EINVAL), "nope");
+        assert_eq!(e.raw_os_error(), Some(EINVAL));
+    }
+
+    #[test]
+    fn test_precopy_info_unsupported() {
+        let mut data = VfioMigrationData {
+            file: File::open("/dev/null").unwrap(),
+        };
+
+        assert_eq!(
+            data.precopy_info().unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+        assert_eq!(data.read(&mut [0; 8]).unwrap(), 0);
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -11,46 +11,92 @@
     non_upper_case_globals
 )]
 mod bindings;
+#[allow(dead_code, non_camel_case_types, non_upper_case_globals)]
+mod bindings_ext;
 
+mod bind;
 mod containers;
+mod custom;
+mod features;
+#[cfg(feature = "vfio-bindings")]
+mod interop;
 mod ioctl;
+#[allow(dead_code, non_camel_case_types, non_upper_case_globals)]
+mod iommufd;
+#[cfg(feature = "kvm")]
+mod kvm;
+mod migration;
 mod regions;
+mod reset;
+mod share;
 
 use libc::{mmap64, munmap, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
-use std::alloc::{self, Layout};
+use std::collections::BTreeMap;
 use std::ffi::CString;
 use std::fmt::Debug;
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, ErrorKind};
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ops::Range;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
 use std::os::unix::prelude::OsStrExt;
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::{mem, ptr};
 
 use crate::backends::vfio::bindings::{
-    __IncompleteArrayField, vfio_device_info, vfio_irq_info, vfio_irq_set, VFIO_DEVICE_FLAGS_PCI,
-    VFIO_IRQ_INFO_EVENTFD, VFIO_IRQ_SET_ACTION_TRIGGER, VFIO_IRQ_SET_DATA_EVENTFD,
-    VFIO_IRQ_SET_DATA_NONE, VFIO_PCI_BAR0_REGION_INDEX, VFIO_PCI_BAR5_REGION_INDEX,
-    VFIO_PCI_CONFIG_REGION_INDEX, VFIO_PCI_INTX_IRQ_INDEX, VFIO_PCI_MSIX_IRQ_INDEX,
-    VFIO_PCI_MSI_IRQ_INDEX, VFIO_PCI_ROM_REGION_INDEX,
+    __IncompleteArrayField, vfio_device_feature, vfio_device_info, vfio_irq_info, vfio_irq_set,
+    VFIO_DEVICE_FLAGS_PCI, VFIO_DEVICE_FLAGS_RESET, VFIO_IRQ_INFO_AUTOMASKED,
+    VFIO_IRQ_INFO_EVENTFD, VFIO_IRQ_INFO_MASKABLE, VFIO_IRQ_INFO_NORESIZE,
+    VFIO_IRQ_SET_ACTION_MASK, VFIO_IRQ_SET_ACTION_TRIGGER, VFIO_IRQ_SET_ACTION_UNMASK,
+    VFIO_IRQ_SET_DATA_EVENTFD, VFIO_IRQ_SET_DATA_NONE, VFIO_PCI_BAR0_REGION_INDEX,
+    VFIO_PCI_BAR5_REGION_INDEX, VFIO_PCI_CONFIG_REGION_INDEX, VFIO_PCI_ERR_IRQ_INDEX,
+    VFIO_PCI_INTX_IRQ_INDEX, VFIO_PCI_MSIX_IRQ_INDEX, VFIO_PCI_MSI_IRQ_INDEX, VFIO_PCI_NUM_IRQS,
+    VFIO_PCI_NUM_REGIONS, VFIO_PCI_REQ_IRQ_INDEX, VFIO_PCI_ROM_REGION_INDEX,
 };
+use crate::backends::vfio::bindings_ext::{
+    vfio_device_attach_iommufd_pt, vfio_device_detach_iommufd_pt, VFIO_DEVICE_ATTACH_PASID,
+    VFIO_DEVICE_DETACH_PASID,
+};
+use crate::backends::vfio::containers::page_size;
 use crate::backends::vfio::ioctl::{
+    vfio_device_attach_iommufd_pt, vfio_device_detach_iommufd_pt, vfio_device_feature,
     vfio_device_get_info, vfio_device_get_irq_info, vfio_device_reset, vfio_device_set_irqs,
     vfio_group_get_device_fd,
 };
 use crate::backends::vfio::regions::{
     set_up_bar_or_rom, set_up_config_space, VfioUnmappedPciRegion,
 };
+use crate::backends::vfio::reset::VfioBarRestore;
+use crate::claim::PciDeviceClaim;
+use crate::config::caps::MsiXCapability;
 use crate::config::PciConfig;
-use crate::device::{PciDevice, PciDeviceInternal};
+use crate::device::{
+    PciAddress, PciDevice, PciDeviceInfo, PciDeviceInternal, PciResetCapabilities,
+};
+use crate::enumerate::PciDeviceFilter;
+use crate::identity::PciDeviceIdentity;
 use crate::interrupts::{PciInterruptKind, PciInterrupts};
-use crate::iommu::PciIommu;
+use crate::iommu::{PciIommu, PciIommuInternal};
+use crate::quirks::{self, PciQuirks};
 use crate::regions::{
-    BackedByPciSubregion, OwningPciRegion, PciRegion, Permissions, RegionIdentifier,
+    BackedByPciSubregion, Cacheability, OwningPciRegion, PciRegion, Permissions, RegionIdentifier,
 };
+use crate::sysfs::PciSysfsDevice;
 
-pub use containers::VfioContainer;
+pub use bind::{
+    bind_to_vfio_pci, unbind_from_vfio_pci, vfio_group_blockers, vfio_group_status, VfioGroupStatus,
+};
+pub use containers::{MemlockLimitExceeded, VfioContainer, VfioIommuBackend};
+pub use custom::{vfio_ioctl, vfio_ioctl_request, VfioIoctlArg};
+pub use features::VfioFeatureSupport;
+#[cfg(feature = "kvm")]
+pub use kvm::VfioKvmDevice;
+pub use migration::{VfioMigrationData, VfioMigrationState, VfioMigrationSupport, VfioPrecopyInfo};
+pub use regions::{VfioExtraRegion, VfioIgdOpRegionHeader, VfioRegionType};
+pub use reset::VfioHotResetDevice;
+#[cfg(feature = "vfio-bindings")]
+pub use vfio_bindings;
 
 /* ---------------------------------------------------------------------------------------------- */
 
@@ -61,6 +107,82 @@ fn get_device_address<P: AsRef<Path>>(device_sysfs_path: P) -> io::Result<CStrin
     Ok(CString::new(address.as_bytes()).unwrap())
 }
 
+fn parse_device_address(device_sysfs_path: &Path) -> io::Result<PciAddress> {
+    device_sysfs_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .parse()
+}
+
+/// Returns the path of the device's VFIO cdev, or `None` if the kernel doesn't create them.
+fn get_device_cdev_path<P: AsRef<Path>>(device_sysfs_path: P) -> io::Result<Option<PathBuf>> {
+    let entries = match fs::read_dir(device_sysfs_path.as_ref().join("vfio-dev")) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let name = entry?.file_name();
+        if name.as_bytes().starts_with(b"vfio") {
+            return Ok(Some(Path::new("/dev/vfio/devices").join(name)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Figures out the sysfs directory of the device that the given VFIO device fd refers to. See
+/// [`VfioPciDevice::from_device_fd`].
+fn get_device_fd_sysfs_path(device_fd: RawFd, container: &VfioContainer) -> io::Result<PathBuf> {
+    // Linux 6.9 and later say it in the fd's fdinfo
+
+    if let Ok(fdinfo) = fs::read_to_string(format!("/proc/self/fdinfo/{}", device_fd)) {
+        for line in fdinfo.lines() {
+            if let Some(path) = line.strip_prefix("vfio-device-syspath:") {
+                return Ok(PathBuf::from(path.trim()));
+            }
+        }
+    }
+
+    // cdevs are linked to from the device's sysfs directory
+
+    if let Ok(target) = fs::read_link(format!("/proc/self/fd/{}", device_fd)) {
+        if let (Ok(suffix), Some(name)) =
+            (target.strip_prefix("/dev/vfio/devices"), target.file_name())
+        {
+            if suffix == Path::new(name) {
+                return Ok(Path::new("/sys/class/vfio-dev").join(name).join("device"));
+            }
+        }
+    }
+
+    // otherwise, hope there is only one candidate
+
+    let mut candidates = Vec::new();
+
+    for &group in container.groups() {
+        for entry in PciDeviceFilter::new().iommu_group(group).devices()? {
+            if entry
+                .driver
+                .as_deref()
+                .map_or(false, |d| d.contains("vfio"))
+            {
+                candidates.push(entry);
+            }
+        }
+    }
+
+    match candidates.pop() {
+        Some(entry) if candidates.is_empty() => Ok(entry.sysfs_path().to_path_buf()),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Can't determine which device the VFIO device fd refers to",
+        )),
+    }
+}
+
 fn get_device_group_number<P: AsRef<Path>>(device_sysfs_path: P) -> io::Result<u32> {
     let group_sysfs_path = device_sysfs_path
         .as_ref()
@@ -80,6 +202,41 @@ fn get_device_group_number<P: AsRef<Path>>(device_sysfs_path: P) -> io::Result<u
 
 /* ---------------------------------------------------------------------------------------------- */
 
+/// What the kernel reports about a VFIO device through `VFIO_DEVICE_GET_INFO`. See
+/// [`VfioPciDevice::device_info`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct VfioDeviceInfo {
+    /// The device's `VFIO_DEVICE_FLAGS_*`.
+    pub flags: u32,
+    /// The number of VFIO regions the device has, including the standard BAR, ROM, config space,
+    /// and VGA regions, whether or not they are actually present.
+    pub num_regions: u32,
+    /// The number of VFIO IRQ indices the device has, including the standard INTx, MSI, MSI-X,
+    /// error, and request indices.
+    pub num_irqs: u32,
+}
+
+impl VfioDeviceInfo {
+    /// Whether the kernel reports that the device supports `VFIO_DEVICE_RESET`.
+    pub fn supports_reset(&self) -> bool {
+        self.flags & VFIO_DEVICE_FLAGS_RESET != 0
+    }
+
+    /// Whether the device has VFIO regions beyond the standard ones, _e.g._, device-specific
+    /// regions. See [`VfioPciDevice::extra_regions`].
+    pub fn has_extra_regions(&self) -> bool {
+        self.num_regions > VFIO_PCI_NUM_REGIONS
+    }
+
+    /// Whether the device has VFIO IRQ indices beyond the standard ones.
+    pub fn has_extra_irqs(&self) -> bool {
+        self.num_irqs > VFIO_PCI_NUM_IRQS
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
 /// Provides control over a PCI device using VFIO.
 #[derive(Debug)]
 pub struct VfioPciDevice {
@@ -94,24 +251,217 @@ impl VfioPciDevice {
     /// Note that this only works if no other [`VfioContainer`] already contains the device's group,
     /// and so you must use [`VfioPciDevice::open_in_container`] if you want to drive several
     /// devices from the same VFIO group.
-    pub fn open<P: AsRef<Path>>(sysfs_path: P, noiommu: bool) -> io::Result<VfioPciDevice> {
+    pub fn open<P: AsRef<Path>>(sysfs_path: P) -> io::Result<VfioPciDevice> {
         let group_number = get_device_group_number(&sysfs_path)?;
-        let container = Arc::new(VfioContainer::new(&[group_number], noiommu)?);
+        let container = Arc::new(VfioContainer::new(&[group_number])?);
 
         Self::open_in_container(sysfs_path, container)
     }
 
+    /// Same as [`VfioPciDevice::open`], but creates the [`VfioContainer`] in VFIO's no-IOMMU mode
+    /// with [`VfioContainer::new_noiommu`]. The device's group must be a no-IOMMU group.
+    ///
+    /// The returned device has no [`PciIommu`], and [`VfioPciDevice::is_noiommu`] returns true for
+    /// it.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`VfioContainer::new_noiommu`]: nothing prevents the device from doing DMA to
+    /// any memory, and the caller must ensure that it doesn't.
+    pub unsafe fn open_noiommu<P: AsRef<Path>>(sysfs_path: P) -> io::Result<VfioPciDevice> {
+        let group_number = get_device_group_number(&sysfs_path)?;
+        let container = Arc::new(unsafe { VfioContainer::new_noiommu(&[group_number])? });
+
+        Self::open_in_container(sysfs_path, container)
+    }
+
+    /// Opens a vfio-pci device through its VFIO character device, _e.g._,
+    /// `/dev/vfio/devices/vfio0`, binding it to a new iommufd-backed [`VfioContainer`] of its own.
+    ///
+    /// Unlike [`VfioPciDevice::open`], this doesn't open the device's group, and so doesn't require
+    /// the other devices in the group to be bound to vfio-pci. This requires Linux 6.6 or later,
+    /// built with `CONFIG_VFIO_DEVICE_CDEV` and `CONFIG_IOMMUFD`; fails with
+    /// [`ErrorKind::Unsupported`] otherwise. See also [`VfioPciDevice::open_auto`].
+    pub fn open_cdev<P: AsRef<Path>>(sysfs_path: P) -> io::Result<VfioPciDevice> {
+        let sysfs_path = sysfs_path.as_ref().canonicalize()?;
+        let group_number = get_device_group_number(&sysfs_path)?;
+
+        let cdev_path = get_device_cdev_path(&sysfs_path)?.ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::Unsupported,
+                "The kernel doesn't support VFIO device cdevs",
+            )
+        })?;
+
+        let device_file = OpenOptions::new().read(true).write(true).open(cdev_path)?;
+
+        let container = VfioContainer::with_device_cdevs(&[group_number], &[&device_file])?;
+
+        let device = Self::from_device_file(sysfs_path, Arc::new(container), device_file)?;
+        device.inner.regions()?;
+        Ok(device)
+    }
+
+    /// Opens a vfio-pci device with [`VfioPciDevice::open_cdev`] if the kernel supports VFIO device
+    /// cdevs and iommufd, and with [`VfioPciDevice::open`] otherwise.
+    pub fn open_auto<P: AsRef<Path>>(sysfs_path: P) -> io::Result<VfioPciDevice> {
+        let cdev_supported =
+            get_device_cdev_path(&sysfs_path)?.is_some() && Path::new("/dev/iommu").exists();
+
+        if cdev_supported {
+            Self::open_cdev(sysfs_path)
+        } else {
+            Self::open(sysfs_path)
+        }
+    }
+
+    /// Finds the function with the given identity, which may be at a different address than when
+    /// the identity was captured, and opens it like [`VfioPciDevice::open`].
+    ///
+    /// Fails with [`ErrorKind::NotFound`] if no such function is present.
+    pub fn open_identity(identity: &PciDeviceIdentity) -> io::Result<VfioPciDevice> {
+        let entry = identity.find()?.ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("No PCI function with identity {}", identity),
+            )
+        })?;
+
+        Self::open(entry)
+    }
+
+    /// Same as [`VfioPciDevice::open`], but first takes a [`PciDeviceClaim`] on the device, waiting
+    /// for any other process to release it. The claim is held until the returned `VfioPciDevice`
+    /// and everything that shares ownership of its resources are dropped.
+    pub fn open_claimed<P: AsRef<Path>>(sysfs_path: P) -> io::Result<VfioPciDevice> {
+        let address = parse_device_address(&sysfs_path.as_ref().canonicalize()?)?;
+        let claim = PciDeviceClaim::acquire(address)?;
+
+        Ok(Self::open(sysfs_path)?.with_claim(claim))
+    }
+
+    /// Same as [`VfioPciDevice::open_claimed`], but fails with [`ErrorKind::WouldBlock`] if another
+    /// process holds the claim, saying which if it is known.
+    pub fn try_open_claimed<P: AsRef<Path>>(sysfs_path: P) -> io::Result<VfioPciDevice> {
+        let address = parse_device_address(&sysfs_path.as_ref().canonicalize()?)?;
+        let claim = PciDeviceClaim::try_acquire(address)?;
+
+        Ok(Self::open(sysfs_path)?.with_claim(claim))
+    }
+
+    /// Same as [`VfioPciDevice::open`], but creates the [`VfioContainer`] with the given IOMMU
+    /// backend, which can't be [`VfioIommuBackend::NoIommu`] (see
+    /// [`VfioPciDevice::open_noiommu`]).
+    pub fn open_with_iommu_backend<P: AsRef<Path>>(
+        sysfs_path: P,
+        backend: VfioIommuBackend,
+    ) -> io::Result<VfioPciDevice> {
+        let group_number = get_device_group_number(&sysfs_path)?;
+        let container = Arc::new(VfioContainer::with_iommu_backend(&[group_number], backend)?);
+
+        Self::open_in_container(sysfs_path, container)
+    }
+
+    /// Opens all functions of the multi-function device that the given function belongs to, in a
+    /// single new [`VfioContainer`] containing all their groups, and returns them keyed by function
+    /// number.
+    ///
+    /// `sysfs_path` may correspond to any of the functions. All functions must be bound to
+    /// vfio-pci, and no other [`VfioContainer`] may contain their groups. This also works for
+    /// single-function devices, returning only that function.
+    pub fn open_all_functions<P: AsRef<Path>>(
+        sysfs_path: P,
+    ) -> io::Result<BTreeMap<u8, VfioPciDevice>> {
+        let sysfs_path = sysfs_path.as_ref().canonicalize()?;
+        let address = parse_device_address(&sysfs_path)?;
+
+        // functions of the same device are siblings in the sysfs hierarchy
+
+        let parent = sysfs_path.parent().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} isn't a PCI device's sysfs path", sysfs_path.display()),
+            )
+        })?;
+
+        let mut functions = BTreeMap::new();
+
+        for entry in fs::read_dir(parent)? {
+            let path = entry?.path();
+
+            if let Ok(sibling) = parse_device_address(&path) {
+                if (sibling.domain, sibling.bus, sibling.device)
+                    == (address.domain, address.bus, address.device)
+                {
+                    functions.insert(sibling.function, path);
+                }
+            }
+        }
+
+        let mut groups = functions
+            .values()
+            .map(get_device_group_number)
+            .collect::<io::Result<Vec<_>>>()?;
+        groups.sort_unstable();
+        groups.dedup();
+
+        let container = Arc::new(VfioContainer::new(&groups)?);
+
+        functions
+            .into_iter()
+            .map(|(function, path)| {
+                let device = Self::open_in_container(path, Arc::clone(&container))?;
+                Ok((function, device))
+            })
+            .collect()
+    }
+
     /// Opens a vfio-pci device and adds it to the given container.
     ///
     /// `sysfs_path` must correspond to the device's sysfs directory, *e.g.*,
     /// `/sys/bus/pci/devices/0000:00:01.0`. `container` must contain the group to which the device
-    /// belongs.
+    /// belongs, and so can't be one created by [`VfioPciDevice::open_cdev`].
     ///
     /// Returns a `VfioPciDevice` corresponding to the opened device.
     pub fn open_in_container<P: AsRef<Path>>(
         sysfs_path: P,
         container: Arc<VfioContainer>,
     ) -> io::Result<VfioPciDevice> {
+        let device = Self::open_in_container_lazy(sysfs_path, container)?;
+        device.set_up_regions()?;
+        Ok(device)
+    }
+
+    /// Same as [`VfioPciDevice::open`], but sets up the device's BARs, Expansion ROM, and
+    /// device-specific regions lazily. See [`VfioPciDevice::open_in_container_lazy`].
+    pub fn open_lazy<P: AsRef<Path>>(sysfs_path: P) -> io::Result<VfioPciDevice> {
+        let group_number = get_device_group_number(&sysfs_path)?;
+        let container = Arc::new(VfioContainer::new(&[group_number])?);
+
+        Self::open_in_container_lazy(sysfs_path, container)
+    }
+
+    /// Same as [`VfioPciDevice::open_in_container`], but sets up the device's BARs, Expansion ROM,
+    /// and device-specific regions on first use instead of when opening the device.
+    ///
+    /// Setting up the regions takes at least one `VFIO_DEVICE_GET_REGION_INFO` ioctl per region
+    /// (there are at least 7), plus reading the device's MSI-X Capability, which adds up when
+    /// opening many devices, _e.g._, dozens of SR-IOV VFs, of which only some will have their
+    /// regions accessed. Opening a device this way only issues the ioctls needed to read its
+    /// config space and query its interrupts.
+    ///
+    /// The `open_benchmark` example in the repository measures the difference on your devices, by
+    /// opening them both this way and like [`VfioPciDevice::open_in_container`] does.
+    ///
+    /// The catch is that errors setting up the regions can't be reported when opening the device.
+    /// If that fails, [`PciDevice::bar`] and [`PciDevice::rom`] return `None` and
+    /// [`VfioPciDevice::extra_regions`] returns nothing, and setting up is retried the next time.
+    /// Call [`VfioPciDevice::set_up_regions`] before using the regions to get the error instead.
+    pub fn open_in_container_lazy<P: AsRef<Path>>(
+        sysfs_path: P,
+        container: Arc<VfioContainer>,
+    ) -> io::Result<VfioPciDevice> {
+        let sysfs_path = sysfs_path.as_ref().canonicalize()?;
         let device_address = get_device_address(&sysfs_path)?;
         let group_number = get_device_group_number(&sysfs_path)?;
 
@@ -126,9 +476,41 @@ impl VfioPciDevice {
 
         let device_file = unsafe {
             let fd = vfio_group_get_device_fd(group_file.as_raw_fd(), device_address.as_ptr())?;
-            Arc::new(File::from_raw_fd(fd))
+            File::from_raw_fd(fd)
         };
 
+        Self::from_device_file(sysfs_path, container, device_file)
+    }
+
+    /// Creates a `VfioPciDevice` from an already opened VFIO device file descriptor, _e.g._, one
+    /// obtained by a privileged process and passed over a Unix socket. `container` must be the
+    /// container the device was opened through, _e.g._, created with
+    /// [`VfioContainer::from_raw_fds`] from file descriptors passed the same way.
+    ///
+    /// The device's sysfs directory is found from the file descriptor on Linux 6.9 or later, or if
+    /// it refers to a VFIO cdev. Otherwise, the container's groups must contain only one function
+    /// bound to a VFIO driver, which is then assumed to be the device. Fails with
+    /// [`ErrorKind::InvalidInput`] if the device can't be determined.
+    pub fn from_device_fd(
+        device_fd: OwnedFd,
+        container: Arc<VfioContainer>,
+    ) -> io::Result<VfioPciDevice> {
+        let sysfs_path = get_device_fd_sysfs_path(device_fd.as_raw_fd(), &container)?;
+        let device =
+            Self::from_device_file(sysfs_path.canonicalize()?, container, File::from(device_fd))?;
+        device.inner.regions()?;
+        Ok(device)
+    }
+
+    /// `sysfs_path` must be canonical, and `device_file` must already have been attached to the
+    /// container's IOMMU context. The device's regions other than config space aren't set up yet.
+    fn from_device_file(
+        sysfs_path: PathBuf,
+        container: Arc<VfioContainer>,
+        device_file: File,
+    ) -> io::Result<VfioPciDevice> {
+        let device_file = Arc::new(device_file);
+
         // validate device info
 
         let mut device_info = vfio_device_info {
@@ -150,7 +532,7 @@ impl VfioPciDevice {
 
         // get interrupt info
 
-        let get_max_interrupts = |index| {
+        let get_interrupt_info = |index| {
             let mut irq_info = vfio_irq_info {
                 argsz: mem::size_of::<vfio_irq_info>() as u32,
                 flags: 0,
@@ -164,61 +546,202 @@ impl VfioPciDevice {
                 return Err(io::Error::new(ErrorKind::Other, "TODO"));
             }
 
-            Ok(irq_info.count as usize)
+            Ok((irq_info.count as usize, irq_info.flags))
         };
 
-        let max_interrupts = [
-            get_max_interrupts(VFIO_PCI_INTX_IRQ_INDEX)?,
-            get_max_interrupts(VFIO_PCI_MSI_IRQ_INDEX)?,
-            get_max_interrupts(VFIO_PCI_MSIX_IRQ_INDEX)?,
-        ];
-
-        // set up config space
+        let mut max_interrupts = [0; INTERRUPT_KINDS.len()];
+        let mut interrupt_flags = [0; INTERRUPT_KINDS.len()];
 
-        let config_region = set_up_config_space(&device_file)?;
+        for &kind in &INTERRUPT_KINDS {
+            let index = interrupt_index_from_kind(kind);
 
-        // set up BARs and ROM
+            // very old kernels don't have the error and request IRQ indices
+            if index < device_info.num_irqs {
+                let (max, flags) = get_interrupt_info(index)?;
+                max_interrupts[kind as usize] = max;
+                interrupt_flags[kind as usize] = flags;
+            }
+        }
 
-        let bars = (VFIO_PCI_BAR0_REGION_INDEX..=VFIO_PCI_BAR5_REGION_INDEX)
-            .map(|index| set_up_bar_or_rom(&device_file, index))
-            .collect::<io::Result<_>>()?;
+        // set up config space
 
-        let rom = set_up_bar_or_rom(&device_file, VFIO_PCI_ROM_REGION_INDEX)?;
+        let config_region = set_up_config_space(&device_file)?;
+        let info = PciDeviceInfo::read(&PciConfig::backed_by(&config_region))?;
+        let quirks = quirks::lookup(&info);
 
         // success
 
         Ok(VfioPciDevice {
             inner: Arc::new(VfioPciDeviceInner {
                 container,
+                address: parse_device_address(&sysfs_path).ok(),
+                sysfs_path,
                 file: device_file,
+                device_info: VfioDeviceInfo {
+                    flags: device_info.flags,
+                    num_regions: device_info.num_regions,
+                    num_irqs: device_info.num_irqs,
+                },
+                info,
+                quirks,
                 config_region,
-                bars,
-                rom,
+                regions: Mutex::new(None),
+                region_generation: AtomicU64::new(0),
                 max_interrupts,
+                interrupt_flags,
+                interrupt_triggers: Mutex::new(Default::default()),
+                bar_restore: VfioBarRestore::default(),
+                claim: None,
             }),
         })
     }
 
+    fn with_claim(mut self, claim: PciDeviceClaim) -> VfioPciDevice {
+        // nothing else shares the inner state of a device that was just opened
+        Arc::get_mut(&mut self.inner).unwrap().claim = Some(claim);
+        self
+    }
+
     /// Returns a reference to the container to which the device's group belongs.
     pub fn container(&self) -> &Arc<VfioContainer> {
         &self.inner.container
     }
+
+    /// Returns the flags and the numbers of regions and IRQ indices that the kernel reports for the
+    /// device.
+    pub fn device_info(&self) -> VfioDeviceInfo {
+        self.inner.device_info
+    }
+
+    /// Whether the device's container is in VFIO's no-IOMMU mode, in which case nothing isolates
+    /// the host from the device's DMA. See [`VfioPciDevice::open_noiommu`].
+    pub fn is_noiommu(&self) -> bool {
+        self.inner.container.is_noiommu()
+    }
+
+    /// Returns the type VFIO reports for the given BAR, or `None` if it reports none or there is no
+    /// such BAR.
+    pub fn bar_region_type(&self, index: usize) -> Option<VfioRegionType> {
+        self.inner
+            .regions()
+            .ok()?
+            .bars
+            .get(index)?
+            .as_ref()?
+            .region_type()
+    }
+
+    /// Returns the type VFIO reports for the Expansion ROM, or `None` if it reports none or there
+    /// is no Expansion ROM.
+    pub fn rom_region_type(&self) -> Option<VfioRegionType> {
+        self.inner.regions().ok()?.rom.as_ref()?.region_type()
+    }
+
+    /// Sets up the device's BARs, Expansion ROM, and device-specific regions, if that hasn't been
+    /// done yet, returning any error.
+    ///
+    /// This is only useful for devices opened with, _e.g._,
+    /// [`VfioPciDevice::open_in_container_lazy`], whose regions are otherwise set up on first use,
+    /// where errors can't be reported.
+    pub fn set_up_regions(&self) -> io::Result<()> {
+        self.inner.regions()?;
+        Ok(())
+    }
+
+    /// Returns the device-specific regions that VFIO exposes in addition to the BARs, Expansion
+    /// ROM, config space, and VGA region, in ascending order of VFIO region index.
+    ///
+    /// These are identified by their [type](VfioRegionType), _e.g._, the Intel Graphics Device
+    /// OpRegion. Regions that can be neither read nor written are left out.
+    pub fn extra_regions(&self) -> Vec<VfioExtraRegion> {
+        let regions = match self.inner.regions() {
+            Ok(regions) => regions,
+            Err(_) => return Vec::new(),
+        };
+
+        regions
+            .extra
+            .iter()
+            .map(|(&index, region)| VfioExtraRegion {
+                index,
+                region_type: region.region_type(),
+                region: OwningPciRegion::new(
+                    Arc::<VfioPciDeviceInner>::clone(&self.inner),
+                    Arc::<VfioUnmappedPciRegion>::clone(region),
+                    RegionIdentifier::Other(index),
+                    region.mappable_ranges(),
+                ),
+            })
+            .collect()
+    }
+
+    /// Returns the first device-specific region of the given type, if any. See
+    /// [`VfioPciDevice::extra_regions`].
+    pub fn find_extra_region(&self, region_type: VfioRegionType) -> Option<VfioExtraRegion> {
+        self.extra_regions()
+            .into_iter()
+            .find(|region| region.region_type == Some(region_type))
+    }
+
+    /// Returns the Intel Graphics Device OpRegion, if the device is an IGD for which VFIO exposes
+    /// it. See [`VfioRegionType::INTEL_IGD_OPREGION`].
+    pub fn igd_opregion(&self) -> Option<OwningPciRegion> {
+        self.find_extra_region(VfioRegionType::INTEL_IGD_OPREGION)
+            .map(|region| region.region)
+    }
+
+    /// Returns the read-only copy of the host bridge's config space that VFIO exposes for Intel
+    /// Graphics Devices, if any. See [`VfioRegionType::INTEL_IGD_HOST_CFG`].
+    pub fn igd_host_bridge_config(&self) -> Option<OwningPciRegion> {
+        self.find_extra_region(VfioRegionType::INTEL_IGD_HOST_CFG)
+            .map(|region| region.region)
+    }
+
+    /// Returns the read-only copy of the LPC bridge's config space that VFIO exposes for Intel
+    /// Graphics Devices, if any. See [`VfioRegionType::INTEL_IGD_LPC_CFG`].
+    pub fn igd_lpc_bridge_config(&self) -> Option<OwningPciRegion> {
+        self.find_extra_region(VfioRegionType::INTEL_IGD_LPC_CFG)
+            .map(|region| region.region)
+    }
+
+    /// Returns the device's sysfs directory, through which you can, _e.g._, prevent the kernel from
+    /// runtime-suspending the device while you drive it.
+    pub fn sysfs(&self) -> PciSysfsDevice {
+        PciSysfsDevice::new(&self.inner.sysfs_path)
+    }
 }
 
 impl crate::device::Sealed for VfioPciDevice {}
 impl PciDevice for VfioPciDevice {
-    fn config(&self) -> PciConfig {
-        PciConfig::backed_by(&self.inner.config_region)
+    fn config(&self) -> PciConfig<'_> {
+        self.inner.config_space()
+    }
+
+    fn info(&self) -> PciDeviceInfo {
+        self.inner.info
+    }
+
+    fn quirks(&self) -> PciQuirks {
+        self.inner.quirks
+    }
+
+    fn address(&self) -> Option<PciAddress> {
+        self.inner.address
+    }
+
+    fn numa_node(&self) -> io::Result<Option<u32>> {
+        self.sysfs().numa_node()
     }
 
     fn bar(&self, index: usize) -> Option<OwningPciRegion> {
-        let bar = self.inner.bars.get(index)?.as_ref()?;
+        let regions = self.inner.regions().ok()?;
+        let bar = regions.bars.get(index)?.as_ref()?;
 
         Some(OwningPciRegion::new(
             Arc::<VfioPciDeviceInner>::clone(&self.inner),
             Arc::<VfioUnmappedPciRegion>::clone(bar),
             RegionIdentifier::Bar(index),
-            bar.is_mappable(),
+            bar.mappable_ranges(),
         ))
     }
 
@@ -231,29 +754,67 @@ impl PciDevice for VfioPciDevice {
     }
 
     fn rom(&self) -> Option<OwningPciRegion> {
-        let rom = self.inner.rom.as_ref()?;
+        let regions = self.inner.regions().ok()?;
+        let rom = regions.rom.as_ref()?;
 
         Some(OwningPciRegion::new(
             Arc::<VfioPciDeviceInner>::clone(&self.inner),
             Arc::<VfioUnmappedPciRegion>::clone(rom),
             RegionIdentifier::Rom,
-            rom.is_mappable(),
+            rom.mappable_ranges(),
         ))
     }
 
-    fn iommu(&self) -> Option<PciIommu> {
-        self.inner.container.iommu()
+    fn iommu(&self) -> Option<PciIommu<'_>> {
+        self.inner.container.iommu()?;
+        Some(PciIommu {
+            internal: &*self.inner,
+        })
     }
 
-    fn interrupts(&self) -> PciInterrupts {
+    fn interrupts(&self) -> PciInterrupts<'_> {
         PciInterrupts {
             device: &*self.inner,
         }
     }
 
     fn reset(&self) -> io::Result<()> {
+        self.check_reset_quirks()?;
         unsafe { vfio_device_reset(self.inner.file.as_raw_fd())? };
-        Ok(())
+        self.restore_bars_if_enabled()
+    }
+
+    fn reset_capabilities(&self) -> io::Result<PciResetCapabilities> {
+        let mut capabilities = PciResetCapabilities::from_config(&self.config())?;
+
+        // VFIO picks whichever function-granular mechanism works, and tells us if there is one
+        capabilities.function_reset =
+            self.inner.device_info.supports_reset() && self.check_reset_quirks().is_ok();
+        capabilities.hot_reset = self.hot_reset_devices().is_ok();
+
+        let quirks = self.inner.quirks;
+        capabilities.flr &= !quirks.no_flr;
+        capabilities.af_flr &= !quirks.no_flr;
+        capabilities.pm_reset &= !quirks.no_pm_reset;
+        capabilities.hot_reset &= !quirks.no_hot_reset;
+
+        Ok(capabilities)
+    }
+}
+
+/// The device's VFIO device file descriptor, _e.g._, for issuing ioctls this crate doesn't wrap.
+///
+/// Changing the device's state through it (_e.g._, its interrupt setup) may leave this
+/// `VfioPciDevice` out of sync with the device.
+impl AsFd for VfioPciDevice {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.file.as_fd()
+    }
+}
+
+impl AsRawFd for VfioPciDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.file.as_raw_fd()
     }
 }
 
@@ -263,31 +824,81 @@ impl PciDevice for VfioPciDevice {
 struct VfioPciDeviceInner {
     container: Arc<VfioContainer>,
 
+    sysfs_path: PathBuf,
+    address: Option<PciAddress>,
     file: Arc<File>,
+    device_info: VfioDeviceInfo,
+    info: PciDeviceInfo,
+    quirks: PciQuirks,
 
     config_region: VfioUnmappedPciRegion,
+    /// `None` until first needed. See [`VfioPciDeviceInner::regions`].
+    regions: Mutex<Option<Arc<VfioRegions>>>,
+    /// Incremented by [`VfioPciDevice::reinitialize`].
+    region_generation: AtomicU64,
+
+    max_interrupts: [usize; INTERRUPT_KINDS.len()],
+    interrupt_flags: [u32; INTERRUPT_KINDS.len()],
+    interrupt_triggers: Mutex<[InterruptTriggers; INTERRUPT_KINDS.len()]>,
+
+    /// See [`VfioPciDevice::set_bar_restore`].
+    bar_restore: VfioBarRestore,
+
+    /// Released after everything else is dropped.
+    claim: Option<PciDeviceClaim>,
+}
+
+/// The regions of a device other than config space.
+#[derive(Debug)]
+struct VfioRegions {
     bars: Box<[Option<Arc<VfioUnmappedPciRegion>>]>,
     rom: Option<Arc<VfioUnmappedPciRegion>>,
+    /// Keyed by VFIO region index.
+    extra: BTreeMap<u32, Arc<VfioUnmappedPciRegion>>,
+}
 
-    max_interrupts: [usize; 3],
+/// The eventfds set as triggers of an interrupt mechanism's vectors.
+#[derive(Debug, Default)]
+struct InterruptTriggers {
+    /// -1 for vectors without one.
+    eventfds: Vec<RawFd>,
+    /// The eventfds that were handed over to us, which are closed when their vectors are disabled
+    /// or get another eventfd.
+    owned: Vec<Option<OwnedFd>>,
 }
 
 impl PciDeviceInternal for VfioPciDeviceInner {
+    // Config space
+
+    fn config_space(&self) -> PciConfig<'_> {
+        PciConfig::backed_by(&self.config_region)
+    }
+
     // BARs / ROM
 
+    fn region_generation(&self) -> u64 {
+        self.region_generation.load(Ordering::Acquire)
+    }
+
     fn region_map(
         &self,
         identifier: RegionIdentifier,
         offset: u64,
         len: usize,
         permissions: Permissions,
+        cacheability: Cacheability,
     ) -> io::Result<*mut u8> {
+        let regions = self.regions()?;
+
         let region = match identifier {
-            RegionIdentifier::Bar(index) => &self.bars[index],
-            RegionIdentifier::Rom => &self.rom,
+            RegionIdentifier::Bar(index) => regions.bars[index].as_ref(),
+            RegionIdentifier::Rom => regions.rom.as_ref(),
+            RegionIdentifier::Other(index) => regions.extra.get(&index),
         };
 
-        let region = region.as_ref().unwrap();
+        // stale regions may refer to one that no longer exists after VfioPciDevice::reinitialize()
+        let region = region
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "No such region to map"))?;
 
         let prot_flags = match permissions {
             Permissions::Read => PROT_READ,
@@ -295,14 +906,59 @@ impl PciDeviceInternal for VfioPciDeviceInner {
             Permissions::ReadWrite => PROT_READ | PROT_WRITE,
         };
 
+        // VFIO always maps regions uncached, so for write-combined mappings we go through the
+        // resource<N>_wc sysfs file instead, which the kernel only provides for prefetchable
+        // Memory Space BARs. That file maps the BAR starting at offset 0.
+
+        let (wc_file, file_offset) = match (cacheability, identifier) {
+            (Cacheability::Uncached, _) => (None, region.offset_in_device_file() + offset),
+            (Cacheability::WriteCombined, RegionIdentifier::Bar(index)) => {
+                let path = self.sysfs_path.join(format!("resource{}_wc", index));
+
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(permissions.can_write())
+                    .open(&path)
+                    .map_err(|e| {
+                        if e.kind() == ErrorKind::NotFound {
+                            io::Error::new(
+                                ErrorKind::InvalidInput,
+                                format!("BAR {} can't be mapped write-combined", index),
+                            )
+                        } else {
+                            e
+                        }
+                    })?;
+
+                (Some(file), offset)
+            }
+            (Cacheability::WriteCombined, RegionIdentifier::Rom) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "The Expansion ROM can't be mapped write-combined",
+                ));
+            }
+            (Cacheability::WriteCombined, RegionIdentifier::Other(_)) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "Device-specific regions can't be mapped write-combined",
+                ));
+            }
+        };
+
+        let fd = match &wc_file {
+            Some(file) => file.as_raw_fd(),
+            None => self.file.as_raw_fd(),
+        };
+
         let address = unsafe {
             mmap64(
                 ptr::null_mut(),
                 len,
                 prot_flags,
                 MAP_SHARED,
-                self.file.as_raw_fd(),
-                region.offset_in_device_file() as i64 + offset as i64,
+                fd,
+                file_offset as i64,
             )
         };
 
@@ -330,56 +986,154 @@ impl PciDeviceInternal for VfioPciDeviceInner {
         self.max_interrupts[kind as usize]
     }
 
-    fn interrupts_enable(&self, kind: PciInterruptKind, eventfds: &[RawFd]) -> io::Result<()> {
-        if eventfds.len() > self.max_interrupts[kind as usize] {
-            return Err(io::Error::new(ErrorKind::Other, "TODO"));
+    fn interrupts_enable(
+        &self,
+        kind: PciInterruptKind,
+        start: usize,
+        eventfds: &[RawFd],
+        owned: Option<Vec<OwnedFd>>,
+    ) -> io::Result<()> {
+        let max = self.max_interrupts[kind as usize];
+
+        if start > max || eventfds.len() > max - start {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Vectors [{}, {}) exceed the maximum of {}",
+                    start,
+                    start.saturating_add(eventfds.len()),
+                    max
+                ),
+            ));
         }
 
-        // allocate memory for vfio_irq_set
+        let data: Vec<u8> = eventfds
+            .iter()
+            .flat_map(|fd| fd.to_ne_bytes().to_vec())
+            .collect();
 
-        let eventfds_size = std::mem::size_of_val(eventfds);
-        let total_size = mem::size_of::<vfio_irq_set>() + eventfds_size;
+        let mut triggers = self.interrupt_triggers.lock().unwrap();
 
-        let layout = Layout::from_size_align(total_size, 4)
-            .map_err(|_| io::Error::new(ErrorKind::Other, "TODO"))?;
+        self.set_irqs(
+            kind,
+            VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+            start,
+            eventfds.len(),
+            &data,
+        )?;
 
-        let mem = unsafe { alloc::alloc(layout) };
+        let triggers = &mut triggers[kind as usize];
+        let end = start + eventfds.len();
 
-        if mem.is_null() {
-            alloc::handle_alloc_error(layout);
+        if triggers.eventfds.len() < end {
+            triggers.eventfds.resize(end, -1);
+            triggers.owned.resize_with(end, || None);
+        }
+        triggers.eventfds[start..end].copy_from_slice(eventfds);
+
+        // this closes the eventfds we owned for these vectors, if any
+        match owned {
+            Some(owned) => {
+                for (slot, fd) in triggers.owned[start..end].iter_mut().zip(owned) {
+                    *slot = Some(fd);
+                }
+            }
+            None => triggers.owned[start..end]
+                .iter_mut()
+                .for_each(|slot| *slot = None),
         }
 
-        // initialize vfio_irq_set
+        Ok(())
+    }
+
+    fn interrupts_eventfds(&self, kind: PciInterruptKind) -> Vec<RawFd> {
+        self.interrupt_triggers.lock().unwrap()[kind as usize]
+            .eventfds
+            .clone()
+    }
 
-        let irq_set = mem as *mut vfio_irq_set;
+    fn interrupts_owned_eventfds(
+        &self,
+        kind: PciInterruptKind,
+    ) -> io::Result<Vec<Option<OwnedFd>>> {
+        self.interrupt_triggers.lock().unwrap()[kind as usize]
+            .owned
+            .iter()
+            .map(|fd| fd.as_ref().map(OwnedFd::try_clone).transpose())
+            .collect()
+    }
 
-        unsafe {
-            (*irq_set).argsz = total_size as u32;
-            (*irq_set).flags = VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER;
-            (*irq_set).index = interrupt_index_from_kind(kind);
-            (*irq_set).start = 0;
-            (*irq_set).count = eventfds.len() as u32;
-        }
+    fn interrupts_is_maskable(&self, kind: PciInterruptKind) -> bool {
+        self.interrupt_flags[kind as usize] & VFIO_IRQ_INFO_MASKABLE != 0
+    }
 
-        let eventfd_mem_iter = unsafe {
-            (*irq_set)
-                .data
-                .as_mut_slice(eventfds_size)
-                .chunks_exact_mut(4)
+    fn interrupts_is_automasked(&self, kind: PciInterruptKind) -> bool {
+        self.interrupt_flags[kind as usize] & VFIO_IRQ_INFO_AUTOMASKED != 0
+    }
+
+    fn interrupts_is_resizable(&self, kind: PciInterruptKind) -> bool {
+        self.interrupt_flags[kind as usize] & VFIO_IRQ_INFO_NORESIZE == 0
+    }
+
+    fn interrupts_set_masked(&self, kind: PciInterruptKind, masked: bool) -> io::Result<()> {
+        self.check_maskable(kind)?;
+
+        let action = if masked {
+            VFIO_IRQ_SET_ACTION_MASK
+        } else {
+            VFIO_IRQ_SET_ACTION_UNMASK
         };
 
-        for (mem, eventfd) in eventfd_mem_iter.zip(eventfds) {
-            mem.copy_from_slice(&eventfd.to_ne_bytes());
-        }
+        let count = self.max_interrupts[kind as usize];
 
-        // enable interrupt vectors
+        self.set_irqs(kind, VFIO_IRQ_SET_DATA_NONE | action, 0, count, &[])
+    }
 
-        unsafe { vfio_device_set_irqs(self.file.as_raw_fd(), irq_set)? };
+    fn interrupts_set_unmask_eventfd(
+        &self,
+        kind: PciInterruptKind,
+        eventfd: RawFd,
+    ) -> io::Result<()> {
+        self.check_maskable(kind)?;
+
+        let count = self.max_interrupts[kind as usize];
+        let data: Vec<u8> = (0..count)
+            .flat_map(|_| eventfd.to_ne_bytes().to_vec())
+            .collect();
+
+        self.set_irqs(
+            kind,
+            VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_UNMASK,
+            0,
+            count,
+            &data,
+        )
+    }
 
-        Ok(())
+    fn interrupts_trigger(&self, kind: PciInterruptKind, vector: usize) -> io::Result<()> {
+        let enabled = self.interrupt_triggers.lock().unwrap()[kind as usize]
+            .eventfds
+            .len();
+
+        if vector >= enabled {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Vector {} is not enabled", vector),
+            ));
+        }
+
+        self.set_irqs(
+            kind,
+            VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_TRIGGER,
+            vector,
+            1,
+            &[],
+        )
     }
 
     fn interrupts_disable(&self, kind: PciInterruptKind) -> io::Result<()> {
+        let mut triggers = self.interrupt_triggers.lock().unwrap();
+
         let irq_set = vfio_irq_set {
             argsz: mem::size_of::<vfio_irq_set>() as u32,
             flags: VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_TRIGGER,
@@ -391,15 +1145,314 @@ impl PciDeviceInternal for VfioPciDeviceInner {
 
         unsafe { vfio_device_set_irqs(self.file.as_raw_fd(), &irq_set)? };
 
+        triggers[kind as usize] = InterruptTriggers::default();
+
         Ok(())
     }
+
+    fn interrupts_irq_name(&self, kind: PciInterruptKind, vector: usize) -> Option<String> {
+        let address = self.sysfs_path.file_name()?.to_str()?;
+
+        // these are the names that vfio-pci gives to the IRQs it requests
+        match kind {
+            PciInterruptKind::Intx => Some(format!("vfio-intx({})", address)),
+            PciInterruptKind::Msi => Some(format!("vfio-msi[{}]({})", vector, address)),
+            PciInterruptKind::MsiX => Some(format!("vfio-msix[{}]({})", vector, address)),
+            _ => None,
+        }
+    }
 }
 
+/// A device's IOMMU is its container's, except that PASIDs are attached to the device itself.
+impl PciIommuInternal for VfioPciDeviceInner {
+    fn alignment(&self) -> usize {
+        self.container_iommu().alignment()
+    }
+
+    fn page_sizes(&self) -> u64 {
+        self.container_iommu().page_sizes()
+    }
+
+    fn valid_iova_ranges(&self) -> &[Range<u64>] {
+        self.container_iommu().valid_iova_ranges()
+    }
+
+    fn max_num_mappings(&self) -> u32 {
+        self.container_iommu().max_num_mappings()
+    }
+
+    fn num_mappings(&self) -> io::Result<u32> {
+        self.container_iommu().num_mappings()
+    }
+
+    unsafe fn map(
+        &self,
+        iova: u64,
+        length: usize,
+        address: *const u8,
+        device_permissions: Permissions,
+    ) -> io::Result<()> {
+        unsafe {
+            self.container_iommu()
+                .map(iova, length, address, device_permissions)
+        }
+    }
+
+    fn unmap(&self, iova: u64, length: usize) -> io::Result<()> {
+        self.container_iommu().unmap(iova, length)
+    }
+
+    fn unmap_range(&self, iova: u64, length: usize) -> io::Result<u64> {
+        self.container_iommu().unmap_range(iova, length)
+    }
+
+    fn unmap_all(&self) -> io::Result<()> {
+        self.container_iommu().unmap_all()
+    }
+
+    fn attach_pasid(&self, pasid: u32) -> io::Result<()> {
+        self.require_device_cdev()?;
+
+        let mut attach = vfio_device_attach_iommufd_pt {
+            argsz: mem::size_of::<vfio_device_attach_iommufd_pt>() as u32,
+            flags: VFIO_DEVICE_ATTACH_PASID,
+            pt_id: self.container.ioas_id,
+            pasid,
+        };
+
+        unsafe { vfio_device_attach_iommufd_pt(self.file.as_raw_fd(), &mut attach)? };
+
+        Ok(())
+    }
+
+    fn detach_pasid(&self, pasid: u32) -> io::Result<()> {
+        self.require_device_cdev()?;
+
+        let mut detach = vfio_device_detach_iommufd_pt {
+            argsz: mem::size_of::<vfio_device_detach_iommufd_pt>() as u32,
+            flags: VFIO_DEVICE_DETACH_PASID,
+            pasid,
+        };
+
+        unsafe { vfio_device_detach_iommufd_pt(self.file.as_raw_fd(), &mut detach)? };
+
+        Ok(())
+    }
+
+    unsafe fn remap(&self, iova: u64, length: usize, new_address: *const u8) -> io::Result<()> {
+        unsafe { self.container_iommu().remap(iova, length, new_address) }
+    }
+
+    unsafe fn protect(
+        &self,
+        iova: u64,
+        length: usize,
+        device_permissions: Permissions,
+    ) -> io::Result<()> {
+        unsafe {
+            self.container_iommu()
+                .protect(iova, length, device_permissions)
+        }
+    }
+}
+
+impl VfioPciDeviceInner {
+    fn container_iommu(&self) -> &dyn PciIommuInternal {
+        &*self.container
+    }
+
+    fn require_device_cdev(&self) -> io::Result<()> {
+        if self.container.uses_device_cdevs() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "PASIDs can only be attached to devices opened through their VFIO cdev",
+            ))
+        }
+    }
+
+    fn check_maskable(&self, kind: PciInterruptKind) -> io::Result<()> {
+        if !self.interrupts_is_maskable(kind) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "This interrupt mechanism can't be masked",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Issues a `VFIO_DEVICE_SET_IRQS` ioctl with the given arguments and trailing data.
+    fn set_irqs(
+        &self,
+        kind: PciInterruptKind,
+        flags: u32,
+        start: usize,
+        count: usize,
+        data: &[u8],
+    ) -> io::Result<()> {
+        // use a u32 buffer so that the vfio_irq_set is properly aligned
+
+        let total_size = mem::size_of::<vfio_irq_set>() + data.len();
+        let mut buffer = vec![0_u32; total_size / 4 + 1];
+
+        let irq_set = buffer.as_mut_ptr() as *mut vfio_irq_set;
+
+        unsafe {
+            (*irq_set).argsz = total_size as u32;
+            (*irq_set).flags = flags;
+            (*irq_set).index = interrupt_index_from_kind(kind);
+            (*irq_set).start = start as u32;
+            (*irq_set).count = count as u32;
+
+            (*irq_set)
+                .data
+                .as_mut_slice(data.len())
+                .copy_from_slice(data);
+        }
+
+        unsafe { vfio_device_set_irqs(self.file.as_raw_fd(), irq_set)? };
+
+        Ok(())
+    }
+
+    /// Returns the device's BARs, Expansion ROM, and device-specific regions, setting them up if
+    /// that hasn't been done yet.
+    fn regions(&self) -> io::Result<Arc<VfioRegions>> {
+        let mut regions = self.regions.lock().unwrap();
+
+        if let Some(regions) = regions.as_ref() {
+            return Ok(Arc::clone(regions));
+        }
+
+        let device_file = &self.file;
+
+        // set up BARs and ROM
+
+        let allow_mmap = !self.quirks.no_bar_mmap;
+        let msix_ranges = get_msix_ranges(&self.config_space())?;
+
+        let bars = (VFIO_PCI_BAR0_REGION_INDEX..=VFIO_PCI_BAR5_REGION_INDEX)
+            .map(|index| {
+                let msix_ranges = &msix_ranges[index as usize];
+                set_up_bar_or_rom(device_file, index, allow_mmap, msix_ranges)
+            })
+            .collect::<io::Result<_>>()?;
+
+        let rom = if self.quirks.no_rom {
+            None
+        } else {
+            set_up_bar_or_rom(device_file, VFIO_PCI_ROM_REGION_INDEX, allow_mmap, &[])?
+        };
+
+        // set up device-specific regions
+
+        let mut extra = BTreeMap::new();
+
+        for index in VFIO_PCI_NUM_REGIONS..self.device_info.num_regions {
+            if let Some(region) = set_up_bar_or_rom(device_file, index, true, &[])? {
+                extra.insert(index, region);
+            }
+        }
+
+        let new_regions = Arc::new(VfioRegions { bars, rom, extra });
+        *regions = Some(Arc::clone(&new_regions));
+
+        Ok(new_regions)
+    }
+
+    /// Issues a `VFIO_DEVICE_FEATURE` ioctl with the given flags, which select the feature and
+    /// whether to get or set it, and the feature's data, which is updated with what the kernel
+    /// returns.
+    fn device_feature<T: Copy>(&self, flags: u32, data: &mut T) -> io::Result<()> {
+        // use a u64 buffer so that the data, which follows the 8-byte header, is properly aligned
+
+        let total_size = mem::size_of::<vfio_device_feature>() + mem::size_of::<T>();
+        let mut buffer = vec![0_u64; total_size / 8 + 1];
+
+        let feature = buffer.as_mut_ptr() as *mut vfio_device_feature;
+
+        unsafe {
+            (*feature).argsz = total_size as u32;
+            (*feature).flags = flags;
+            ptr::write_unaligned((*feature).data.as_mut_ptr() as *mut T, *data);
+
+            vfio_device_feature(self.file.as_raw_fd(), feature)?;
+
+            *data = ptr::read_unaligned((*feature).data.as_ptr() as *const T);
+        }
+
+        Ok(())
+    }
+
+    /// Like [`VfioPciDeviceInner::device_feature`], but for feature data given as raw bytes.
+    fn device_feature_bytes(&self, flags: u32, data: &mut [u8]) -> io::Result<()> {
+        let total_size = mem::size_of::<vfio_device_feature>() + data.len();
+        let mut buffer = vec![0_u64; total_size / 8 + 1];
+
+        let feature = buffer.as_mut_ptr() as *mut vfio_device_feature;
+
+        unsafe {
+            (*feature).argsz = total_size as u32;
+            (*feature).flags = flags;
+            ptr::copy_nonoverlapping(data.as_ptr(), (*feature).data.as_mut_ptr(), data.len());
+
+            vfio_device_feature(self.file.as_raw_fd(), feature)?;
+
+            ptr::copy_nonoverlapping((*feature).data.as_ptr(), data.as_mut_ptr(), data.len());
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns, for each BAR, the page-aligned ranges holding the MSI-X Table or PBA, which VFIO may not
+/// allow mapping.
+fn get_msix_ranges(config: &PciConfig) -> io::Result<[Vec<Range<u64>>; 6]> {
+    let mut ranges: [Vec<Range<u64>>; 6] = Default::default();
+
+    let cap = match config.capabilities()?.of_type::<MsiXCapability>()?.next() {
+        Some(cap) => cap,
+        None => return Ok(ranges),
+    };
+
+    let num_vectors = cap.num_vectors()? as u64;
+    let page_mask = page_size() as u64 - 1;
+
+    let (table_bar, table_offset) = cap.table_location()?;
+    let (pba_bar, pba_offset) = cap.pba_location()?;
+
+    let locations = [
+        (table_bar, table_offset, num_vectors * 16),
+        (pba_bar, pba_offset, (num_vectors + 63) / 64 * 8),
+    ];
+
+    for &(bar, offset, length) in &locations {
+        if let Some(bar_ranges) = ranges.get_mut(bar) {
+            bar_ranges.push(offset & !page_mask..(offset + length + page_mask) & !page_mask);
+        }
+    }
+
+    Ok(ranges)
+}
+
+/// All interrupt kinds, in the order of their discriminants.
+const INTERRUPT_KINDS: [PciInterruptKind; 5] = [
+    PciInterruptKind::Intx,
+    PciInterruptKind::Msi,
+    PciInterruptKind::MsiX,
+    PciInterruptKind::Error,
+    PciInterruptKind::Request,
+];
+
 fn interrupt_index_from_kind(kind: PciInterruptKind) -> u32 {
     match kind {
         PciInterruptKind::Intx => VFIO_PCI_INTX_IRQ_INDEX,
         PciInterruptKind::Msi => VFIO_PCI_MSI_IRQ_INDEX,
         PciInterruptKind::MsiX => VFIO_PCI_MSIX_IRQ_INDEX,
+        PciInterruptKind::Error => VFIO_PCI_ERR_IRQ_INDEX,
+        PciInterruptKind::Request => VFIO_PCI_REQ_IRQ_INDEX,
     }
 }
 
@@ -5,36 +5,157 @@
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::{self, ErrorKind};
+use std::iter;
 use std::mem;
+use std::ops::Range;
 use std::os::unix::fs::FileExt;
 use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use crate::backends::vfio::bindings::{
-    vfio_region_info, VFIO_PCI_CONFIG_REGION_INDEX, VFIO_REGION_INFO_FLAG_MMAP,
-    VFIO_REGION_INFO_FLAG_READ, VFIO_REGION_INFO_FLAG_WRITE,
+    vfio_info_cap_header, vfio_region_info, vfio_region_info_cap_sparse_mmap,
+    vfio_region_info_cap_type, VFIO_PCI_CONFIG_REGION_INDEX, VFIO_PCI_NUM_REGIONS,
+    VFIO_REGION_INFO_CAP_MSIX_MAPPABLE, VFIO_REGION_INFO_CAP_SPARSE_MMAP,
+    VFIO_REGION_INFO_CAP_TYPE, VFIO_REGION_INFO_FLAG_CAPS, VFIO_REGION_INFO_FLAG_MMAP,
+    VFIO_REGION_INFO_FLAG_READ, VFIO_REGION_INFO_FLAG_WRITE, VFIO_REGION_SUBTYPE_GFX_EDID,
+    VFIO_REGION_SUBTYPE_INTEL_IGD_HOST_CFG, VFIO_REGION_SUBTYPE_INTEL_IGD_LPC_CFG,
+    VFIO_REGION_SUBTYPE_INTEL_IGD_OPREGION, VFIO_REGION_TYPE_GFX, VFIO_REGION_TYPE_PCI_VENDOR_MASK,
+    VFIO_REGION_TYPE_PCI_VENDOR_TYPE,
 };
 use crate::backends::vfio::ioctl::vfio_device_get_region_info;
-use crate::regions::{AsPciSubregion, PciRegion, PciSubregion, Permissions};
+use crate::pci_struct;
+use crate::regions::structured::PciRegisterRo;
+use crate::regions::{AsPciSubregion, OwningPciRegion, PciRegion, PciSubregion, Permissions};
 
 /* ---------------------------------------------------------------------------------------------- */
 
+/// The type and subtype that VFIO reports for a region, which identify what device-specific
+/// regions contain. See [`VfioPciDevice::bar_region_type`](super::VfioPciDevice::bar_region_type).
+///
+/// The values are defined in `include/uapi/linux/vfio.h` in the Linux source tree, _e.g._, type
+/// `0x80008086` (Intel) with subtype 1 for the Intel Graphics Device OpRegion.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct VfioRegionType {
+    /// The region's type, which for vendor-specific types has bit 31 set and the PCI Vendor ID in
+    /// the lower 16 bits.
+    pub region_type: u32,
+    /// The region's subtype, whose meaning depends on the type.
+    pub subtype: u32,
+}
+
+impl VfioRegionType {
+    /// The Intel Graphics Device OpRegion, a copy of the ACPI OpRegion through which the graphics
+    /// driver and firmware communicate. Its header can be accessed with
+    /// [`VfioIgdOpRegionHeader`].
+    pub const INTEL_IGD_OPREGION: VfioRegionType =
+        VfioRegionType::pci_vendor(0x8086, VFIO_REGION_SUBTYPE_INTEL_IGD_OPREGION);
+
+    /// A read-only copy of the config space of the host bridge (00:00.0) of a system with an Intel
+    /// Graphics Device, which its driver inspects. It can be accessed as a
+    /// [`PciConfig`](crate::config::PciConfig).
+    pub const INTEL_IGD_HOST_CFG: VfioRegionType =
+        VfioRegionType::pci_vendor(0x8086, VFIO_REGION_SUBTYPE_INTEL_IGD_HOST_CFG);
+
+    /// A read-only copy of the config space of the LPC bridge (00:1f.0) of a system with an Intel
+    /// Graphics Device, which its driver inspects. It can be accessed as a
+    /// [`PciConfig`](crate::config::PciConfig).
+    pub const INTEL_IGD_LPC_CFG: VfioRegionType =
+        VfioRegionType::pci_vendor(0x8086, VFIO_REGION_SUBTYPE_INTEL_IGD_LPC_CFG);
+
+    /// The EDID of the display attached to a virtual graphics device, along with the registers
+    /// that control it.
+    pub const GFX_EDID: VfioRegionType = VfioRegionType {
+        region_type: VFIO_REGION_TYPE_GFX,
+        subtype: VFIO_REGION_SUBTYPE_GFX_EDID,
+    };
+
+    /// Returns the vendor-specific region type with the given PCI Vendor ID and subtype.
+    pub const fn pci_vendor(vendor_id: u16, subtype: u32) -> VfioRegionType {
+        VfioRegionType {
+            region_type: VFIO_REGION_TYPE_PCI_VENDOR_TYPE | vendor_id as u32,
+            subtype,
+        }
+    }
+
+    /// Returns the PCI Vendor ID of vendor-specific region types, or `None` for other types.
+    pub fn pci_vendor_id(&self) -> Option<u16> {
+        if self.region_type & VFIO_REGION_TYPE_PCI_VENDOR_TYPE != 0 {
+            Some((self.region_type & VFIO_REGION_TYPE_PCI_VENDOR_MASK) as u16)
+        } else {
+            None
+        }
+    }
+}
+
+pci_struct! {
+    /// The header of the Intel Graphics Device OpRegion. See
+    /// [`VfioRegionType::INTEL_IGD_OPREGION`].
+    pub struct VfioIgdOpRegionHeader<'a> : 0x100 {
+        /// The size of the OpRegion, in KiB.
+        size           @ 0x10 : PciRegisterRo<'a, u32>,
+        revision       @ 0x15 : PciRegisterRo<'a, u8>,
+        minor_version  @ 0x16 : PciRegisterRo<'a, u8>,
+        major_version  @ 0x17 : PciRegisterRo<'a, u8>,
+        /// The mailboxes the OpRegion supports, as a bit mask.
+        mailboxes      @ 0x58 : PciRegisterRo<'a, u32>,
+    }
+}
+
+impl VfioIgdOpRegionHeader<'_> {
+    /// The signature that OpRegions start with.
+    pub const SIGNATURE: &'static [u8; 16] = b"IntelGraphicsMem";
+
+    /// Whether the OpRegion starts with [`VfioIgdOpRegionHeader::SIGNATURE`].
+    pub fn has_valid_signature(&self) -> io::Result<bool> {
+        let mut signature = [0_u8; 16];
+        self.subregion.read_bytes(0, &mut signature)?;
+        Ok(&signature == Self::SIGNATURE)
+    }
+}
+
+/// A device-specific region. See
+/// [`VfioPciDevice::extra_regions`](super::VfioPciDevice::extra_regions).
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct VfioExtraRegion {
+    /// The VFIO region index, which is at least `VFIO_PCI_NUM_REGIONS`.
+    pub index: u32,
+    /// The type VFIO reports for the region, if any.
+    pub region_type: Option<VfioRegionType>,
+    /// The region itself.
+    pub region: OwningPciRegion,
+}
+
 #[derive(Debug)]
 pub struct VfioUnmappedPciRegion {
     device_file: Arc<File>,
     offset_in_device_file: u64,
     length: u64,
     permissions: Permissions,
-    is_mappable: bool,
+    /// Sorted and non-overlapping.
+    mappable_ranges: Box<[Range<u64>]>,
+    region_type: Option<VfioRegionType>,
+    /// Set by [`VfioPciDevice::reinitialize`](super::VfioPciDevice::reinitialize).
+    invalidated: AtomicBool,
 }
 
 impl VfioUnmappedPciRegion {
+    /// Makes all further accesses to the region fail.
+    pub(crate) fn invalidate(&self) {
+        self.invalidated.store(true, Ordering::Relaxed);
+    }
+
     pub(crate) fn offset_in_device_file(&self) -> u64 {
         self.offset_in_device_file
     }
 
-    pub(crate) fn is_mappable(&self) -> bool {
-        self.is_mappable
+    pub(crate) fn mappable_ranges(&self) -> &[Range<u64>] {
+        &self.mappable_ranges
+    }
+
+    pub(crate) fn region_type(&self) -> Option<VfioRegionType> {
+        self.region_type
     }
 
     fn validate_access(
@@ -43,6 +164,13 @@ impl VfioUnmappedPciRegion {
         offset: u64,
         length: usize,
     ) -> io::Result<()> {
+        if self.invalidated.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                "The region was invalidated by VfioPciDevice::reinitialize(), get it again",
+            ));
+        }
+
         let end = offset + length as u64;
 
         if end > self.length {
@@ -141,16 +269,7 @@ impl<'a> AsPciSubregion<'a> for &'a VfioUnmappedPciRegion {
 /* ---------------------------------------------------------------------------------------------- */
 
 pub(crate) fn set_up_config_space(device_file: &Arc<File>) -> io::Result<VfioUnmappedPciRegion> {
-    let mut region_info = vfio_region_info {
-        argsz: mem::size_of::<vfio_region_info>() as u32,
-        flags: 0,
-        index: VFIO_PCI_CONFIG_REGION_INDEX,
-        cap_offset: 0,
-        size: 0,
-        offset: 0,
-    };
-
-    unsafe { vfio_device_get_region_info(device_file.as_raw_fd(), &mut region_info)? };
+    let region_info = get_region_info(device_file, VFIO_PCI_CONFIG_REGION_INDEX)?;
 
     if region_info.size == 0 {
         return Err(io::Error::new(ErrorKind::InvalidData, "TODO"));
@@ -170,26 +289,25 @@ pub(crate) fn set_up_config_space(device_file: &Arc<File>) -> io::Result<VfioUnm
         offset_in_device_file: region_info.offset,
         length: region_info.size,
         permissions: Permissions::ReadWrite,
-        is_mappable: false,
+        mappable_ranges: Box::new([]),
+        region_type: region_info.region_type,
+        invalidated: AtomicBool::new(false),
     };
 
     Ok(region)
 }
 
+/// `msix_ranges` are the parts of the region holding the MSI-X Table or PBA, which are excluded
+/// from the mappable ranges unless VFIO says they can be mapped.
+///
+/// Device-specific regions that are neither readable nor writable are skipped like absent ones.
 pub(crate) fn set_up_bar_or_rom(
     device_file: &Arc<File>,
     vfio_region_index: u32,
+    allow_mmap: bool,
+    msix_ranges: &[Range<u64>],
 ) -> io::Result<Option<Arc<VfioUnmappedPciRegion>>> {
-    let mut region_info = vfio_region_info {
-        argsz: mem::size_of::<vfio_region_info>() as u32,
-        flags: 0,
-        index: vfio_region_index,
-        cap_offset: 0,
-        size: 0,
-        offset: 0,
-    };
-
-    unsafe { vfio_device_get_region_info(device_file.as_raw_fd(), &mut region_info)? };
+    let region_info = get_region_info(device_file, vfio_region_index)?;
 
     if region_info.size == 0 {
         return Ok(None); // no such region
@@ -198,27 +316,192 @@ pub(crate) fn set_up_bar_or_rom(
     let readable = region_info.flags & VFIO_REGION_INFO_FLAG_READ != 0;
     let writable = region_info.flags & VFIO_REGION_INFO_FLAG_WRITE != 0;
 
-    let permissions = Permissions::new(readable, writable).ok_or_else(|| {
-        io::Error::new(
-            ErrorKind::Other,
-            "Found a region that is neither readable nor writeable",
-        )
-    })?;
+    let permissions = match Permissions::new(readable, writable) {
+        Some(permissions) => permissions,
+        // these only exist for their capabilities, so they can't be used through here anyway
+        None if vfio_region_index >= VFIO_PCI_NUM_REGIONS => return Ok(None),
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                "Found a region that is neither readable nor writeable",
+            ))
+        }
+    };
+
+    let mappable_ranges = if !allow_mmap {
+        Box::new([])
+    } else if region_info.msix_mappable {
+        region_info.mappable_ranges(&[])
+    } else {
+        region_info.mappable_ranges(msix_ranges)
+    };
 
     let region = VfioUnmappedPciRegion {
         device_file: Arc::clone(device_file),
         offset_in_device_file: region_info.offset,
         length: region_info.size,
         permissions,
-        is_mappable: region_is_mappable(&region_info),
+        mappable_ranges,
+        region_type: region_info.region_type,
+        invalidated: AtomicBool::new(false),
     };
 
     Ok(Some(Arc::new(region)))
 }
 
-fn region_is_mappable(region_info: &vfio_region_info) -> bool {
-    // TODO: Probably not necessary to check if length fits in address space?
-    region_info.flags & VFIO_REGION_INFO_FLAG_MMAP != 0 && region_info.size <= usize::MAX as u64
+/// A `vfio_region_info` along with what we care about from its capability chain.
+struct RegionInfo {
+    flags: u32,
+    size: u64,
+    offset: u64,
+    /// `None` if the region has no sparse mmap capability, in which case all of it is mappable if
+    /// it is mappable at all.
+    sparse_mmap_areas: Option<Vec<Range<u64>>>,
+    region_type: Option<VfioRegionType>,
+    /// Whether the parts of the region holding the MSI-X Table and PBA can be mapped.
+    msix_mappable: bool,
+}
+
+impl RegionInfo {
+    /// Returns the mappable ranges of the region, minus the given ones.
+    fn mappable_ranges(&self, excluded: &[Range<u64>]) -> Box<[Range<u64>]> {
+        // TODO: Probably not necessary to check if length fits in address space?
+        if self.flags & VFIO_REGION_INFO_FLAG_MMAP == 0 || self.size > usize::MAX as u64 {
+            return Box::new([]);
+        }
+
+        let mut ranges: Vec<_> = match &self.sparse_mmap_areas {
+            Some(areas) => areas
+                .iter()
+                .map(|area| area.start..area.end.min(self.size))
+                .collect(),
+            None => iter::once(0..self.size).collect(),
+        };
+
+        for excluded in excluded {
+            ranges = ranges
+                .into_iter()
+                .flat_map(|r| {
+                    let before = r.start..r.end.min(excluded.start);
+                    let after = r.start.max(excluded.end)..r.end;
+                    iter::once(before).chain(iter::once(after))
+                })
+                .collect();
+        }
+
+        ranges.retain(|r| r.start < r.end);
+        ranges.sort_by_key(|r| r.start);
+        ranges.into_boxed_slice()
+    }
+}
+
+fn get_region_info(device_file: &File, index: u32) -> io::Result<RegionInfo> {
+    let mut region_info = vfio_region_info {
+        argsz: mem::size_of::<vfio_region_info>() as u32,
+        flags: 0,
+        index,
+        cap_offset: 0,
+        size: 0,
+        offset: 0,
+    };
+
+    unsafe { vfio_device_get_region_info(device_file.as_raw_fd(), &mut region_info)? };
+
+    let mut info = RegionInfo {
+        flags: region_info.flags,
+        size: region_info.size,
+        offset: region_info.offset,
+        sparse_mmap_areas: None,
+        region_type: None,
+        msix_mappable: false,
+    };
+
+    let full_size = region_info.argsz as usize;
+
+    if region_info.flags & VFIO_REGION_INFO_FLAG_CAPS == 0
+        || full_size <= mem::size_of::<vfio_region_info>()
+    {
+        return Ok(info);
+    }
+
+    // the capability chain follows the vfio_region_info, so retrieve it again with the full argsz,
+    // using a u64 buffer so that everything is properly aligned
+
+    let mut buffer = vec![0_u64; full_size / 8 + 1];
+    let bigger_info = buffer.as_mut_ptr() as *mut vfio_region_info;
+
+    unsafe {
+        *bigger_info = vfio_region_info {
+            argsz: full_size as u32,
+            flags: 0,
+            index,
+            cap_offset: 0,
+            size: 0,
+            offset: 0,
+        };
+
+        vfio_device_get_region_info(device_file.as_raw_fd(), bigger_info)?;
+    }
+
+    let mut offset = unsafe { (*bigger_info).cap_offset } as usize;
+
+    while offset != 0 && offset + mem::size_of::<vfio_info_cap_header>() <= full_size {
+        let header = unsafe { bigger_info.cast::<u8>().add(offset) }.cast::<vfio_info_cap_header>();
+        let header_id = u32::from(unsafe { (*header).id });
+
+        match header_id {
+            VFIO_REGION_INFO_CAP_SPARSE_MMAP => {
+                let cap = header.cast::<vfio_region_info_cap_sparse_mmap>();
+                let areas = unsafe { (*cap).areas.as_slice((*cap).nr_areas as usize) };
+                info.sparse_mmap_areas = Some(
+                    areas
+                        .iter()
+                        .map(|area| area.offset..area.offset.saturating_add(area.size))
+                        .collect(),
+                );
+            }
+            VFIO_REGION_INFO_CAP_TYPE => {
+                let cap = header.cast::<vfio_region_info_cap_type>();
+                info.region_type = Some(VfioRegionType {
+                    region_type: unsafe { (*cap).type_ },
+                    subtype: unsafe { (*cap).subtype },
+                });
+            }
+            VFIO_REGION_INFO_CAP_MSIX_MAPPABLE => info.msix_mappable = true,
+            _ => {}
+        }
+
+        offset = unsafe { (*header).next } as usize;
+    }
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VfioRegionType;
+
+    #[test]
+    fn test_region_type_pci_vendor() {
+        let region_type = VfioRegionType::pci_vendor(0x10de, 3);
+        assert_eq!(region_type.region_type, 0x8000_10de);
+        assert_eq!(region_type.subtype, 3);
+        assert_eq!(region_type.pci_vendor_id(), Some(0x10de));
+
+        assert_eq!(VfioRegionType::INTEL_IGD_OPREGION.region_type, 0x8000_8086);
+        assert_eq!(
+            VfioRegionType::INTEL_IGD_OPREGION.pci_vendor_id(),
+            Some(0x8086)
+        );
+        assert_eq!(VfioRegionType::GFX_EDID.pci_vendor_id(), None);
+
+        // bits between the vendor type flag and the vendor ID are ignored
+        let region_type = VfioRegionType {
+            region_type: 0x8123_8086,
+            subtype: 1,
+        };
+        assert_eq!(region_type.pci_vendor_id(), Some(0x8086));
+    }
 }
 
 /* ---------------------------------------------------------------------------------------------- */
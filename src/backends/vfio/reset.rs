@@ -0,0 +1,552 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::ENOSPC;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use crate::backends::vfio::bindings::{
+    vfio_pci_dependent_device, vfio_pci_hot_reset, vfio_pci_hot_reset_info,
+};
+use crate::backends::vfio::ioctl::{vfio_device_get_pci_hot_reset_info, vfio_device_pci_hot_reset};
+use crate::backends::vfio::regions::set_up_config_space;
+use crate::backends::vfio::{VfioPciDevice, INTERRUPT_KINDS};
+use crate::device::PciAddress;
+use crate::device::{PciDevice, PciDeviceInternal, PciResetCapabilities};
+use crate::regions::{PciRegion, Permissions};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// A PCI function affected by a hot reset. See [`VfioPciDevice::hot_reset_devices`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct VfioHotResetDevice {
+    /// The address of the function.
+    pub address: PciAddress,
+    /// The number of the IOMMU group the function belongs to.
+    pub group: u32,
+}
+
+impl VfioPciDevice {
+    /// Returns the PCI functions that would be reset along with this one by
+    /// [`VfioPciDevice::hot_reset`], including this one.
+    ///
+    /// This fails if the device doesn't support being reset through a secondary bus reset or slot
+    /// reset.
+    pub fn hot_reset_devices(&self) -> io::Result<Vec<VfioHotResetDevice>> {
+        get_hot_reset_devices(self.inner.file.as_raw_fd())
+    }
+
+    /// Returns the IOMMU groups of the functions returned by [`VfioPciDevice::hot_reset_devices`]
+    /// that aren't in this device's container, in ascending order, without duplicates.
+    ///
+    /// [`VfioPciDevice::hot_reset`] can only succeed if this is empty, which lets you find out
+    /// whether a hot reset is possible without attempting it. For devices opened with
+    /// [`VfioPciDevice::open_cdev`], this is always empty, as ownership of the affected functions
+    /// is then only checked by the kernel when resetting.
+    pub fn hot_reset_missing_groups(&self) -> io::Result<Vec<u32>> {
+        if self.inner.container.uses_device_cdevs() {
+            return Ok(Vec::new());
+        }
+
+        let devices = self.hot_reset_devices()?;
+        Ok(get_missing_groups(&self.inner.container.groups, &devices))
+    }
+
+    /// Resets this function through a secondary bus reset or slot reset, which also resets all
+    /// the functions returned by [`VfioPciDevice::hot_reset_devices`].
+    ///
+    /// All the IOMMU groups of the affected functions must be in this device's container, and the
+    /// functions must not be in use elsewhere. This fails otherwise, naming the groups that are
+    /// missing from the container (see [`VfioPciDevice::hot_reset_missing_groups`]). For devices
+    /// opened with [`VfioPciDevice::open_cdev`], all the affected functions must instead be bound
+    /// to the same iommufd. This also fails if the device's
+    /// [quirks](crate::device::PciDevice::quirks) say hot reset must be avoided.
+    ///
+    /// Afterwards, call [`VfioPciDevice::reinitialize`] on the affected devices.
+    pub fn hot_reset(&self) -> io::Result<()> {
+        if self.inner.quirks.no_hot_reset {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Hot reset is known not to work on this device",
+            ));
+        }
+
+        let device_fd = self.inner.file.as_raw_fd();
+
+        // with device cdevs, the kernel checks ownership through iommufd and takes no group fds
+        if self.inner.container.uses_device_cdevs() {
+            pci_hot_reset(device_fd, &[])?;
+        } else {
+            let devices = self.hot_reset_devices()?;
+            let group_fds = get_hot_reset_group_fds(&self.inner.container.groups, &devices)?;
+            pci_hot_reset(device_fd, &group_fds)?;
+        }
+
+        self.restore_bars_if_enabled()
+    }
+
+    /// Enables or disables reprogramming the device's BARs, Expansion ROM BAR, and Command
+    /// register after it is reset.
+    ///
+    /// Devices come out of reset with their BARs and Command register cleared, after which memory
+    /// and I/O accesses to them silently fail. When enabled, the current values of these registers
+    /// are recorded (so this is best called right after opening the device), and both
+    /// [`PciDevice::reset`] and [`VfioPciDevice::hot_reset`] write them back after resetting, much
+    /// like Linux does when it resets devices itself. Enabling this again records the registers
+    /// anew. Disabled by default.
+    ///
+    /// vfio-pci virtualizes these registers, so they are accessed through the device's `config`
+    /// sysfs attribute instead, which requires root privileges. Enabling this fails with
+    /// [`ErrorKind::PermissionDenied`] if that attribute can't be written.
+    ///
+    /// Other functions affected by [`VfioPciDevice::hot_reset`] aren't restored, call
+    /// [`VfioPciDevice::restore_bars`] on them.
+    pub fn set_bar_restore(&self, enabled: bool) -> io::Result<()> {
+        if enabled {
+            self.inner.bar_restore.enable(&self.sysfs().config_space()?)
+        } else {
+            self.inner.bar_restore.disable();
+            Ok(())
+        }
+    }
+
+    /// Writes back the BARs, Expansion ROM BAR, and Command register recorded by
+    /// [`VfioPciDevice::set_bar_restore`], _e.g._, after a reset that this `VfioPciDevice` didn't
+    /// perform itself.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if restoring the registers isn't enabled.
+    pub fn restore_bars(&self) -> io::Result<()> {
+        self.inner
+            .bar_restore
+            .restore(&self.sysfs().config_space()?)
+    }
+
+    /// Writes back the recorded registers if restoring them is enabled, and does nothing otherwise.
+    pub(crate) fn restore_bars_if_enabled(&self) -> io::Result<()> {
+        if self.inner.bar_restore.is_enabled() {
+            self.restore_bars()?;
+        }
+
+        Ok(())
+    }
+
+    /// Brings this `VfioPciDevice` back in sync with the device after the device was reset, _e.g._,
+    /// by [`VfioPciDevice::hot_reset`] or a hot reset through another function, so that it doesn't
+    /// have to be dropped and reopened.
+    ///
+    /// This checks that the device responds again and that VFIO still exposes its config space at
+    /// the same place, disables all enabled interrupt mechanisms (resetting the device clears its
+    /// interrupt configuration, but not the eventfds VFIO signals), and sets up the BARs, Expansion
+    /// ROM, and device-specific regions anew.
+    ///
+    /// Regions obtained from the device before calling this are invalidated, and accessing,
+    /// mapping, or calling [`mapped`](crate::regions::OwningPciRegion::mapped) on them fails from
+    /// then on, so they must be obtained again. Memory mappings created from them before stay
+    /// mapped until dropped, but should be replaced with mappings of the new regions too.
+    pub fn reinitialize(&self) -> io::Result<()> {
+        let inner = &self.inner;
+
+        // check config space
+
+        let config_region = set_up_config_space(&inner.file)?;
+
+        if config_region.offset_in_device_file() != inner.config_region.offset_in_device_file()
+            || config_region.len() != inner.config_region.len()
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "VFIO now exposes the device's config space differently, reopen the device",
+            ));
+        }
+
+        if inner.config_space().vendor_id().read()? == 0xffff {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                "The device doesn't respond after being reset",
+            ));
+        }
+
+        // disable interrupts
+
+        for &kind in &INTERRUPT_KINDS {
+            if !inner.interrupts_eventfds(kind).is_empty() {
+                inner.interrupts_disable(kind)?;
+            }
+        }
+
+        // set up regions anew
+
+        let mut regions = inner.regions.lock().unwrap();
+
+        inner.region_generation.fetch_add(1, Ordering::AcqRel);
+
+        if let Some(regions) = regions.take() {
+            regions
+                .bars
+                .iter()
+                .flatten()
+                .chain(&regions.rom)
+                .chain(regions.extra.values())
+                .for_each(|region| region.invalidate());
+        }
+
+        drop(regions);
+        inner.regions()?;
+
+        Ok(())
+    }
+
+    /// Fails if the device's [quirks](crate::device::PciDevice::quirks) say it must not be reset
+    /// the way `VFIO_DEVICE_RESET` would reset it.
+    ///
+    /// The kernel tries the reset methods listed in the device's `reset_method` sysfs attribute in
+    /// order, so the first one is the one it uses. Kernels older than 5.15 don't have that
+    /// attribute, but prefer both kinds of FLR over the D3<sub>hot</sub> round trip.
+    pub(crate) fn check_reset_quirks(&self) -> io::Result<()> {
+        if !self.inner.quirks.no_pm_reset {
+            return Ok(());
+        }
+
+        let uses_pm_reset = match fs::read_to_string(self.inner.sysfs_path.join("reset_method")) {
+            Ok(methods) => methods.split_whitespace().next() == Some("pm"),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let capabilities = PciResetCapabilities::from_config(&self.config())?;
+                capabilities.pm_reset && !capabilities.flr && !capabilities.af_flr
+            }
+            Err(e) => return Err(e),
+        };
+
+        if uses_pm_reset {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "The device would be reset through D3hot, which is known not to work on it",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The registers that [`VfioPciDevice::set_bar_restore`] records, if enabled.
+#[derive(Debug, Default)]
+pub(crate) struct VfioBarRestore {
+    saved: Mutex<Option<VfioSavedBars>>,
+}
+
+impl VfioBarRestore {
+    fn is_enabled(&self) -> bool {
+        self.saved.lock().unwrap().is_some()
+    }
+
+    /// Records the registers from `config`, which must be writable so that they can be restored.
+    fn enable(&self, config: &dyn PciRegion) -> io::Result<()> {
+        check_writable(config)?;
+        *self.saved.lock().unwrap() = Some(VfioSavedBars::save(config)?);
+        Ok(())
+    }
+
+    fn disable(&self) {
+        *self.saved.lock().unwrap() = None;
+    }
+
+    fn restore(&self, config: &dyn PciRegion) -> io::Result<()> {
+        let saved = self.saved.lock().unwrap().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "Restoring BARs isn't enabled, see VfioPciDevice::set_bar_restore()",
+            )
+        })?;
+
+        check_writable(config)?;
+        saved.restore(config)
+    }
+}
+
+fn check_writable(config: &dyn PciRegion) -> io::Result<()> {
+    if config.permissions() != Permissions::ReadWrite {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "Restoring BARs requires write access to the device's sysfs config attribute",
+        ));
+    }
+
+    Ok(())
+}
+
+/// VFIO only supports devices with a type 0 config space header.
+#[derive(Clone, Copy, Debug)]
+struct VfioSavedBars {
+    command: u16,
+    bars: [u32; 6],
+    rom: u32,
+}
+
+impl VfioSavedBars {
+    fn save(config: &dyn PciRegion) -> io::Result<VfioSavedBars> {
+        let mut bars = [0; 6];
+        for (i, bar) in bars.iter_mut().enumerate() {
+            *bar = config.read_le_u32(0x10 + i as u64 * 4)?;
+        }
+
+        Ok(VfioSavedBars {
+            command: config.read_le_u16(0x04)?,
+            bars,
+            rom: config.read_le_u32(0x30)?,
+        })
+    }
+
+    fn restore(&self, config: &dyn PciRegion) -> io::Result<()> {
+        // program the addresses before enabling decoding through the Command register
+
+        for (i, &bar) in self.bars.iter().enumerate() {
+            config.write_le_u32(0x10 + i as u64 * 4, bar)?;
+        }
+
+        config.write_le_u32(0x30, self.rom)?;
+        config.write_le_u16(0x04, self.command)
+    }
+}
+
+/// Returns the functions that a hot reset through the given device would affect.
+pub(crate) fn get_hot_reset_devices(device_fd: RawFd) -> io::Result<Vec<VfioHotResetDevice>> {
+    let header_size = mem::size_of::<vfio_pci_hot_reset_info>();
+    let device_size = mem::size_of::<vfio_pci_dependent_device>();
+
+    let mut count = 0;
+
+    loop {
+        let size = header_size + count * device_size;
+        let mut buffer = vec![0_u32; size / 4 + 1];
+        let info = buffer.as_mut_ptr() as *mut vfio_pci_hot_reset_info;
+
+        unsafe { (*info).argsz = size as u32 };
+
+        let result = unsafe { vfio_device_get_pci_hot_reset_info(device_fd, info) };
+
+        let new_count = unsafe { (*info).count } as usize;
+
+        match result {
+            Ok(_) => {
+                let devices = unsafe { (*info).devices.as_slice(new_count.min(count)) };
+                return Ok(devices.iter().map(hot_reset_device_from_raw).collect());
+            }
+            // our buffer was too small, and the kernel told us how many devices there are
+            Err(e) if e.raw_os_error() == Some(ENOSPC) && new_count > count => {
+                count = new_count;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Returns the fds of the groups of all the given devices, or fails naming the groups that aren't
+/// among `groups`.
+pub(crate) fn get_hot_reset_group_fds(
+    groups: &HashMap<u32, File>,
+    devices: &[VfioHotResetDevice],
+) -> io::Result<Vec<RawFd>> {
+    let missing: Vec<String> = get_missing_groups(groups, devices)
+        .iter()
+        .map(|group| group.to_string())
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Hot reset would also affect groups {}, which aren't in the container",
+                missing.join(", ")
+            ),
+        ));
+    }
+
+    Ok(get_device_groups(devices)
+        .iter()
+        .map(|group| groups[group].as_raw_fd())
+        .collect())
+}
+
+/// Returns the groups of the given devices that aren't among `groups`.
+fn get_missing_groups(groups: &HashMap<u32, File>, devices: &[VfioHotResetDevice]) -> Vec<u32> {
+    let mut missing = get_device_groups(devices);
+    missing.retain(|group| !groups.contains_key(group));
+    missing
+}
+
+/// Returns the groups of the given devices, in ascending order, without duplicates.
+fn get_device_groups(devices: &[VfioHotResetDevice]) -> Vec<u32> {
+    let mut group_numbers: Vec<u32> = devices.iter().map(|device| device.group).collect();
+
+    group_numbers.sort_unstable();
+    group_numbers.dedup();
+
+    group_numbers
+}
+
+/// Issues a hot reset through the given device, proving ownership of the affected functions with
+/// the given group fds.
+pub(crate) fn pci_hot_reset(device_fd: RawFd, group_fds: &[RawFd]) -> io::Result<()> {
+    let size = mem::size_of::<vfio_pci_hot_reset>() + mem::size_of_val(group_fds);
+    let mut buffer = vec![0_u32; size / 4 + 1];
+    let reset = buffer.as_mut_ptr() as *mut vfio_pci_hot_reset;
+
+    unsafe {
+        (*reset).argsz = size as u32;
+        (*reset).count = group_fds.len() as u32;
+        (*reset)
+            .group_fds
+            .as_mut_slice(group_fds.len())
+            .copy_from_slice(group_fds);
+    }
+
+    unsafe { vfio_device_pci_hot_reset(device_fd, reset)? };
+
+    Ok(())
+}
+
+fn hot_reset_device_from_raw(device: &vfio_pci_dependent_device) -> VfioHotResetDevice {
+    VfioHotResetDevice {
+        address: PciAddress {
+            domain: device.segment.into(),
+            bus: device.bus,
+            device: device.devfn >> 3,
+            function: device.devfn & 0x7,
+        },
+        group: device.group_id,
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, ErrorKind};
+    use std::sync::Mutex;
+
+    use crate::backends::mock::{MockDeviceBuilder, MockPciDevice};
+    use crate::device::PciDevice;
+    use crate::regions::{PciMemoryRegion, PciRegion, Permissions, Sealed};
+
+    use super::{VfioBarRestore, VfioSavedBars};
+
+    #[test]
+    fn test_save_bars() {
+        let saved_bars = VfioSavedBars::save(&MockPciDevice.config()).unwrap();
+
+        assert_eq!(saved_bars.bars[0], 0x9820_0004);
+        assert_eq!(saved_bars.bars[1], 0);
+    }
+
+    #[test]
+    fn test_restore_bars() {
+        let device = MockDeviceBuilder::new(0x1234, 0x5678)
+            .bar(0, vec![0; 0x1000])
+            .rom(vec![0; 0x800])
+            .build();
+
+        let config = device.config();
+        config.write_le_u16(0x04, 0x0006).unwrap();
+
+        let bar_restore = VfioBarRestore::default();
+        let error = bar_restore.restore(&config).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        bar_restore.enable(&config).unwrap();
+
+        // as the device would look after being reset
+        for offset in (0x10..0x28).step_by(4).chain([0x30]) {
+            config.write_le_u32(offset, 0).unwrap();
+        }
+        config.write_le_u16(0x04, 0).unwrap();
+
+        let recording = RecordingRegion {
+            region: &config,
+            writes: Mutex::new(Vec::new()),
+        };
+        bar_restore.restore(&recording).unwrap();
+
+        assert_eq!(config.read_le_u32(0x10).unwrap(), 0xc000_0000);
+        assert_eq!(config.read_le_u32(0x30).unwrap(), 0xc000_1000);
+        assert_eq!(config.read_le_u16(0x04).unwrap(), 0x0006);
+
+        // decoding is only enabled once the addresses are in place
+        let writes = recording.writes.into_inner().unwrap();
+        assert_eq!(writes.last(), Some(&0x04));
+        assert_eq!(writes.iter().filter(|&&offset| offset == 0x04).count(), 1);
+
+        let data = [0; 0x40];
+        let read_only = PciMemoryRegion::new(&data);
+        let error = bar_restore.restore(&read_only).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        let error = VfioBarRestore::default().enable(&read_only).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+        bar_restore.disable();
+        assert!(!bar_restore.is_enabled());
+    }
+
+    /// Delegates to another region, recording the offsets written to.
+    #[derive(Debug)]
+    struct RecordingRegion<'a> {
+        region: &'a dyn PciRegion,
+        writes: Mutex<Vec<u64>>,
+    }
+
+    impl Sealed for RecordingRegion<'_> {}
+    impl PciRegion for RecordingRegion<'_> {
+        fn len(&self) -> u64 {
+            self.region.len()
+        }
+
+        fn permissions(&self) -> Permissions {
+            self.region.permissions()
+        }
+
+        fn as_ptr(&self) -> Option<*const u8> {
+            None
+        }
+
+        fn as_mut_ptr(&self) -> Option<*mut u8> {
+            None
+        }
+
+        fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+            self.region.read_bytes(offset, buffer)
+        }
+
+        fn read_u8(&self, offset: u64) -> io::Result<u8> {
+            self.region.read_u8(offset)
+        }
+
+        fn write_u8(&self, offset: u64, value: u8) -> io::Result<()> {
+            self.writes.lock().unwrap().push(offset);
+            self.region.write_u8(offset, value)
+        }
+
+        fn read_le_u16(&self, offset: u64) -> io::Result<u16> {
+            self.region.read_le_u16(offset)
+        }
+
+        fn write_le_u16(&self, offset: u64, value: u16) -> io::Result<()> {
+            self.writes.lock().unwrap().push(offset);
+            self.region.write_le_u16(offset, value)
+        }
+
+        fn read_le_u32(&self, offset: u64) -> io::Result<u32> {
+            self.region.read_le_u32(offset)
+        }
+
+        fn write_le_u32(&self, offset: u64, value: u32) -> io::Result<()> {
+            self.writes.lock().unwrap().push(offset);
+            self.region.write_le_u32(offset, value)
+        }
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -0,0 +1,320 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::{
+    c_void, iovec, msghdr, CMSG_DATA, CMSG_FIRSTHDR, CMSG_LEN, CMSG_NXTHDR, CMSG_SPACE,
+    MSG_CMSG_CLOEXEC, MSG_CTRUNC, MSG_NOSIGNAL, MSG_TRUNC, SCM_RIGHTS, SOL_SOCKET,
+};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::iter;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+use crate::backends::vfio::{VfioContainer, VfioIommuBackend};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// The most fds the kernel lets a single message carry (`SCM_MAX_FD`).
+const MAX_FDS: usize = 253;
+
+/// Starts the messages exchanged by [`VfioContainer::send_to`] and
+/// [`VfioContainer::receive_from`], followed by the backend, the number of groups, and the group
+/// numbers, all as little-endian `u32`s.
+const MESSAGE_MAGIC: u32 = 0x5646_4301;
+
+impl VfioContainer {
+    /// Sends the container's file descriptor and those of its groups over the given Unix domain
+    /// socket, along with what the peer needs to reconstruct the container with
+    /// [`VfioContainer::receive_from`].
+    ///
+    /// This lets, _e.g._, a privileged broker process open and set up the container and hand it
+    /// over to a sandboxed process that drives the devices. Both processes then share the same
+    /// IOMMU context, including any mappings that already exist. Mapping tracking and
+    /// [`VfioContainer::pinned_bytes`] are per process and start out empty on the receiving side.
+    ///
+    /// Fails with [`ErrorKind::Unsupported`] for containers using the
+    /// [`VfioIommuBackend::Iommufd`] backend.
+    pub fn send_to(&self, socket: &UnixStream) -> io::Result<()> {
+        let backend = match self.iommu_backend() {
+            VfioIommuBackend::Type1 => 0,
+            VfioIommuBackend::Type1Nesting => 1,
+            VfioIommuBackend::NoIommu => 2,
+            VfioIommuBackend::Iommufd => {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "iommufd-backed containers can't be sent to other processes",
+                ))
+            }
+        };
+
+        let groups = self.groups();
+
+        let words = [MESSAGE_MAGIC, backend, groups.len() as u32]
+            .iter()
+            .chain(groups)
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<u8>>();
+
+        let fds = iter::once(self.file.as_raw_fd())
+            .chain(groups.iter().map(|group| self.groups[group].as_raw_fd()))
+            .collect::<Vec<RawFd>>();
+
+        send_with_fds(socket, &words, &fds)
+    }
+
+    /// Receives a container sent with [`VfioContainer::send_to`] over the given Unix domain socket.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if the sender's container is in VFIO's no-IOMMU mode,
+    /// in which case [`VfioContainer::receive_noiommu_from`] must be used instead. The received file
+    /// descriptors are closed in that case.
+    pub fn receive_from(socket: &UnixStream) -> io::Result<VfioContainer> {
+        VfioContainer::receive(socket, false)
+    }
+
+    /// Like [`VfioContainer::receive_from`], but also accepts containers in VFIO's no-IOMMU mode,
+    /// _i.e._, using the [`VfioIommuBackend::NoIommu`] backend.
+    ///
+    /// # Safety
+    ///
+    /// If the returned container is in no-IOMMU mode, the obligations of
+    /// [`VfioContainer::new_noiommu`] apply.
+    pub unsafe fn receive_noiommu_from(socket: &UnixStream) -> io::Result<VfioContainer> {
+        VfioContainer::receive(socket, true)
+    }
+
+    fn receive(socket: &UnixStream, allow_noiommu: bool) -> io::Result<VfioContainer> {
+        let mut buffer = vec![0_u8; 4 * (3 + MAX_FDS)];
+        let (length, fds) = receive_with_fds(socket, &mut buffer)?;
+
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                "Received an invalid VFIO container message",
+            )
+        };
+
+        if length % 4 != 0 {
+            return Err(invalid());
+        }
+
+        let words: Vec<u32> = buffer[..length]
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        let (header, group_numbers) = match words.get(..3) {
+            Some(header) => (header, &words[3..]),
+            None => return Err(invalid()),
+        };
+
+        if header[0] != MESSAGE_MAGIC
+            || header[2] as usize != group_numbers.len()
+            || fds.len() != 1 + group_numbers.len()
+        {
+            return Err(invalid());
+        }
+
+        let backend = match header[1] {
+            0 => VfioIommuBackend::Type1,
+            1 => VfioIommuBackend::Type1Nesting,
+            2 => VfioIommuBackend::NoIommu,
+            _ => return Err(invalid()),
+        };
+
+        if backend == VfioIommuBackend::NoIommu && !allow_noiommu {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "No-IOMMU containers must be received with VfioContainer::receive_noiommu_from()",
+            ));
+        }
+
+        let mut fds = fds.into_iter().map(File::from);
+        let file = fds.next().unwrap();
+        let groups: HashMap<u32, File> = group_numbers.iter().copied().zip(fds).collect();
+
+        VfioContainer::from_files(file, groups, backend)
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Sends `data` and the given fds over `socket` in a single message.
+fn send_with_fds(socket: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    if fds.len() > MAX_FDS {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Can't send more than {} fds in one message", MAX_FDS),
+        ));
+    }
+
+    let mut iov = iovec {
+        iov_base: data.as_ptr() as *mut c_void,
+        iov_len: data.len(),
+    };
+
+    let fds_size = mem::size_of_val(fds) as u32;
+    let control_size = unsafe { CMSG_SPACE(fds_size) } as usize;
+    let mut control = vec![0_u64; control_size / 8 + 1];
+
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = control_size as _;
+
+    unsafe {
+        let cmsg = CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = SOL_SOCKET;
+        (*cmsg).cmsg_type = SCM_RIGHTS;
+        (*cmsg).cmsg_len = CMSG_LEN(fds_size) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+    }
+
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, MSG_NOSIGNAL) };
+
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if sent as usize != data.len() {
+        return Err(io::Error::new(
+            ErrorKind::WriteZero,
+            "Failed to send the whole message",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Receives a single message from `socket` into `buffer`, returning its length and the fds that
+/// came with it.
+fn receive_with_fds(socket: &UnixStream, buffer: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let mut iov = iovec {
+        iov_base: buffer.as_mut_ptr() as *mut c_void,
+        iov_len: buffer.len(),
+    };
+
+    let control_size = unsafe { CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) } as usize;
+    let mut control = vec![0_u64; control_size / 8 + 1];
+
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = control_size as _;
+
+    let received = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, MSG_CMSG_CLOEXEC) };
+
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // take ownership of the fds first, so that they get closed if anything below fails
+
+    let mut fds = Vec::new();
+
+    unsafe {
+        let mut cmsg = CMSG_FIRSTHDR(&msg);
+
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == SOL_SOCKET && (*cmsg).cmsg_type == SCM_RIGHTS {
+                let data_size = (*cmsg).cmsg_len as usize - CMSG_LEN(0) as usize;
+                let data = CMSG_DATA(cmsg) as *const RawFd;
+
+                for i in 0..data_size / mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+
+            cmsg = CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if received == 0 {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "The peer closed the socket",
+        ));
+    }
+
+    if msg.msg_flags & (MSG_TRUNC | MSG_CTRUNC) != 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Received a message that was too large",
+        ));
+    }
+
+    Ok((received as usize, fds))
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::net::UnixStream;
+
+    use super::{receive_with_fds, send_with_fds, MESSAGE_MAGIC};
+    use crate::backends::vfio::VfioContainer;
+
+    #[test]
+    fn test_send_and_receive_fds() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+
+        let mut file = File::from(memfd("test"));
+        file.write_all(b"hello").unwrap();
+
+        send_with_fds(&sender, b"data", &[file.as_raw_fd()]).unwrap();
+        drop(file);
+
+        let mut buffer = [0_u8; 16];
+        let (length, fds) = receive_with_fds(&receiver, &mut buffer).unwrap();
+
+        assert_eq!(&buffer[..length], b"data");
+        assert_eq!(fds.len(), 1);
+
+        let mut received = File::from(fds.into_iter().next().unwrap());
+        let mut contents = String::new();
+        received.seek(SeekFrom::Start(0)).unwrap();
+        received.read_to_string(&mut contents).unwrap();
+
+        assert_eq!(contents, "hello");
+    }
+
+    #[test]
+    fn test_receive_rejects_noiommu() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+
+        let container = memfd("container");
+        let group = memfd("group");
+
+        // a no-IOMMU container with a single group
+        let words = [MESSAGE_MAGIC, 2, 1, 42]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<u8>>();
+
+        send_with_fds(&sender, &words, &[container.as_raw_fd(), group.as_raw_fd()]).unwrap();
+
+        let error = VfioContainer::receive_from(&receiver).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    fn memfd(name: &str) -> OwnedFd {
+        let name = CString::new(name).unwrap();
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        unsafe { OwnedFd::from_raw_fd(fd) }
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -0,0 +1,296 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Advisory claims on PCI functions.
+//!
+//! Nothing stops two processes from driving the same function at once, with confusing results. A
+//! [`PciDeviceClaim`] is an exclusive advisory lock on a function, keyed by its address, that
+//! processes using this crate can take to avoid that. Claims are held until they are dropped, and
+//! are released automatically if their process exits.
+//!
+//! Claims are opt-in: they only keep out processes that also take them, _e.g._, through
+//! `VfioPciDevice::open_claimed`.
+//!
+//! ```no_run
+//! use pci_driver::claim::PciDeviceClaim;
+//!
+//! let claim = PciDeviceClaim::try_acquire("0000:01:00.0".parse()?)?;
+//! // ... drive the function ...
+//! drop(claim);
+//! # std::io::Result::Ok(())
+//! ```
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::{access, flock, getuid, EWOULDBLOCK, LOCK_EX, LOCK_NB, W_OK};
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::device::PciAddress;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Where lock files are created, if it is writable.
+const LOCK_DIRECTORY: &str = "/run/lock";
+
+/// An exclusive advisory claim on a PCI function, released when dropped. See the
+/// [module documentation](self).
+///
+/// The claim is an `flock()` on the file `/run/lock/pci-driver-<address>.lock`, in which the
+/// holder's process ID is also written while the claim is held.
+///
+/// If `/run/lock` isn't writable, the file is created in the user's runtime directory instead,
+/// _i.e._, `$XDG_RUNTIME_DIR` or `/run/user/<uid>`, in which case the claim only keeps out
+/// processes of the same user. Claiming fails if neither is writable.
+#[derive(Debug)]
+pub struct PciDeviceClaim {
+    address: PciAddress,
+    /// Closing this releases the lock.
+    file: File,
+}
+
+impl PciDeviceClaim {
+    /// Claims the function with the given address, waiting for any other holder to release it.
+    pub fn acquire(address: PciAddress) -> io::Result<PciDeviceClaim> {
+        PciDeviceClaim::lock(&lock_directory()?, address, true)
+    }
+
+    /// Claims the function with the given address, or fails with [`ErrorKind::WouldBlock`] if
+    /// someone else holds the claim. The error message says who, if it is known.
+    ///
+    /// See also [`PciDeviceClaim::holder`].
+    pub fn try_acquire(address: PciAddress) -> io::Result<PciDeviceClaim> {
+        PciDeviceClaim::lock(&lock_directory()?, address, false)
+    }
+
+    /// Returns the process ID of the current holder of the claim on the function with the given
+    /// address, or `None` if it isn't claimed or the holder isn't known.
+    ///
+    /// The answer may be stale by the time it is returned.
+    pub fn holder(address: PciAddress) -> io::Result<Option<u32>> {
+        PciDeviceClaim::holder_in(&lock_directory()?, address)
+    }
+
+    fn holder_in(directory: &Path, address: PciAddress) -> io::Result<Option<u32>> {
+        // probing the lock by taking it would make concurrent try_acquire() calls fail, so rely on
+        // holders clearing the file when they release the claim instead
+        let pid = match read_holder(&lock_file_path(directory, address)) {
+            Ok(pid) => pid,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        // processes that exited without releasing the claim leave their ID behind
+        Ok(pid.filter(|pid| Path::new("/proc").join(pid.to_string()).exists()))
+    }
+
+    /// The address of the claimed function.
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    fn lock(directory: &Path, address: PciAddress, wait: bool) -> io::Result<PciDeviceClaim> {
+        let path = lock_file_path(directory, address);
+
+        // lock files may have been created by other users, in which case we can only read them
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(&path)
+        {
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => File::open(&path)?,
+            result => result?,
+        };
+
+        if wait {
+            let ret = unsafe { flock(file.as_raw_fd(), LOCK_EX) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        } else if !try_flock(&file)? {
+            let message = match read_holder(&path)? {
+                Some(pid) => format!("{} is claimed by process {}", address, pid),
+                None => format!("{} is claimed by another process", address),
+            };
+            return Err(io::Error::new(ErrorKind::WouldBlock, message));
+        }
+
+        // best effort, only used to tell others who holds the claim
+        if file.set_len(0).is_ok() {
+            let _ = write!(file, "{}", process::id());
+        }
+
+        Ok(PciDeviceClaim { address, file })
+    }
+}
+
+impl Drop for PciDeviceClaim {
+    fn drop(&mut self) {
+        // best effort, like writing the process ID in the first place
+        let _ = self.file.set_len(0);
+    }
+}
+
+fn lock_file_path(directory: &Path, address: PciAddress) -> PathBuf {
+    directory.join(format!("pci-driver-{}.lock", address))
+}
+
+fn lock_directory() -> io::Result<PathBuf> {
+    let runtime_directory = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(format!("/run/user/{}", unsafe { getuid() })),
+    };
+
+    first_writable_directory(&[PathBuf::from(LOCK_DIRECTORY), runtime_directory])
+}
+
+fn first_writable_directory(candidates: &[PathBuf]) -> io::Result<PathBuf> {
+    candidates
+        .iter()
+        .find(|path| is_writable_directory(path))
+        .cloned()
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "Can't claim PCI functions, as none of {:?} is a writable directory",
+                    candidates
+                ),
+            )
+        })
+}
+
+fn is_writable_directory(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => path.is_dir() && unsafe { access(c_path.as_ptr(), W_OK) } == 0,
+        Err(_) => false,
+    }
+}
+
+/// Returns whether the lock was taken.
+fn try_flock(file: &File) -> io::Result<bool> {
+    let ret = unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) };
+
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(EWOULDBLOCK) {
+            return Ok(false);
+        }
+        return Err(e);
+    }
+
+    Ok(true)
+}
+
+fn read_holder(path: &Path) -> io::Result<Option<u32>> {
+    let contents = fs::read_to_string(path)?;
+    Ok(contents.trim().parse().ok())
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{first_writable_directory, PciDeviceClaim};
+    use crate::device::PciAddress;
+
+    /// Tests use directories of their own, as the real ones may not be writable.
+    fn test_directory(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pci-driver-{}-{}", name, process::id()));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_try_acquire_and_holder() {
+        let directory = test_directory("claim-test-try-acquire");
+        let address: PciAddress = "0000:ff:1f.0".parse().unwrap();
+
+        let holder = || PciDeviceClaim::holder_in(&directory, address).unwrap();
+        let try_acquire = || PciDeviceClaim::lock(&directory, address, false);
+
+        assert_eq!(holder(), None);
+
+        let claim = try_acquire().unwrap();
+        assert_eq!(claim.address(), address);
+        assert_eq!(holder(), Some(process::id()));
+
+        let error = try_acquire().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::WouldBlock);
+        assert!(error.to_string().contains(&process::id().to_string()));
+
+        drop(claim);
+        assert_eq!(holder(), None);
+
+        drop(try_acquire().unwrap());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_acquire_waits_for_release() {
+        let directory = test_directory("claim-test-acquire");
+        let address: PciAddress = "0000:ff:1f.1".parse().unwrap();
+        let claim = PciDeviceClaim::lock(&directory, address, true).unwrap();
+
+        let acquired = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let directory = directory.clone();
+            let acquired = Arc::clone(&acquired);
+            thread::spawn(move || {
+                let claim = PciDeviceClaim::lock(&directory, address, true).unwrap();
+                acquired.store(true, Ordering::SeqCst);
+                claim
+            })
+        };
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(!acquired.load(Ordering::SeqCst));
+
+        drop(claim);
+
+        drop(waiter.join().unwrap());
+        assert!(acquired.load(Ordering::SeqCst));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_first_writable_directory() {
+        let directory = test_directory("claim-test-directories");
+        let missing = directory.join("missing");
+        let file = directory.join("file");
+        fs::write(&file, "").unwrap();
+
+        assert_eq!(
+            first_writable_directory(&[missing.clone(), file.clone(), directory.clone()]).unwrap(),
+            directory
+        );
+
+        let error = first_writable_directory(&[missing, file]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert!(error.to_string().contains("missing"));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
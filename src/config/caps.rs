@@ -169,7 +169,7 @@ impl<'a> IntoIterator for PciCapabilities<'a> {
     }
 }
 
-impl<'a, 'b> IntoIterator for &'b PciCapabilities<'a> {
+impl<'a> IntoIterator for &PciCapabilities<'a> {
     type Item = UnspecifiedCapability<'a>;
     type IntoIter = PciCapabilitiesIter<'a, UnspecifiedCapability<'a>>;
 
@@ -324,11 +324,37 @@ pci_capability! {
         Id = 0x01,
         Length = |_cap| Ok(0x08),
         Fields = {
+            capabilities   @ 0x02 : PowerManagementCapabilities<'a>,
+            control_status @ 0x04 : PowerManagementControlStatus<'a>,
             // TODO
         },
     }
 }
 
+pci_bit_field! {
+    pub struct PowerManagementCapabilities<'a> : RO u16 {
+        version                        @   0--2 : RO u8,
+        pme_clock                      @      3 : RO,
+        immediate_readiness_on_d0      @      4 : RO,
+        device_specific_initialization @      5 : RO,
+        aux_current                    @   6--8 : RO u8,
+        d1_support                     @      9 : RO,
+        d2_support                     @     10 : RO,
+        pme_support                    @ 11--15 : RO u8,
+    }
+
+    pub struct PowerManagementControlStatus<'a> : RW u16 {
+        power_state   @   0--1 : RW u8,
+        __            @      2 : RsvdP,
+        no_soft_reset @      3 : RO,
+        __            @   4--7 : RsvdP,
+        pme_enable    @      8 : RW,
+        data_select   @  9--12 : RW u8,
+        data_scale    @ 13--14 : RO u8,
+        pme_status    @     15 : RW1C,
+    }
+}
+
 // 7.5.3 PCI Express Capability Structure
 
 pci_capability! {
@@ -337,18 +363,18 @@ pci_capability! {
         Id = 0x10,
         Length = |_cap| Ok(0x3c),
         Fields = {
-            capabilities          @ 0x02 : PciExpressCapabilities,
-            device_capabilities   @ 0x04 : PciExpressDeviceCapabilities,
-            device_control        @ 0x08 : PciExpressDeviceControl,
-            device_status         @ 0x0a : PciExpressDeviceStatus,
-            link_capabilities     @ 0x0c : PciExpressLinkCapabilities,
-            link_control          @ 0x10 : PciExpressLinkControl,
-            link_status           @ 0x12 : PciExpressLinkStatus,
-            device_capabilities_2 @ 0x24 : PciExpressDeviceCapabilities2,
-            device_control_2      @ 0x28 : PciExpressDeviceControl2,
-            link_capabilities_2   @ 0x2c : PciExpressLinkCapabilities2,
-            link_control_2        @ 0x30 : PciExpressLinkControl2,
-            link_status_2         @ 0x32 : PciExpressLinkStatus2,
+            capabilities          @ 0x02 : PciExpressCapabilities<'a>,
+            device_capabilities   @ 0x04 : PciExpressDeviceCapabilities<'a>,
+            device_control        @ 0x08 : PciExpressDeviceControl<'a>,
+            device_status         @ 0x0a : PciExpressDeviceStatus<'a>,
+            link_capabilities     @ 0x0c : PciExpressLinkCapabilities<'a>,
+            link_control          @ 0x10 : PciExpressLinkControl<'a>,
+            link_status           @ 0x12 : PciExpressLinkStatus<'a>,
+            device_capabilities_2 @ 0x24 : PciExpressDeviceCapabilities2<'a>,
+            device_control_2      @ 0x28 : PciExpressDeviceControl2<'a>,
+            link_capabilities_2   @ 0x2c : PciExpressLinkCapabilities2<'a>,
+            link_control_2        @ 0x30 : PciExpressLinkControl2<'a>,
+            link_status_2         @ 0x32 : PciExpressLinkStatus2<'a>,
         },
     }
 }
@@ -376,23 +402,74 @@ pci_bit_field! {
     }
 
     pub struct PciExpressDeviceControl<'a> : RW u16 {
-        // TODO
+        correctable_error_reporting_enable   @      0 : RW,
+        non_fatal_error_reporting_enable     @      1 : RW,
+        fatal_error_reporting_enable         @      2 : RW,
+        unsupported_request_reporting_enable @      3 : RW,
+        enable_relaxed_ordering              @      4 : RW,
+        max_payload_size                     @   5--7 : RW u8,
+        extended_tag_field_enable            @      8 : RW,
+        phantom_functions_enable             @      9 : RW,
+        aux_power_pm_enable                  @     10 : RW,
+        enable_no_snoop                      @     11 : RW,
+        max_read_request_size                @ 12--14 : RW u8,
+        /// This is "Bridge Configuration Retry Enable" for PCI Express to PCI/PCI-X Bridges. It
+        /// always reads as 0 for Endpoints.
+        initiate_function_level_reset        @     15 : RW,
     }
 
     pub struct PciExpressDeviceStatus<'a> : RW u16 {
-        // TODO
+        correctable_error_detected           @      0 : RW1C,
+        non_fatal_error_detected             @      1 : RW1C,
+        fatal_error_detected                 @      2 : RW1C,
+        unsupported_request_detected         @      3 : RW1C,
+        aux_power_detected                   @      4 : RO,
+        transactions_pending                 @      5 : RO,
+        emergency_power_reduction_detected   @      6 : RW1C,
+        __                                   @  7--15 : RsvdZ,
     }
 
     pub struct PciExpressLinkCapabilities<'a> : RO u32 {
-        // TODO
+        max_link_speed                                @   0--3 : RO u8,
+        maximum_link_width                            @   4--9 : RO u8,
+        aspm_support                                  @ 10--11 : RO u8,
+        l0s_exit_latency                              @ 12--14 : RO u8,
+        l1_exit_latency                               @ 15--17 : RO u8,
+        clock_power_management                        @     18 : RO,
+        surprise_down_error_reporting_capable         @     19 : RO,
+        data_link_layer_link_active_reporting_capable @     20 : RO,
+        link_bandwidth_notification_capability        @     21 : RO,
+        aspm_optionality_compliance                   @     22 : RO,
+        __                                            @     23 : RsvdP,
+        port_number                                   @ 24--31 : RO u8,
     }
 
     pub struct PciExpressLinkControl<'a> : RW u16 {
-        // TODO
+        aspm_control                               @   0--1 : RW u8,
+        __                                         @      2 : RsvdP,
+        read_completion_boundary                   @      3 : RW,
+        link_disable                               @      4 : RW,
+        /// Only meaningful for Downstream Ports. Always reads as 0.
+        retrain_link                               @      5 : RW,
+        common_clock_configuration                 @      6 : RW,
+        extended_synch                             @      7 : RW,
+        enable_clock_power_management              @      8 : RW,
+        hardware_autonomous_width_disable          @      9 : RW,
+        link_bandwidth_management_interrupt_enable @     10 : RW,
+        link_autonomous_bandwidth_interrupt_enable @     11 : RW,
+        __                                         @ 12--13 : RsvdP,
+        drs_signaling_control                      @ 14--15 : RW u8,
     }
 
     pub struct PciExpressLinkStatus<'a> : RW u16 {
-        // TODO
+        current_link_speed               @   0--3 : RO u8,
+        negotiated_link_width            @   4--9 : RO u8,
+        __                               @     10 : RsvdZ,
+        link_training                    @     11 : RO,
+        slot_clock_configuration         @     12 : RO,
+        data_link_layer_link_active      @     13 : RO,
+        link_bandwidth_management_status @     14 : RW1C,
+        link_autonomous_bandwidth_status @     15 : RW1C,
     }
 
     pub struct PciExpressDeviceCapabilities2<'a> : RO u32 {
@@ -404,15 +481,42 @@ pci_bit_field! {
     }
 
     pub struct PciExpressLinkCapabilities2<'a> : RO u32 {
-        // TODO
+        __                                              @      0 : RsvdP,
+        supported_link_speeds_vector                    @   1--7 : RO u8,
+        crosslink_supported                             @      8 : RO,
+        lower_skp_os_generation_supported_speeds_vector @  9--15 : RO u8,
+        lower_skp_os_reception_supported_speeds_vector  @ 16--22 : RO u8,
+        retimer_presence_detect_supported               @     23 : RO,
+        two_retimers_presence_detect_supported          @     24 : RO,
+        __                                              @ 25--30 : RsvdP,
+        drs_supported                                   @     31 : RO,
     }
 
     pub struct PciExpressLinkControl2<'a> : RW u16 {
-        // TODO
+        /// For Downstream Ports, the upper limit on the link speed.
+        target_link_speed                 @   0--3 : RW u8,
+        enter_compliance                  @      4 : RW,
+        hardware_autonomous_speed_disable @      5 : RW,
+        selectable_de_emphasis            @      6 : RO,
+        transmit_margin                   @   7--9 : RW u8,
+        enter_modified_compliance         @     10 : RW,
+        compliance_sos                    @     11 : RW,
+        compliance_preset_de_emphasis     @ 12--15 : RW u8,
     }
 
     pub struct PciExpressLinkStatus2<'a> : RW u16 {
-        // TODO
+        current_de_emphasis_level       @      0 : RO,
+        equalization_8gts_complete      @      1 : RO,
+        equalization_8gts_phase_1       @      2 : RO,
+        equalization_8gts_phase_2       @      3 : RO,
+        equalization_8gts_phase_3       @      4 : RO,
+        link_equalization_request_8gts  @      5 : RW1C,
+        retimer_presence_detected       @      6 : RO,
+        two_retimers_presence_detected  @      7 : RO,
+        crosslink_resolution            @   8--9 : RO u8,
+        __                              @ 10--11 : RsvdZ,
+        downstream_component_presence   @ 12--14 : RO u8,
+        drs_message_received            @     15 : RW1C,
     }
 }
 
@@ -514,11 +618,44 @@ pci_capability! {
         Id = 0x11,
         Length = |_cap| Ok(0x0c),
         Fields = {
-            // TODO
+            message_control  @ 0x02 : MsiXMessageControl<'a>,
+            table_offset_bir @ 0x04 : PciRegisterRo<'a, u32>,
+            pba_offset_bir   @ 0x08 : PciRegisterRo<'a, u32>,
         },
     }
 }
 
+pci_bit_field! {
+    pub struct MsiXMessageControl<'a> : RW u16 {
+        table_size    @  0--10 : RO u16,
+        __            @ 11--13 : RsvdP,
+        function_mask @     14 : RW,
+        msi_x_enable  @     15 : RW,
+    }
+}
+
+impl MsiXCapability<'_> {
+    /// The number of entries in the MSI-X Table.
+    pub fn num_vectors(&self) -> io::Result<usize> {
+        Ok(usize::from(self.message_control().table_size().read()?) + 1)
+    }
+
+    /// The index of the BAR containing the MSI-X Table, and the Table's offset into that BAR.
+    pub fn table_location(&self) -> io::Result<(usize, u64)> {
+        Ok(split_offset_bir(self.table_offset_bir().read()?))
+    }
+
+    /// The index of the BAR containing the MSI-X Pending Bit Array, and the PBA's offset into that
+    /// BAR.
+    pub fn pba_location(&self) -> io::Result<(usize, u64)> {
+        Ok(split_offset_bir(self.pba_offset_bir().read()?))
+    }
+}
+
+fn split_offset_bir(value: u32) -> (usize, u64) {
+    ((value & 0x7) as usize, u64::from(value & !0x7))
+}
+
 // 7.8.5 Enhanced Allocation Capability Structure (EA)
 
 pci_capability! {
@@ -558,7 +695,7 @@ pci_capability! {
         Id = 0x03,
         Length = |_cap| Ok(0x08),
         Fields = {
-            vpd_address_register @ 0x02 : VpdAddressRegister,
+            vpd_address_register @ 0x02 : VpdAddressRegister<'a>,
             vpd_data_register    @ 0x04 : PciRegisterRw<'a, u32>,
         },
     }
@@ -580,11 +717,31 @@ pci_capability! {
         Id = 0x13,
         Length = |_cap| Ok(0x06),
         Fields = {
-            // TODO
+            af_capabilities @ 0x03 : AdvancedFeaturesCapabilities<'a>,
+            af_control      @ 0x04 : AdvancedFeaturesControl<'a>,
+            af_status       @ 0x05 : AdvancedFeaturesStatus<'a>,
         },
     }
 }
 
+pci_bit_field! {
+    pub struct AdvancedFeaturesCapabilities<'a> : RO u8 {
+        tp_capable  @    0 : RO,
+        flr_capable @    1 : RO,
+        __          @ 2--7 : RsvdP,
+    }
+
+    pub struct AdvancedFeaturesControl<'a> : RW u8 {
+        initiate_flr @    0 : RW,
+        __           @ 1--7 : RsvdP,
+    }
+
+    pub struct AdvancedFeaturesStatus<'a> : RO u8 {
+        transactions_pending @    0 : RO,
+        __                   @ 1--7 : RsvdP,
+    }
+}
+
 // 7.9.27 Null Capability
 
 pci_capability! {
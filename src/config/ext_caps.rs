@@ -10,8 +10,11 @@
 //!
 //! | Section number | Section title | Type |
 //! |-|-|-|
+//! | 7.8.8 | PASID Extended Capability Structure | [`PasidExtendedCapability`] |
+//! | 7.9.3 | Device Serial Number Extended Capability | [`DeviceSerialNumberExtendedCapability`] |
 //! | 7.9.5 | Vendor-Specific Extended Capability | [`VendorSpecificExtendedCapability`] |
 //! | 7.9.28 | Null Extended Capability | [`NullExtendedCapability`] |
+//! | 10.5.2 | Page Request Extended Capability Structure | [`PageRequestExtendedCapability`] |
 
 /* ---------------------------------------------------------------------------------------------- */
 
@@ -25,6 +28,7 @@ use std::vec;
 use crate::config::caps::PciExpressCapability;
 use crate::config::PciConfig;
 use crate::pci_bit_field;
+use crate::regions::structured::{PciRegisterRo, PciRegisterRw};
 use crate::regions::{AsPciSubregion, BackedByPciSubregion, PciRegion, PciSubregion};
 
 /* ---------------------------------------------------------------------------------------------- */
@@ -76,11 +80,11 @@ impl<'a> PciExtendedCapabilities<'a> {
         // Number of 2-byte words in extended config space
         const ITERATIONS_UPPER_BOUND: usize = (CAP_RANGE.end - CAP_RANGE.start) / 2;
 
-        if config_space.len() < 0x1000 {
+        if !config_space.has_extended_config_space() {
             return Err(io::Error::new(
-                ErrorKind::InvalidInput,
+                ErrorKind::Unsupported,
                 format!(
-                    "Config space is 0x{:x} bytes long, expected at least 0x1000",
+                    "Config space is 0x{:x} bytes long, so extended config space isn't available",
                     config_space.len()
                 ),
             ));
@@ -185,7 +189,7 @@ impl<'a> IntoIterator for PciExtendedCapabilities<'a> {
     }
 }
 
-impl<'a, 'b> IntoIterator for &'b PciExtendedCapabilities<'a> {
+impl<'a> IntoIterator for &PciExtendedCapabilities<'a> {
     type Item = UnspecifiedExtendedCapability<'a>;
     type IntoIter = PciExtendedCapabilitiesIter<'a, UnspecifiedExtendedCapability<'a>>;
 
@@ -341,6 +345,67 @@ pci_extended_capability! {
     }
 }
 
+// 7.8.8 PASID Extended Capability Structure
+
+pci_extended_capability! {
+    /// Described in Section 7.8.8 of the "PCI Express® Base Specification Revision 6.0".
+    pub struct PasidExtendedCapability<'a> {
+        Id = 0x001b,
+        Length = |_cap| Ok(0x008),
+        Fields = {
+            pasid_capability @ 0x004 : PasidCapabilityRegister<'a>,
+            pasid_control    @ 0x006 : PasidControlRegister<'a>,
+        },
+    }
+}
+
+pci_bit_field! {
+    /// Described in Section 7.8.8.2 of the "PCI Express® Base Specification Revision 6.0".
+    pub struct PasidCapabilityRegister<'a> : RO u16 {
+        __                                       @      0 : RsvdP,
+        execute_permission_supported             @      1 : RO,
+        privileged_mode_supported                @      2 : RO,
+        translated_requests_with_pasid_supported @      3 : RO,
+        __                                       @   4--7 : RsvdP,
+        /// PASIDs used by the function are less than `1 << max_pasid_width`.
+        max_pasid_width                          @  8--12 : RO u8,
+        __                                       @ 13--15 : RsvdP,
+    }
+
+    /// Described in Section 7.8.8.3 of the "PCI Express® Base Specification Revision 6.0".
+    pub struct PasidControlRegister<'a> : RW u16 {
+        pasid_enable                          @      0 : RW,
+        execute_permission_enable             @      1 : RW,
+        privileged_mode_enable                @      2 : RW,
+        translated_requests_with_pasid_enable @      3 : RW,
+        __                                    @  4--15 : RsvdP,
+    }
+}
+
+// 7.9.3 Device Serial Number Extended Capability
+
+pci_extended_capability! {
+    /// Described in Section 7.9.3 of the "PCI Express® Base Specification Revision 6.0".
+    pub struct DeviceSerialNumberExtendedCapability<'a> {
+        Id = 0x0003,
+        Length = |_cap| Ok(0x00c),
+        Fields = {
+            serial_number_lower @ 0x004 : PciRegisterRo<'a, u32>,
+            serial_number_upper @ 0x008 : PciRegisterRo<'a, u32>,
+        },
+    }
+}
+
+impl DeviceSerialNumberExtendedCapability<'_> {
+    /// The full 64-bit serial number, which is unique to the device. Its upper 24 bits are often
+    /// the vendor's IEEE OUI.
+    pub fn serial_number(&self) -> io::Result<u64> {
+        let lower = self.serial_number_lower().read()?;
+        let upper = self.serial_number_upper().read()?;
+        Ok(u64::from(upper) << 32 | u64::from(lower))
+    }
+}
+
 // 7.9.5 Vendor-Specific Extended Capability
 
 pci_extended_capability! {
@@ -350,7 +415,7 @@ pci_extended_capability! {
         MinVersion = 0x1,
         Length = |cap| cap.vendor_specific_header().vsec_length().read(),
         Fields = {
-            vendor_specific_header @ 0x004 : VendorSpecificHeader,
+            vendor_specific_header @ 0x004 : VendorSpecificHeader<'a>,
             // TODO
         },
     }
@@ -376,4 +441,39 @@ pci_extended_capability! {
     }
 }
 
+// 10.5.2 Page Request Extended Capability Structure
+
+pci_extended_capability! {
+    /// Described in Section 10.5.2 of the "PCI Express® Base Specification Revision 6.0".
+    pub struct PageRequestExtendedCapability<'a> {
+        Id = 0x0013,
+        Length = |_cap| Ok(0x010),
+        Fields = {
+            page_request_control                @ 0x004 : PageRequestControlRegister<'a>,
+            page_request_status                 @ 0x006 : PageRequestStatusRegister<'a>,
+            outstanding_page_request_capacity   @ 0x008 : PciRegisterRo<'a, u32>,
+            outstanding_page_request_allocation @ 0x00c : PciRegisterRw<'a, u32>,
+        },
+    }
+}
+
+pci_bit_field! {
+    /// Described in Section 10.5.2.2 of the "PCI Express® Base Specification Revision 6.0".
+    pub struct PageRequestControlRegister<'a> : RW u16 {
+        enable @     0 : RW,
+        reset  @     1 : RW,
+        __     @ 2--15 : RsvdP,
+    }
+
+    /// Described in Section 10.5.2.3 of the "PCI Express® Base Specification Revision 6.0".
+    pub struct PageRequestStatusRegister<'a> : RW u16 {
+        response_failure                    @      0 : RW1C,
+        unexpected_page_request_group_index @      1 : RW1C,
+        __                                  @   2--7 : RsvdZ,
+        stopped                             @      8 : RO,
+        __                                  @  9--14 : RsvdZ,
+        prg_response_pasid_required         @     15 : RO,
+    }
+}
+
 /* ---------------------------------------------------------------------------------------------- */
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::fmt::{self, Display};
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+use crate::config::caps::PciExpressCapability;
+use crate::device::poll_until;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// A PCI Express link speed.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[non_exhaustive]
+pub enum PciLinkSpeed {
+    /// 2.5 GT/s.
+    Gt2_5 = 1,
+    /// 5.0 GT/s.
+    Gt5 = 2,
+    /// 8.0 GT/s.
+    Gt8 = 3,
+    /// 16.0 GT/s.
+    Gt16 = 4,
+    /// 32.0 GT/s.
+    Gt32 = 5,
+    /// 64.0 GT/s.
+    Gt64 = 6,
+}
+
+impl PciLinkSpeed {
+    /// Converts from the encoding used by the Current Link Speed and Target Link Speed fields.
+    pub fn from_value(value: u8) -> Option<PciLinkSpeed> {
+        match value {
+            1 => Some(PciLinkSpeed::Gt2_5),
+            2 => Some(PciLinkSpeed::Gt5),
+            3 => Some(PciLinkSpeed::Gt8),
+            4 => Some(PciLinkSpeed::Gt16),
+            5 => Some(PciLinkSpeed::Gt32),
+            6 => Some(PciLinkSpeed::Gt64),
+            _ => None,
+        }
+    }
+}
+
+impl Display for PciLinkSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let speed = match self {
+            PciLinkSpeed::Gt2_5 => "2.5",
+            PciLinkSpeed::Gt5 => "5.0",
+            PciLinkSpeed::Gt8 => "8.0",
+            PciLinkSpeed::Gt16 => "16.0",
+            PciLinkSpeed::Gt32 => "32.0",
+            PciLinkSpeed::Gt64 => "64.0",
+        };
+        write!(f, "{} GT/s", speed)
+    }
+}
+
+/// The negotiated state of a PCI Express link. See [`PciExpressCapability::link_state`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct PciLinkState {
+    /// The negotiated link speed, or `None` if it has an unknown encoding.
+    pub speed: Option<PciLinkSpeed>,
+    /// The negotiated link width, _e.g._, 4 for a x4 link.
+    pub width: u8,
+    /// The maximum link speed the port supports, or `None` if it has an unknown encoding.
+    pub max_speed: Option<PciLinkSpeed>,
+    /// The maximum link width the port supports.
+    pub max_width: u8,
+}
+
+impl PciLinkState {
+    /// Whether the link trained to a lower speed or width than the port supports.
+    pub fn is_downtrained(&self) -> bool {
+        self.speed < self.max_speed || self.width < self.max_width
+    }
+}
+
+impl PciExpressCapability<'_> {
+    /// Returns the negotiated speed and width of the link, along with the maximum ones the port
+    /// supports.
+    ///
+    /// Note that the link may be limited by the port on the other end as well.
+    pub fn link_state(&self) -> io::Result<PciLinkState> {
+        let capabilities = self.link_capabilities();
+        let status = self.link_status();
+
+        Ok(PciLinkState {
+            speed: PciLinkSpeed::from_value(status.current_link_speed().read()?),
+            width: status.negotiated_link_width().read()?,
+            max_speed: PciLinkSpeed::from_value(capabilities.max_link_speed().read()?),
+            max_width: capabilities.maximum_link_width().read()?,
+        })
+    }
+
+    /// Sets the Target Link Speed, which for Downstream Ports is the upper limit on the link speed
+    /// negotiated on the next retraining. See [`PciExpressCapability::retrain_link`].
+    pub fn set_target_link_speed(&self, speed: PciLinkSpeed) -> io::Result<()> {
+        self.link_control_2().target_link_speed().write(speed as u8)
+    }
+
+    /// Retrains the link, waits for training to complete, and returns the resulting link state.
+    ///
+    /// This only works on Downstream Ports, _i.e._, Root Ports and Switch Downstream Ports, and
+    /// retrains the link to the device below the port. Fails with [`ErrorKind::TimedOut`] if
+    /// training doesn't complete within `timeout`.
+    pub fn retrain_link(&self, timeout: Duration) -> io::Result<PciLinkState> {
+        let status = self.link_status();
+
+        // wait for any ongoing training to complete, as Linux does, so that the new request isn't
+        // lost
+        poll_until(timeout, || Ok(!status.link_training().read()?))?;
+
+        self.link_control().retrain_link().write(true)?;
+
+        if !poll_until(timeout, || Ok(!status.link_training().read()?))? {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "Link training didn't complete in time",
+            ));
+        }
+
+        self.link_state()
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use crate::backends::mock::MockPciDevice;
+    use crate::config::caps::PciExpressCapability;
+    use crate::config::{PciLinkSpeed, PciLinkState};
+    use crate::device::PciDevice;
+
+    #[test]
+    fn test_link_state() {
+        let device = MockPciDevice;
+        let config = device.config();
+        let cap = config
+            .capabilities()
+            .unwrap()
+            .of_type::<PciExpressCapability>()
+            .unwrap()
+            .next()
+            .unwrap();
+
+        let state = cap.link_state().unwrap();
+
+        assert_eq!(
+            state,
+            PciLinkState {
+                speed: Some(PciLinkSpeed::Gt8),
+                width: 4,
+                max_speed: Some(PciLinkSpeed::Gt8),
+                max_width: 4,
+            }
+        );
+        assert!(!state.is_downtrained());
+        assert_eq!(PciLinkSpeed::Gt2_5.to_string(), "2.5 GT/s");
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -4,14 +4,20 @@
 
 pub mod caps;
 pub mod ext_caps;
+mod link;
+mod state;
 
 use std::io;
 
 use crate::config::caps::PciCapabilities;
 use crate::config::ext_caps::PciExtendedCapabilities;
 use crate::regions::structured::{PciRegisterRo, PciRegisterRw};
+use crate::regions::PciRegion;
 use crate::{pci_bit_field, pci_struct};
 
+pub use link::{PciLinkSpeed, PciLinkState};
+pub use state::PciConfigState;
+
 /* ---------------------------------------------------------------------------------------------- */
 
 pci_struct! {
@@ -50,10 +56,21 @@ impl<'a> PciConfig<'a> {
 
     /// Returns a thing that lets you access the PCI Extended Capabilities.
     ///
-    /// Calling this will (re)scan all Extended Capabilities, which is why it can fail.
+    /// Calling this will (re)scan all Extended Capabilities, which is why it can fail. Fails with
+    /// [`ErrorKind::Unsupported`](io::ErrorKind::Unsupported) if extended config space isn't
+    /// available. See [`PciConfig::has_extended_config_space`].
     pub fn extended_capabilities(&self) -> io::Result<PciExtendedCapabilities<'a>> {
         PciExtendedCapabilities::backed_by(*self)
     }
+
+    /// Whether the full 4 KiB of config space, including the extended config space where Extended
+    /// Capabilities live, are accessible.
+    ///
+    /// This is not the case for conventional PCI functions, but also for PCI Express functions if
+    /// the platform doesn't provide ECAM or the backend doesn't expose more than 256 bytes.
+    pub fn has_extended_config_space(&self) -> bool {
+        self.len() >= 0x1000
+    }
 }
 
 // 7.5.1.1.3 Command Register
@@ -130,10 +147,14 @@ pci_bit_field! {
 
 #[cfg(test)]
 mod tests {
+    use std::io::ErrorKind;
+
     use crate::backends::mock::MockPciDevice;
-    use crate::config::caps::Capability;
+    use crate::config::caps::{Capability, MsiXCapability};
     use crate::config::ext_caps::ExtendedCapability;
+    use crate::config::PciConfig;
     use crate::device::PciDevice;
+    use crate::regions::{BackedByPciSubregion, PciMemoryRegion};
 
     #[test]
     fn test_lifetimes() {
@@ -170,6 +191,25 @@ mod tests {
         assert_eq!(cap_ids, vec![0x01, 0x05, 0x10, 0x11]);
     }
 
+    #[test]
+    fn test_msi_x_capability() {
+        let device: &dyn PciDevice = &MockPciDevice;
+        let config = device.config();
+
+        let cap = config
+            .capabilities()
+            .unwrap()
+            .of_type::<MsiXCapability>()
+            .unwrap()
+            .next()
+            .unwrap();
+
+        assert_eq!(cap.num_vectors().unwrap(), 33);
+        assert!(cap.message_control().msi_x_enable().read().unwrap());
+        assert_eq!(cap.table_location().unwrap(), (0, 0x3000));
+        assert_eq!(cap.pba_location().unwrap(), (0, 0x2000));
+    }
+
     #[test]
     fn test_extended_capabilities() {
         let device: &dyn PciDevice = &MockPciDevice;
@@ -187,6 +227,21 @@ mod tests {
             vec![0x0001, 0x0003, 0x0004, 0x0019, 0x0018, 0x001e]
         );
     }
+
+    #[test]
+    fn test_no_extended_config_space() {
+        let data = [0; 0x100];
+        let region = PciMemoryRegion::new(&data);
+        let config = PciConfig::backed_by(&region);
+
+        assert!(!config.has_extended_config_space());
+        assert_eq!(
+            config.extended_capabilities().unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+
+        assert!(MockPciDevice.config().has_extended_config_space());
+    }
 }
 
 /* ---------------------------------------------------------------------------------------------- */
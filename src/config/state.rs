@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::io;
+
+use crate::config::caps::{MsiCapability, MsiXCapability, PciExpressCapability};
+use crate::config::PciConfig;
+use crate::regions::{AsPciSubregion, PciRegion, PciSubregion};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// A snapshot of the writable configuration state of a PCI function, which can be reapplied after
+/// the function is reset or goes through a power state transition that loses it.
+///
+/// This captures the standard config space header (Command register, BARs, _etc._), the control
+/// registers of the PCI Express Capability, and the setup of the MSI and MSI-X Capabilities, much
+/// like Linux's `pci_save_state()` and `pci_restore_state()` do. The MSI-X Table lives in a BAR and
+/// isn't captured.
+///
+/// Note that backends may virtualize some of these registers, _e.g._, VFIO manages MSI and MSI-X
+/// itself, in which case restoring them has no effect on the actual function.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PciConfigState {
+    header: [u32; 16],
+    /// Restored, in order, before the header.
+    before_header: Vec<SavedRegister>,
+    /// Restored, in order, after the header.
+    after_header: Vec<SavedRegister>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct SavedRegister {
+    offset: u64,
+    value: u16,
+}
+
+impl PciConfigState {
+    /// Captures the current configuration state of the function with the given config space.
+    pub fn save(config: &PciConfig) -> io::Result<PciConfigState> {
+        let mut header = [0; 16];
+        for (i, dword) in header.iter_mut().enumerate() {
+            *dword = config.read_le_u32(i as u64 * 4)?;
+        }
+
+        // capability offsets relative to the start of config space
+        let base = config.as_subregion().offset_in_underlying_region();
+        let offset_of = |cap: PciSubregion| cap.offset_in_underlying_region() - base;
+
+        let save = |registers: &mut Vec<SavedRegister>, offset: u64| -> io::Result<()> {
+            registers.push(SavedRegister {
+                offset,
+                value: config.read_le_u16(offset)?,
+            });
+            Ok(())
+        };
+
+        let capabilities = config.capabilities()?;
+
+        let mut before_header = Vec::new();
+
+        if let Some(cap) = capabilities.of_type::<PciExpressCapability>()?.next() {
+            let offset = offset_of(cap.as_subregion());
+
+            // Device, Link, Slot, and Root Control
+            for &register in &[0x08, 0x10, 0x18, 0x1c] {
+                save(&mut before_header, offset + register)?;
+            }
+
+            // Device, Link, and Slot Control 2 only exist from version 2 on
+            if config.read_le_u16(offset + 0x02)? & 0xf >= 2 {
+                for &register in &[0x28, 0x30, 0x38] {
+                    save(&mut before_header, offset + register)?;
+                }
+            }
+        }
+
+        let mut after_header = Vec::new();
+
+        if let Some(cap) = capabilities.of_type::<MsiCapability>()?.next() {
+            let offset = offset_of(cap.as_subregion());
+            let control = config.read_le_u16(offset + 0x02)?;
+
+            // disable MSI while its address and data are being restored
+            after_header.push(SavedRegister {
+                offset: offset + 0x02,
+                value: control & !0x0001,
+            });
+
+            for register in (0x04..cap.len()).step_by(2) {
+                save(&mut after_header, offset + register)?;
+            }
+
+            save(&mut after_header, offset + 0x02)?;
+        }
+
+        if let Some(cap) = capabilities.of_type::<MsiXCapability>()?.next() {
+            save(&mut after_header, offset_of(cap.as_subregion()) + 0x02)?;
+        }
+
+        Ok(PciConfigState {
+            header,
+            before_header,
+            after_header,
+        })
+    }
+
+    /// Reapplies the captured state to the function with the given config space.
+    ///
+    /// Header registers are only written if their value differs, and the Command register is
+    /// written last so that the function doesn't decode accesses before its BARs are restored.
+    pub fn restore(&self, config: &PciConfig) -> io::Result<()> {
+        for register in &self.before_header {
+            config.write_le_u16(register.offset, register.value)?;
+        }
+
+        for i in (3..16).rev() {
+            let offset = i as u64 * 4;
+            if config.read_le_u32(offset)? != self.header[i] {
+                config.write_le_u32(offset, self.header[i])?;
+            }
+        }
+
+        config.write_le_u16(0x04, self.header[1] as u16)?;
+
+        for register in &self.after_header {
+            config.write_le_u16(register.offset, register.value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use crate::backends::mock::MockPciDevice;
+    use crate::config::PciConfigState;
+    use crate::device::PciDevice;
+
+    #[test]
+    fn test_save() {
+        let state = PciConfigState::save(&MockPciDevice.config()).unwrap();
+
+        assert_eq!(state.header[0], 0xa808_144d); // device and vendor IDs
+        assert_eq!(state.header[4], 0x9820_0004); // BAR 0
+
+        // PCI Express Capability v2 at 0x70
+        let offsets: Vec<u64> = state.before_header.iter().map(|r| r.offset).collect();
+        assert_eq!(offsets, [0x78, 0x80, 0x88, 0x8c, 0x98, 0xa0, 0xa8]);
+
+        // 64-bit MSI Capability at 0x50, then MSI-X Capability at 0xb0
+        let offsets: Vec<u64> = state.after_header.iter().map(|r| r.offset).collect();
+        assert_eq!(
+            offsets,
+            [0x52, 0x54, 0x56, 0x58, 0x5a, 0x5c, 0x5e, 0x52, 0xb2]
+        );
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -2,14 +2,27 @@
 
 /* ---------------------------------------------------------------------------------------------- */
 
-use std::fmt::Debug;
-use std::io;
-use std::os::unix::io::RawFd;
+use std::any::Any;
+use std::fmt::{self, Debug, Display};
+use std::io::{self, ErrorKind};
+use std::os::unix::io::{OwnedFd, RawFd};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::config::PciConfig;
+use crate::config::caps::{
+    ConventionalPciAdvancedFeaturesCapability, PciExpressCapability, PciPowerManagementCapability,
+};
+use crate::config::{PciConfig, PciConfigState};
 use crate::interrupts::{PciInterruptKind, PciInterrupts};
 use crate::iommu::PciIommu;
-use crate::regions::{OwningPciRegion, PciRegion, Permissions, RegionIdentifier};
+use crate::quirks::{self, PciQuirks};
+use crate::regions::{
+    BackedByPciSubregion, Cacheability, OwningPciRegion, PciRegion, Permissions, RegionIdentifier,
+};
+use crate::sysfs::PciSysfsDevice;
 
 /* ---------------------------------------------------------------------------------------------- */
 
@@ -18,17 +31,55 @@ mod private {
     /// Private trait that can be used as a supertrait to make other traits non-implementable from
     /// outside this crate: https://jack.wrenn.fyi/blog/private-trait-methods/
     pub trait Sealed {}
+
+    /// Lets [`super::PciDevice::as_any`] have a default implementation, which it can't otherwise
+    /// have since `Self` isn't `Sized` there.
+    pub trait UpcastAny {
+        fn upcast_any(&self) -> &dyn std::any::Any;
+    }
+
+    impl<T: std::any::Any> UpcastAny for T {
+        fn upcast_any(&self) -> &dyn std::any::Any {
+            self
+        }
+    }
 }
 
 /// Represents a PCI __function__.
 ///
 /// This trait is _sealed_ for forward-compatibility reasons, and thus cannot be implemented by
 /// users of the crate.
-pub trait PciDevice: Debug + Send + Sync + Sealed {
+pub trait PciDevice: Debug + Send + Sync + Sealed + private::UpcastAny {
+    /// Returns the backend device as an [`Any`], so that backend-specific functionality can be
+    /// accessed. See [`downcast_ref`](#method.downcast_ref).
+    fn as_any(&self) -> &dyn Any {
+        self.upcast_any()
+    }
+
     /// Returns a thing that lets you access the PCI configuration space.
     ///
     /// The returned value borrows the `PciDevice`.
-    fn config(&self) -> PciConfig;
+    fn config(&self) -> PciConfig<'_>;
+
+    /// Returns the function's identifying config space values, which are read once when the
+    /// `PciDevice` is created.
+    fn info(&self) -> PciDeviceInfo;
+
+    /// Returns the quirks that apply to the function, which backends look up when it is opened.
+    /// See the [`crate::quirks`] module.
+    fn quirks(&self) -> PciQuirks {
+        quirks::lookup(&self.info())
+    }
+
+    /// Returns the function's address, or `None` if the backend doesn't know it.
+    fn address(&self) -> Option<PciAddress>;
+
+    /// Returns the NUMA node the function is attached to, or `None` if the system isn't NUMA or
+    /// the backend or platform doesn't know it.
+    ///
+    /// Allocating DMA buffers and running the threads that drive the function on this node avoids
+    /// cross-node memory traffic.
+    fn numa_node(&self) -> io::Result<Option<u32>>;
 
     /// Returns a region that corresponds to the Base Address Register (BAR) with the given index,
     /// or `None` if there is no such BAR or it is unused by the device.
@@ -63,12 +114,12 @@ pub trait PciDevice: Debug + Send + Sync + Sealed {
     /// affect IOMMU mappings for other PCI functions.
     ///
     /// The returned value borrows the `PciDevice`.
-    fn iommu(&self) -> Option<PciIommu>;
+    fn iommu(&self) -> Option<PciIommu<'_>>;
 
     /// Returns a thing that lets you manage interrupts.
     ///
     /// The returned value borrows the `PciDevice`.
-    fn interrupts(&self) -> PciInterrupts;
+    fn interrupts(&self) -> PciInterrupts<'_>;
 
     /// Reset this function, and only it.
     ///
@@ -78,22 +129,652 @@ pub trait PciDevice: Debug + Send + Sync + Sealed {
     ///
     /// This can also fail for other unspecified reasons.
     ///
-    /// TODO: Should probably advertise whether this granularity of reset is supported, so the user
-    /// doesn't have to try resetting to find out.
+    /// Use [`PciDevice::reset_capabilities`] to find out whether this is supported without trying
+    /// it.
     fn reset(&self) -> io::Result<()>;
+
+    /// Returns the kinds of reset this function supports.
+    fn reset_capabilities(&self) -> io::Result<PciResetCapabilities>;
+
+    /// Saves the function's configuration, resets it with [`PciDevice::reset`], waits for it to
+    /// become ready with [`PciDevice::wait_ready`] (for up to 60 seconds), and then restores the
+    /// configuration.
+    ///
+    /// The configuration is what a [`PciConfigState`] captures: the Command register, BARs, PCI
+    /// Express control registers, and MSI and MSI-X setup. Interrupts enabled through
+    /// [`PciDevice::interrupts`] are managed by the backend, and may or may not survive the reset.
+    fn reset_and_restore(&self) -> io::Result<()> {
+        let config = self.config();
+        let state = PciConfigState::save(&config)?;
+
+        self.reset()?;
+        self.wait_ready(RESET_READY_TIMEOUT)?;
+
+        state.restore(&config)
+    }
+
+    /// Resets this function by initiating a Function Level Reset (FLR) through its PCI Express
+    /// Capability or, if it doesn't support that, its Conventional PCI Advanced Features
+    /// Capability.
+    ///
+    /// This waits (for up to 1 second) for the function's pending transactions to complete,
+    /// initiates the FLR, waits the mandatory 100 ms, and then polls config space until the
+    /// function responds again, failing with [`ErrorKind::TimedOut`] if it doesn't within 60
+    /// seconds.
+    ///
+    /// Unlike [`PciDevice::reset`], this doesn't let the backend pick the reset mechanism. Note
+    /// that the backend may still intercept the FLR request, as VFIO does, and that the function's
+    /// config space state may or may not be restored afterwards.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if the function doesn't support FLR, or if its
+    /// [quirks](PciDevice::quirks) say FLR must be avoided. See [`PciResetCapabilities::flr`] and
+    /// [`PciResetCapabilities::af_flr`].
+    fn function_level_reset(&self) -> io::Result<()> {
+        if self.quirks().no_flr {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Function Level Reset is known not to work on this function",
+            ));
+        }
+
+        function_level_reset(&self.config())
+    }
+
+    /// Returns the function's current power state, as reported by its Power Management
+    /// Capability.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if the function doesn't have that Capability.
+    fn power_state(&self) -> io::Result<PciPowerState> {
+        let config = self.config();
+        let cap = power_management_capability(&config)?;
+        let value = cap.control_status().power_state().read()?;
+        Ok(PciPowerState::from_value(value))
+    }
+
+    /// Puts the function in the given power state through its Power Management Capability, and
+    /// waits for the delay the spec requires after the transition.
+    ///
+    /// Only transitions to lower power states, or back to [`PciPowerState::D0`], are allowed. The
+    /// function's memory and I/O spaces are inaccessible in D3<sub>hot</sub>.
+    ///
+    /// Functions whose Power Management Capability has the No_Soft_Reset bit clear are reset when
+    /// going from D3<sub>hot</sub> to D0, losing their configuration. If `restore_config` is
+    /// `true`, a [`PciConfigState`] is saved before such a transition and restored after it.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if the function doesn't have that Capability or
+    /// doesn't support the given state.
+    fn set_power_state(&self, state: PciPowerState, restore_config: bool) -> io::Result<()> {
+        set_power_state(&self.config(), state, restore_config)
+    }
+
+    /// Polls the function's config space until it responds to requests, after, _e.g._, a reset
+    /// or a D3<sub>cold</sub>-to-D0 transition.
+    ///
+    /// A function that is still initializing may not respond at all, in which case reads return
+    /// all ones, or may respond with Configuration Request Retry Status, in which case the Vendor
+    /// ID reads as `0x0001` if CRS Software Visibility is enabled. This polls the Vendor ID with
+    /// increasing delays until it reads as neither, and fails with [`ErrorKind::TimedOut`] if that
+    /// doesn't happen within `timeout`.
+    ///
+    /// Note that functions aren't allowed to be accessed for 100 ms after a reset, which this
+    /// doesn't wait for.
+    fn wait_ready(&self, timeout: Duration) -> io::Result<()> {
+        wait_ready(&self.config(), timeout)
+    }
+
+    /// Sets the function's Max_Payload_Size to the largest value that is safe given the bridges
+    /// above it, and returns that value in bytes.
+    ///
+    /// A function that sends TLPs bigger than what a bridge on the way accepts causes errors that
+    /// may hang the system, so the function's Max_Payload_Size shouldn't simply be set to what it
+    /// supports. This walks the Root Port and Switch Ports above the function through sysfs, and
+    /// uses the smallest of the function's supported size and the bridges' current settings.
+    /// Bridges are left unchanged, since they also talk to other functions below them. The
+    /// Max_Read_Request_Size is left unchanged as well, as it doesn't need to match.
+    ///
+    /// The function's Max_Payload_Size is written through its sysfs `config` attribute rather than
+    /// through [`PciDevice::config`], as backends like VFIO virtualize that field, so that writes
+    /// through them never reach the hardware. For the same reason, reading the field through
+    /// [`PciDevice::config`] afterwards may still return the old value.
+    ///
+    /// This requires root privileges, and fails with [`ErrorKind::PermissionDenied`] if the
+    /// function's config space can't be written through sysfs. Fails with
+    /// [`ErrorKind::InvalidInput`] if the function doesn't have a PCI Express Capability, or if
+    /// the backend doesn't know its [address](PciDevice::address).
+    fn configure_max_payload_size(&self) -> io::Result<u16> {
+        let address = self.address().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "Backend doesn't know the function's address",
+            )
+        })?;
+
+        let sysfs = PciSysfsDevice::new(Path::new(SYSFS_PCI_DEVICES).join(address.to_string()));
+        configure_max_payload_size(&sysfs)
+    }
+}
+
+impl dyn PciDevice + '_ {
+    /// Returns the backend device if it is of type `T`, _e.g._, to access the
+    /// `VfioPciDevice::container` of a `&dyn PciDevice` that is known to use VFIO.
+    ///
+    /// ```no_run
+    /// # #[cfg(feature = "vfio")] {
+    /// use pci_driver::backends::vfio::VfioPciDevice;
+    /// use pci_driver::device::PciDevice;
+    ///
+    /// fn container_of(device: &dyn PciDevice) {
+    ///     if let Some(device) = device.downcast_ref::<VfioPciDevice>() {
+    ///         println!("{:?}", device.container());
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn downcast_ref<T: PciDevice + 'static>(&self) -> Option<&T> {
+        self.as_any().downcast_ref()
+    }
+
+    /// Whether the backend device is of type `T`.
+    pub fn is<T: PciDevice + 'static>(&self) -> bool {
+        self.as_any().is::<T>()
+    }
+}
+
+/// A cheaply cloneable, shared handle to a [`PciDevice`].
+///
+/// Use this to hand out the same device to several threads or components, all of which can use it
+/// through its [`PciDevice`] implementation. The device is dropped when the last handle is.
+#[derive(Clone, Debug)]
+pub struct PciDeviceHandle {
+    device: Arc<dyn PciDevice>,
+}
+
+impl PciDeviceHandle {
+    /// Takes ownership of the given device.
+    pub fn new<D: PciDevice + 'static>(device: D) -> PciDeviceHandle {
+        PciDeviceHandle {
+            device: Arc::new(device),
+        }
+    }
+
+    /// Creates a handle that shares ownership of an already shared device.
+    pub fn from_arc(device: Arc<dyn PciDevice>) -> PciDeviceHandle {
+        PciDeviceHandle { device }
+    }
+
+    /// Returns the underlying shared device.
+    pub fn as_arc(&self) -> &Arc<dyn PciDevice> {
+        &self.device
+    }
+}
+
+impl Sealed for PciDeviceHandle {}
+impl PciDevice for PciDeviceHandle {
+    // handles are transparent, so that the device they share can be downcast to
+    fn as_any(&self) -> &dyn Any {
+        self.device.as_any()
+    }
+
+    fn config(&self) -> PciConfig<'_> {
+        self.device.config()
+    }
+
+    fn info(&self) -> PciDeviceInfo {
+        self.device.info()
+    }
+
+    fn quirks(&self) -> PciQuirks {
+        self.device.quirks()
+    }
+
+    fn address(&self) -> Option<PciAddress> {
+        self.device.address()
+    }
+
+    fn numa_node(&self) -> io::Result<Option<u32>> {
+        self.device.numa_node()
+    }
+
+    fn bar(&self, index: usize) -> Option<OwningPciRegion> {
+        self.device.bar(index)
+    }
+
+    fn bar_region(&self, index: usize) -> Option<Box<dyn PciRegion>> {
+        self.device.bar_region(index)
+    }
+
+    fn rom(&self) -> Option<OwningPciRegion> {
+        self.device.rom()
+    }
+
+    fn iommu(&self) -> Option<PciIommu<'_>> {
+        self.device.iommu()
+    }
+
+    fn interrupts(&self) -> PciInterrupts<'_> {
+        self.device.interrupts()
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        self.device.reset()
+    }
+
+    fn reset_capabilities(&self) -> io::Result<PciResetCapabilities> {
+        self.device.reset_capabilities()
+    }
+
+    fn reset_and_restore(&self) -> io::Result<()> {
+        self.device.reset_and_restore()
+    }
+
+    fn function_level_reset(&self) -> io::Result<()> {
+        self.device.function_level_reset()
+    }
+
+    fn power_state(&self) -> io::Result<PciPowerState> {
+        self.device.power_state()
+    }
+
+    fn set_power_state(&self, state: PciPowerState, restore_config: bool) -> io::Result<()> {
+        self.device.set_power_state(state, restore_config)
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> io::Result<()> {
+        self.device.wait_ready(timeout)
+    }
+
+    fn configure_max_payload_size(&self) -> io::Result<u16> {
+        self.device.configure_max_payload_size()
+    }
+}
+
+/// The power state of a PCI function. See [`PciDevice::set_power_state`].
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PciPowerState {
+    /// The fully-operational state.
+    D0 = 0,
+    /// An optional low power state.
+    D1 = 1,
+    /// An optional low power state, lower than D1.
+    D2 = 2,
+    /// The lowest power state that can be entered through config space.
+    D3Hot = 3,
+}
+
+impl PciPowerState {
+    fn from_value(value: u8) -> PciPowerState {
+        match value & 0x3 {
+            0 => PciPowerState::D0,
+            1 => PciPowerState::D1,
+            2 => PciPowerState::D2,
+            _ => PciPowerState::D3Hot,
+        }
+    }
+}
+
+/// The identifying config space values of a PCI function. See [`PciDevice::info`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct PciDeviceInfo {
+    /// The function's Vendor ID.
+    pub vendor_id: u16,
+    /// The function's Device ID.
+    pub device_id: u16,
+    /// The function's Revision ID.
+    pub revision_id: u8,
+    /// The function's Class Code, with the Base Class Code in bits 16 to 23, the Sub-Class Code in
+    /// bits 8 to 15, and the Programming Interface in bits 0 to 7.
+    pub class_code: u32,
+    /// The function's Subsystem Vendor ID, or 0 if its header layout doesn't have one.
+    pub subsystem_vendor_id: u16,
+    /// The function's Subsystem ID, or 0 if its header layout doesn't have one.
+    pub subsystem_id: u16,
+    /// The layout of the rest of the function's config space header, _e.g._, 0 for Endpoints and 1
+    /// for PCI-to-PCI Bridges.
+    pub header_layout: u8,
+    /// Whether the device the function belongs to has multiple functions.
+    pub multi_function_device: bool,
+}
+
+impl PciDeviceInfo {
+    #[allow(dead_code)] // for when pci-driver is built with no backends
+    pub(crate) fn read(config: &PciConfig) -> io::Result<PciDeviceInfo> {
+        let header_layout = config.header_type().header_layout().read()?;
+
+        let (subsystem_vendor_id, subsystem_id) = if header_layout == 0 {
+            (
+                config.subsystem_vendor_id().read()?,
+                config.subsystem_id().read()?,
+            )
+        } else {
+            (0, 0)
+        };
+
+        Ok(PciDeviceInfo {
+            vendor_id: config.vendor_id().read()?,
+            device_id: config.device_id().read()?,
+            revision_id: config.revision_id().read()?,
+            class_code: config.read_le_u32(0x08)? >> 8,
+            subsystem_vendor_id,
+            subsystem_id,
+            header_layout,
+            multi_function_device: config.header_type().multi_function_device().read()?,
+        })
+    }
+}
+
+/// The kinds of reset supported by a PCI function. See [`PciDevice::reset_capabilities`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct PciResetCapabilities {
+    /// Whether [`PciDevice::reset`] is supported, _i.e._, the function can be reset without also
+    /// resetting other functions, through any of the mechanisms below.
+    pub function_reset: bool,
+    /// Whether the function supports Function Level Reset, as advertised in its PCI Express
+    /// Capability.
+    pub flr: bool,
+    /// Whether the function supports Function Level Reset through the Conventional PCI Advanced
+    /// Features Capability.
+    pub af_flr: bool,
+    /// Whether the function is reset when put in the D3<sub>hot</sub> power state and back to D0,
+    /// _i.e._, it has a Power Management Capability with the No_Soft_Reset bit clear.
+    pub pm_reset: bool,
+    /// Whether the function can be reset through a secondary bus reset or slot reset, which may
+    /// also reset other functions.
+    pub hot_reset: bool,
+}
+
+impl PciResetCapabilities {
+    /// Fills in the fields that are advertised in config space, leaving the others `false`.
+    #[allow(dead_code)] // for when pci-driver is built with no backends
+    pub(crate) fn from_config(config: &PciConfig) -> io::Result<PciResetCapabilities> {
+        let capabilities = config.capabilities()?;
+
+        let flr = match capabilities.of_type::<PciExpressCapability>()?.next() {
+            Some(cap) => cap
+                .device_capabilities()
+                .function_level_reset_capability()
+                .read()?,
+            None => false,
+        };
+
+        let af_flr = match capabilities
+            .of_type::<ConventionalPciAdvancedFeaturesCapability>()?
+            .next()
+        {
+            Some(cap) => cap.af_capabilities().flr_capable().read()?,
+            None => false,
+        };
+
+        let pm_reset = match capabilities
+            .of_type::<PciPowerManagementCapability>()?
+            .next()
+        {
+            Some(cap) => !cap.control_status().no_soft_reset().read()?,
+            None => false,
+        };
+
+        Ok(PciResetCapabilities {
+            function_reset: false,
+            flr,
+            af_flr,
+            pm_reset,
+            hot_reset: false,
+        })
+    }
+}
+
+/// How long to wait for a function's pending transactions to complete before an FLR.
+const FLR_PENDING_TRANSACTIONS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a function is given to complete an FLR, as required by the spec.
+const FLR_DELAY: Duration = Duration::from_millis(100);
+
+/// How long to wait for a function to respond after an FLR or [`PciDevice::reset`], as Linux does.
+const RESET_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn function_level_reset(config: &PciConfig) -> io::Result<()> {
+    let capabilities = config.capabilities()?;
+
+    if let Some(cap) = capabilities.of_type::<PciExpressCapability>()?.next() {
+        if cap
+            .device_capabilities()
+            .function_level_reset_capability()
+            .read()?
+        {
+            // the FLR discards any transactions that are still pending, so go ahead regardless
+            poll_until(FLR_PENDING_TRANSACTIONS_TIMEOUT, || {
+                Ok(!cap.device_status().transactions_pending().read()?)
+            })?;
+
+            cap.device_control()
+                .initiate_function_level_reset()
+                .write(true)?;
+
+            return wait_after_flr(config);
+        }
+    }
+
+    if let Some(cap) = capabilities
+        .of_type::<ConventionalPciAdvancedFeaturesCapability>()?
+        .next()
+    {
+        // the spec requires both to be supported for either to be usable
+        let af_capabilities = cap.af_capabilities();
+        if af_capabilities.flr_capable().read()? && af_capabilities.tp_capable().read()? {
+            poll_until(FLR_PENDING_TRANSACTIONS_TIMEOUT, || {
+                Ok(!cap.af_status().transactions_pending().read()?)
+            })?;
+
+            cap.af_control().initiate_flr().write(true)?;
+
+            return wait_after_flr(config);
+        }
+    }
+
+    Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        "Function doesn't support Function Level Reset",
+    ))
+}
+
+fn wait_after_flr(config: &PciConfig) -> io::Result<()> {
+    thread::sleep(FLR_DELAY);
+    wait_ready(config, RESET_READY_TIMEOUT)
+}
+
+fn wait_ready(config: &PciConfig, timeout: Duration) -> io::Result<()> {
+    // reads return all ones while the function doesn't respond, and the Vendor ID reads as 0x0001
+    // while it responds with Configuration Request Retry Status
+    let ready = poll_until(timeout, || {
+        let vendor_id = config.vendor_id().read()?;
+        Ok(vendor_id != 0xffff && vendor_id != 0x0001)
+    })?;
+
+    if !ready {
+        return Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "Function didn't become ready in time",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Calls `condition` with increasing delays until it returns `true` or `timeout` elapses, and
+/// returns whether it returned `true`.
+pub(crate) fn poll_until<F>(timeout: Duration, mut condition: F) -> io::Result<bool>
+where
+    F: FnMut() -> io::Result<bool>,
+{
+    let deadline = Instant::now() + timeout;
+    let mut delay = Duration::from_millis(1);
+
+    loop {
+        if condition()? {
+            return Ok(true);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+
+        thread::sleep(delay.min(deadline - now));
+        delay = (delay * 2).min(Duration::from_millis(100));
+    }
+}
+
+const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+/// The largest Max_Payload_Size encoding, for 4096 bytes.
+const MAX_PAYLOAD_SIZE_4096: u8 = 5;
+
+fn configure_max_payload_size(sysfs: &PciSysfsDevice) -> io::Result<u16> {
+    let config_space = sysfs.config_space()?;
+
+    if config_space.permissions() != Permissions::ReadWrite {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "Setting the Max_Payload_Size requires write access to the function's sysfs config \
+             attribute",
+        ));
+    }
+
+    let config = PciConfig::backed_by(&config_space);
+
+    let cap = config
+        .capabilities()?
+        .of_type::<PciExpressCapability>()?
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "Function doesn't have a PCI Express Capability",
+            )
+        })?;
+
+    let mut max_payload_size = cap
+        .device_capabilities()
+        .max_payload_size_supported()
+        .read()?
+        .min(MAX_PAYLOAD_SIZE_4096);
+
+    for bridge in sysfs.upstream_bridges()? {
+        let config_space = bridge.config_space_read_only()?;
+        let bridge_config = PciConfig::backed_by(&config_space);
+
+        // PCI-to-PCI bridges above a PCI Express function are not on its PCI Express path
+        if let Some(bridge_cap) = bridge_config
+            .capabilities()?
+            .of_type::<PciExpressCapability>()?
+            .next()
+        {
+            let bridge_max_payload_size = bridge_cap.device_control().max_payload_size().read()?;
+            max_payload_size = max_payload_size.min(bridge_max_payload_size);
+        }
+    }
+
+    cap.device_control()
+        .max_payload_size()
+        .write(max_payload_size)?;
+
+    Ok(128 << max_payload_size)
+}
+
+fn power_management_capability<'a>(
+    config: &PciConfig<'a>,
+) -> io::Result<PciPowerManagementCapability<'a>> {
+    config
+        .capabilities()?
+        .of_type::<PciPowerManagementCapability>()?
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "Function doesn't have a Power Management Capability",
+            )
+        })
+}
+
+fn set_power_state(
+    config: &PciConfig,
+    state: PciPowerState,
+    restore_config: bool,
+) -> io::Result<()> {
+    let cap = power_management_capability(config)?;
+    let current = PciPowerState::from_value(cap.control_status().power_state().read()?);
+
+    if state == current {
+        return Ok(());
+    }
+
+    if state != PciPowerState::D0 && state < current {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Can't go from {:?} to {:?}", current, state),
+        ));
+    }
+
+    let supported = match state {
+        PciPowerState::D1 => cap.capabilities().d1_support().read()?,
+        PciPowerState::D2 => cap.capabilities().d2_support().read()?,
+        _ => true,
+    };
+
+    if !supported {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Function doesn't support {:?}", state),
+        ));
+    }
+
+    // config space remains accessible in D3hot, and is only reset when leaving it
+    let resets = current == PciPowerState::D3Hot && !cap.control_status().no_soft_reset().read()?;
+    let saved_state = if restore_config && resets {
+        Some(PciConfigState::save(config)?)
+    } else {
+        None
+    };
+
+    cap.control_status().power_state().write(state as u8)?;
+
+    // 5.9 State Transition Recovery Time Requirements
+    if current == PciPowerState::D3Hot || state == PciPowerState::D3Hot {
+        thread::sleep(Duration::from_millis(10));
+    } else if current == PciPowerState::D2 || state == PciPowerState::D2 {
+        thread::sleep(Duration::from_micros(200));
+    }
+
+    if let Some(saved_state) = saved_state {
+        saved_state.restore(config)?;
+    }
+
+    Ok(())
 }
 
 /* ---------------------------------------------------------------------------------------------- */
 
 pub(crate) trait PciDeviceInternal: Debug + Send + Sync {
+    // Config space
+
+    fn config_space(&self) -> PciConfig<'_>;
+
     // BARs / ROM
 
+    /// Incremented whenever the backend sets up the device's regions anew, which invalidates the
+    /// [`OwningPciRegion`](crate::regions::OwningPciRegion)s obtained before.
+    fn region_generation(&self) -> u64 {
+        0
+    }
+
     fn region_map(
         &self,
         identifier: RegionIdentifier,
         offset: u64,
         len: usize,
         permissions: Permissions,
+        cacheability: Cacheability,
     ) -> io::Result<*mut u8>;
 
     unsafe fn region_unmap(&self, identifier: RegionIdentifier, address: *mut u8, length: usize);
@@ -101,8 +782,273 @@ pub(crate) trait PciDeviceInternal: Debug + Send + Sync {
     // Interrupts
 
     fn interrupts_max(&self, kind: PciInterruptKind) -> usize;
-    fn interrupts_enable(&self, kind: PciInterruptKind, eventfds: &[RawFd]) -> io::Result<()>;
+    fn interrupts_enable(
+        &self,
+        kind: PciInterruptKind,
+        start: usize,
+        eventfds: &[RawFd],
+        owned: Option<Vec<OwnedFd>>,
+    ) -> io::Result<()>;
+    /// The eventfds currently set as triggers of the given mechanism's vectors, -1 for vectors
+    /// without one.
+    fn interrupts_eventfds(&self, kind: PciInterruptKind) -> Vec<RawFd>;
+    /// Duplicates of the eventfds that were handed over to the device along with the given
+    /// mechanism's vectors, `None` for vectors whose eventfd the device doesn't own, if any.
+    fn interrupts_owned_eventfds(&self, kind: PciInterruptKind)
+        -> io::Result<Vec<Option<OwnedFd>>>;
+    fn interrupts_is_maskable(&self, kind: PciInterruptKind) -> bool;
+    fn interrupts_is_automasked(&self, kind: PciInterruptKind) -> bool;
+    fn interrupts_is_resizable(&self, kind: PciInterruptKind) -> bool;
+    fn interrupts_set_masked(&self, kind: PciInterruptKind, masked: bool) -> io::Result<()>;
+    fn interrupts_set_unmask_eventfd(
+        &self,
+        kind: PciInterruptKind,
+        eventfd: RawFd,
+    ) -> io::Result<()>;
+    fn interrupts_trigger(&self, kind: PciInterruptKind, vector: usize) -> io::Result<()>;
     fn interrupts_disable(&self, kind: PciInterruptKind) -> io::Result<()>;
+    /// The name under which the host IRQ backing the given vector appears in `/proc/interrupts`,
+    /// if any.
+    fn interrupts_irq_name(&self, kind: PciInterruptKind, vector: usize) -> Option<String>;
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// The address of a PCI function, made up of its domain (or segment), bus, device, and function
+/// numbers.
+///
+/// This is displayed and parsed in the format used by Linux, _e.g._, `0000:00:1f.3`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PciAddress {
+    /// The domain number. This is usually 16 bits wide, but Linux may use wider ones for devices
+    /// behind some host bridges.
+    pub domain: u32,
+    /// The bus number.
+    pub bus: u8,
+    /// The device number, in [0, 31].
+    pub device: u8,
+    /// The function number, in [0, 7].
+    pub function: u8,
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.domain, self.bus, self.device, self.function
+        )
+    }
+}
+
+impl FromStr for PciAddress {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<PciAddress> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid PCI address {:?}, expected DDDD:BB:DD.F", s),
+            )
+        };
+
+        let mut parts = s.splitn(3, ':');
+        let (domain, bus, rest) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(domain), Some(bus), Some(rest)) => (domain, bus, rest),
+            _ => return Err(invalid()),
+        };
+
+        let mut parts = rest.splitn(2, '.');
+        let (device, function) = match (parts.next(), parts.next()) {
+            (Some(device), Some(function)) => (device, function),
+            _ => return Err(invalid()),
+        };
+
+        let address = PciAddress {
+            domain: u32::from_str_radix(domain, 16).map_err(|_| invalid())?,
+            bus: u8::from_str_radix(bus, 16).map_err(|_| invalid())?,
+            device: u8::from_str_radix(device, 16).map_err(|_| invalid())?,
+            function: u8::from_str_radix(function, 16).map_err(|_| invalid())?,
+        };
+
+        if address.device > 31 || address.function > 7 {
+            return Err(invalid());
+        }
+
+        Ok(address)
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::backends::mock::{MockDeviceBuilder, MockPciDevice};
+    use crate::device::{
+        PciAddress, PciDevice, PciDeviceHandle, PciDeviceInfo, PciResetCapabilities,
+    };
+    use crate::regions::PciRegion;
+
+    #[test]
+    fn test_wait_ready() {
+        MockPciDevice.wait_ready(Duration::ZERO).unwrap();
+
+        // not responding, then responding with CRS, then ready
+        let device = MockDeviceBuilder::new(0xffff, 0x5678).build();
+        let config = device.config();
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                config.write_le_u16(0x00, 0x0001).unwrap();
+                thread::sleep(Duration::from_millis(20));
+                config.write_le_u16(0x00, 0x1234).unwrap();
+            });
+
+            device.wait_ready(Duration::from_secs(10)).unwrap();
+            assert_eq!(config.read_le_u16(0x00).unwrap(), 0x1234);
+        });
+
+        // never ready
+        let device = MockDeviceBuilder::new(0x0001, 0x5678).build();
+        let start = Instant::now();
+        assert_eq!(
+            device
+                .wait_ready(Duration::from_millis(50))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::TimedOut
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_reset_and_restore() {
+        // 32-bit MSI Capability capable of 4 vectors
+        let device = MockDeviceBuilder::new(0x1234, 0x5678)
+            .bar(0, vec![0; 0x1000])
+            .capability(0x05, &[0x04, 0x00, 0, 0, 0, 0, 0, 0])
+            .build();
+
+        let config = device.config();
+        let configure = || {
+            config.write_le_u16(0x04, 0x0006).unwrap(); // Memory Space and Bus Master Enable
+            config.write_le_u32(0x10, 0xd000_0000).unwrap(); // BAR 0
+            config.write_le_u16(0x42, 0x0025).unwrap(); // MSI Enable, 4 vectors enabled
+            config.write_le_u32(0x44, 0xfee0_0000).unwrap(); // Message Address
+            config.write_le_u16(0x48, 0x0041).unwrap(); // Message Data
+        };
+        let check = || {
+            assert_eq!(config.read_le_u16(0x04).unwrap(), 0x0006);
+            assert_eq!(config.read_le_u32(0x10).unwrap(), 0xd000_0000);
+            assert_eq!(config.read_le_u16(0x42).unwrap(), 0x0025);
+            assert_eq!(config.read_le_u32(0x44).unwrap(), 0xfee0_0000);
+            assert_eq!(config.read_le_u16(0x48).unwrap(), 0x0041);
+        };
+
+        // a plain reset loses the configuration
+        configure();
+        device.reset().unwrap();
+        assert_eq!(config.read_le_u16(0x04).unwrap(), 0x0000);
+        assert_eq!(config.read_le_u32(0x10).unwrap(), 0xc000_0000);
+
+        configure();
+        device.reset_and_restore().unwrap();
+        assert_eq!(device.reset_count(), 2);
+        check();
+    }
+
+    #[test]
+    fn test_handle() {
+        let handle = PciDeviceHandle::new(MockPciDevice);
+        let clone = handle.clone();
+
+        let info = thread::spawn(move || clone.info()).join().unwrap();
+        assert_eq!(info, handle.info());
+        assert_eq!(Arc::strong_count(handle.as_arc()), 1);
+    }
+
+    #[test]
+    fn test_downcast() {
+        let device: &dyn PciDevice = &MockPciDevice;
+        assert!(device.is::<MockPciDevice>());
+        assert!(device.downcast_ref::<PciDeviceHandle>().is_none());
+
+        let handle = PciDeviceHandle::new(MockPciDevice);
+        let device: &dyn PciDevice = &handle;
+        assert!(device.downcast_ref::<MockPciDevice>().is_some());
+    }
+
+    #[test]
+    fn test_info() {
+        assert_eq!(
+            MockPciDevice.info(),
+            PciDeviceInfo {
+                vendor_id: 0x144d,
+                device_id: 0xa808,
+                revision_id: 0x00,
+                class_code: 0x010802,
+                subsystem_vendor_id: 0x144d,
+                subsystem_id: 0xa801,
+                header_layout: 0,
+                multi_function_device: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_reset_capabilities_from_config() {
+        let capabilities = PciResetCapabilities::from_config(&MockPciDevice.config()).unwrap();
+
+        // the mock device has a PCI Express Capability advertising FLR, and a Power Management
+        // Capability with No_Soft_Reset set
+        assert_eq!(
+            capabilities,
+            PciResetCapabilities {
+                function_reset: false,
+                flr: true,
+                af_flr: false,
+                pm_reset: false,
+                hot_reset: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_pci_address() {
+        let address: PciAddress = "0000:3b:1f.7".parse().unwrap();
+
+        assert_eq!(
+            address,
+            PciAddress {
+                domain: 0,
+                bus: 0x3b,
+                device: 0x1f,
+                function: 7
+            }
+        );
+        assert_eq!(address.to_string(), "0000:3b:1f.7");
+        assert_eq!(
+            "10000:00:02.0".parse::<PciAddress>().unwrap().to_string(),
+            "10000:00:02.0"
+        );
+
+        for s in &[
+            "",
+            "00:1f.3",
+            "0000:00:20.0",
+            "0000:00:1f.8",
+            "0000:00:1f",
+            "x:00:00.0",
+        ] {
+            assert!(s.parse::<PciAddress>().is_err());
+        }
+    }
 }
 
 /* ---------------------------------------------------------------------------------------------- */
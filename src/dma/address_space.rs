@@ -0,0 +1,255 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind};
+use std::sync::Mutex;
+
+use crate::iommu::{IommuMapping, IovaAllocator, PciIommu};
+use crate::regions::Permissions;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// A device's address space, bundling together a [`PciIommu`], an [`IovaAllocator`] for it, and
+/// a record of the mappings added through it.
+///
+/// This spares you from keeping those three in sync yourself:
+/// [`DeviceAddressSpace::alloc_and_map`] picks an IOVA and adds the mapping,
+/// [`DeviceAddressSpace::translate`] turns IOVAs back into process addresses, and dropping the
+/// `DeviceAddressSpace` removes all the mappings it added.
+///
+/// This is thread-safe.
+#[derive(Debug)]
+pub struct DeviceAddressSpace<'a> {
+    iommu: PciIommu<'a>,
+    allocator: IovaAllocator,
+    /// Keyed by IOVA.
+    mappings: Mutex<BTreeMap<u64, IommuMapping>>,
+}
+
+impl<'a> DeviceAddressSpace<'a> {
+    /// Creates an address space that allocates IOVAs from all of the IOMMU's
+    /// [valid IOVA ranges](PciIommu::valid_iova_ranges).
+    pub fn new(iommu: PciIommu<'a>) -> DeviceAddressSpace<'a> {
+        let allocator = IovaAllocator::new(&iommu);
+        DeviceAddressSpace::with_allocator(iommu, allocator)
+    }
+
+    /// Creates an address space that allocates IOVAs using the given allocator.
+    pub fn with_allocator(iommu: PciIommu<'a>, allocator: IovaAllocator) -> DeviceAddressSpace<'a> {
+        DeviceAddressSpace {
+            iommu,
+            allocator,
+            mappings: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The IOMMU in which mappings are added.
+    pub fn iommu(&self) -> PciIommu<'a> {
+        self.iommu
+    }
+
+    /// The allocator from which IOVAs are obtained.
+    pub fn allocator(&self) -> &IovaAllocator {
+        &self.allocator
+    }
+
+    /// Allocates an IOVA range for the given process memory and maps it there, returning the
+    /// IOVA.
+    ///
+    /// `address` and `length` must be multiples of [`PciIommu::alignment`].
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`PciIommu::map`]. In particular, the memory must remain valid until the mapping
+    /// is removed, either by [`DeviceAddressSpace::unmap`] or by dropping the
+    /// `DeviceAddressSpace`.
+    pub unsafe fn alloc_and_map(
+        &self,
+        address: *const u8,
+        length: usize,
+        device_permissions: Permissions,
+    ) -> io::Result<u64> {
+        let alignment = self.iommu.alignment();
+
+        if length == 0 || length % alignment != 0 || address as usize % alignment != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Address and length must be nonzero multiples of {:#x}",
+                    alignment
+                ),
+            ));
+        }
+
+        let iova = self.allocator.allocate(length)?;
+
+        if let Err(e) = unsafe { self.iommu.map(iova, length, address, device_permissions) } {
+            self.allocator.free(iova, length);
+            return Err(e);
+        }
+
+        let mapping = IommuMapping {
+            iova,
+            length,
+            address,
+            device_permissions,
+        };
+
+        self.mappings.lock().unwrap().insert(iova, mapping);
+
+        Ok(iova)
+    }
+
+    /// Removes the mapping that starts at the given IOVA, which must have been returned by
+    /// [`DeviceAddressSpace::alloc_and_map`], and frees its IOVA range.
+    pub fn unmap(&self, iova: u64) -> io::Result<()> {
+        let mut mappings = self.mappings.lock().unwrap();
+
+        let mapping = *mappings.get(&iova).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("No mapping starts at IOVA {:#x}", iova),
+            )
+        })?;
+
+        self.iommu.unmap(iova, mapping.length)?;
+        self.allocator.free(iova, mapping.length);
+        mappings.remove(&iova);
+
+        Ok(())
+    }
+
+    /// Removes all mappings added through this address space and frees their IOVA ranges.
+    ///
+    /// If removing some mapping fails, this continues with the others and returns the first error
+    /// at the end. The mappings that couldn't be removed remain recorded.
+    pub fn unmap_all(&self) -> io::Result<()> {
+        let mut mappings = self.mappings.lock().unwrap();
+        let mut result = Ok(());
+
+        let iovas: Vec<u64> = mappings.keys().copied().collect();
+
+        for iova in iovas {
+            let length = mappings[&iova].length;
+
+            match self.iommu.unmap(iova, length) {
+                Ok(()) => {
+                    self.allocator.free(iova, length);
+                    mappings.remove(&iova);
+                }
+                Err(e) => {
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Returns the process address that the given IOVA corresponds to, if it falls within a
+    /// mapping added through this address space.
+    pub fn translate(&self, iova: u64) -> Option<*const u8> {
+        let mapping = self.find(iova)?;
+        Some(mapping.address.wrapping_add((iova - mapping.iova) as usize))
+    }
+
+    /// Returns the mapping added through this address space that contains the given IOVA, if any.
+    pub fn find(&self, iova: u64) -> Option<IommuMapping> {
+        let mappings = self.mappings.lock().unwrap();
+        let (_, mapping) = mappings.range(..=iova).next_back()?;
+
+        if iova - mapping.iova < mapping.length as u64 {
+            Some(*mapping)
+        } else {
+            None
+        }
+    }
+
+    /// Returns all mappings added through this address space, sorted by IOVA.
+    pub fn mappings(&self) -> Vec<IommuMapping> {
+        self.mappings.lock().unwrap().values().copied().collect()
+    }
+}
+
+impl Drop for DeviceAddressSpace<'_> {
+    fn drop(&mut self) {
+        let _ = self.unmap_all();
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::dma::DeviceAddressSpace;
+    use crate::iommu::tests::RecordingIommu;
+    use crate::iommu::IovaAllocator;
+    use crate::regions::Permissions;
+
+    #[test]
+    fn test_device_address_space() {
+        let internal = RecordingIommu::new(0x2000);
+        let allocator = IovaAllocator::with_ranges(slice::from_ref(&(0x10000..0x14000)), 0x1000);
+        let space = DeviceAddressSpace::with_allocator(internal.iommu(), allocator);
+
+        let address = |a: usize| a as *const u8;
+
+        // a failed mapping frees its IOVA range again
+        assert!(
+            unsafe { space.alloc_and_map(address(0x80000), 0x3000, Permissions::Read) }.is_err()
+        );
+
+        let first =
+            unsafe { space.alloc_and_map(address(0x50000), 0x2000, Permissions::Read) }.unwrap();
+        let second =
+            unsafe { space.alloc_and_map(address(0x70000), 0x1000, Permissions::Write) }.unwrap();
+
+        assert_eq!(space.translate(first + 0x1234), Some(address(0x51234)));
+        assert_eq!(space.translate(second), Some(address(0x70000)));
+        assert_eq!(space.translate(second + 0x1000), None);
+        assert_eq!(space.find(first + 0x1fff).unwrap().iova, first);
+        assert_eq!(space.mappings().len(), 2);
+
+        assert_eq!(first, 0x10000);
+
+        // misaligned, and out of IOVA space
+        assert!(
+            unsafe { space.alloc_and_map(address(0x50800), 0x1000, Permissions::Read) }.is_err()
+        );
+        assert!(
+            unsafe { space.alloc_and_map(address(0x80000), 0x2000, Permissions::Read) }.is_err()
+        );
+
+        // unmapping frees the IOVA range for reuse
+        space.unmap(first).unwrap();
+        assert!(space.unmap(first).is_err());
+        assert_eq!(space.translate(first), None);
+        let third =
+            unsafe { space.alloc_and_map(address(0x80000), 0x2000, Permissions::Read) }.unwrap();
+        assert_eq!(third, first);
+
+        drop(space);
+
+        let calls = internal.calls.lock().unwrap().clone();
+        assert_eq!(
+            calls,
+            [
+                (first, 0x3000),
+                (first, 0x2000),
+                (second, 0x1000),
+                (first, 0x2000),
+                (first, 0x2000),
+                (first, 0x2000),
+                (second, 0x1000),
+            ]
+        );
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
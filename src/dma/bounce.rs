@@ -0,0 +1,274 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::io::{self, ErrorKind};
+use std::ptr;
+use std::sync::atomic::{self, Ordering};
+use std::sync::{Condvar, Mutex};
+
+use crate::dma::buffer::validate_range;
+use crate::dma::DmaBuffer;
+use crate::iommu::{IovaAllocator, PciIommu};
+use crate::regions::{PciMemoryRegion, Permissions};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// A fixed set of equally sized, pre-mapped [`DmaBuffer`]s that can be checked out and returned.
+///
+/// This is meant for drivers that must DMA to or from memory they can't map into the IOMMU
+/// directly, _e.g._, short-lived buffers handed to them by someone else. Instead, they check out a
+/// [`BounceBuffer`], copy data into it, let the device read it (or let the device write to it and
+/// copy data out of it), and drop it to return it to the pool. This avoids paying for IOMMU
+/// mapping and unmapping on every transfer.
+///
+/// This is thread-safe.
+#[derive(Debug)]
+pub struct BouncePool<'a> {
+    buffers: Box<[DmaBuffer<'a>]>,
+    /// Indices into `buffers` of the buffers that aren't checked out.
+    free: Mutex<Vec<usize>>,
+    returned: Condvar,
+}
+
+impl<'a> BouncePool<'a> {
+    /// Allocates `count` buffers of at least `buffer_size` bytes each, as if by [`DmaBuffer::new`].
+    pub fn new(
+        iommu: PciIommu<'a>,
+        allocator: &'a IovaAllocator,
+        count: usize,
+        buffer_size: usize,
+        device_permissions: Permissions,
+    ) -> io::Result<BouncePool<'a>> {
+        if count == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Bounce pool must have at least one buffer",
+            ));
+        }
+
+        let buffers = (0..count)
+            .map(|_| DmaBuffer::new(iommu, allocator, buffer_size, device_permissions))
+            .collect::<io::Result<Box<[_]>>>()?;
+
+        Ok(BouncePool {
+            buffers,
+            free: Mutex::new((0..count).rev().collect()),
+            returned: Condvar::new(),
+        })
+    }
+
+    /// The number of buffers in the pool, whether checked out or not.
+    pub fn capacity(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// The number of buffers that aren't currently checked out.
+    pub fn available(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    /// The size in bytes of each buffer. This may be more than was requested.
+    pub fn buffer_size(&self) -> usize {
+        self.buffers[0].len()
+    }
+
+    /// Checks out a buffer, or returns `None` if all are currently checked out.
+    pub fn try_checkout(&self) -> Option<BounceBuffer<'_, 'a>> {
+        let index = self.free.lock().unwrap().pop()?;
+        Some(BounceBuffer { pool: self, index })
+    }
+
+    /// Checks out a buffer, waiting for one to be returned if all are currently checked out, and
+    /// copies `data` into it.
+    ///
+    /// Tell the device about the buffer's [IOVA](BounceBuffer::iova), and keep the buffer around
+    /// until the device is done with it. If the device produces a response in the same buffer, get
+    /// it using [`BounceBuffer::copy_from_device`].
+    pub fn copy_to_device(&self, data: &[u8]) -> io::Result<BounceBuffer<'_, 'a>> {
+        validate_range(0, data.len(), self.buffer_size())?;
+
+        let mut buffer = self.checkout();
+        buffer.copy_in(0, data)?;
+
+        Ok(buffer)
+    }
+
+    /// Checks out a buffer, waiting for one to be returned if all are currently checked out.
+    pub fn checkout(&self) -> BounceBuffer<'_, 'a> {
+        let mut free = self.free.lock().unwrap();
+
+        loop {
+            if let Some(index) = free.pop() {
+                return BounceBuffer { pool: self, index };
+            }
+
+            free = self.returned.wait(free).unwrap();
+        }
+    }
+}
+
+/// A buffer checked out from a [`BouncePool`]. Dropping it returns it to the pool.
+#[derive(Debug)]
+pub struct BounceBuffer<'p, 'a> {
+    pool: &'p BouncePool<'a>,
+    index: usize,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl BounceBuffer<'_, '_> {
+    fn buffer(&self) -> &DmaBuffer<'_> {
+        &self.pool.buffers[self.index]
+    }
+
+    /// The address of the buffer in the device's address space.
+    pub fn iova(&self) -> u64 {
+        self.buffer().iova()
+    }
+
+    /// The length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.buffer().len()
+    }
+
+    /// Copies `data` into the buffer, starting at `offset`.
+    ///
+    /// This is followed by a release fence, so that the data is visible to the device once you
+    /// tell it about the buffer. The device must not be accessing the buffer while this runs.
+    pub fn copy_in(&mut self, offset: usize, data: &[u8]) -> io::Result<()> {
+        validate_range(offset, data.len(), self.len())?;
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                self.buffer().as_mut_ptr().add(offset),
+                data.len(),
+            )
+        };
+
+        atomic::fence(Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Copies data from the buffer, starting at `offset`, into `data`.
+    ///
+    /// This is preceded by an acquire fence, so that data written by the device before it
+    /// signalled completion is observed. The device must not be accessing the buffer while this
+    /// runs.
+    pub fn copy_out(&self, offset: usize, data: &mut [u8]) -> io::Result<()> {
+        validate_range(offset, data.len(), self.len())?;
+
+        atomic::fence(Ordering::Acquire);
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                self.buffer().as_ptr().add(offset),
+                data.as_mut_ptr(),
+                data.len(),
+            )
+        };
+
+        Ok(())
+    }
+
+    /// Returns a [`PciRegion`](crate::regions::PciRegion) through which the buffer can be accessed
+    /// using volatile operations.
+    pub fn region(&self) -> PciMemoryRegion<'_> {
+        self.buffer().region()
+    }
+
+    /// Copies data from the beginning of the buffer into `data`, and returns the buffer to the
+    /// pool.
+    ///
+    /// This is [`BounceBuffer::copy_out`] followed by dropping the buffer.
+    pub fn copy_from_device(self, data: &mut [u8]) -> io::Result<()> {
+        self.copy_out(0, data)
+    }
+}
+
+impl Drop for BounceBuffer<'_, '_> {
+    fn drop(&mut self) {
+        self.pool.free.lock().unwrap().push(self.index);
+        self.pool.returned.notify_one();
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::dma::BouncePool;
+    use crate::iommu::tests::RecordingIommu;
+    use crate::iommu::IovaAllocator;
+    use crate::regions::Permissions;
+
+    #[test]
+    fn test_bounce_pool() {
+        let internal = RecordingIommu::new(0x1000);
+        let allocator = IovaAllocator::with_ranges(slice::from_ref(&(0x10000..0x13000)), 0x1000);
+
+        let pool =
+            BouncePool::new(internal.iommu(), &allocator, 2, 0x800, Permissions::Read).unwrap();
+        assert_eq!(pool.capacity(), 2);
+        assert_eq!(pool.buffer_size(), 0x1000);
+        assert_eq!(internal.calls.lock().unwrap().len(), 2);
+
+        let first = pool.try_checkout().unwrap();
+        let second = pool.try_checkout().unwrap();
+        assert_ne!(first.iova(), second.iova());
+        assert_eq!(pool.available(), 0);
+        assert!(pool.try_checkout().is_none());
+
+        // returned buffers are checked out again, without mapping anything
+        let first_iova = first.iova();
+        drop(first);
+        assert_eq!(pool.available(), 1);
+        assert_eq!(pool.checkout().iova(), first_iova);
+        assert_eq!(internal.calls.lock().unwrap().len(), 2);
+        drop(second);
+
+        // buffers that fail to be allocated are released again
+        assert!(
+            BouncePool::new(internal.iommu(), &allocator, 2, 0x1000, Permissions::Read).is_err()
+        );
+        assert_eq!(allocator.allocate(0x1000).unwrap(), 0x12000);
+        allocator.free(0x12000, 0x1000);
+
+        assert!(
+            BouncePool::new(internal.iommu(), &allocator, 0, 0x1000, Permissions::Read).is_err()
+        );
+    }
+
+    #[test]
+    fn test_bounce_copy_to_from_device() {
+        let internal = RecordingIommu::new(0x1000);
+        let allocator = IovaAllocator::with_ranges(slice::from_ref(&(0x10000..0x11000)), 0x1000);
+
+        let pool = BouncePool::new(
+            internal.iommu(),
+            &allocator,
+            1,
+            0x1000,
+            Permissions::ReadWrite,
+        )
+        .unwrap();
+
+        assert!(pool.copy_to_device(&[0; 0x1001]).is_err());
+        assert_eq!(pool.available(), 1);
+
+        let mut buffer = pool.copy_to_device(&[1, 2, 3]).unwrap();
+        assert_eq!(buffer.iova(), 0x10000);
+        buffer.copy_in(2, &[4]).unwrap();
+        assert!(buffer.copy_in(0x1000, &[4]).is_err());
+
+        let mut data = [0; 4];
+        buffer.copy_from_device(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 4, 0]);
+        assert_eq!(pool.available(), 1);
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -0,0 +1,456 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::{
+    c_int, madvise, mmap, munmap, sysconf, _SC_PAGESIZE, MADV_DONTFORK, MADV_HUGEPAGE,
+    MAP_ANONYMOUS, MAP_FAILED, MAP_HUGETLB, MAP_HUGE_SHIFT, MAP_PRIVATE, PROT_READ, PROT_WRITE,
+};
+use std::io::{self, ErrorKind};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{self, Ordering};
+
+use crate::iommu::{IovaAllocator, PciIommu};
+use crate::regions::{PciMemoryRegion, Permissions};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Process memory that is mapped into a device's address space through an IOMMU.
+///
+/// Creating a `DmaBuffer` allocates page-aligned memory, obtains an IOVA range for it from an
+/// [`IovaAllocator`], and maps it there using [`PciIommu::map`]. Dropping the `DmaBuffer` undoes
+/// all of that.
+///
+/// The memory is initially zeroed. Keep in mind that the device may access it at any time, so
+/// either access it using volatile operations (_e.g._, through [`DmaBuffer::region`]) or make sure
+/// to synchronize with the device appropriately.
+#[derive(Debug)]
+pub struct DmaBuffer<'a> {
+    iommu: PciIommu<'a>,
+    allocator: &'a IovaAllocator,
+    ptr: *mut u8,
+    length: usize,
+    iova: u64,
+    device_permissions: Permissions,
+}
+
+unsafe impl Send for DmaBuffer<'_> {}
+unsafe impl Sync for DmaBuffer<'_> {}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a> DmaBuffer<'a> {
+    /// Allocates a buffer of at least `length` bytes backed by regular anonymous memory and maps
+    /// it into `iommu` at an IOVA obtained from `allocator`, giving the device the given
+    /// permissions.
+    ///
+    /// The length is rounded up to a multiple of the IOMMU's and the allocator's alignment. The
+    /// buffer is placed so that the IOMMU can map it using the biggest
+    /// [page size](PciIommu::page_sizes) that fits in it, if possible, and transparent huge pages
+    /// are requested for it when that is bigger than the system's page size.
+    pub fn new(
+        iommu: PciIommu<'a>,
+        allocator: &'a IovaAllocator,
+        length: usize,
+        device_permissions: Permissions,
+    ) -> io::Result<DmaBuffer<'a>> {
+        DmaBuffer::allocate(iommu, allocator, length, device_permissions, None, u64::MAX)
+    }
+
+    /// Like [`DmaBuffer::new`], but places the buffer at an IOVA range that ends at or below
+    /// `dma_mask`, for devices that can only generate addresses of a limited width. See
+    /// [`IovaAllocator::allocate_masked`].
+    pub fn new_masked(
+        iommu: PciIommu<'a>,
+        allocator: &'a IovaAllocator,
+        length: usize,
+        device_permissions: Permissions,
+        dma_mask: u64,
+    ) -> io::Result<DmaBuffer<'a>> {
+        DmaBuffer::allocate(iommu, allocator, length, device_permissions, None, dma_mask)
+    }
+
+    /// Like [`DmaBuffer::new`], but backs the buffer with huge pages of the given size, _e.g._,
+    /// `2 << 20` for 2 MiB pages.
+    ///
+    /// Huge pages of that size must have been reserved beforehand, see
+    /// <https://www.kernel.org/doc/html/latest/admin-guide/mm/hugetlbpage.html>. The length is
+    /// also rounded up to a multiple of `huge_page_size`.
+    pub fn new_huge(
+        iommu: PciIommu<'a>,
+        allocator: &'a IovaAllocator,
+        length: usize,
+        device_permissions: Permissions,
+        huge_page_size: usize,
+    ) -> io::Result<DmaBuffer<'a>> {
+        if !huge_page_size.is_power_of_two() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Huge page size must be a power of two",
+            ));
+        }
+
+        DmaBuffer::allocate(
+            iommu,
+            allocator,
+            length,
+            device_permissions,
+            Some(huge_page_size),
+            u64::MAX,
+        )
+    }
+
+    fn allocate(
+        iommu: PciIommu<'a>,
+        allocator: &'a IovaAllocator,
+        length: usize,
+        device_permissions: Permissions,
+        huge_page_size: Option<usize>,
+        dma_mask: u64,
+    ) -> io::Result<DmaBuffer<'a>> {
+        if length == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Can't allocate an empty DMA buffer",
+            ));
+        }
+
+        let page_size = huge_page_size.unwrap_or_else(system_page_size);
+        let alignment = iommu.alignment().max(allocator.alignment()).max(page_size);
+
+        let length = length
+            .checked_add(alignment - 1)
+            .map(|l| l & !(alignment - 1))
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Length is too big"))?;
+
+        // Place the buffer at a process address and IOVA that are aligned to the biggest IOMMU page
+        // size that fits in it, so that the IOMMU can map it using big pages.
+
+        let preferred_alignment = preferred_alignment(iommu.page_sizes(), length).max(alignment);
+
+        // allocate process memory
+
+        let ptr = map_memory(length, preferred_alignment, huge_page_size)?;
+
+        if huge_page_size.is_none() && preferred_alignment > alignment {
+            // Ask for transparent huge pages. This is just an optimization, so ignore failures.
+            unsafe { madvise(ptr.cast(), length, MADV_HUGEPAGE) };
+        }
+
+        // allocate IOVA range
+
+        let iova = match allocator
+            .allocate_masked(length, preferred_alignment, dma_mask)
+            .or_else(|_| allocator.allocate_masked(length, alignment, dma_mask))
+        {
+            Ok(iova) => iova,
+            Err(e) => {
+                unsafe { unmap_memory(ptr, length) };
+                return Err(e);
+            }
+        };
+
+        // map it
+
+        if let Err(e) = unsafe { iommu.map(iova, length, ptr, device_permissions) } {
+            allocator.free(iova, length);
+            unsafe { unmap_memory(ptr, length) };
+            return Err(e);
+        }
+
+        Ok(DmaBuffer {
+            iommu,
+            allocator,
+            ptr,
+            length,
+            iova,
+            device_permissions,
+        })
+    }
+
+    /// The address of the buffer in the device's address space.
+    pub fn iova(&self) -> u64 {
+        self.iova
+    }
+
+    /// The length of the buffer in bytes. This may be more than was requested.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// The operations the device may perform on the buffer.
+    pub fn device_permissions(&self) -> Permissions {
+        self.device_permissions
+    }
+
+    /// Returns a constant pointer to the beginning of the buffer in the current process' address
+    /// space.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    /// Returns a mutable pointer to the beginning of the buffer in the current process' address
+    /// space.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Returns a [`PciRegion`](crate::regions::PciRegion) through which the buffer can be accessed
+    /// using volatile operations.
+    pub fn region(&self) -> PciMemoryRegion<'_> {
+        unsafe { PciMemoryRegion::new_raw(self.ptr, self.length, Permissions::ReadWrite) }
+    }
+
+    /// Copies `data` into the buffer, starting at `offset`, and returns the IOVA at which the
+    /// device can find it.
+    ///
+    /// This is followed by a release fence, so that the data is visible to the device once you
+    /// tell it about the returned IOVA. The device must not be accessing that part of the buffer
+    /// while this runs.
+    pub fn copy_to_device(&mut self, offset: usize, data: &[u8]) -> io::Result<u64> {
+        validate_range(offset, data.len(), self.length)?;
+
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(offset), data.len()) };
+
+        atomic::fence(Ordering::Release);
+
+        Ok(self.iova + offset as u64)
+    }
+
+    /// Copies data from the buffer, starting at `offset`, into `data`.
+    ///
+    /// This is preceded by an acquire fence, so that data written by the device before it
+    /// signalled completion is observed. The device must not be writing to that part of the buffer
+    /// while this runs.
+    pub fn copy_from_device(&self, offset: usize, data: &mut [u8]) -> io::Result<()> {
+        validate_range(offset, data.len(), self.length)?;
+
+        atomic::fence(Ordering::Acquire);
+
+        unsafe { ptr::copy_nonoverlapping(self.ptr.add(offset), data.as_mut_ptr(), data.len()) };
+
+        Ok(())
+    }
+
+    /// Returns the buffer's contents as a slice.
+    ///
+    /// # Safety
+    ///
+    /// The device must not write to the buffer while the returned slice exists.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.length) }
+    }
+
+    /// Returns the buffer's contents as a mutable slice.
+    ///
+    /// # Safety
+    ///
+    /// The device must not access the buffer while the returned slice exists.
+    pub unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.length) }
+    }
+}
+
+impl Drop for DmaBuffer<'_> {
+    fn drop(&mut self) {
+        // If unmapping fails, the device may still be able to access the memory, so we leak it
+        // (and its IOVA range) rather than risk having it reused.
+
+        if self.iommu.unmap(self.iova, self.length).is_ok() {
+            self.allocator.free(self.iova, self.length);
+            unsafe { unmap_memory(self.ptr, self.length) };
+        }
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Returns the biggest page size in `page_sizes` (a bitmap as returned by
+/// [`PciIommu::page_sizes`]) that is no bigger than `length` or 1 GiB, or 1 if there is none.
+fn preferred_alignment(page_sizes: u64, length: usize) -> usize {
+    let max = (length as u64).min(1 << 30);
+
+    (0..64)
+        .rev()
+        .map(|n| 1u64 << n)
+        .find(|&size| size <= max && page_sizes & size != 0)
+        .unwrap_or(1) as usize
+}
+
+/// Fails unless `[offset, offset + len)` is contained in a buffer of `buffer_len` bytes.
+pub(super) fn validate_range(offset: usize, len: usize, buffer_len: usize) -> io::Result<()> {
+    match offset.checked_add(len) {
+        Some(end) if end <= buffer_len => Ok(()),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Range [{:#x}, {:#x}) is out of bounds of buffer of {:#x} bytes",
+                offset,
+                offset.saturating_add(len),
+                buffer_len
+            ),
+        )),
+    }
+}
+
+pub(super) fn system_page_size() -> usize {
+    unsafe { sysconf(_SC_PAGESIZE) as usize }
+}
+
+/// Maps anonymous, zeroed, read-write memory aligned to `alignment`, which must be a multiple of
+/// the page size.
+pub(super) fn map_memory(
+    length: usize,
+    alignment: usize,
+    huge_page_size: Option<usize>,
+) -> io::Result<*mut u8> {
+    let mut flags = MAP_PRIVATE | MAP_ANONYMOUS;
+
+    if let Some(size) = huge_page_size {
+        flags |= MAP_HUGETLB | ((size.trailing_zeros() as c_int) << MAP_HUGE_SHIFT);
+    }
+
+    // mmap() only guarantees (huge) page alignment, so we may need to over-allocate and then trim
+    // the excess
+
+    let page_size = huge_page_size.unwrap_or_else(system_page_size);
+    let excess = alignment.saturating_sub(page_size);
+
+    let total_length = length
+        .checked_add(excess)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Length is too big"))?;
+
+    let address = unsafe {
+        mmap(
+            ptr::null_mut(),
+            total_length,
+            PROT_READ | PROT_WRITE,
+            flags,
+            -1,
+            0,
+        )
+    };
+
+    if address == MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    let address = address as usize;
+    let start = (address + alignment - 1) & !(alignment - 1);
+    let end = start + length;
+
+    unsafe {
+        if start > address {
+            unmap_memory(address as *mut u8, start - address);
+        }
+        if address + total_length > end {
+            unmap_memory(end as *mut u8, address + total_length - end);
+        }
+    }
+
+    // Don't let the memory be shared copy-on-write with child processes, as the IOMMU mapping
+    // would then refer to pages that the parent no longer uses once it writes to them. This is
+    // just an optimization, so ignore failures.
+
+    unsafe { madvise(start as *mut _, length, MADV_DONTFORK) };
+
+    Ok(start as *mut u8)
+}
+
+pub(super) unsafe fn unmap_memory(address: *mut u8, length: usize) {
+    let result = if unsafe { munmap(address.cast(), length) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    };
+
+    // TODO: Do something other than crash on failure?
+    result.unwrap();
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::dma::DmaBuffer;
+    use crate::iommu::tests::RecordingIommu;
+    use crate::iommu::IovaAllocator;
+    use crate::regions::Permissions;
+
+    #[test]
+    fn test_dma_buffer() {
+        let internal = RecordingIommu::new(0x2000);
+        let allocator = IovaAllocator::with_ranges(slice::from_ref(&(0x10000..0x14000)), 0x1000);
+
+        let first =
+            DmaBuffer::new(internal.iommu(), &allocator, 0x1800, Permissions::Read).unwrap();
+        assert_eq!(first.len(), 0x2000);
+        assert_eq!(first.device_permissions(), Permissions::Read);
+        assert_eq!(first.as_ptr() as usize % 0x1000, 0);
+        assert!(unsafe { first.as_slice() }.iter().all(|&b| b == 0));
+
+        let second =
+            DmaBuffer::new(internal.iommu(), &allocator, 0x2000, Permissions::Write).unwrap();
+        assert_ne!(first.iova(), second.iova());
+
+        // the IOVA space is exhausted
+        assert!(DmaBuffer::new(internal.iommu(), &allocator, 0x1000, Permissions::Read).is_err());
+
+        // dropping a buffer unmaps it and frees its IOVA range for reuse
+        let first_iova = first.iova();
+        drop(first);
+        let third =
+            DmaBuffer::new(internal.iommu(), &allocator, 0x1000, Permissions::Read).unwrap();
+        assert_eq!(third.iova(), first_iova);
+        drop(third);
+
+        // a failed mapping frees the IOVA range again
+        let second_iova = second.iova();
+        drop(second);
+        assert!(DmaBuffer::new(internal.iommu(), &allocator, 0x3000, Permissions::Read).is_err());
+        assert_eq!(allocator.allocate(0x4000).unwrap(), first_iova);
+        allocator.free(first_iova, 0x4000);
+
+        assert!(DmaBuffer::new(internal.iommu(), &allocator, 0, Permissions::Read).is_err());
+        assert!(
+            DmaBuffer::new_huge(internal.iommu(), &allocator, 0x1000, Permissions::Read, 3)
+                .is_err()
+        );
+
+        let calls = internal.calls.lock().unwrap().clone();
+        assert_eq!(
+            calls,
+            [
+                (first_iova, 0x2000),
+                (second_iova, 0x2000),
+                (first_iova, 0x2000),
+                (first_iova, 0x1000),
+                (first_iova, 0x1000),
+                (second_iova, 0x2000),
+                (first_iova, 0x3000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_copy_to_from_device() {
+        let internal = RecordingIommu::new(0x1000);
+        let allocator = IovaAllocator::with_ranges(slice::from_ref(&(0x10000..0x11000)), 0x1000);
+
+        let mut buffer =
+            DmaBuffer::new(internal.iommu(), &allocator, 0x1000, Permissions::ReadWrite).unwrap();
+
+        assert_eq!(buffer.copy_to_device(0x10, &[1, 2, 3]).unwrap(), 0x10010);
+
+        let mut data = [0; 4];
+        buffer.copy_from_device(0xf, &mut data).unwrap();
+        assert_eq!(data, [0, 1, 2, 3]);
+
+        assert!(buffer.copy_to_device(0xffe, &[1, 2, 3]).is_err());
+        assert!(buffer.copy_from_device(usize::MAX, &mut data).is_err());
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
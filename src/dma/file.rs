@@ -0,0 +1,279 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::{
+    fcntl, madvise, mmap, off_t, F_GETFL, MADV_DONTFORK, MAP_FAILED, MAP_FIXED, MAP_SHARED,
+    O_ACCMODE, O_RDONLY, O_RDWR, PROT_READ, PROT_WRITE,
+};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::AsRawFd;
+
+use crate::dma::buffer::{map_memory, system_page_size, unmap_memory};
+use crate::iommu::{IovaAllocator, PciIommu};
+use crate::regions::{PciMemoryRegion, Permissions};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// A range of a file that is mapped into a device's address space through an IOMMU.
+///
+/// This lets you use memory that is shared with other processes, _e.g._, through a memfd or a file
+/// in a hugetlbfs or tmpfs mount, as a DMA target. The file is kept open for as long as the
+/// `DmaFileMapping` exists. Dropping the `DmaFileMapping` removes the IOMMU mapping, returns the
+/// IOVA range to the [`IovaAllocator`], and unmaps the file from the process' address space if it
+/// was mapped by [`DmaFileMapping::new`].
+#[derive(Debug)]
+pub struct DmaFileMapping<'a> {
+    iommu: PciIommu<'a>,
+    allocator: &'a IovaAllocator,
+    file: File,
+    ptr: *mut u8,
+    length: usize,
+    iova: u64,
+    process_permissions: Permissions,
+    device_permissions: Permissions,
+    owns_process_mapping: bool,
+}
+
+unsafe impl Send for DmaFileMapping<'_> {}
+unsafe impl Sync for DmaFileMapping<'_> {}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a> DmaFileMapping<'a> {
+    /// Maps `length` bytes of `file` starting at `offset` into the current process' address space,
+    /// and then into `iommu` at an IOVA obtained from `allocator`, giving the device the given
+    /// permissions.
+    ///
+    /// `offset` must be a multiple of the system's page size. `length` is rounded up to a multiple
+    /// of the IOMMU's and the allocator's alignment, and the file must be at least `offset` plus
+    /// that many bytes long.
+    ///
+    /// The file is mapped shared, with read permissions, and with write permissions if it was
+    /// opened for writing.
+    pub fn new(
+        iommu: PciIommu<'a>,
+        allocator: &'a IovaAllocator,
+        file: File,
+        offset: u64,
+        length: usize,
+        device_permissions: Permissions,
+    ) -> io::Result<DmaFileMapping<'a>> {
+        if length == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Can't map an empty range of a file",
+            ));
+        }
+
+        if offset % system_page_size() as u64 != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Offset must be a multiple of the page size",
+            ));
+        }
+
+        let alignment = iommu
+            .alignment()
+            .max(allocator.alignment())
+            .max(system_page_size());
+
+        let length = length
+            .checked_add(alignment - 1)
+            .map(|l| l & !(alignment - 1))
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Length is too big"))?;
+
+        // figure out how the file may be mapped
+
+        let access_mode = unsafe { fcntl(file.as_raw_fd(), F_GETFL) };
+        if access_mode < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (prot_flags, process_permissions) = match access_mode & O_ACCMODE {
+            O_RDONLY => (PROT_READ, Permissions::Read),
+            O_RDWR => (PROT_READ | PROT_WRITE, Permissions::ReadWrite),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "File must be opened for reading",
+                ))
+            }
+        };
+
+        // Reserve a suitably aligned range of the address space, then map the file over it.
+
+        let ptr = map_memory(length, alignment, None)?;
+
+        let address = unsafe {
+            mmap(
+                ptr.cast(),
+                length,
+                prot_flags,
+                MAP_SHARED | MAP_FIXED,
+                file.as_raw_fd(),
+                offset as off_t,
+            )
+        };
+
+        if address == MAP_FAILED {
+            let e = io::Error::last_os_error();
+            unsafe { unmap_memory(ptr, length) };
+            return Err(e);
+        }
+
+        // See map_memory() for why. The flag was lost when the file mapping replaced the
+        // anonymous one.
+
+        unsafe { madvise(ptr.cast(), length, MADV_DONTFORK) };
+
+        let result = unsafe {
+            DmaFileMapping::map(
+                iommu,
+                allocator,
+                file,
+                ptr,
+                length,
+                process_permissions,
+                device_permissions,
+                true,
+            )
+        };
+
+        if result.is_err() {
+            unsafe { unmap_memory(ptr, length) };
+        }
+
+        result
+    }
+
+    /// Maps an existing mapping of `file` in the current process' address space into `iommu` at
+    /// an IOVA obtained from `allocator`, giving the device the given permissions.
+    ///
+    /// `process_permissions` are the permissions with which the file is mapped in the current
+    /// process. `address` and `length` must be multiples of the IOMMU's and the allocator's
+    /// alignment. The mapping is _not_ unmapped when the `DmaFileMapping` is dropped.
+    ///
+    /// # Safety
+    ///
+    /// `[address, address + length)` must be a mapping of `file` that remains valid for the
+    /// lifetime of the `DmaFileMapping`, and allow the given `process_permissions`.
+    pub unsafe fn from_existing_mapping(
+        iommu: PciIommu<'a>,
+        allocator: &'a IovaAllocator,
+        file: File,
+        address: *mut u8,
+        length: usize,
+        process_permissions: Permissions,
+        device_permissions: Permissions,
+    ) -> io::Result<DmaFileMapping<'a>> {
+        let alignment = iommu.alignment().max(allocator.alignment());
+
+        if length == 0 || length % alignment != 0 || address as usize % alignment != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Address and length must be nonzero multiples of {:#x}",
+                    alignment
+                ),
+            ));
+        }
+
+        unsafe {
+            DmaFileMapping::map(
+                iommu,
+                allocator,
+                file,
+                address,
+                length,
+                process_permissions,
+                device_permissions,
+                false,
+            )
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn map(
+        iommu: PciIommu<'a>,
+        allocator: &'a IovaAllocator,
+        file: File,
+        ptr: *mut u8,
+        length: usize,
+        process_permissions: Permissions,
+        device_permissions: Permissions,
+        owns_process_mapping: bool,
+    ) -> io::Result<DmaFileMapping<'a>> {
+        let iova = allocator.allocate(length)?;
+
+        if let Err(e) = unsafe { iommu.map(iova, length, ptr, device_permissions) } {
+            allocator.free(iova, length);
+            return Err(e);
+        }
+
+        Ok(DmaFileMapping {
+            iommu,
+            allocator,
+            file,
+            ptr,
+            length,
+            iova,
+            process_permissions,
+            device_permissions,
+            owns_process_mapping,
+        })
+    }
+
+    /// The file that is mapped.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// The address of the mapped range in the device's address space.
+    pub fn iova(&self) -> u64 {
+        self.iova
+    }
+
+    /// The length of the mapped range in bytes. This may be more than was requested.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// The operations the device may perform on the mapped range.
+    pub fn device_permissions(&self) -> Permissions {
+        self.device_permissions
+    }
+
+    /// Returns a constant pointer to the beginning of the mapped range in the current process'
+    /// address space.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    /// Returns a mutable pointer to the beginning of the mapped range in the current process'
+    /// address space.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Returns a [`PciRegion`](crate::regions::PciRegion) through which the mapped range can be
+    /// accessed using volatile operations.
+    pub fn region(&self) -> PciMemoryRegion<'_> {
+        unsafe { PciMemoryRegion::new_raw(self.ptr, self.length, self.process_permissions) }
+    }
+}
+
+impl Drop for DmaFileMapping<'_> {
+    fn drop(&mut self) {
+        // Same as for DmaBuffer, leak the IOVA range and process mapping if this fails.
+
+        if self.iommu.unmap(self.iova, self.length).is_ok() {
+            self.allocator.free(self.iova, self.length);
+            if self.owns_process_mapping {
+                unsafe { unmap_memory(self.ptr, self.length) };
+            }
+        }
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -0,0 +1,22 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Higher-level helpers for setting up memory that devices can access using DMA.
+//!
+//! These build on [`PciIommu`](crate::iommu::PciIommu) and
+//! [`IovaAllocator`](crate::iommu::IovaAllocator).
+
+/* ---------------------------------------------------------------------------------------------- */
+
+mod address_space;
+mod bounce;
+mod buffer;
+mod file;
+mod pool;
+
+pub use address_space::DeviceAddressSpace;
+pub use bounce::{BounceBuffer, BouncePool};
+pub use buffer::DmaBuffer;
+pub use file::DmaFileMapping;
+pub use pool::{DmaChunk, DmaPool};
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::io::{self, ErrorKind};
+use std::sync::Mutex;
+
+use crate::dma::DmaBuffer;
+use crate::iommu::{IovaAllocator, PciIommu};
+use crate::regions::{PciMemoryRegion, Permissions};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// A pool of small, equally sized, aligned chunks of DMA memory, carved out of a single
+/// [`DmaBuffer`].
+///
+/// This is similar to the Linux kernel's `dma_pool` API, and is meant for things like descriptors
+/// and completion queue entries, which are too small to each get their own [`DmaBuffer`] and IOMMU
+/// mapping. Allocating a chunk gives you a [`DmaChunk`], and dropping it returns it to the pool.
+///
+/// This is thread-safe.
+#[derive(Debug)]
+pub struct DmaPool<'a> {
+    buffer: DmaBuffer<'a>,
+    chunk_size: usize,
+    count: usize,
+    /// Indices of the chunks that aren't allocated.
+    free: Mutex<Vec<usize>>,
+}
+
+impl<'a> DmaPool<'a> {
+    /// Creates a pool of `count` chunks of `chunk_size` bytes each, whose IOVAs and process
+    /// addresses are multiples of `alignment`, backed by a single buffer allocated as if by
+    /// [`DmaBuffer::new`].
+    ///
+    /// `alignment` must be a power of two no greater than [`PciIommu::alignment`]. Chunks are laid
+    /// out back to back, with `chunk_size` rounded up to a multiple of `alignment`.
+    pub fn new(
+        iommu: PciIommu<'a>,
+        allocator: &'a IovaAllocator,
+        chunk_size: usize,
+        alignment: usize,
+        count: usize,
+        device_permissions: Permissions,
+    ) -> io::Result<DmaPool<'a>> {
+        if chunk_size == 0 || count == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "DMA pool must have at least one nonempty chunk",
+            ));
+        }
+
+        if !alignment.is_power_of_two() || alignment > iommu.alignment() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Alignment must be a power of two no greater than {:#x}",
+                    iommu.alignment()
+                ),
+            ));
+        }
+
+        let too_big = || io::Error::new(ErrorKind::InvalidInput, "Pool is too big");
+
+        let chunk_size = chunk_size
+            .checked_add(alignment - 1)
+            .map(|s| s & !(alignment - 1))
+            .ok_or_else(too_big)?;
+
+        let total_size = chunk_size.checked_mul(count).ok_or_else(too_big)?;
+
+        let buffer = DmaBuffer::new(iommu, allocator, total_size, device_permissions)?;
+
+        Ok(DmaPool {
+            buffer,
+            chunk_size,
+            count,
+            free: Mutex::new((0..count).rev().collect()),
+        })
+    }
+
+    /// The size in bytes of each chunk. This may be more than was requested.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// The number of chunks in the pool, whether allocated or not.
+    pub fn capacity(&self) -> usize {
+        self.count
+    }
+
+    /// The number of chunks that aren't currently allocated.
+    pub fn available(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    /// Allocates a chunk, or returns `None` if all are currently allocated.
+    ///
+    /// The chunk's contents are whatever was last written to it, or zero if it was never written.
+    pub fn alloc(&self) -> Option<DmaChunk<'_, 'a>> {
+        let index = self.free.lock().unwrap().pop()?;
+        Some(DmaChunk { pool: self, index })
+    }
+}
+
+/// A chunk allocated from a [`DmaPool`]. Dropping it returns it to the pool.
+#[derive(Debug)]
+pub struct DmaChunk<'p, 'a> {
+    pool: &'p DmaPool<'a>,
+    index: usize,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl DmaChunk<'_, '_> {
+    fn offset(&self) -> usize {
+        self.index * self.pool.chunk_size
+    }
+
+    /// The address of the chunk in the device's address space.
+    pub fn iova(&self) -> u64 {
+        self.pool.buffer.iova() + self.offset() as u64
+    }
+
+    /// The length of the chunk in bytes.
+    pub fn len(&self) -> usize {
+        self.pool.chunk_size
+    }
+
+    /// Returns a constant pointer to the beginning of the chunk in the current process' address
+    /// space.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.as_mut_ptr()
+    }
+
+    /// Returns a mutable pointer to the beginning of the chunk in the current process' address
+    /// space.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        unsafe { self.pool.buffer.as_mut_ptr().add(self.offset()) }
+    }
+
+    /// Returns a [`PciRegion`](crate::regions::PciRegion) through which the chunk can be accessed
+    /// using volatile operations.
+    pub fn region(&self) -> PciMemoryRegion<'_> {
+        unsafe { PciMemoryRegion::new_raw(self.as_mut_ptr(), self.len(), Permissions::ReadWrite) }
+    }
+}
+
+impl Drop for DmaChunk<'_, '_> {
+    fn drop(&mut self) {
+        self.pool.free.lock().unwrap().push(self.index);
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::dma::DmaPool;
+    use crate::iommu::tests::RecordingIommu;
+    use crate::iommu::IovaAllocator;
+    use crate::regions::{PciRegion, Permissions};
+
+    #[test]
+    fn test_dma_pool() {
+        let internal = RecordingIommu::new(0x1000);
+        let allocator = IovaAllocator::with_ranges(slice::from_ref(&(0x10000..0x11000)), 0x1000);
+
+        let pool =
+            DmaPool::new(internal.iommu(), &allocator, 24, 0x40, 3, Permissions::Read).unwrap();
+        assert_eq!(pool.chunk_size(), 0x40);
+        assert_eq!(pool.capacity(), 3);
+        assert_eq!(pool.available(), 3);
+
+        // the whole pool is mapped once
+        assert_eq!(*internal.calls.lock().unwrap(), [(0x10000, 0x1000)]);
+
+        let chunks: Vec<_> = (0..3).map(|_| pool.alloc().unwrap()).collect();
+        let iovas: Vec<u64> = chunks.iter().map(|c| c.iova()).collect();
+        assert_eq!(iovas, [0x10000, 0x10040, 0x10080]);
+        assert_eq!(pool.available(), 0);
+        assert!(pool.alloc().is_none());
+
+        chunks[2].region().write_le_u32(0, 0x1234_5678).unwrap();
+        assert_eq!(
+            unsafe { chunks[0].as_ptr().add(0x80).cast::<u32>().read() },
+            0x1234_5678_u32.to_le()
+        );
+
+        // the most recently freed chunk is reused, keeping its contents
+        drop(chunks);
+        assert_eq!(pool.available(), 3);
+        let chunk = pool.alloc().unwrap();
+        assert_eq!(chunk.iova(), 0x10080);
+        assert_eq!(chunk.region().read_le_u32(0).unwrap(), 0x1234_5678);
+        drop(chunk);
+
+        assert!(DmaPool::new(internal.iommu(), &allocator, 0, 0x40, 3, Permissions::Read).is_err());
+        assert!(
+            DmaPool::new(internal.iommu(), &allocator, 24, 0x30, 3, Permissions::Read).is_err()
+        );
+        assert!(DmaPool::new(
+            internal.iommu(),
+            &allocator,
+            24,
+            0x2000,
+            3,
+            Permissions::Read
+        )
+        .is_err());
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
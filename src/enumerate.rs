@@ -0,0 +1,282 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Listing the PCI devices present in the system.
+//!
+//! This reads `/sys/bus/pci/devices`, and doesn't require opening, or having permission to open,
+//! any device. The [`PciDeviceEntry`] values it returns can be passed directly to, _e.g._,
+//! `VfioPciDevice::open`.
+//!
+//! ```no_run
+//! use pci_driver::enumerate::PciDeviceFilter;
+//!
+//! // All NVMe controllers bound to vfio-pci
+//! let devices = PciDeviceFilter::new()
+//!     .class_code(0x010802, 0xffffff)
+//!     .driver("vfio-pci")
+//!     .devices()?;
+//!
+//! for device in &devices {
+//!     println!("{} in IOMMU group {:?}", device.address, device.iommu_group);
+//! }
+//! # std::io::Result::Ok(())
+//! ```
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::device::PciAddress;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+/// Describes a PCI function present in the system, as found in sysfs.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct PciDeviceEntry {
+    /// The address of the function.
+    pub address: PciAddress,
+    /// The function's Vendor ID.
+    pub vendor_id: u16,
+    /// The function's Device ID.
+    pub device_id: u16,
+    /// The function's Subsystem Vendor ID, or 0 if it doesn't have one.
+    pub subsystem_vendor_id: u16,
+    /// The function's Subsystem ID, or 0 if it doesn't have one.
+    pub subsystem_id: u16,
+    /// The function's Class Code, with the Base Class Code in bits 16 to 23, the Sub-Class Code in
+    /// bits 8 to 15, and the Programming Interface in bits 0 to 7.
+    pub class_code: u32,
+    /// The number of the IOMMU group the function belongs to, if any.
+    pub iommu_group: Option<u32>,
+    /// The name of the kernel driver the function is bound to, if any.
+    pub driver: Option<String>,
+    sysfs_path: PathBuf,
+}
+
+impl PciDeviceEntry {
+    /// The function's sysfs directory, _e.g._, `/sys/bus/pci/devices/0000:00:01.0`.
+    pub fn sysfs_path(&self) -> &Path {
+        &self.sysfs_path
+    }
+
+    fn read(sysfs_path: PathBuf, address: PciAddress) -> io::Result<PciDeviceEntry> {
+        let read_hex = |name: &str| -> io::Result<u32> {
+            let contents = fs::read_to_string(sysfs_path.join(name))?;
+            let trimmed = contents.trim();
+            let digits = trimmed.strip_prefix("0x").unwrap_or(trimmed);
+
+            u32::from_str_radix(digits, 16).map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid contents in {}: {:?}", name, contents),
+                )
+            })
+        };
+
+        let link_name = |name: &str| -> Option<String> {
+            let target = fs::read_link(sysfs_path.join(name)).ok()?;
+            Some(target.file_name()?.to_str()?.to_string())
+        };
+
+        Ok(PciDeviceEntry {
+            address,
+            vendor_id: read_hex("vendor")? as u16,
+            device_id: read_hex("device")? as u16,
+            subsystem_vendor_id: read_hex("subsystem_vendor").unwrap_or(0) as u16,
+            subsystem_id: read_hex("subsystem_device").unwrap_or(0) as u16,
+            class_code: read_hex("class")?,
+            iommu_group: link_name("iommu_group").and_then(|group| group.parse().ok()),
+            driver: link_name("driver"),
+            sysfs_path,
+        })
+    }
+}
+
+impl AsRef<Path> for PciDeviceEntry {
+    fn as_ref(&self) -> &Path {
+        &self.sysfs_path
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Selects PCI functions by their IDs, class, or driver. A function must match all given criteria
+/// to be selected.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PciDeviceFilter {
+    vendor_id: Option<u16>,
+    device_id: Option<u16>,
+    class_code: Option<(u32, u32)>,
+    driver: Option<String>,
+    iommu_group: Option<u32>,
+}
+
+impl PciDeviceFilter {
+    /// Creates a filter that selects all functions.
+    pub fn new() -> PciDeviceFilter {
+        PciDeviceFilter::default()
+    }
+
+    /// Only select functions with the given Vendor ID.
+    pub fn vendor_id(mut self, vendor_id: u16) -> PciDeviceFilter {
+        self.vendor_id = Some(vendor_id);
+        self
+    }
+
+    /// Only select functions with the given Device ID.
+    pub fn device_id(mut self, device_id: u16) -> PciDeviceFilter {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    /// Only select functions whose Class Code, laid out as in [`PciDeviceEntry::class_code`],
+    /// matches `class_code` in the bits set in `mask`. For instance, use a `mask` of `0xff0000` to
+    /// match only the Base Class Code.
+    pub fn class_code(mut self, class_code: u32, mask: u32) -> PciDeviceFilter {
+        self.class_code = Some((class_code & mask, mask));
+        self
+    }
+
+    /// Only select functions bound to the kernel driver with the given name, _e.g._, `vfio-pci`.
+    pub fn driver(mut self, driver: &str) -> PciDeviceFilter {
+        self.driver = Some(driver.to_string());
+        self
+    }
+
+    /// Only select functions in the IOMMU group with the given number.
+    pub fn iommu_group(mut self, iommu_group: u32) -> PciDeviceFilter {
+        self.iommu_group = Some(iommu_group);
+        self
+    }
+
+    /// Whether the given function is selected by this filter.
+    pub fn matches(&self, entry: &PciDeviceEntry) -> bool {
+        self.vendor_id.map_or(true, |id| entry.vendor_id == id)
+            && self.device_id.map_or(true, |id| entry.device_id == id)
+            && self
+                .class_code
+                .map_or(true, |(code, mask)| entry.class_code & mask == code)
+            && self
+                .driver
+                .as_ref()
+                .map_or(true, |driver| entry.driver.as_ref() == Some(driver))
+            && self
+                .iommu_group
+                .map_or(true, |group| entry.iommu_group == Some(group))
+    }
+
+    /// Lists the functions present in the system that are selected by this filter, sorted by
+    /// address.
+    pub fn devices(&self) -> io::Result<Vec<PciDeviceEntry>> {
+        list_devices(Path::new(SYSFS_PCI_DEVICES), self)
+    }
+}
+
+/// Lists all PCI functions present in the system, sorted by address.
+pub fn devices() -> io::Result<Vec<PciDeviceEntry>> {
+    PciDeviceFilter::new().devices()
+}
+
+pub(crate) fn list_devices(
+    root: &Path,
+    filter: &PciDeviceFilter,
+) -> io::Result<Vec<PciDeviceEntry>> {
+    let mut devices = Vec::new();
+
+    for dir_entry in fs::read_dir(root)? {
+        let dir_entry = dir_entry?;
+
+        let address = match dir_entry.file_name().to_str().map(str::parse) {
+            Some(Ok(address)) => address,
+            _ => continue,
+        };
+
+        let entry = match PciDeviceEntry::read(dir_entry.path(), address) {
+            Ok(entry) => entry,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue, // removed concurrently
+            Err(e) => return Err(e),
+        };
+
+        if filter.matches(&entry) {
+            devices.push(entry);
+        }
+    }
+
+    devices.sort_by_key(|entry| entry.address);
+
+    Ok(devices)
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    use crate::enumerate::{list_devices, PciDeviceFilter};
+
+    #[test]
+    fn test_list_devices() {
+        let root = std::env::temp_dir().join(format!("pci-driver-test-{}", process::id()));
+
+        let add = |address: &str, vendor: &str, class: &str, driver: Option<&str>| {
+            let dir = root.join(address);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("vendor"), vendor).unwrap();
+            fs::write(dir.join("device"), "0x1234\n").unwrap();
+            fs::write(dir.join("class"), class).unwrap();
+            symlink("../../../kernel/iommu_groups/7", dir.join("iommu_group")).unwrap();
+            if let Some(driver) = driver {
+                symlink(
+                    format!("../../../bus/pci/drivers/{}", driver),
+                    dir.join("driver"),
+                )
+                .unwrap();
+            }
+        };
+
+        add("0000:01:00.0", "0x144d\n", "0x010802\n", Some("vfio-pci"));
+        add("0000:00:02.0", "0x8086\n", "0x030000\n", Some("i915"));
+        add("0000:00:1f.0", "0x8086\n", "0x060100\n", None);
+        fs::create_dir_all(root.join("not-a-device")).unwrap();
+
+        let all = list_devices(&root, &PciDeviceFilter::new()).unwrap();
+        let addresses: Vec<String> = all.iter().map(|e| e.address.to_string()).collect();
+        assert_eq!(addresses, ["0000:00:02.0", "0000:00:1f.0", "0000:01:00.0"]);
+
+        assert_eq!(all[0].vendor_id, 0x8086);
+        assert_eq!(all[0].device_id, 0x1234);
+        assert_eq!(all[0].class_code, 0x030000);
+        assert_eq!(all[0].iommu_group, Some(7));
+        assert_eq!(all[0].driver.as_deref(), Some("i915"));
+        assert_eq!(all[1].driver, None);
+
+        let filter = PciDeviceFilter::new()
+            .vendor_id(0x8086)
+            .class_code(0x06, 0xff0000);
+        assert_eq!(list_devices(&root, &filter).unwrap(), []);
+
+        let filter = PciDeviceFilter::new()
+            .vendor_id(0x8086)
+            .class_code(0x060000, 0xff0000);
+        assert_eq!(list_devices(&root, &filter).unwrap(), [all[1].clone()]);
+
+        let filter = PciDeviceFilter::new().driver("vfio-pci");
+        assert_eq!(list_devices(&root, &filter).unwrap(), [all[2].clone()]);
+
+        let filter = PciDeviceFilter::new().iommu_group(7);
+        assert_eq!(list_devices(&root, &filter).unwrap(), all);
+        let filter = PciDeviceFilter::new().iommu_group(8);
+        assert_eq!(list_devices(&root, &filter).unwrap(), []);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -0,0 +1,293 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Watching for PCI devices being removed from, or added to, the system.
+//!
+//! Long-running drivers should be prepared for the device they are driving to disappear, _e.g._,
+//! because it is surprise-removed, or because the kernel wants it back. A [`PciHotplugWatcher`]
+//! listens for the kernel's uevents about PCI functions, and optionally also for the device's
+//! [request interrupt](crate::interrupts::PciInterrupts::request), so that you can react to both
+//! in a single place.
+//!
+//! ```no_run
+//! use pci_driver::hotplug::{PciHotplugEvent, PciHotplugWatcher};
+//!
+//! let watcher = PciHotplugWatcher::new()?.address("0000:01:00.0".parse()?);
+//!
+//! loop {
+//!     match watcher.next_event(None)? {
+//!         Some(PciHotplugEvent::Removed(address)) => println!("{} is gone", address),
+//!         Some(event) => println!("{:?}", event),
+//!         None => {}
+//!     }
+//! }
+//! # std::io::Result::Ok(())
+//! ```
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::{
+    c_void, poll, pollfd, recvfrom, sa_family_t, sockaddr, sockaddr_nl, socket, socklen_t,
+    AF_NETLINK, EAGAIN, EINTR, NETLINK_KOBJECT_UEVENT, POLLIN, SOCK_CLOEXEC, SOCK_DGRAM,
+};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::str;
+use std::time::{Duration, Instant};
+
+use crate::device::PciAddress;
+use crate::interrupts::read_eventfd;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// The netlink multicast group to which the kernel sends uevents.
+const UEVENT_KERNEL_GROUP: u32 = 1;
+
+/// Uevents are limited to about 2 KiB by the kernel.
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+/// Something that happened to a PCI function. See [`PciHotplugWatcher`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum PciHotplugEvent {
+    /// The function with the given address was removed from the system.
+    Removed(PciAddress),
+    /// A function with the given address was added to the system, possibly after being removed.
+    Added(PciAddress),
+    /// The eventfd given to [`PciHotplugWatcher::request_eventfd`] was signalled, _i.e._, the
+    /// kernel asked for the device to be released.
+    ReleaseRequested,
+}
+
+/// Waits for PCI functions to be removed from or added to the system, and for the kernel to ask
+/// for a device to be released.
+///
+/// Removals and additions are learned from the kernel's uevents, which doesn't require any
+/// privileges. Events are only reported for the functions given to
+/// [`PciHotplugWatcher::address`], or for all functions if none is given.
+#[derive(Debug)]
+pub struct PciHotplugWatcher {
+    socket: File,
+    addresses: Vec<PciAddress>,
+    request_eventfd: Option<RawFd>,
+}
+
+impl PciHotplugWatcher {
+    /// Starts listening for uevents. Events that happen before this is called aren't reported.
+    pub fn new() -> io::Result<PciHotplugWatcher> {
+        let fd = unsafe {
+            socket(
+                AF_NETLINK,
+                SOCK_DGRAM | SOCK_CLOEXEC,
+                NETLINK_KOBJECT_UEVENT,
+            )
+        };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let socket = unsafe { File::from_raw_fd(fd) };
+
+        let mut address: sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = AF_NETLINK as sa_family_t;
+        address.nl_groups = UEVENT_KERNEL_GROUP;
+
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &address as *const sockaddr_nl as *const sockaddr,
+                mem::size_of::<sockaddr_nl>() as socklen_t,
+            )
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(PciHotplugWatcher {
+            socket,
+            addresses: Vec::new(),
+            request_eventfd: None,
+        })
+    }
+
+    /// Only report removals and additions of the function with the given address, and of others
+    /// passed to this method.
+    pub fn address(mut self, address: PciAddress) -> PciHotplugWatcher {
+        self.addresses.push(address);
+        self
+    }
+
+    /// Also report [`PciHotplugEvent::ReleaseRequested`] when the given eventfd is signalled.
+    ///
+    /// This should be the eventfd enabled for the device's
+    /// [request interrupt](crate::interrupts::PciInterrupts::request). It isn't owned by the
+    /// watcher, and must be kept open for as long as the watcher exists.
+    pub fn request_eventfd(mut self, eventfd: RawFd) -> PciHotplugWatcher {
+        self.request_eventfd = Some(eventfd);
+        self
+    }
+
+    /// Blocks until an event is available and returns it, or returns `None` if `timeout` is given
+    /// and elapses first.
+    pub fn next_event(&self, timeout: Option<Duration>) -> io::Result<Option<PciHotplugEvent>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let timeout_ms = match deadline {
+                None => -1,
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    ((remaining.as_micros() + 999) / 1000)
+                        .try_into()
+                        .unwrap_or(i32::MAX)
+                }
+            };
+
+            let mut fds = [
+                pollfd {
+                    fd: self.socket.as_raw_fd(),
+                    events: POLLIN,
+                    revents: 0,
+                },
+                pollfd {
+                    fd: self.request_eventfd.unwrap_or(-1), // ignored by poll() if negative
+                    events: POLLIN,
+                    revents: 0,
+                },
+            ];
+
+            let ret = unsafe { poll(fds.as_mut_ptr(), fds.len() as _, timeout_ms) };
+
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() == Some(EINTR) {
+                    continue;
+                }
+                return Err(e);
+            } else if ret == 0 {
+                return Ok(None);
+            }
+
+            if fds[1].revents & POLLIN != 0 {
+                match read_eventfd(fds[1].fd) {
+                    Ok(_) => return Ok(Some(PciHotplugEvent::ReleaseRequested)),
+                    Err(e) if e.raw_os_error() == Some(EAGAIN) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if fds[0].revents & POLLIN != 0 {
+                if let Some(event) = self.receive_uevent()? {
+                    return Ok(Some(event));
+                }
+            }
+        }
+    }
+
+    fn receive_uevent(&self) -> io::Result<Option<PciHotplugEvent>> {
+        let mut buffer = vec![0_u8; UEVENT_BUFFER_SIZE];
+        let mut sender: sockaddr_nl = unsafe { mem::zeroed() };
+        let mut sender_len = mem::size_of::<sockaddr_nl>() as socklen_t;
+
+        let ret = unsafe {
+            recvfrom(
+                self.socket.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut c_void,
+                buffer.len(),
+                0,
+                &mut sender as *mut sockaddr_nl as *mut sockaddr,
+                &mut sender_len,
+            )
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // only trust messages sent by the kernel
+        if sender.nl_pid != 0 {
+            return Ok(None);
+        }
+
+        let event = parse_uevent(&buffer[..ret as usize]);
+
+        Ok(event.filter(|event| match event {
+            PciHotplugEvent::Removed(address) | PciHotplugEvent::Added(address) => {
+                self.addresses.is_empty() || self.addresses.contains(address)
+            }
+            _ => true,
+        }))
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Parses a kernel uevent, which is like `"remove@/devices/...\0ACTION=remove\0SUBSYSTEM=pci\0"`
+/// followed by more `KEY=value` fields, each terminated by a NUL byte.
+fn parse_uevent(uevent: &[u8]) -> Option<PciHotplugEvent> {
+    let mut action = None;
+    let mut subsystem = None;
+    let mut slot_name = None;
+
+    for field in uevent.split(|&b| b == 0).skip(1) {
+        let field = str::from_utf8(field).ok()?;
+
+        if let Some(value) = field.strip_prefix("ACTION=") {
+            action = Some(value);
+        } else if let Some(value) = field.strip_prefix("SUBSYSTEM=") {
+            subsystem = Some(value);
+        } else if let Some(value) = field.strip_prefix("PCI_SLOT_NAME=") {
+            slot_name = Some(value);
+        }
+    }
+
+    if subsystem != Some("pci") {
+        return None;
+    }
+
+    let address = slot_name?.parse().ok()?;
+
+    match action? {
+        "add" => Some(PciHotplugEvent::Added(address)),
+        "remove" => Some(PciHotplugEvent::Removed(address)),
+        _ => None,
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use crate::device::PciAddress;
+    use crate::hotplug::{parse_uevent, PciHotplugEvent};
+
+    #[test]
+    fn test_parse_uevent() {
+        let address: PciAddress = "0000:01:00.0".parse().unwrap();
+
+        let uevent = b"remove@/devices/pci0000:00/0000:00:1c.0/0000:01:00.0\0ACTION=remove\0\
+            DEVPATH=/devices/pci0000:00/0000:00:1c.0/0000:01:00.0\0SUBSYSTEM=pci\0\
+            PCI_CLASS=10802\0PCI_SLOT_NAME=0000:01:00.0\0SEQNUM=4242\0";
+        assert_eq!(
+            parse_uevent(uevent),
+            Some(PciHotplugEvent::Removed(address))
+        );
+
+        let uevent = b"add@/devices/pci0000:00/0000:00:1c.0/0000:01:00.0\0ACTION=add\0\
+            SUBSYSTEM=pci\0PCI_SLOT_NAME=0000:01:00.0\0";
+        assert_eq!(parse_uevent(uevent), Some(PciHotplugEvent::Added(address)));
+
+        let uevent = b"bind@/devices/pci0000:00/0000:00:1c.0/0000:01:00.0\0ACTION=bind\0\
+            SUBSYSTEM=pci\0DRIVER=vfio-pci\0PCI_SLOT_NAME=0000:01:00.0\0";
+        assert_eq!(parse_uevent(uevent), None);
+
+        let uevent = b"remove@/devices/virtual/net/tap0\0ACTION=remove\0SUBSYSTEM=net\0";
+        assert_eq!(parse_uevent(uevent), None);
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
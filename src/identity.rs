@@ -0,0 +1,403 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Recognizing the same physical device across reboots and hotplug.
+//!
+//! A function's address may change, _e.g._, when the system is rebooted with another device
+//! plugged in, or when the device is hot-removed and added back elsewhere. A [`PciDeviceIdentity`]
+//! captures what identifies the function regardless: its IDs, its Device Serial Number if it has
+//! one, and its position in the PCI topology ignoring bus numbers. It can be stored as a string and
+//! later used to find the function again.
+//!
+//! ```no_run
+//! use pci_driver::identity::PciDeviceIdentity;
+//! use pci_driver::sysfs::PciSysfsDevice;
+//!
+//! let sysfs = PciSysfsDevice::new("/sys/bus/pci/devices/0000:01:00.0");
+//! let identity = PciDeviceIdentity::of_sysfs(&sysfs)?;
+//! let stored = identity.to_string();
+//!
+//! // ... later, maybe after a reboot ...
+//!
+//! let identity: PciDeviceIdentity = stored.parse()?;
+//! if let Some(entry) = identity.find()? {
+//!     println!("Found it at {}", entry.address);
+//! }
+//! # std::io::Result::Ok(())
+//! ```
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::fmt::{self, Display};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::config::ext_caps::DeviceSerialNumberExtendedCapability;
+use crate::config::PciConfig;
+use crate::device::{PciAddress, PciDevice};
+use crate::enumerate::{list_devices, PciDeviceEntry, PciDeviceFilter};
+use crate::regions::{BackedByPciSubregion, PciRegion};
+use crate::sysfs::PciSysfsDevice;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+/// What identifies a PCI function regardless of its address. See the
+/// [module documentation](self).
+///
+/// Its string representation is like `144d:a808:144d:a801 serial=0011223344556677
+/// path=0000:1c.0/00.0`, with the `serial` part only present for functions with a Device Serial
+/// Number.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct PciDeviceIdentity {
+    /// The function's Vendor ID.
+    pub vendor_id: u16,
+    /// The function's Device ID.
+    pub device_id: u16,
+    /// The function's Subsystem Vendor ID, or 0 if it doesn't have one.
+    pub subsystem_vendor_id: u16,
+    /// The function's Subsystem ID, or 0 if it doesn't have one.
+    pub subsystem_id: u16,
+    /// The serial number in the function's Device Serial Number Extended Capability, if it has
+    /// one and it could be read.
+    pub serial_number: Option<u64>,
+    /// The PCI domain the function is in.
+    pub domain: u32,
+    /// The device and function numbers of the bridges above the function, starting at the root
+    /// bus, followed by those of the function itself.
+    pub path: Vec<(u8, u8)>,
+}
+
+impl PciDeviceIdentity {
+    /// Captures the identity of the given function, which must know its
+    /// [address](PciDevice::address).
+    pub fn of(device: &dyn PciDevice) -> io::Result<PciDeviceIdentity> {
+        let address = device.address().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "Backend doesn't know the function's address",
+            )
+        })?;
+
+        let sysfs = PciSysfsDevice::new(Path::new(SYSFS_PCI_DEVICES).join(address.to_string()));
+        let info = device.info();
+
+        Ok(PciDeviceIdentity {
+            vendor_id: info.vendor_id,
+            device_id: info.device_id,
+            subsystem_vendor_id: info.subsystem_vendor_id,
+            subsystem_id: info.subsystem_id,
+            serial_number: read_serial_number(&device.config())?,
+            domain: address.domain,
+            path: topology_path(&sysfs, address)?,
+        })
+    }
+
+    /// Captures the identity of the function with the given sysfs directory.
+    ///
+    /// The serial number can only be read with root privileges, and is `None` otherwise.
+    pub fn of_sysfs(sysfs: &PciSysfsDevice) -> io::Result<PciDeviceIdentity> {
+        let address = sysfs.address()?;
+
+        let config_space = sysfs.config_space_read_only()?;
+        let config = PciConfig::backed_by(&config_space);
+
+        // the Subsystem IDs are only defined for header layout 0
+        let (subsystem_vendor_id, subsystem_id) = if config.read_u8(0x0e)? & 0x7f == 0 {
+            (config.read_le_u16(0x2c)?, config.read_le_u16(0x2e)?)
+        } else {
+            (0, 0)
+        };
+
+        let serial_number = match read_serial_number(&config) {
+            Ok(serial_number) => serial_number,
+            // reading past the first 64 bytes is not permitted
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => None,
+            Err(e) => return Err(e),
+        };
+
+        Ok(PciDeviceIdentity {
+            vendor_id: config.read_le_u16(0x00)?,
+            device_id: config.read_le_u16(0x02)?,
+            subsystem_vendor_id,
+            subsystem_id,
+            serial_number,
+            domain: address.domain,
+            path: topology_path(sysfs, address)?,
+        })
+    }
+
+    /// Whether `other` is the identity of the same function.
+    ///
+    /// The IDs must match. Then, if both have a serial number, those must match, and otherwise
+    /// their positions in the topology must.
+    pub fn matches(&self, other: &PciDeviceIdentity) -> bool {
+        let same_ids = (
+            self.vendor_id,
+            self.device_id,
+            self.subsystem_vendor_id,
+            self.subsystem_id,
+        ) == (
+            other.vendor_id,
+            other.device_id,
+            other.subsystem_vendor_id,
+            other.subsystem_id,
+        );
+
+        let same_function = match (self.serial_number, other.serial_number) {
+            (Some(serial), Some(other_serial)) => {
+                // all functions of a device have the same serial number
+                serial == other_serial && self.path.last() == other.path.last()
+            }
+            _ => self.domain == other.domain && self.path == other.path,
+        };
+
+        same_ids && same_function
+    }
+
+    /// Looks for the function with this identity among those present in the system. The returned
+    /// entry can be passed to, _e.g._, `VfioPciDevice::open`.
+    ///
+    /// Functions with the same IDs whose identity can't be captured, _e.g._, because they were
+    /// removed concurrently, are skipped.
+    pub fn find(&self) -> io::Result<Option<PciDeviceEntry>> {
+        self.find_in(Path::new(SYSFS_PCI_DEVICES))
+    }
+
+    fn find_in(&self, root: &Path) -> io::Result<Option<PciDeviceEntry>> {
+        let filter = PciDeviceFilter::new()
+            .vendor_id(self.vendor_id)
+            .device_id(self.device_id);
+
+        for candidate in list_devices(root, &filter)? {
+            match PciDeviceIdentity::of_sysfs(&PciSysfsDevice::new(&candidate)) {
+                Ok(identity) if self.matches(&identity) => return Ok(Some(candidate)),
+                _ => continue,
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Display for PciDeviceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:04x}:{:04x}:{:04x}",
+            self.vendor_id, self.device_id, self.subsystem_vendor_id, self.subsystem_id
+        )?;
+
+        if let Some(serial_number) = self.serial_number {
+            write!(f, " serial={:016x}", serial_number)?;
+        }
+
+        write!(f, " path={:04x}:", self.domain)?;
+
+        for (i, (device, function)) in self.path.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "/" };
+            write!(f, "{}{:02x}.{}", separator, device, function)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for PciDeviceIdentity {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<PciDeviceIdentity> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid PCI device identity: {:?}", s),
+            )
+        };
+
+        let hex_u16 = |value: &str| u16::from_str_radix(value, 16).map_err(|_| invalid());
+
+        let mut parts = s.split_whitespace();
+
+        let ids = parts
+            .next()
+            .ok_or_else(invalid)?
+            .split(':')
+            .map(hex_u16)
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let (vendor_id, device_id, subsystem_vendor_id, subsystem_id) = match ids[..] {
+            [a, b, c, d] => (a, b, c, d),
+            _ => return Err(invalid()),
+        };
+
+        let mut serial_number = None;
+        let mut location = None;
+
+        for part in parts {
+            if let Some(value) = part.strip_prefix("serial=") {
+                serial_number = Some(u64::from_str_radix(value, 16).map_err(|_| invalid())?);
+            } else if let Some(value) = part.strip_prefix("path=") {
+                let (domain, path) = value.split_once(':').ok_or_else(invalid)?;
+
+                let path = path
+                    .split('/')
+                    .map(|slot| {
+                        let (device, function) = slot.split_once('.').ok_or_else(invalid)?;
+                        let device = u8::from_str_radix(device, 16).map_err(|_| invalid())?;
+                        let function = function.parse::<u8>().map_err(|_| invalid())?;
+                        Ok((device, function))
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+
+                let domain = u32::from_str_radix(domain, 16).map_err(|_| invalid())?;
+                location = Some((domain, path));
+            } else {
+                return Err(invalid());
+            }
+        }
+
+        let (domain, path) = location.ok_or_else(invalid)?;
+
+        Ok(PciDeviceIdentity {
+            vendor_id,
+            device_id,
+            subsystem_vendor_id,
+            subsystem_id,
+            serial_number,
+            domain,
+            path,
+        })
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Returns `None` if the function has no Device Serial Number or no extended config space.
+fn read_serial_number(config: &PciConfig) -> io::Result<Option<u64>> {
+    if !config.has_extended_config_space() {
+        return Ok(None);
+    }
+
+    match config
+        .extended_capabilities()?
+        .of_type::<DeviceSerialNumberExtendedCapability>()?
+        .next()
+    {
+        Some(cap) => Ok(Some(cap.serial_number()?)),
+        None => Ok(None),
+    }
+}
+
+fn topology_path(sysfs: &PciSysfsDevice, address: PciAddress) -> io::Result<Vec<(u8, u8)>> {
+    let mut path = Vec::new();
+
+    for bridge in sysfs.upstream_bridges()? {
+        let bridge = bridge.address()?;
+        path.push((bridge.device, bridge.function));
+    }
+
+    path.push((address.device, address.function));
+
+    Ok(path)
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    use crate::identity::PciDeviceIdentity;
+    use crate::sysfs::PciSysfsDevice;
+
+    #[test]
+    fn test_string_representation() {
+        let identity = PciDeviceIdentity {
+            vendor_id: 0x144d,
+            device_id: 0xa808,
+            subsystem_vendor_id: 0x144d,
+            subsystem_id: 0xa801,
+            serial_number: Some(0x0011_2233_4455_6677),
+            domain: 0,
+            path: vec![(0x1c, 0), (0x00, 0)],
+        };
+
+        let string = identity.to_string();
+        assert_eq!(
+            string,
+            "144d:a808:144d:a801 serial=0011223344556677 path=0000:1c.0/00.0"
+        );
+        assert_eq!(string.parse::<PciDeviceIdentity>().unwrap(), identity);
+
+        let moved = "144d:a808:144d:a801 serial=0011223344556677 path=0000:01.0/00.0"
+            .parse::<PciDeviceIdentity>()
+            .unwrap();
+        assert!(identity.matches(&moved));
+
+        let unknown_serial = PciDeviceIdentity {
+            serial_number: None,
+            ..moved.clone()
+        };
+        assert!(!identity.matches(&unknown_serial));
+        assert_eq!(
+            unknown_serial.to_string(),
+            "144d:a808:144d:a801 path=0000:01.0/00.0"
+        );
+
+        assert!("144d:a808 path=0000:00.0"
+            .parse::<PciDeviceIdentity>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_of_sysfs_and_find() {
+        let root = std::env::temp_dir().join(format!("pci-driver-identity-test-{}", process::id()));
+        let devices = root.join("devices/pci0000:00");
+        let bus = root.join("bus");
+        fs::create_dir_all(&bus).unwrap();
+
+        let add = |path: &str, config: Option<&[u8]>| {
+            let dir = devices.join(path);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("vendor"), "0x144d\n").unwrap();
+            fs::write(dir.join("device"), "0xa808\n").unwrap();
+            fs::write(dir.join("class"), "0x010802\n").unwrap();
+            if let Some(config) = config {
+                fs::write(dir.join("config"), config).unwrap();
+            }
+            symlink(&dir, bus.join(dir.file_name().unwrap())).unwrap();
+        };
+
+        let mut config = [0_u8; 64];
+        config[0x00..0x04].copy_from_slice(&[0x4d, 0x14, 0x08, 0xa8]);
+        config[0x2c..0x30].copy_from_slice(&[0x4d, 0x14, 0x01, 0xa8]);
+
+        // a function with the same IDs whose config space can't be read, listed first
+        add("0000:00:05.0", None);
+        add("0000:00:1c.0/0000:01:00.0", Some(&config));
+
+        let identity =
+            PciDeviceIdentity::of_sysfs(&PciSysfsDevice::new(bus.join("0000:01:00.0"))).unwrap();
+        assert_eq!(
+            identity.to_string(),
+            "144d:a808:144d:a801 path=0000:1c.0/00.0"
+        );
+
+        let found = identity.find_in(&bus).unwrap().unwrap();
+        assert_eq!(found.address, "0000:01:00.0".parse().unwrap());
+
+        let elsewhere = PciDeviceIdentity {
+            path: vec![(0x1d, 0), (0x00, 0)],
+            ..identity
+        };
+        assert_eq!(elsewhere.find_in(&bus).unwrap(), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
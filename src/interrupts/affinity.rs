@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::fs;
+use std::io::{self, ErrorKind};
+
+use crate::interrupts::PciInterruptMechanism;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+impl PciInterruptMechanism<'_> {
+    /// Returns the number of the Linux IRQ that backs the given, enabled vector, as found in
+    /// `/proc/interrupts`.
+    ///
+    /// Fails with [`ErrorKind::NotFound`] if the vector isn't enabled or doesn't have its own IRQ,
+    /// as is the case for [`PciInterrupts::error`](crate::interrupts::PciInterrupts::error) and
+    /// [`PciInterrupts::request`](crate::interrupts::PciInterrupts::request).
+    pub fn irq_number(&self, vector: usize) -> io::Result<u32> {
+        let not_found = || {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("{:?} vector {} has no IRQ", self.kind, vector),
+            )
+        };
+
+        let name = self
+            .device_internal
+            .interrupts_irq_name(self.kind, vector)
+            .ok_or_else(not_found)?;
+
+        let interrupts = fs::read_to_string("/proc/interrupts")?;
+
+        find_irq_number(&interrupts, &name).ok_or_else(not_found)
+    }
+
+    /// Returns the CPUs that the IRQ backing the given, enabled vector may be delivered to.
+    pub fn affinity(&self, vector: usize) -> io::Result<Vec<usize>> {
+        let irq = self.irq_number(vector)?;
+        let list = fs::read_to_string(format!("/proc/irq/{}/smp_affinity_list", irq))?;
+
+        parse_cpu_list(list.trim()).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid affinity list for IRQ {}: {:?}", irq, list),
+            )
+        })
+    }
+
+    /// Sets the CPUs that the IRQ backing the given, enabled vector may be delivered to, _e.g._, so
+    /// that it is handled close to the thread that polls for it.
+    ///
+    /// This usually requires root privileges, and the kernel may reject the affinity or stop
+    /// honoring it, _e.g._, if the CPUs are taken offline, or if `irqbalance` is running.
+    pub fn set_affinity(&self, vector: usize, cpus: &[usize]) -> io::Result<()> {
+        if cpus.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Affinity must include at least one CPU",
+            ));
+        }
+
+        let irq = self.irq_number(vector)?;
+        let list: Vec<String> = cpus.iter().map(|cpu| cpu.to_string()).collect();
+
+        fs::write(
+            format!("/proc/irq/{}/smp_affinity_list", irq),
+            list.join(","),
+        )
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Finds the IRQ with the given action name in the contents of `/proc/interrupts`, where each
+/// line is like `" 42:  0  1  IR-PCI-MSIX-0000:01:00.0  0-edge  foo, bar"`.
+fn find_irq_number(interrupts: &str, name: &str) -> Option<u32> {
+    interrupts.lines().find_map(|line| {
+        let colon = line.find(':')?;
+        let irq = line[..colon].trim().parse().ok()?;
+
+        // several actions are separated by ", " if the IRQ is shared
+        line[colon + 1..]
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .any(|token| token == name)
+            .then_some(irq)
+    })
+}
+
+/// Parses a list of CPUs like `"0-3,8,10-11"`.
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for item in list.split(',') {
+        let mut bounds = item.splitn(2, '-');
+        let first: usize = bounds.next()?.parse().ok()?;
+        let last: usize = match bounds.next() {
+            Some(last) => last.parse().ok()?,
+            None => first,
+        };
+        cpus.extend(first..=last);
+    }
+
+    Some(cpus)
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use crate::interrupts::affinity::{find_irq_number, parse_cpu_list};
+
+    #[test]
+    fn test_find_irq_number() {
+        let interrupts = "           CPU0       CPU1
+  16:          0          3  IR-IO-APIC   16-fasteoi   ehci_hcd:usb1, vfio-intx(0000:00:1a.0)
+ 130:          7          0  IR-PCI-MSIX-0000:01:00.0    0-edge      vfio-msix[0](0000:01:00.0)
+ 131:          0          2  IR-PCI-MSIX-0000:01:00.0    1-edge      vfio-msix[1](0000:01:00.0)
+ NMI:          0          0   Non-maskable interrupts
+";
+
+        assert_eq!(
+            find_irq_number(interrupts, "vfio-intx(0000:00:1a.0)"),
+            Some(16)
+        );
+        assert_eq!(
+            find_irq_number(interrupts, "vfio-msix[1](0000:01:00.0)"),
+            Some(131)
+        );
+        assert_eq!(
+            find_irq_number(interrupts, "vfio-msix[2](0000:01:00.0)"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("3"), Some(vec![3]));
+        assert_eq!(
+            parse_cpu_list("0-2,8,10-11"),
+            Some(vec![0, 1, 2, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list("0-"), None);
+        assert_eq!(parse_cpu_list(""), None);
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
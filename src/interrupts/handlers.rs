@@ -0,0 +1,375 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::io::{self, ErrorKind};
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use crate::interrupts::{InterruptEvent, InterruptPoller, PciInterruptKind, PciInterruptMechanism};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+type Handler = Arc<Mutex<dyn FnMut(u64) + Send>>;
+
+/// Runs user-provided closures whenever interrupts are delivered, from a thread owned by this
+/// value.
+///
+/// Register a closure per vector with [`InterruptHandlers::register`], or one for all enabled
+/// vectors of a mechanism with [`InterruptHandlers::register_mechanism`]. Each time a vector's
+/// eventfd is signalled, its closure is called with the number of times it was signalled since it
+/// was last called. This spares simple drivers from writing any event-loop code at all.
+///
+/// Closures are called one at a time, and shouldn't block for long, since that delays the handling
+/// of other vectors. If a closure panics, only the vector it was called for is unregistered, and
+/// then reported by [`InterruptHandlers::panicked`]; other vectors, even ones sharing the closure
+/// through [`InterruptHandlers::register_mechanism`], keep being handled.
+///
+/// As with [`InterruptPoller`], eventfds passed to [`InterruptHandlers::register`] aren't owned by
+/// this value, and must be kept open for as long as they are registered. The thread is stopped
+/// when this value is dropped, or when [`InterruptHandlers::stop`] is called. It also stops on its
+/// own if waiting for interrupts fails, which [`InterruptHandlers::check`] reports.
+pub struct InterruptHandlers {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+struct Shared {
+    poller: InterruptPoller,
+    handlers: Mutex<HashMap<(PciInterruptKind, usize), Handler>>,
+    panicked: Mutex<Vec<(PciInterruptKind, usize)>>,
+    /// Why the thread stopped on its own, if it did.
+    failure: Mutex<Option<(ErrorKind, String)>>,
+    stop: AtomicBool,
+}
+
+impl InterruptHandlers {
+    /// Spawns the thread that will run the handlers, with no handlers registered yet.
+    pub fn new() -> io::Result<InterruptHandlers> {
+        let shared = Arc::new(Shared {
+            poller: InterruptPoller::new()?,
+            handlers: Mutex::new(HashMap::new()),
+            panicked: Mutex::new(Vec::new()),
+            failure: Mutex::new(None),
+            stop: AtomicBool::new(false),
+        });
+
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("pci-driver-irq".to_string())
+                .spawn(move || shared.run())?
+        };
+
+        Ok(InterruptHandlers {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Registers a closure to be called whenever the given vector's eventfd is signalled.
+    ///
+    /// Fails if the vector already has a handler.
+    pub fn register<F>(
+        &self,
+        kind: PciInterruptKind,
+        vector: usize,
+        eventfd: RawFd,
+        handler: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(u64) + Send + 'static,
+    {
+        self.insert(kind, vector, eventfd, None, Arc::new(Mutex::new(handler)))
+    }
+
+    /// Registers a closure to be called whenever the eventfd of any vector of the given mechanism
+    /// that is currently enabled with one is signalled. The closure gets the index of the vector
+    /// and the number of times its eventfd was signalled.
+    ///
+    /// As with [`InterruptPoller::add_mechanism`], duplicates of the eventfds are kept open, and
+    /// this fails if any vector is enabled with an eventfd that the device doesn't own.
+    ///
+    /// If the closure panics, it keeps being called for the other vectors, so it must not rely on
+    /// state that a panic could leave inconsistent.
+    pub fn register_mechanism<F>(
+        &self,
+        mechanism: &PciInterruptMechanism,
+        handler: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(usize, u64) + Send + 'static,
+    {
+        let handler = Arc::new(Mutex::new(handler));
+
+        for (vector, eventfd) in mechanism.owned_eventfds()?.into_iter().enumerate() {
+            if let Some(eventfd) = eventfd {
+                let handler = Arc::clone(&handler);
+                let handler = Arc::new(Mutex::new(move |count| {
+                    (lock_ignoring_poison(&handler))(vector, count)
+                }));
+                self.insert(
+                    mechanism.kind,
+                    vector,
+                    eventfd.as_raw_fd(),
+                    Some(eventfd),
+                    handler,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn insert(
+        &self,
+        kind: PciInterruptKind,
+        vector: usize,
+        eventfd: RawFd,
+        owned: Option<OwnedFd>,
+        handler: Handler,
+    ) -> io::Result<()> {
+        let mut handlers = self.shared.handlers.lock().unwrap();
+
+        if handlers.contains_key(&(kind, vector)) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{:?} vector {} already has a handler", kind, vector),
+            ));
+        }
+
+        match owned {
+            Some(owned) => self.shared.poller.add_owned(kind, vector, owned)?,
+            None => self.shared.poller.add(kind, vector, eventfd)?,
+        }
+        handlers.insert((kind, vector), handler);
+
+        Ok(())
+    }
+
+    /// Unregisters the handler of the given vector.
+    ///
+    /// The handler may still be running when this returns, but won't be called again.
+    pub fn unregister(&self, kind: PciInterruptKind, vector: usize) -> io::Result<()> {
+        self.shared.unregister(kind, vector)
+    }
+
+    /// Returns, for each vector with a handler, the number of interrupts delivered to it since the
+    /// handler was registered or [`InterruptHandlers::reset_counts`] was last called. See
+    /// [`InterruptPoller::counts`].
+    pub fn counts(&self) -> Vec<InterruptEvent> {
+        self.shared.poller.counts()
+    }
+
+    /// Resets the counts of all vectors with a handler to zero.
+    pub fn reset_counts(&self) {
+        self.shared.poller.reset_counts()
+    }
+
+    /// The vectors whose handlers panicked, and were thus unregistered.
+    pub fn panicked(&self) -> Vec<(PciInterruptKind, usize)> {
+        self.shared.panicked.lock().unwrap().clone()
+    }
+
+    /// Fails if the thread has stopped on its own because waiting for interrupts failed, in which
+    /// case no handlers are called anymore.
+    pub fn check(&self) -> io::Result<()> {
+        match &*self.shared.failure.lock().unwrap() {
+            Some((kind, message)) => Err(io::Error::new(
+                *kind,
+                format!("Waiting for interrupts failed: {}", message),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Stops and joins the thread. Fails if the thread had stopped on its own because waiting for
+    /// interrupts failed, with the error that caused it.
+    pub fn stop(mut self) -> io::Result<()> {
+        self.stop_and_join()
+    }
+
+    fn stop_and_join(&mut self) -> io::Result<()> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+
+        self.shared.stop.store(true, Ordering::Release);
+        self.shared.poller.wake()?;
+
+        thread.join().unwrap()
+    }
+}
+
+impl Debug for InterruptHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut vectors: Vec<_> = self
+            .shared
+            .handlers
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect();
+        vectors.sort_by_key(|&(kind, vector)| (kind as usize, vector));
+
+        f.debug_struct("InterruptHandlers")
+            .field("vectors", &vectors)
+            .finish()
+    }
+}
+
+impl Drop for InterruptHandlers {
+    fn drop(&mut self) {
+        let _ = self.stop_and_join();
+    }
+}
+
+impl Shared {
+    fn run(&self) -> io::Result<()> {
+        let result = self.handle_events();
+
+        if let Err(e) = &result {
+            *self.failure.lock().unwrap() = Some((e.kind(), e.to_string()));
+        }
+
+        result
+    }
+
+    fn handle_events(&self) -> io::Result<()> {
+        while !self.stop.load(Ordering::Acquire) {
+            for event in self.poller.poll(None)? {
+                let key = (event.kind, event.vector);
+
+                // don't hold the lock while the handler runs, so that it may (un)register handlers
+                let handler = match self.handlers.lock().unwrap().get(&key) {
+                    Some(handler) => Arc::clone(handler),
+                    None => continue, // unregistered concurrently
+                };
+
+                // the lock is poisoned if the handler panicked, but it isn't called again then
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    (lock_ignoring_poison(&handler))(event.count)
+                }));
+
+                if result.is_err() {
+                    let _ = self.unregister(event.kind, event.vector);
+                    self.panicked.lock().unwrap().push(key);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unregister(&self, kind: PciInterruptKind, vector: usize) -> io::Result<()> {
+        let mut handlers = self.handlers.lock().unwrap();
+
+        self.poller.remove(kind, vector)?;
+        handlers.remove(&(kind, vector));
+
+        Ok(())
+    }
+}
+
+/// Locks the mutex even if a handler panicked while holding it.
+fn lock_ignoring_poison<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use libc::{close, eventfd, EFD_NONBLOCK};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use crate::backends::mock::MockDeviceBuilder;
+    use crate::device::PciDevice;
+    use crate::interrupts::{write_eventfd, InterruptHandlers, PciInterruptKind};
+
+    #[test]
+    fn test_interrupt_handlers() {
+        let a = unsafe { eventfd(0, EFD_NONBLOCK) };
+        let b = unsafe { eventfd(0, EFD_NONBLOCK) };
+        assert!(a >= 0 && b >= 0);
+
+        let handlers = InterruptHandlers::new().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        handlers
+            .register(PciInterruptKind::MsiX, 0, a, move |count| {
+                sender.send(count).unwrap()
+            })
+            .unwrap();
+
+        handlers
+            .register(PciInterruptKind::MsiX, 1, b, |_| panic!("oops"))
+            .unwrap();
+
+        assert!(handlers
+            .register(PciInterruptKind::MsiX, 0, b, |_| {})
+            .is_err());
+
+        write_eventfd(a, 2).unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), 2);
+
+        write_eventfd(b, 1).unwrap();
+        while handlers.panicked().is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(handlers.panicked(), [(PciInterruptKind::MsiX, 1)]);
+
+        handlers.check().unwrap();
+        handlers.stop().unwrap();
+
+        unsafe {
+            close(a);
+            close(b);
+        }
+    }
+
+    #[test]
+    fn test_mechanism_handler_panic() {
+        let device = MockDeviceBuilder::new(0x1234, 0x5678)
+            .interrupts(PciInterruptKind::MsiX, 2)
+            .build();
+
+        let interrupts = device.interrupts();
+        let msi_x = interrupts.msi_x();
+        let _eventfds = msi_x.enable_count(2, true).unwrap();
+
+        let handlers = InterruptHandlers::new().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        handlers
+            .register_mechanism(&msi_x, move |vector, count| {
+                assert_ne!(vector, 1, "oops");
+                sender.send((vector, count)).unwrap()
+            })
+            .unwrap();
+
+        msi_x.trigger(1).unwrap();
+        while handlers.panicked().is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(handlers.panicked(), [(PciInterruptKind::MsiX, 1)]);
+
+        // the other vector is still handled by the same closure
+        msi_x.trigger(0).unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), (0, 1));
+
+        handlers.stop().unwrap();
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -0,0 +1,838 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+mod affinity;
+mod handlers;
+mod poller;
+#[cfg(feature = "async")]
+mod stream;
+
+use libc::{
+    c_void, eventfd, poll, pollfd, read, write, EAGAIN, EFD_CLOEXEC, EFD_NONBLOCK, EINTR, POLLIN,
+};
+use std::convert::TryInto;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant};
+
+use crate::config::caps::MsiCapability;
+use crate::device::PciDeviceInternal;
+
+pub use handlers::InterruptHandlers;
+pub use poller::{InterruptEvent, InterruptPoller};
+#[cfg(feature = "async")]
+pub use stream::IrqStream;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Gives you control over a PCI device's interrupt mechanisms: INTx, MSI, and MSI-X.
+///
+/// Each device may only support a subset of these mechanisms. The [`PciInterruptMechanism::max`]
+/// method returns 0 for unsupported mechanisms.
+pub struct PciInterrupts<'a> {
+    pub(crate) device: &'a dyn PciDeviceInternal,
+}
+
+impl PciInterrupts<'_> {
+    /// Returns a thing that gives you control over a PCI device's INTx interrupts.
+    pub fn intx(&self) -> PciInterruptMechanism<'_> {
+        PciInterruptMechanism {
+            device_internal: self.device,
+            kind: PciInterruptKind::Intx,
+        }
+    }
+
+    /// Returns a thing that gives you control over a PCI device's MSI interrupts.
+    pub fn msi(&self) -> PciInterruptMechanism<'_> {
+        PciInterruptMechanism {
+            device_internal: self.device,
+            kind: PciInterruptKind::Msi,
+        }
+    }
+
+    /// Returns a thing that gives you control over a PCI device's MSI-X interrupts.
+    pub fn msi_x(&self) -> PciInterruptMechanism<'_> {
+        PciInterruptMechanism {
+            device_internal: self.device,
+            kind: PciInterruptKind::MsiX,
+        }
+    }
+
+    /// Returns a thing that gives you control over the notification of errors detected on a PCI
+    /// device, _e.g._, through Advanced Error Reporting (AER).
+    ///
+    /// This isn't an interrupt mechanism of the device itself, but the kernel signals the eventfd
+    /// enabled for its single vector when it detects an uncorrectable error on the device. This lets
+    /// you learn of errors right away, instead of only when accesses to the device start failing.
+    /// The VFIO backend only supports this for PCI Express devices.
+    pub fn error(&self) -> PciInterruptMechanism<'_> {
+        PciInterruptMechanism {
+            device_internal: self.device,
+            kind: PciInterruptKind::Error,
+        }
+    }
+
+    /// Returns a thing that gives you control over the notification of requests from the kernel to
+    /// release a PCI device.
+    ///
+    /// Like [`PciInterrupts::error`], this isn't an interrupt mechanism of the device itself. The
+    /// kernel signals the eventfd enabled for its single vector when it wants the device back,
+    /// _e.g._, because it is being hot-unplugged or unbound from its driver. The kernel then waits
+    /// for the device to be closed, signalling the eventfd again from time to time, so you should
+    /// react by releasing the device gracefully.
+    pub fn request(&self) -> PciInterruptMechanism<'_> {
+        PciInterruptMechanism {
+            device_internal: self.device,
+            kind: PciInterruptKind::Request,
+        }
+    }
+
+    /// Enables up to `vectors` vectors of the best interrupt mechanism the device supports, using
+    /// eventfds created as if by [`PciInterruptMechanism::enable_count`].
+    ///
+    /// MSI-X is preferred, then MSI, then INTx, except that mechanisms with at least `vectors`
+    /// vectors are preferred over those with fewer. If enabling a mechanism fails, the next one is
+    /// tried. Returns which mechanism was enabled and the eventfds of its enabled vectors, of which
+    /// there may be fewer than `vectors`.
+    pub fn enable_best(
+        &self,
+        vectors: usize,
+        nonblocking: bool,
+    ) -> io::Result<(PciInterruptKind, Vec<OwnedFd>)> {
+        if vectors == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Must enable at least one vector",
+            ));
+        }
+
+        let mechanisms = [self.msi_x(), self.msi(), self.intx()];
+
+        // prefer a mechanism with enough vectors, and then the one with the most
+        let mut candidates: Vec<_> = mechanisms.iter().filter(|m| m.max() > 0).collect();
+        candidates.sort_by_key(|m| vectors.saturating_sub(m.max()));
+
+        let mut error = io::Error::new(
+            ErrorKind::NotFound,
+            "Device doesn't support any interrupt mechanism",
+        );
+
+        for mechanism in candidates {
+            match mechanism.enable_count(vectors.min(mechanism.max()), nonblocking) {
+                Ok(eventfds) => return Ok((mechanism.kind, eventfds)),
+                Err(e) => error = e,
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Returns which of INTx, MSI, and MSI-X is currently enabled, and with how many vectors, or
+    /// `None` if none is. See [`PciInterruptMechanism::enabled_vectors`].
+    ///
+    /// Devices can only use one of these mechanisms at a time.
+    pub fn enabled(&self) -> Option<(PciInterruptKind, usize)> {
+        [self.intx(), self.msi(), self.msi_x()]
+            .iter()
+            .map(|m| (m.kind, m.enabled_vectors()))
+            .find(|&(_, vectors)| vectors > 0)
+    }
+
+    /// Describes what each interrupt mechanism supports, in the order INTx, MSI, MSI-X. This
+    /// doesn't include [`PciInterrupts::error`] and [`PciInterrupts::request`].
+    ///
+    /// This lets you pick a mechanism, _e.g._, the first one that is supported and has enough
+    /// vectors, without trying to enable each in turn.
+    pub fn info(&self) -> Vec<PciInterruptInfo> {
+        vec![self.intx().info(), self.msi().info(), self.msi_x().info()]
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Describes what a PCI device's specific interrupt mechanism supports. See
+/// [`PciInterrupts::info`] and [`PciInterruptMechanism::info`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct PciInterruptInfo {
+    /// Which interrupt mechanism this describes.
+    pub kind: PciInterruptKind,
+    /// See [`PciInterruptMechanism::max`].
+    pub max: usize,
+    /// See [`PciInterruptMechanism::is_maskable`].
+    pub maskable: bool,
+    /// See [`PciInterruptMechanism::is_automasked`].
+    pub automasked: bool,
+    /// See [`PciInterruptMechanism::is_resizable`].
+    pub resizable: bool,
+}
+
+impl PciInterruptInfo {
+    /// Whether the device supports this interrupt mechanism at all, _i.e._, whether it has at
+    /// least one vector.
+    pub fn is_supported(&self) -> bool {
+        self.max > 0
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Gives you control over a PCI device's specific interrupt mechanism, which may be INTx, MSI, or
+/// MSI-X.
+pub struct PciInterruptMechanism<'a> {
+    pub(crate) device_internal: &'a dyn PciDeviceInternal,
+    pub(crate) kind: PciInterruptKind,
+}
+
+impl PciInterruptMechanism<'_> {
+    /// Which interrupt mechanism this is.
+    pub fn kind(&self) -> PciInterruptKind {
+        self.kind
+    }
+
+    /// Maximum number of vectors that may be enabled for this particular interrupt mechanism.
+    pub fn max(&self) -> usize {
+        self.device_internal.interrupts_max(self.kind)
+    }
+
+    /// The number of vectors of this particular interrupt mechanism that are currently enabled, as
+    /// tracked across calls to enabling and disabling methods. This is 0 if it is disabled.
+    ///
+    /// This includes vectors that were enabled without a trigger, _e.g._, by
+    /// [`PciInterruptMechanism::enable_range`] with a nonzero `start`.
+    pub fn enabled_vectors(&self) -> usize {
+        self.device_internal.interrupts_eventfds(self.kind).len()
+    }
+
+    /// Whether this particular interrupt mechanism is currently enabled, _i.e._, whether
+    /// [`PciInterruptMechanism::enabled_vectors`] is nonzero.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled_vectors() > 0
+    }
+
+    /// Describes what this particular interrupt mechanism supports.
+    pub fn info(&self) -> PciInterruptInfo {
+        PciInterruptInfo {
+            kind: self.kind,
+            max: self.max(),
+            maskable: self.is_maskable(),
+            automasked: self.is_automasked(),
+            resizable: self.is_resizable(),
+        }
+    }
+
+    /// Enables vectors `0` through `eventfds.len() - 1` of this particular interrupt mechanism.
+    ///
+    /// Fails if `eventfds.len() > self.max()`.
+    pub fn enable(&self, eventfds: &[RawFd]) -> io::Result<()> {
+        self.enable_range(0, eventfds)
+    }
+
+    /// Creates `count` eventfds and enables vectors `0` through `count - 1` of this particular
+    /// interrupt mechanism with them, returning the eventfds.
+    ///
+    /// The eventfds are created with the close-on-exec flag, and in non-blocking mode if
+    /// `nonblocking` is true, which you'll want if you use them with an event loop. As with
+    /// [`PciInterruptMechanism::enable_owned`], the device keeps the eventfds open for as long as
+    /// their vectors are enabled with them, and the returned ones are duplicates of them, which you
+    /// may close at any time.
+    ///
+    /// Fails if `count > self.max()`.
+    pub fn enable_count(&self, count: usize, nonblocking: bool) -> io::Result<Vec<OwnedFd>> {
+        let flags = if nonblocking {
+            EFD_CLOEXEC | EFD_NONBLOCK
+        } else {
+            EFD_CLOEXEC
+        };
+
+        let eventfds = (0..count)
+            .map(|_| {
+                let fd = unsafe { eventfd(0, flags) };
+                if fd < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+                }
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let duplicates = eventfds
+            .iter()
+            .map(OwnedFd::try_clone)
+            .collect::<io::Result<Vec<_>>>()?;
+
+        self.enable_owned(eventfds)?;
+
+        Ok(duplicates)
+    }
+
+    /// Enables vectors `start` through `start + eventfds.len() - 1` of this particular interrupt
+    /// mechanism, leaving the triggers of other vectors untouched.
+    ///
+    /// This lets you, _e.g._, enable only the MSI-X vectors of the queues currently in use, and
+    /// enable more later on. Note that if the mechanism isn't enabled yet, vectors `0` through
+    /// `start - 1` are also allocated, but without a trigger. With MSI, and with MSI-X on kernels
+    /// older than 6.5, vectors can't be added to an already enabled mechanism (see
+    /// [`PciInterruptMechanism::is_resizable`]): this then fails if `start + eventfds.len()`
+    /// exceeds the number of vectors that were initially enabled.
+    ///
+    /// For MSI, this also checks that the device's MSI Capability supports the requested number of
+    /// vectors, and updates its Multiple Message Enable and MSI Enable fields to match the enabled
+    /// vectors.
+    ///
+    /// Fails if `start + eventfds.len() > self.max()`.
+    pub fn enable_range(&self, start: usize, eventfds: &[RawFd]) -> io::Result<()> {
+        self.enable_internal(start, eventfds, None)
+    }
+
+    /// Same as [`PciInterruptMechanism::enable`], but takes borrowed file descriptors, like
+    /// `&[BorrowedFd]`, `&[OwnedFd]`, or `&[File]`, instead of raw ones.
+    ///
+    /// The device duplicates the eventfds and keeps the duplicates open for as long as their
+    /// vectors are enabled with them, as with [`PciInterruptMechanism::enable_owned`], so you may
+    /// close yours at any time.
+    pub fn enable_fds<F: AsFd>(&self, eventfds: &[F]) -> io::Result<()> {
+        self.enable_range_fds(0, eventfds)
+    }
+
+    /// Same as [`PciInterruptMechanism::enable_range`], but takes borrowed file descriptors, which
+    /// are duplicated. See [`PciInterruptMechanism::enable_fds`].
+    pub fn enable_range_fds<F: AsFd>(&self, start: usize, eventfds: &[F]) -> io::Result<()> {
+        let duplicates = eventfds
+            .iter()
+            .map(|fd| fd.as_fd().try_clone_to_owned())
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let raw_eventfds: Vec<RawFd> = duplicates.iter().map(AsRawFd::as_raw_fd).collect();
+        self.enable_internal(start, &raw_eventfds, Some(duplicates))
+    }
+
+    /// Same as [`PciInterruptMechanism::enable`], but hands the eventfds over to the device, which
+    /// keeps them open for as long as their vectors are enabled with them.
+    ///
+    /// Each eventfd is closed when its vector is disabled or given another eventfd, or when the
+    /// device is dropped.
+    pub fn enable_owned(&self, eventfds: Vec<OwnedFd>) -> io::Result<()> {
+        let raw_eventfds: Vec<RawFd> = eventfds.iter().map(AsRawFd::as_raw_fd).collect();
+        self.enable_internal(0, &raw_eventfds, Some(eventfds))
+    }
+
+    fn enable_internal(
+        &self,
+        start: usize,
+        eventfds: &[RawFd],
+        owned: Option<Vec<OwnedFd>>,
+    ) -> io::Result<()> {
+        if self.kind == PciInterruptKind::Msi {
+            self.check_msi_capability(start.saturating_add(eventfds.len()))?;
+        }
+
+        self.device_internal
+            .interrupts_enable(self.kind, start, eventfds, owned)?;
+
+        if self.kind == PciInterruptKind::Msi {
+            let enabled = self.device_internal.interrupts_eventfds(self.kind).len();
+
+            if let Err(e) = self.program_msi_capability(enabled) {
+                let _ = self.disable();
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Changes the eventfd that is signalled when the given, already enabled vector fires.
+    ///
+    /// The kernel swaps the trigger in place, so unlike disabling and re-enabling the mechanism,
+    /// this doesn't tear down and set up interrupt routing again, and no interrupts from other
+    /// vectors are lost. Pass `-1` to remove the vector's trigger while leaving it allocated.
+    pub fn update_vector(&self, index: usize, eventfd: RawFd) -> io::Result<()> {
+        self.enable_range(index, &[eventfd])
+    }
+
+    /// Fires the given, enabled vector as if the device had raised it, signalling its eventfd.
+    ///
+    /// This lets you exercise interrupt handling paths, _e.g._, in integration tests, without any
+    /// actual device activity.
+    pub fn trigger(&self, vector: usize) -> io::Result<()> {
+        self.device_internal.interrupts_trigger(self.kind, vector)
+    }
+
+    /// Disables all enabled vectors of this particular interrupt mechanism.
+    pub fn disable(&self) -> io::Result<()> {
+        self.device_internal.interrupts_disable(self.kind)?;
+
+        // without an MSI Capability, there is nothing to update
+        if self.kind == PciInterruptKind::Msi && self.find_msi_capability()?.is_some() {
+            self.program_msi_capability(0)?;
+        }
+
+        Ok(())
+    }
+
+    fn find_msi_capability(&self) -> io::Result<Option<MsiCapability<'_>>> {
+        Ok(self
+            .device_internal
+            .config_space()
+            .capabilities()?
+            .of_type::<MsiCapability>()?
+            .next())
+    }
+
+    fn msi_capability(&self) -> io::Result<MsiCapability<'_>> {
+        self.find_msi_capability()?
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Device has no MSI Capability"))
+    }
+
+    /// Checks that the MSI Capability supports the given number of vectors.
+    fn check_msi_capability(&self, vectors: usize) -> io::Result<()> {
+        let capable = self
+            .msi_capability()?
+            .message_control()
+            .multiple_message_capable()
+            .read()?;
+
+        // values above 0b101 (32 vectors) are reserved
+        if capable > 5 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "MSI Capability has reserved Multiple Message Capable value {}",
+                    capable
+                ),
+            ));
+        }
+
+        let supported = 1_usize << capable;
+
+        if vectors > supported {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "MSI Capability supports {} vectors, but {} were requested",
+                    supported, vectors
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Updates the MSI Capability to reflect that the given number of vectors are enabled, and
+    /// checks that it took effect.
+    fn program_msi_capability(&self, vectors: usize) -> io::Result<()> {
+        let capability = self.msi_capability()?;
+        let control = capability.message_control();
+
+        // the number of allocated vectors is a power of two
+        let log2 = vectors.next_power_of_two().trailing_zeros() as u8;
+
+        control.multiple_message_enable().write(log2)?;
+        control.msi_enable().write(vectors > 0)?;
+
+        let actual_log2 = control.multiple_message_enable().read()?;
+        let actual_enable = control.msi_enable().read()?;
+
+        if actual_log2 != log2 || actual_enable != (vectors > 0) {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                format!(
+                    "MSI Capability has MSI Enable = {} and Multiple Message Enable = {} but {} \
+                     vectors are enabled",
+                    actual_enable, actual_log2, vectors
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Masks this particular interrupt mechanism, so that the device's interrupts are not
+    /// delivered until [unmasked](PciInterruptMechanism::unmask).
+    ///
+    /// VFIO only supports masking INTx interrupts, so this fails for other mechanisms.
+    pub fn mask(&self) -> io::Result<()> {
+        self.device_internal.interrupts_set_masked(self.kind, true)
+    }
+
+    /// Unmasks this particular interrupt mechanism. See [`PciInterruptMechanism::mask`].
+    pub fn unmask(&self) -> io::Result<()> {
+        self.device_internal.interrupts_set_masked(self.kind, false)
+    }
+
+    /// Registers an eventfd that unmasks this particular interrupt mechanism whenever it is
+    /// signalled, as if by calling [`PciInterruptMechanism::unmask`]. Pass `-1` to unregister it.
+    ///
+    /// This is also known as a resample eventfd, and lets event-loop-driven drivers re-arm
+    /// level-triggered INTx interrupts by writing to an eventfd, which can be batched with other
+    /// work or even be done by another component (like KVM), instead of issuing an ioctl after
+    /// every interrupt.
+    ///
+    /// VFIO only supports this for INTx interrupts, so this fails for other mechanisms.
+    pub fn set_unmask_eventfd(&self, eventfd: RawFd) -> io::Result<()> {
+        self.device_internal
+            .interrupts_set_unmask_eventfd(self.kind, eventfd)
+    }
+
+    /// Blocks until the given vector's eventfd is signalled, and returns the number of times it was
+    /// signalled since it was last read, resetting it.
+    ///
+    /// Fails with [`ErrorKind::TimedOut`] if `timeout` is given and elapses first, and with
+    /// [`ErrorKind::InvalidInput`] if the vector isn't enabled with an eventfd owned by the device,
+    /// _i.e._, one given to [`PciInterruptMechanism::enable_owned`],
+    /// [`PciInterruptMechanism::enable_fds`], or [`PciInterruptMechanism::enable_range_fds`], or
+    /// created by [`PciInterruptMechanism::enable_count`]. Other eventfds might be closed while
+    /// waiting.
+    ///
+    /// This is meant for simple synchronous drivers and tests. Consider
+    /// [`PciInterruptMechanism::wait_any`] if you use several vectors.
+    pub fn wait(&self, vector: usize, timeout: Option<Duration>) -> io::Result<u64> {
+        let eventfd = self
+            .device_internal
+            .interrupts_owned_eventfds(self.kind)?
+            .into_iter()
+            .nth(vector)
+            .flatten()
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Vector {} is not enabled with an eventfd owned by the device",
+                        vector
+                    ),
+                )
+            })?;
+
+        wait_for_eventfds(&[eventfd.as_raw_fd()], timeout).map(|(_, count)| count)
+    }
+
+    /// Blocks until the eventfd of any enabled vector is signalled, and returns the index of that
+    /// vector and the number of times its eventfd was signalled since it was last read, resetting
+    /// it.
+    ///
+    /// If several eventfds are signalled, the one of the lowest vector is read. Fails with
+    /// [`ErrorKind::TimedOut`] if `timeout` is given and elapses first, and with
+    /// [`ErrorKind::InvalidInput`] if no vectors are enabled with an eventfd, or if some are
+    /// enabled with eventfds not owned by the device (see [`PciInterruptMechanism::wait`]).
+    pub fn wait_any(&self, timeout: Option<Duration>) -> io::Result<(usize, u64)> {
+        let eventfds = self.owned_eventfds()?;
+
+        if eventfds.iter().all(Option::is_none) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "No vectors are enabled with an eventfd",
+            ));
+        }
+
+        let raw_eventfds: Vec<RawFd> = eventfds
+            .iter()
+            .map(|fd| fd.as_ref().map_or(-1, AsRawFd::as_raw_fd))
+            .collect();
+
+        wait_for_eventfds(&raw_eventfds, timeout)
+    }
+
+    /// Duplicates of the eventfds of this particular interrupt mechanism's vectors, `None` for
+    /// vectors without one.
+    ///
+    /// Fails if any vector is enabled with an eventfd that the device doesn't own, since nothing
+    /// keeps it from being closed, and its number reused, while we use it.
+    pub(crate) fn owned_eventfds(&self) -> io::Result<Vec<Option<OwnedFd>>> {
+        let owned = self.device_internal.interrupts_owned_eventfds(self.kind)?;
+        let eventfds = self.device_internal.interrupts_eventfds(self.kind);
+
+        for (vector, &eventfd) in eventfds.iter().enumerate() {
+            if eventfd >= 0 && owned.get(vector).map_or(true, Option::is_none) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Vector {} is enabled with an eventfd not owned by the device",
+                        vector
+                    ),
+                ));
+            }
+        }
+
+        Ok(owned)
+    }
+
+    /// Whether this particular interrupt mechanism is automatically masked whenever an interrupt
+    /// is delivered.
+    ///
+    /// This is the case for VFIO's INTx, since INTx is level-triggered and the kernel must stop it
+    /// from firing over and over until the device is serviced. A typical handling loop then waits
+    /// for the eventfd to be signalled, services the device (_e.g._, reads and acknowledges its
+    /// interrupt status, causing it to deassert the interrupt), and calls
+    /// [`PciInterruptMechanism::unmask`].
+    pub fn is_automasked(&self) -> bool {
+        self.device_internal.interrupts_is_automasked(self.kind)
+    }
+
+    /// Whether this particular interrupt mechanism can be [masked](PciInterruptMechanism::mask),
+    /// and have an [unmask eventfd](PciInterruptMechanism::set_unmask_eventfd).
+    pub fn is_maskable(&self) -> bool {
+        self.device_internal.interrupts_is_maskable(self.kind)
+    }
+
+    /// Whether vectors can be added to this particular interrupt mechanism while it is enabled,
+    /// using [`PciInterruptMechanism::enable_range`], without disabling it first.
+    pub fn is_resizable(&self) -> bool {
+        self.device_internal.interrupts_is_resizable(self.kind)
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Waits until one of the given eventfds (negative ones are ignored) is signalled, and reads it.
+/// Returns the index of the eventfd and the value read from it.
+fn wait_for_eventfds(eventfds: &[RawFd], timeout: Option<Duration>) -> io::Result<(usize, u64)> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+
+    let mut pollfds: Vec<pollfd> = eventfds
+        .iter()
+        .map(|&fd| pollfd {
+            fd,
+            events: POLLIN,
+            revents: 0,
+        })
+        .collect();
+
+    loop {
+        let timeout_ms = match deadline {
+            None => -1,
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                // round up so that we don't spin when less than a millisecond remains
+                let ms = (remaining.as_micros() + 999) / 1000;
+                ms.try_into().unwrap_or(i32::MAX)
+            }
+        };
+
+        let ret = unsafe { poll(pollfds.as_mut_ptr(), pollfds.len() as _, timeout_ms) };
+
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(EINTR) {
+                continue;
+            }
+            return Err(e);
+        }
+
+        if ret == 0 {
+            if timeout_ms != 0 {
+                continue; // recompute the remaining time, in case poll() returned early
+            }
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "Timed out waiting for interrupt",
+            ));
+        }
+
+        for (index, pollfd) in pollfds.iter().enumerate() {
+            if pollfd.revents != 0 {
+                match read_eventfd(pollfd.fd) {
+                    Ok(count) => return Ok((index, count)),
+                    // someone else read it first, if the eventfd is non-blocking
+                    Err(e) if e.raw_os_error() == Some(EAGAIN) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+}
+
+fn write_eventfd(fd: RawFd, value: u64) -> io::Result<()> {
+    let ret = unsafe {
+        write(
+            fd,
+            &value as *const u64 as *const c_void,
+            mem::size_of::<u64>(),
+        )
+    };
+
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn read_eventfd(fd: RawFd) -> io::Result<u64> {
+    let mut count: u64 = 0;
+
+    let ret = unsafe {
+        read(
+            fd,
+            &mut count as *mut u64 as *mut c_void,
+            mem::size_of::<u64>(),
+        )
+    };
+
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(count)
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Identifies an interrupt mechanism.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum PciInterruptKind {
+    /// Legacy, level-triggered INTx interrupts.
+    Intx = 0,
+    /// Message Signaled Interrupts.
+    Msi = 1,
+    /// Extended Message Signaled Interrupts.
+    MsiX = 2,
+    /// Error notifications. See [`PciInterrupts::error`].
+    Error = 3,
+    /// Requests to release the device. See [`PciInterrupts::request`].
+    Request = 4,
+}
+
+impl PciInterruptKind {
+    fn from_index(index: u64) -> Option<PciInterruptKind> {
+        match index {
+            0 => Some(PciInterruptKind::Intx),
+            1 => Some(PciInterruptKind::Msi),
+            2 => Some(PciInterruptKind::MsiX),
+            3 => Some(PciInterruptKind::Error),
+            4 => Some(PciInterruptKind::Request),
+            _ => None,
+        }
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use libc::{c_void, close, eventfd, write, EFD_NONBLOCK};
+    use std::io::ErrorKind;
+    use std::mem;
+    use std::os::unix::io::BorrowedFd;
+    use std::time::Duration;
+
+    use crate::backends::mock::MockDeviceBuilder;
+    use crate::device::PciDevice;
+    use crate::interrupts::{wait_for_eventfds, InterruptPoller, PciInterruptKind};
+    use crate::regions::PciRegion;
+
+    #[test]
+    fn test_wait_for_eventfds() {
+        let a = unsafe { eventfd(0, EFD_NONBLOCK) };
+        let b = unsafe { eventfd(0, EFD_NONBLOCK) };
+        assert!(a >= 0 && b >= 0);
+
+        let eventfds = [-1, a, b];
+
+        let timeout = Some(Duration::from_millis(10));
+
+        let e = wait_for_eventfds(&eventfds, timeout).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+
+        for _ in 0..3 {
+            let value: u64 = 1;
+            let ret = unsafe {
+                write(
+                    eventfds[2],
+                    &value as *const u64 as *const c_void,
+                    mem::size_of::<u64>(),
+                )
+            };
+            assert_eq!(ret, mem::size_of::<u64>() as isize);
+        }
+
+        assert_eq!(wait_for_eventfds(&eventfds, timeout).unwrap(), (2, 3));
+        assert!(wait_for_eventfds(&eventfds, Some(Duration::from_millis(0))).is_err());
+
+        for &fd in &eventfds[1..] {
+            unsafe { close(fd) };
+        }
+    }
+
+    #[test]
+    fn test_msi_disable() {
+        // no MSI Capability
+        let device = MockDeviceBuilder::new(0x1234, 0x5678)
+            .interrupts(PciInterruptKind::Msi, 1)
+            .build();
+
+        let interrupts = device.interrupts();
+        assert!(interrupts.msi().enable_count(1, false).is_err());
+        interrupts.msi().disable().unwrap();
+
+        // 32-bit MSI Capability with MSI Enable set and Multiple Message Capable = 2 vectors
+        let device = MockDeviceBuilder::new(0x1234, 0x5678)
+            .capability(0x05, &[0x03, 0x00, 0, 0, 0, 0, 0, 0])
+            .interrupts(PciInterruptKind::Msi, 2)
+            .build();
+
+        let interrupts = device.interrupts();
+        let msi = interrupts.msi();
+
+        let _eventfds = msi.enable_count(2, false).unwrap();
+        let control = device.config().read_le_u16(0x42).unwrap();
+        assert_eq!(control & 0x71, 0x11);
+
+        msi.disable().unwrap();
+        assert_eq!(device.config().read_le_u16(0x42).unwrap() & 0x71, 0x00);
+    }
+
+    #[test]
+    fn test_wait_uses_owned_eventfds() {
+        let device = MockDeviceBuilder::new(0x1234, 0x5678)
+            .interrupts(PciInterruptKind::MsiX, 2)
+            .build();
+
+        let interrupts = device.interrupts();
+        let msi_x = interrupts.msi_x();
+        let timeout = Some(Duration::from_secs(1));
+
+        // the device keeps its own eventfds open
+        drop(msi_x.enable_count(2, true).unwrap());
+
+        let poller = InterruptPoller::new().unwrap();
+        poller.add_mechanism(&msi_x).unwrap();
+
+        msi_x.trigger(1).unwrap();
+        assert_eq!(msi_x.wait_any(timeout).unwrap(), (1, 1));
+
+        msi_x.trigger(0).unwrap();
+        let events = poller.poll(timeout).unwrap();
+        assert_eq!((events[0].vector, events[0].count), (0, 1));
+
+        // borrowed eventfds are duplicated
+        let eventfd = unsafe { eventfd(0, EFD_NONBLOCK) };
+        assert!(eventfd >= 0);
+        msi_x
+            .enable_range_fds(1, &[unsafe { BorrowedFd::borrow_raw(eventfd) }])
+            .unwrap();
+        msi_x.trigger(1).unwrap();
+        assert_eq!(msi_x.wait(1, timeout).unwrap(), 1);
+
+        // raw eventfds the device doesn't own may be closed at any time
+        msi_x.update_vector(1, eventfd).unwrap();
+
+        assert_eq!(
+            msi_x.wait(1, timeout).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert!(msi_x.wait_any(timeout).is_err());
+        assert!(InterruptPoller::new()
+            .unwrap()
+            .add_mechanism(&msi_x)
+            .is_err());
+
+        msi_x.disable().unwrap();
+        unsafe { close(eventfd) };
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -0,0 +1,430 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use libc::{
+    epoll_create1, epoll_ctl, epoll_event, epoll_wait, eventfd, EAGAIN, EFD_CLOEXEC, EFD_NONBLOCK,
+    EINTR, EPOLLIN, EPOLL_CLOEXEC, EPOLL_CTL_ADD, EPOLL_CTL_DEL,
+};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::interrupts::{read_eventfd, write_eventfd, PciInterruptKind, PciInterruptMechanism};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// The maximum number of events returned by a single call to [`InterruptPoller::poll`].
+const MAX_EVENTS: usize = 256;
+
+/// The epoll data of the eventfd used by [`InterruptPoller::wake`], which can't collide with
+/// those of vectors since their upper half is a [`PciInterruptKind`].
+const WAKE_TOKEN: u64 = u64::MAX;
+
+/// Waits for interrupts on many vectors at once, using a single epoll instance.
+///
+/// Register the eventfds of the vectors you are interested in with [`InterruptPoller::add`] or
+/// [`InterruptPoller::add_mechanism`], and then call [`InterruptPoller::poll`] to get the vectors
+/// whose eventfds were signalled. This spares drivers with many MSI-X vectors from keeping track
+/// of which eventfd belongs to which vector.
+///
+/// The poller also counts the interrupts delivered to each registered vector, which you can get
+/// with [`InterruptPoller::counts`], _e.g._, to report interrupt rates or to detect vectors that
+/// never fire due to some misconfiguration.
+///
+/// Eventfds registered with [`InterruptPoller::add`] aren't owned by the poller, and must be kept
+/// open for as long as they are registered. [`InterruptPoller::add_mechanism`] instead registers
+/// duplicates of the eventfds owned by the device, which the poller keeps open itself.
+///
+/// This is thread-safe.
+#[derive(Debug)]
+pub struct InterruptPoller {
+    epoll: File,
+    waker: File,
+    registered: Mutex<HashMap<(PciInterruptKind, usize), Registration>>,
+}
+
+#[derive(Debug)]
+struct Registration {
+    eventfd: RawFd,
+    /// Set if the poller keeps `eventfd` open itself.
+    _owned: Option<OwnedFd>,
+    /// The number of interrupts delivered since registration or the last reset.
+    count: u64,
+}
+
+/// An interrupt reported by [`InterruptPoller::poll`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InterruptEvent {
+    /// The interrupt mechanism of the vector.
+    pub kind: PciInterruptKind,
+    /// The index of the vector.
+    pub vector: usize,
+    /// How many times the vector's eventfd was signalled since it was last read.
+    pub count: u64,
+}
+
+impl InterruptPoller {
+    /// Creates a poller with no registered vectors.
+    pub fn new() -> io::Result<InterruptPoller> {
+        let fd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let epoll = unsafe { File::from_raw_fd(fd) };
+
+        let fd = unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let waker = unsafe { File::from_raw_fd(fd) };
+
+        let mut event = epoll_event {
+            events: EPOLLIN as u32,
+            u64: WAKE_TOKEN,
+        };
+
+        let ret = unsafe { epoll_ctl(epoll.as_raw_fd(), EPOLL_CTL_ADD, fd, &mut event) };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(InterruptPoller {
+            epoll,
+            waker,
+            registered: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Registers the eventfd of the given vector.
+    ///
+    /// Fails if the vector is already registered.
+    pub fn add(&self, kind: PciInterruptKind, vector: usize, eventfd: RawFd) -> io::Result<()> {
+        self.add_internal(kind, vector, eventfd, None)
+    }
+
+    /// Like [`InterruptPoller::add`], but keeps the eventfd open for as long as it is registered.
+    pub(crate) fn add_owned(
+        &self,
+        kind: PciInterruptKind,
+        vector: usize,
+        eventfd: OwnedFd,
+    ) -> io::Result<()> {
+        self.add_internal(kind, vector, eventfd.as_raw_fd(), Some(eventfd))
+    }
+
+    fn add_internal(
+        &self,
+        kind: PciInterruptKind,
+        vector: usize,
+        eventfd: RawFd,
+        owned: Option<OwnedFd>,
+    ) -> io::Result<()> {
+        let mut registered = self.registered.lock().unwrap();
+
+        if registered.contains_key(&(kind, vector)) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{:?} vector {} is already registered", kind, vector),
+            ));
+        }
+
+        let mut event = epoll_event {
+            events: EPOLLIN as u32,
+            u64: ((kind as u64) << 32) | vector as u64,
+        };
+
+        let ret = unsafe { epoll_ctl(self.epoll.as_raw_fd(), EPOLL_CTL_ADD, eventfd, &mut event) };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        registered.insert(
+            (kind, vector),
+            Registration {
+                eventfd,
+                _owned: owned,
+                count: 0,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Registers the eventfds of all vectors of the given mechanism that are currently enabled with
+    /// one.
+    ///
+    /// The poller keeps duplicates of the eventfds open, so they need not outlive the vectors'
+    /// current configuration. This fails if any vector is enabled with an eventfd that the device
+    /// doesn't own (see [`PciInterruptMechanism::wait`]).
+    pub fn add_mechanism(&self, mechanism: &PciInterruptMechanism) -> io::Result<()> {
+        for (vector, eventfd) in mechanism.owned_eventfds()?.into_iter().enumerate() {
+            if let Some(eventfd) = eventfd {
+                self.add_owned(mechanism.kind, vector, eventfd)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unregisters the given vector.
+    ///
+    /// Fails if the vector isn't registered.
+    pub fn remove(&self, kind: PciInterruptKind, vector: usize) -> io::Result<()> {
+        let mut registered = self.registered.lock().unwrap();
+
+        let eventfd = registered
+            .get(&(kind, vector))
+            .map(|r| r.eventfd)
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("{:?} vector {} is not registered", kind, vector),
+                )
+            })?;
+
+        let ret = unsafe {
+            epoll_ctl(
+                self.epoll.as_raw_fd(),
+                EPOLL_CTL_DEL,
+                eventfd,
+                ptr::null_mut(),
+            )
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        registered.remove(&(kind, vector));
+
+        Ok(())
+    }
+
+    /// The number of registered vectors.
+    pub fn len(&self) -> usize {
+        self.registered.lock().unwrap().len()
+    }
+
+    /// Whether no vectors are registered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Blocks until the eventfd of at least one registered vector is signalled, and returns the
+    /// vectors whose eventfds were signalled, reading (and thus resetting) those eventfds.
+    ///
+    /// At most 256 vectors are returned at once; call this again to get the rest. Returns an empty
+    /// vector if `timeout` is given and elapses first, or if [`InterruptPoller::wake`] is called.
+    pub fn poll(&self, timeout: Option<Duration>) -> io::Result<Vec<InterruptEvent>> {
+        let timeout_ms = match timeout {
+            None => -1,
+            Some(t) => ((t.as_micros() + 999) / 1000)
+                .try_into()
+                .unwrap_or(i32::MAX),
+        };
+
+        let mut events = vec![epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+
+        let num_events = loop {
+            let ret = unsafe {
+                epoll_wait(
+                    self.epoll.as_raw_fd(),
+                    events.as_mut_ptr(),
+                    MAX_EVENTS as i32,
+                    timeout_ms,
+                )
+            };
+
+            if ret >= 0 {
+                break ret as usize;
+            }
+
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(EINTR) {
+                return Err(e);
+            }
+        };
+
+        let mut interrupts = Vec::with_capacity(num_events);
+
+        for event in &events[..num_events] {
+            let data = event.u64;
+
+            if data == WAKE_TOKEN {
+                match read_eventfd(self.waker.as_raw_fd()) {
+                    Ok(_) => {}
+                    Err(e) if e.raw_os_error() == Some(EAGAIN) => {}
+                    Err(e) => return Err(e),
+                }
+                continue;
+            }
+
+            let kind = PciInterruptKind::from_index(data >> 32).unwrap();
+            let vector = (data & 0xffff_ffff) as usize;
+
+            let eventfd = match self.registered.lock().unwrap().get(&(kind, vector)) {
+                Some(registration) => registration.eventfd,
+                None => continue, // removed concurrently
+            };
+
+            match read_eventfd(eventfd) {
+                Ok(count) => {
+                    if let Some(registration) =
+                        self.registered.lock().unwrap().get_mut(&(kind, vector))
+                    {
+                        registration.count = registration.count.saturating_add(count);
+                    }
+                    interrupts.push(InterruptEvent {
+                        kind,
+                        vector,
+                        count,
+                    });
+                }
+                // someone else read it first, if the eventfd is non-blocking
+                Err(e) if e.raw_os_error() == Some(EAGAIN) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(interrupts)
+    }
+
+    /// Returns, for each registered vector, the number of interrupts delivered to it since it was
+    /// registered or [`InterruptPoller::reset_counts`] was last called, sorted by kind and vector.
+    ///
+    /// Only interrupts returned by [`InterruptPoller::poll`] are counted.
+    pub fn counts(&self) -> Vec<InterruptEvent> {
+        let mut counts: Vec<_> = self
+            .registered
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(kind, vector), registration)| InterruptEvent {
+                kind,
+                vector,
+                count: registration.count,
+            })
+            .collect();
+
+        counts.sort_by_key(|event| (event.kind as usize, event.vector));
+        counts
+    }
+
+    /// Resets the counts of all registered vectors to zero.
+    pub fn reset_counts(&self) {
+        for registration in self.registered.lock().unwrap().values_mut() {
+            registration.count = 0;
+        }
+    }
+
+    /// Makes a [`InterruptPoller::poll`] call that is blocked in another thread return, or if there
+    /// is none, makes the next call return immediately.
+    pub fn wake(&self) -> io::Result<()> {
+        write_eventfd(self.waker.as_raw_fd(), 1)
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use libc::{close, eventfd, EFD_NONBLOCK};
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::interrupts::{write_eventfd, InterruptEvent, InterruptPoller, PciInterruptKind};
+
+    #[test]
+    fn test_interrupt_poller() {
+        let a = unsafe { eventfd(0, EFD_NONBLOCK) };
+        let b = unsafe { eventfd(0, EFD_NONBLOCK) };
+        assert!(a >= 0 && b >= 0);
+
+        let event = |kind, vector, count| InterruptEvent {
+            kind,
+            vector,
+            count,
+        };
+
+        let timeout = Some(Duration::from_millis(10));
+
+        let poller = Arc::new(InterruptPoller::new().unwrap());
+        assert!(poller.is_empty());
+
+        poller.add(PciInterruptKind::MsiX, 0, a).unwrap();
+        poller.add(PciInterruptKind::MsiX, 7, b).unwrap();
+        assert_eq!(
+            poller.add(PciInterruptKind::MsiX, 7, b).unwrap_err().kind(),
+            ErrorKind::AlreadyExists
+        );
+        assert_eq!(poller.len(), 2);
+
+        assert_eq!(poller.poll(timeout).unwrap(), []);
+
+        write_eventfd(b, 2).unwrap();
+        assert_eq!(
+            poller.poll(timeout).unwrap(),
+            [event(PciInterruptKind::MsiX, 7, 2)]
+        );
+
+        write_eventfd(a, 1).unwrap();
+        write_eventfd(b, 1).unwrap();
+        let mut events = poller.poll(timeout).unwrap();
+        events.sort_by_key(|e| e.vector);
+        assert_eq!(
+            events,
+            [
+                event(PciInterruptKind::MsiX, 0, 1),
+                event(PciInterruptKind::MsiX, 7, 1),
+            ]
+        );
+
+        assert_eq!(
+            poller.counts(),
+            [
+                event(PciInterruptKind::MsiX, 0, 1),
+                event(PciInterruptKind::MsiX, 7, 3),
+            ]
+        );
+        poller.reset_counts();
+        assert!(poller.counts().iter().all(|e| e.count == 0));
+
+        // removed vectors are no longer reported
+        poller.remove(PciInterruptKind::MsiX, 0).unwrap();
+        assert_eq!(
+            poller.remove(PciInterruptKind::MsiX, 0).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        write_eventfd(a, 1).unwrap();
+        assert_eq!(poller.poll(timeout).unwrap(), []);
+
+        // waking up a blocked poll
+        let waiter = {
+            let poller = Arc::clone(&poller);
+            thread::spawn(move || poller.poll(None))
+        };
+        poller.wake().unwrap();
+        assert_eq!(waiter.join().unwrap().unwrap(), []);
+
+        unsafe {
+            close(a);
+            close(b);
+        }
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
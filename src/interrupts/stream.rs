@@ -0,0 +1,132 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use futures_core::Stream;
+use libc::{fcntl, F_GETFL, F_SETFL, O_NONBLOCK};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::unix::AsyncFd;
+
+use crate::interrupts::read_eventfd;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// A [`Stream`] of the interrupts signalled through a set of eventfds, for use with the tokio
+/// runtime.
+///
+/// Give the same eventfds to, _e.g._, [`PciInterruptMechanism::enable`], and the stream then
+/// yields `(vector, count)` pairs, where `vector` is the index of the eventfd in the set and
+/// `count` is the number of times it was signalled since it was last read. The stream never ends.
+///
+/// This is only available with the `async` crate feature enabled.
+///
+/// [`PciInterruptMechanism::enable`]: crate::interrupts::PciInterruptMechanism::enable
+#[derive(Debug)]
+pub struct IrqStream<F: AsRawFd> {
+    eventfds: Box<[AsyncFd<F>]>,
+    /// Where to start looking for a signalled eventfd next, so that busy vectors don't starve
+    /// others.
+    next: usize,
+    /// The number of interrupts yielded per vector since creation or the last reset.
+    counts: Box<[u64]>,
+}
+
+impl<F: AsRawFd> IrqStream<F> {
+    /// Creates a stream of the interrupts signalled through the given eventfds, which are put in
+    /// non-blocking mode.
+    ///
+    /// Must be called from within a tokio runtime with I/O enabled.
+    pub fn new(eventfds: Vec<F>) -> io::Result<IrqStream<F>> {
+        let eventfds: Box<[_]> = eventfds
+            .into_iter()
+            .map(|eventfd| {
+                set_nonblocking(eventfd.as_raw_fd())?;
+                AsyncFd::new(eventfd)
+            })
+            .collect::<io::Result<_>>()?;
+
+        let counts = vec![0; eventfds.len()].into_boxed_slice();
+
+        Ok(IrqStream {
+            eventfds,
+            next: 0,
+            counts,
+        })
+    }
+
+    /// The eventfds this stream reads from.
+    pub fn eventfds(&self) -> impl Iterator<Item = &F> {
+        self.eventfds.iter().map(AsyncFd::get_ref)
+    }
+
+    /// The number of interrupts yielded for each vector since the stream was created or
+    /// [`IrqStream::reset_counts`] was last called.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Resets the counts of all vectors to zero.
+    pub fn reset_counts(&mut self) {
+        self.counts.iter_mut().for_each(|count| *count = 0);
+    }
+
+    /// Consumes the stream, returning its eventfds.
+    pub fn into_eventfds(self) -> Vec<F> {
+        self.eventfds
+            .into_vec()
+            .into_iter()
+            .map(AsyncFd::into_inner)
+            .collect()
+    }
+}
+
+impl<F: AsRawFd> Unpin for IrqStream<F> {}
+
+impl<F: AsRawFd> Stream for IrqStream<F> {
+    type Item = io::Result<(usize, u64)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let len = self.eventfds.len();
+
+        for i in 0..len {
+            let vector = (self.next + i) % len;
+
+            loop {
+                let mut guard = match self.eventfds[vector].poll_read_ready(cx) {
+                    Poll::Ready(Ok(guard)) => guard,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    Poll::Pending => break,
+                };
+
+                match guard.try_io(|eventfd| read_eventfd(eventfd.as_raw_fd())) {
+                    Ok(Ok(count)) => {
+                        self.counts[vector] = self.counts[vector].saturating_add(count);
+                        self.next = (vector + 1) % len;
+                        return Poll::Ready(Some(Ok((vector, count))));
+                    }
+                    Ok(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+fn set_nonblocking(fd: i32) -> io::Result<()> {
+    let flags = unsafe { fcntl(fd, F_GETFL) };
+
+    if flags < 0 || unsafe { fcntl(fd, F_SETFL, flags | O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/* ---------------------------------------------------------------------------------------------- */
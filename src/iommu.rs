@@ -2,9 +2,17 @@
 
 /* ---------------------------------------------------------------------------------------------- */
 
-use std::io;
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::io::{self, ErrorKind};
+use std::mem;
 use std::ops::Range;
+use std::sync::Mutex;
 
+#[cfg(feature = "vm-memory")]
+use vm_memory::{Address, GuestMemoryBackend, GuestMemoryRegion, MemoryRegionAddress};
+
+use crate::config::ext_caps::PasidExtendedCapability;
 use crate::regions::Permissions;
 
 /* ---------------------------------------------------------------------------------------------- */
@@ -14,11 +22,15 @@ use crate::regions::Permissions;
 /// You'll probably need [`std::sync::atomic::fence`] or use types like
 /// [`AtomicU32`](std::sync::atomic::AtomicU32) somewhere to synchronize accesses properly with the
 /// device.
+///
+/// See also [`DmaBuffer`](crate::dma::DmaBuffer), which takes care of allocating, mapping, and
+/// unmapping memory for you.
+#[derive(Clone, Copy, Debug)]
 pub struct PciIommu<'a> {
     pub(crate) internal: &'a dyn PciIommuInternal,
 }
 
-impl PciIommu<'_> {
+impl<'a> PciIommu<'a> {
     /// Both `iova` and process `address` must be aligned to this value.
     ///
     /// This is always a power of 2, and never less than the system's page size.
@@ -26,17 +38,42 @@ impl PciIommu<'_> {
         self.internal.alignment()
     }
 
+    /// The sizes of the pages that the IOMMU can use to map memory, as a bitmap: bit `n` is set if
+    /// pages of `1 << n` bytes are supported.
+    ///
+    /// The smallest of these is [`PciIommu::alignment`]. Mappings whose IOVA, process address,
+    /// and length are all aligned to a bigger page size (and which are backed by equally big
+    /// pages in the process) may be mapped using bigger pages, reducing IOTLB pressure.
+    pub fn page_sizes(&self) -> u64 {
+        self.internal.page_sizes()
+    }
+
     /// IOVA ranges given to [`PciIommu::map`] must be contained in one of the ranges that this
     /// method returns.
+    ///
+    /// These exclude the regions that the IOMMU reserves for other purposes, like the x86 MSI
+    /// window at `0xfee00000`.
     pub fn valid_iova_ranges(&self) -> &[Range<u64>] {
         self.internal.valid_iova_ranges()
     }
 
     /// The maximum number of mappings that may be in effect simultaneously.
+    ///
+    /// Trying to add more mappings than this fails. If you need lots of mappings, consider
+    /// coalescing them, _e.g._, using [`PciIommu::batch_map`].
     pub fn max_num_mappings(&self) -> u32 {
         self.internal.max_num_mappings()
     }
 
+    /// The number of mappings currently in effect.
+    ///
+    /// This may include mappings added through other means than this `PciIommu`, _e.g._, through
+    /// other devices sharing the same IOMMU. Depending on the backend, this may only work if the
+    /// IOMMU tracks its mappings, see [`PciIommu::remap`].
+    pub fn num_mappings(&self) -> io::Result<u32> {
+        self.internal.num_mappings()
+    }
+
     /// Add the given mapping to the IOMMU.
     ///
     /// - `iova` is the start address of the region in the device's address space.
@@ -68,17 +105,446 @@ impl PciIommu<'_> {
     pub fn unmap(&self, iova: u64, size: usize) -> io::Result<()> {
         self.internal.unmap(iova, size)
     }
+
+    /// Remove all mappings contained in the given range from the IOMMU, returning the number of
+    /// bytes that were actually unmapped.
+    ///
+    /// Unlike [`PciIommu::unmap`], the range doesn't have to be fully covered by mappings, and
+    /// this may return less than `size`, or even 0, if parts of the range weren't mapped. However,
+    /// the range may still not cover only part of a mapping, as the kernel can't split mappings.
+    ///
+    /// Backends that can't report how much was unmapped behave like [`PciIommu::unmap`] and return
+    /// `size` on success.
+    pub fn unmap_range(&self, iova: u64, size: usize) -> io::Result<u64> {
+        self.internal.unmap_range(iova, size)
+    }
+
+    /// Remove all mappings from the IOMMU, _e.g._, to tear down a device's DMA state
+    /// deterministically, or to reuse the IOMMU context from scratch.
+    ///
+    /// Backends use a single kernel operation where available, and may otherwise need to be
+    /// tracking their mappings, failing if they aren't. For the VFIO backend, see
+    /// `VfioContainer::unmap_all`.
+    pub fn unmap_all(&self) -> io::Result<()> {
+        self.internal.unmap_all()
+    }
+
+    /// Like [`PciIommu::map`], but returns a guard that removes the mapping when dropped.
+    ///
+    /// This makes it hard to forget to remove mappings, _e.g._, in error paths. Use
+    /// [`MappingGuard::leak`] or [`MappingGuard::into_raw`] to keep the mapping around.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`PciIommu::map`].
+    pub unsafe fn map_guarded(
+        &self,
+        iova: u64,
+        length: usize,
+        address: *const u8,
+        device_permissions: Permissions,
+    ) -> io::Result<MappingGuard<'a>> {
+        unsafe { self.map(iova, length, address, device_permissions)? };
+
+        Ok(MappingGuard {
+            iommu: *self,
+            mapping: IommuMapping {
+                iova,
+                length,
+                address,
+                device_permissions,
+            },
+        })
+    }
+
+    /// Replace the process memory that backs an existing mapping, keeping its IOVA range and
+    /// permissions.
+    ///
+    /// `iova` and `length` must match exactly a mapping previously added through an IOMMU that
+    /// tracks its mappings, such as a [`VfioContainer`](crate::backends::vfio::VfioContainer) with
+    /// [mapping tracking](crate::backends::vfio::VfioContainer::set_mapping_tracking) enabled.
+    /// Otherwise, this fails.
+    ///
+    /// The kernel doesn't let mappings be replaced in place, so the device may briefly observe the
+    /// range as unmapped. If adding the new mapping fails, the old one is restored.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`PciIommu::map`], with `new_address` as the process address.
+    pub unsafe fn remap(&self, iova: u64, length: usize, new_address: *const u8) -> io::Result<()> {
+        unsafe { self.internal.remap(iova, length, new_address) }
+    }
+
+    /// Change the operations the device may perform on an existing mapping.
+    ///
+    /// As for [`PciIommu::remap`], `iova` and `length` must match exactly a mapping previously
+    /// added through an IOMMU that tracks its mappings, and the device may briefly observe the
+    /// range as unmapped. If adding the mapping with the new permissions fails, the old one is
+    /// restored.
+    ///
+    /// This is handy for, _e.g._, write-protecting buffers while validating their contents.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`PciIommu::map`], with `device_permissions` as the new permissions.
+    pub unsafe fn protect(
+        &self,
+        iova: u64,
+        length: usize,
+        device_permissions: Permissions,
+    ) -> io::Result<()> {
+        unsafe { self.internal.protect(iova, length, device_permissions) }
+    }
+
+    /// Make DMA that devices tag with the given PASID (Process Address Space ID) be translated
+    /// using this IOMMU's mappings.
+    ///
+    /// Devices must also have PASID enabled in their
+    /// [PASID Extended Capability](crate::config::ext_caps::PasidExtendedCapability) for this to
+    /// be useful, and you can use a [`PasidAllocator`] to pick PASIDs.
+    ///
+    /// PASIDs are attached to a single function, so this only works on the `PciIommu` of a device,
+    /// and fails with [`ErrorKind::Unsupported`] if the backend doesn't support PASIDs. With the VFIO backend, the device must have
+    /// been opened with
+    /// [`VfioPciDevice::open_cdev`](crate::backends::vfio::VfioPciDevice::open_cdev), and this
+    /// requires Linux 6.14 or later and an IOMMU that supports PASIDs.
+    pub fn attach_pasid(&self, pasid: u32) -> io::Result<()> {
+        self.internal.attach_pasid(pasid)
+    }
+
+    /// Undo [`PciIommu::attach_pasid`].
+    pub fn detach_pasid(&self, pasid: u32) -> io::Result<()> {
+        self.internal.detach_pasid(pasid)
+    }
+
+    /// Add all the given mappings to the IOMMU, reporting one result per mapping and the mappings
+    /// that were actually added.
+    ///
+    /// Mappings that are contiguous both in IOVA space and in the process' address space and have
+    /// the same permissions are coalesced and added as a single mapping, which is a lot faster than
+    /// calling [`PciIommu::map`] for each of them when there are many. If adding a coalesced
+    /// mapping fails, its constituent mappings are retried one by one, so that each gets its own
+    /// result.
+    ///
+    /// Backends can only remove whole mappings, so a coalesced mapping can't be removed piecemeal:
+    /// use the ranges in [`BatchMapResult::mappings`] when removing them, _e.g._, by passing them
+    /// to [`PciIommu::batch_unmap`].
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`PciIommu::map`], for every mapping.
+    pub unsafe fn batch_map(&self, mappings: &[IommuMapping]) -> BatchMapResult {
+        let mut order: Vec<usize> = (0..mappings.len()).collect();
+        order.sort_by_key(|&i| mappings[i].iova);
+
+        let mut results: Vec<Option<io::Result<()>>> = mappings.iter().map(|_| None).collect();
+        let mut added = Vec::new();
+
+        for run in coalesce(&order, |prev, next| {
+            let (prev, next) = (&mappings[prev], &mappings[next]);
+            prev.device_permissions == next.device_permissions
+                && prev.iova.checked_add(prev.length as u64) == Some(next.iova)
+                && (prev.address as usize).checked_add(prev.length) == Some(next.address as usize)
+        }) {
+            let first = &mappings[run[0]];
+
+            if run.len() > 1 {
+                let length = run.iter().map(|&i| mappings[i].length).sum();
+                let result = unsafe {
+                    self.internal
+                        .map(first.iova, length, first.address, first.device_permissions)
+                };
+
+                if result.is_ok() {
+                    for &i in run {
+                        results[i] = Some(Ok(()));
+                    }
+                    added.push(IommuMapping { length, ..*first });
+                    continue;
+                }
+            }
+
+            for &i in run {
+                let m = &mappings[i];
+                let result = unsafe {
+                    self.internal
+                        .map(m.iova, m.length, m.address, m.device_permissions)
+                };
+                if result.is_ok() {
+                    added.push(*m);
+                }
+                results[i] = Some(result);
+            }
+        }
+
+        BatchMapResult {
+            results: results.into_iter().map(Option::unwrap).collect(),
+            mappings: added,
+        }
+    }
+
+    /// Remove all the given mappings from the IOMMU, returning one result per `(iova, size)` pair,
+    /// in the same order.
+    ///
+    /// Contiguous ranges are coalesced and removed in one go, as for [`PciIommu::batch_map`]. If
+    /// that fails, they are retried one by one. The same constraints as for [`PciIommu::unmap`]
+    /// apply.
+    pub fn batch_unmap(&self, ranges: &[(u64, usize)]) -> Vec<io::Result<()>> {
+        let mut order: Vec<usize> = (0..ranges.len()).collect();
+        order.sort_by_key(|&i| ranges[i].0);
+
+        let mut results: Vec<Option<io::Result<()>>> = ranges.iter().map(|_| None).collect();
+
+        for run in coalesce(&order, |prev, next| {
+            let ((prev_iova, prev_size), (next_iova, _)) = (ranges[prev], ranges[next]);
+            prev_iova.checked_add(prev_size as u64) == Some(next_iova)
+        }) {
+            if run.len() > 1 {
+                let size = run.iter().map(|&i| ranges[i].1).sum();
+
+                if self.internal.unmap(ranges[run[0]].0, size).is_ok() {
+                    for &i in run {
+                        results[i] = Some(Ok(()));
+                    }
+                    continue;
+                }
+            }
+
+            for &i in run {
+                let (iova, size) = ranges[i];
+                results[i] = Some(self.internal.unmap(iova, size));
+            }
+        }
+
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Add one mapping for each region of the given guest memory, using the region's guest
+    /// physical address as its IOVA.
+    ///
+    /// This is meant for VMMs (*e.g.*, vhost-user backends) that drive devices with guest RAM. If
+    /// adding the mapping for some region fails, the mappings added so far are removed and the
+    /// error is returned.
+    ///
+    /// # Safety
+    ///
+    /// The guest memory must remain mapped in the current process until the mappings are removed,
+    /// *e.g.*, using [`PciIommu::unmap_guest_memory`].
+    #[cfg(feature = "vm-memory")]
+    pub unsafe fn map_guest_memory<M: GuestMemoryBackend>(
+        &self,
+        memory: &M,
+        device_permissions: Permissions,
+    ) -> io::Result<()> {
+        let mut mapped = Vec::new();
+
+        for region in memory.iter() {
+            let iova = region.start_addr().raw_value();
+            let length = region.len() as usize;
+
+            let result = region
+                .get_host_address(MemoryRegionAddress(0))
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))
+                .and_then(|address| unsafe {
+                    self.internal.map(iova, length, address, device_permissions)
+                });
+
+            if let Err(e) = result {
+                for (iova, length) in mapped {
+                    let _ = self.internal.unmap(iova, length);
+                }
+                return Err(e);
+            }
+
+            mapped.push((iova, length));
+        }
+
+        Ok(())
+    }
+
+    /// Remove the mappings added by [`PciIommu::map_guest_memory`] for the given guest memory.
+    ///
+    /// Tries to remove the mappings for all regions even if removing some fails, and returns the
+    /// first error.
+    #[cfg(feature = "vm-memory")]
+    pub fn unmap_guest_memory<M: GuestMemoryBackend>(&self, memory: &M) -> io::Result<()> {
+        let ranges: Vec<_> = memory
+            .iter()
+            .map(|region| (region.start_addr().raw_value(), region.len() as usize))
+            .collect();
+
+        self.batch_unmap(&ranges).into_iter().collect()
+    }
+}
+
+/// Splits `order` into maximal runs of consecutive elements for which `contiguous(prev, next)`
+/// holds.
+fn coalesce<'a>(
+    order: &'a [usize],
+    mut contiguous: impl FnMut(usize, usize) -> bool + 'a,
+) -> impl Iterator<Item = &'a [usize]> + 'a {
+    let mut rest = order;
+
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+
+        let mut len = 1;
+        while len < rest.len() && contiguous(rest[len - 1], rest[len]) {
+            len += 1;
+        }
+
+        let (run, remaining) = rest.split_at(len);
+        rest = remaining;
+        Some(run)
+    })
+}
+
+/// Describes an IOMMU mapping, such as one to be added with [`PciIommu::batch_map`].
+///
+/// The fields have the same meaning as the arguments of [`PciIommu::map`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IommuMapping {
+    pub iova: u64,
+    pub length: usize,
+    pub address: *const u8,
+    pub device_permissions: Permissions,
+}
+
+unsafe impl Send for IommuMapping {}
+unsafe impl Sync for IommuMapping {}
+
+/// What [`PciIommu::batch_map`] did.
+#[derive(Debug)]
+pub struct BatchMapResult {
+    /// One result per given mapping, in the same order.
+    pub results: Vec<io::Result<()>>,
+    /// The mappings that were added to the IOMMU after coalescing, sorted by IOVA. Each must be
+    /// removed as a whole.
+    pub mappings: Vec<IommuMapping>,
+}
+
+/// An IOMMU mapping that is removed when this is dropped. See [`PciIommu::map_guarded`].
+///
+/// Failure to remove the mapping on drop is ignored. Call [`MappingGuard::unmap`] instead if you
+/// need to handle it.
+#[derive(Debug)]
+#[must_use = "the mapping is removed as soon as the guard is dropped"]
+pub struct MappingGuard<'a> {
+    iommu: PciIommu<'a>,
+    mapping: IommuMapping,
+}
+
+impl MappingGuard<'_> {
+    /// The mapping this guards.
+    pub fn mapping(&self) -> IommuMapping {
+        self.mapping
+    }
+
+    /// The start address of the mapping in the device's address space.
+    pub fn iova(&self) -> u64 {
+        self.mapping.iova
+    }
+
+    /// Removes the mapping, returning any error.
+    pub fn unmap(self) -> io::Result<()> {
+        let result = self.iommu.unmap(self.mapping.iova, self.mapping.length);
+        mem::forget(self);
+        result
+    }
+
+    /// Consumes the guard without removing the mapping, which then remains in effect until removed
+    /// through other means.
+    pub fn leak(self) {
+        mem::forget(self);
+    }
+
+    /// Consumes the guard without removing the mapping, returning a description of it so that you
+    /// can remove it later with [`PciIommu::unmap`].
+    pub fn into_raw(self) -> IommuMapping {
+        let mapping = self.mapping;
+        mem::forget(self);
+        mapping
+    }
+}
+
+impl Drop for MappingGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.iommu.unmap(self.mapping.iova, self.mapping.length);
+    }
 }
 
 /* ---------------------------------------------------------------------------------------------- */
 
-pub(crate) trait PciIommuInternal {
+/// Something that can map process memory into a device's address space.
+///
+/// This is implemented by [`PciIommu`], and is meant to let memory pools, VMM components, and the
+/// like work with any DMA mapper without depending on `pci-driver`'s concrete types. The methods
+/// behave like the [`PciIommu`] methods of the same name.
+pub trait DmaMapper {
+    /// See [`PciIommu::alignment`].
     fn alignment(&self) -> usize;
 
+    /// See [`PciIommu::valid_iova_ranges`].
+    fn valid_iova_ranges(&self) -> &[Range<u64>];
+
+    /// See [`PciIommu::map`].
+    ///
+    /// # Safety
+    ///
+    /// See [`PciIommu::map`].
+    unsafe fn map(
+        &self,
+        iova: u64,
+        length: usize,
+        address: *const u8,
+        device_permissions: Permissions,
+    ) -> io::Result<()>;
+
+    /// See [`PciIommu::unmap`].
+    fn unmap(&self, iova: u64, length: usize) -> io::Result<()>;
+}
+
+impl DmaMapper for PciIommu<'_> {
+    fn alignment(&self) -> usize {
+        self.internal.alignment()
+    }
+
+    fn valid_iova_ranges(&self) -> &[Range<u64>] {
+        self.internal.valid_iova_ranges()
+    }
+
+    unsafe fn map(
+        &self,
+        iova: u64,
+        length: usize,
+        address: *const u8,
+        device_permissions: Permissions,
+    ) -> io::Result<()> {
+        unsafe { self.internal.map(iova, length, address, device_permissions) }
+    }
+
+    fn unmap(&self, iova: u64, length: usize) -> io::Result<()> {
+        self.internal.unmap(iova, length)
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+pub(crate) trait PciIommuInternal: Debug + Send + Sync {
+    fn alignment(&self) -> usize;
+
+    fn page_sizes(&self) -> u64 {
+        self.alignment() as u64
+    }
+
     fn valid_iova_ranges(&self) -> &[Range<u64>];
 
     fn max_num_mappings(&self) -> u32;
 
+    fn num_mappings(&self) -> io::Result<u32>;
+
     unsafe fn map(
         &self,
         iova: u64,
@@ -88,6 +554,588 @@ pub(crate) trait PciIommuInternal {
     ) -> io::Result<()>;
 
     fn unmap(&self, iova: u64, length: usize) -> io::Result<()>;
+
+    fn unmap_range(&self, iova: u64, length: usize) -> io::Result<u64> {
+        self.unmap(iova, length)?;
+        Ok(length as u64)
+    }
+
+    fn unmap_all(&self) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Other,
+            "This IOMMU can't remove all its mappings at once",
+        ))
+    }
+
+    fn attach_pasid(&self, _pasid: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "This IOMMU doesn't support PASIDs",
+        ))
+    }
+
+    fn detach_pasid(&self, _pasid: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "This IOMMU doesn't support PASIDs",
+        ))
+    }
+
+    unsafe fn remap(&self, _iova: u64, _length: usize, _new_address: *const u8) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Other,
+            "This IOMMU doesn't track its mappings and so can't remap them",
+        ))
+    }
+
+    unsafe fn protect(
+        &self,
+        _iova: u64,
+        _length: usize,
+        _device_permissions: Permissions,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Other,
+            "This IOMMU doesn't track its mappings and so can't change their permissions",
+        ))
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Hands out non-overlapping, aligned ranges of IOVA space.
+///
+/// [`PciIommu`] leaves it up to you to decide where in the device's address space to map things.
+/// This keeps track of which parts of the valid IOVA ranges are in use, so you don't have to. It
+/// knows nothing about actual mappings, though, and only makes sense if all mappings in an IOMMU
+/// are placed at IOVAs obtained from the same `IovaAllocator`.
+///
+/// This is thread-safe.
+#[derive(Debug)]
+pub struct IovaAllocator {
+    alignment: u64,
+    /// Sorted, non-overlapping, non-adjacent, and aligned to `alignment`.
+    free: Mutex<Vec<Range<u64>>>,
+}
+
+impl IovaAllocator {
+    /// Creates an allocator that hands out ranges from the given IOMMU's
+    /// [valid IOVA ranges](PciIommu::valid_iova_ranges), with the IOMMU's
+    /// [alignment](PciIommu::alignment).
+    pub fn new(iommu: &PciIommu) -> IovaAllocator {
+        IovaAllocator::with_ranges(iommu.valid_iova_ranges(), iommu.alignment())
+    }
+
+    /// Creates an allocator that hands out ranges from the given ones, aligned to `alignment`.
+    ///
+    /// Panics if `alignment` is not a power of two or if the given ranges overlap.
+    pub fn with_ranges(ranges: &[Range<u64>], alignment: usize) -> IovaAllocator {
+        assert!(alignment.is_power_of_two());
+
+        let alignment = alignment as u64;
+
+        let mut free: Vec<Range<u64>> = ranges
+            .iter()
+            .filter_map(|range| {
+                let start = align_up(range.start, alignment)?;
+                let end = range.end & !(alignment - 1);
+                if start < end {
+                    Some(start..end)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        free.sort_by_key(|range| range.start);
+
+        let allocator = IovaAllocator {
+            alignment,
+            free: Mutex::new(Vec::new()),
+        };
+
+        for range in free {
+            allocator.insert_free(range);
+        }
+
+        allocator
+    }
+
+    /// All allocated ranges start at a multiple of this, and have a length that is a multiple of
+    /// this.
+    pub fn alignment(&self) -> usize {
+        self.alignment as usize
+    }
+
+    /// Allocates a range of IOVA space of at least `length` bytes, and returns its start address.
+    ///
+    /// `length` is rounded up to a multiple of [`IovaAllocator::alignment`], and you must pass the
+    /// same `length` to [`IovaAllocator::free`] later on.
+    pub fn allocate(&self, length: usize) -> io::Result<u64> {
+        self.allocate_aligned(length, self.alignment as usize)
+    }
+
+    /// Like [`IovaAllocator::allocate`], but the returned address is also a multiple of
+    /// `alignment`, which must be a power of two.
+    pub fn allocate_aligned(&self, length: usize, alignment: usize) -> io::Result<u64> {
+        self.allocate_masked(length, alignment, u64::MAX)
+    }
+
+    /// Like [`IovaAllocator::allocate_aligned`], but the whole allocated range is also addressable
+    /// using only the bits set in `dma_mask`, _i.e._, it ends at or below `dma_mask`.
+    ///
+    /// This is for devices that can only generate addresses of a limited width. For instance, pass
+    /// `0xffff_ffff` for a device that can only do 32-bit DMA.
+    pub fn allocate_masked(
+        &self,
+        length: usize,
+        alignment: usize,
+        dma_mask: u64,
+    ) -> io::Result<u64> {
+        if !alignment.is_power_of_two() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Alignment must be a power of two",
+            ));
+        }
+
+        let length = self.rounded_length(length)?;
+        let alignment = (alignment as u64).max(self.alignment);
+
+        let mut free = self.free.lock().unwrap();
+
+        for i in 0..free.len() {
+            let range = free[i].clone();
+
+            let start = match align_up(range.start, alignment) {
+                Some(start) => start,
+                None => continue,
+            };
+
+            let usable_end = match dma_mask.checked_add(1) {
+                Some(limit) => range.end.min(limit),
+                None => range.end,
+            };
+
+            if start >= usable_end || usable_end - start < length {
+                continue;
+            }
+
+            // carve the allocation out of the free range
+
+            let before = range.start..start;
+            let after = start + length..range.end;
+
+            match (before.start < before.end, after.start < after.end) {
+                (false, false) => {
+                    free.remove(i);
+                }
+                (true, false) => free[i] = before,
+                (false, true) => free[i] = after,
+                (true, true) => {
+                    free[i] = before;
+                    free.insert(i + 1, after);
+                }
+            }
+
+            return Ok(start);
+        }
+
+        Err(io::Error::new(
+            ErrorKind::Other,
+            format!("No free IOVA range of {:#x} bytes available", length),
+        ))
+    }
+
+    /// Returns a range previously obtained from [`IovaAllocator::allocate`] or
+    /// [`IovaAllocator::allocate_aligned`] to the allocator.
+    ///
+    /// Panics if the range overlaps a range that is not currently allocated.
+    pub fn free(&self, iova: u64, length: usize) {
+        let length = self
+            .rounded_length(length)
+            .expect("length should have been accepted by allocate()");
+
+        self.insert_free(iova..iova + length);
+    }
+
+    fn rounded_length(&self, length: usize) -> io::Result<u64> {
+        match align_up((length as u64).max(1), self.alignment) {
+            Some(length) => Ok(length),
+            None => Err(io::Error::new(ErrorKind::InvalidInput, "Length is too big")),
+        }
+    }
+
+    fn insert_free(&self, range: Range<u64>) {
+        let mut free = self.free.lock().unwrap();
+
+        let i = free
+            .iter()
+            .position(|r| r.start >= range.start)
+            .unwrap_or_else(|| free.len());
+
+        assert!(
+            (i == 0 || free[i - 1].end <= range.start)
+                && (i == free.len() || range.end <= free[i].start),
+            "Freed IOVA range [{:#x}, {:#x}) overlaps a free range",
+            range.start,
+            range.end
+        );
+
+        // merge with neighbors where possible
+
+        let merges_prev = i > 0 && free[i - 1].end == range.start;
+        let merges_next = i < free.len() && free[i].start == range.end;
+
+        match (merges_prev, merges_next) {
+            (false, false) => free.insert(i, range),
+            (true, false) => free[i - 1].end = range.end,
+            (false, true) => free[i].start = range.start,
+            (true, true) => {
+                free[i - 1].end = free[i].end;
+                free.remove(i);
+            }
+        }
+    }
+}
+
+/// Hands out PASIDs (Process Address Space IDs) that aren't in use.
+///
+/// PASID 0 is never handed out, since some IOMMUs use it for DMA that isn't tagged with a PASID.
+///
+/// This is thread-safe.
+#[derive(Debug)]
+pub struct PasidAllocator {
+    max_pasid_width: u8,
+    used: Mutex<BTreeSet<u32>>,
+}
+
+impl PasidAllocator {
+    /// Creates an allocator that hands out PASIDs less than `1 << max_pasid_width`.
+    ///
+    /// Panics if `max_pasid_width` is greater than 20, the widest PASID PCIe supports.
+    pub fn new(max_pasid_width: u8) -> PasidAllocator {
+        assert!(max_pasid_width <= 20);
+
+        PasidAllocator {
+            max_pasid_width,
+            used: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Creates an allocator that hands out PASIDs supported by the function with the given PASID
+    /// Extended Capability.
+    pub fn for_capability(capability: &PasidExtendedCapability) -> io::Result<PasidAllocator> {
+        let width = capability.pasid_capability().max_pasid_width().read()?;
+        Ok(PasidAllocator::new(width.min(20)))
+    }
+
+    /// All PASIDs handed out are less than `1 << max_pasid_width()`.
+    pub fn max_pasid_width(&self) -> u8 {
+        self.max_pasid_width
+    }
+
+    /// Returns the lowest PASID that isn't in use, and marks it as used.
+    pub fn allocate(&self) -> io::Result<u32> {
+        let mut used = self.used.lock().unwrap();
+
+        // used PASIDs are sorted, so the first gap is the lowest free one
+
+        let mut pasid = 1;
+        for &p in used.range(1..) {
+            if p != pasid {
+                break;
+            }
+            pasid += 1;
+        }
+
+        if pasid >= 1 << self.max_pasid_width {
+            return Err(io::Error::new(ErrorKind::Other, "No free PASIDs available"));
+        }
+
+        used.insert(pasid);
+        Ok(pasid)
+    }
+
+    /// Returns a PASID previously obtained from [`PasidAllocator::allocate`] to the allocator.
+    ///
+    /// Panics if the PASID is not currently allocated.
+    pub fn free(&self, pasid: u32) {
+        assert!(
+            self.used.lock().unwrap().remove(&pasid),
+            "PASID {} is not allocated",
+            pasid
+        );
+    }
+}
+
+fn align_up(value: u64, alignment: u64) -> Option<u64> {
+    Some(value.checked_add(alignment - 1)? & !(alignment - 1))
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{self, ErrorKind};
+    use std::ops::Range;
+    use std::sync::Mutex;
+
+    use crate::iommu::{IommuMapping, IovaAllocator, PasidAllocator, PciIommu, PciIommuInternal};
+    use crate::regions::Permissions;
+
+    /// Records calls, and refuses to map more than `max_length` bytes at once.
+    ///
+    /// This is also used by the tests of the DMA helpers in [`crate::dma`].
+    #[derive(Debug)]
+    pub(crate) struct RecordingIommu {
+        pub(crate) max_length: usize,
+        pub(crate) calls: Mutex<Vec<(u64, usize)>>,
+    }
+
+    impl RecordingIommu {
+        pub(crate) fn new(max_length: usize) -> RecordingIommu {
+            RecordingIommu {
+                max_length,
+                calls: Mutex::new(Vec::new()),
+            }
+        }
+
+        pub(crate) fn iommu(&self) -> PciIommu<'_> {
+            PciIommu { internal: self }
+        }
+    }
+
+    impl PciIommuInternal for RecordingIommu {
+        fn alignment(&self) -> usize {
+            0x1000
+        }
+
+        fn valid_iova_ranges(&self) -> &[Range<u64>] {
+            &[]
+        }
+
+        fn max_num_mappings(&self) -> u32 {
+            u32::MAX
+        }
+
+        fn num_mappings(&self) -> io::Result<u32> {
+            Ok(0)
+        }
+
+        unsafe fn map(
+            &self,
+            iova: u64,
+            length: usize,
+            _address: *const u8,
+            _device_permissions: Permissions,
+        ) -> io::Result<()> {
+            self.calls.lock().unwrap().push((iova, length));
+            if length <= self.max_length {
+                Ok(())
+            } else {
+                Err(io::Error::new(ErrorKind::Other, "too long"))
+            }
+        }
+
+        fn unmap(&self, iova: u64, length: usize) -> io::Result<()> {
+            self.calls.lock().unwrap().push((iova, length));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_batch_map() {
+        let internal = RecordingIommu {
+            max_length: 0x2000,
+            calls: Mutex::new(Vec::new()),
+        };
+        let iommu = PciIommu {
+            internal: &internal,
+        };
+
+        let mapping = |iova: u64, address: usize, length: usize, device_permissions| IommuMapping {
+            iova,
+            length,
+            address: address as *const u8,
+            device_permissions,
+        };
+
+        let mappings = [
+            mapping(0x11000, 0x51000, 0x1000, Permissions::Read),
+            mapping(0x10000, 0x50000, 0x1000, Permissions::Read),
+            mapping(0x12000, 0x52000, 0x1000, Permissions::ReadWrite),
+            mapping(0x20000, 0x60000, 0x2000, Permissions::Write),
+            mapping(0x22000, 0x62000, 0x1000, Permissions::Write),
+            mapping(0x30000, 0x70000, 0x4000, Permissions::Write),
+        ];
+
+        let result = unsafe { iommu.batch_map(&mappings) };
+        assert_eq!(result.results.len(), mappings.len());
+        assert!(result.results[..5].iter().all(|r| r.is_ok()));
+        assert!(result.results[5].is_err());
+        assert_eq!(
+            result.mappings,
+            [
+                mapping(0x10000, 0x50000, 0x2000, Permissions::Read),
+                mapping(0x12000, 0x52000, 0x1000, Permissions::ReadWrite),
+                mapping(0x20000, 0x60000, 0x2000, Permissions::Write),
+                mapping(0x22000, 0x62000, 0x1000, Permissions::Write),
+            ]
+        );
+
+        assert_eq!(
+            *internal.calls.lock().unwrap(),
+            [
+                (0x10000, 0x2000),
+                (0x12000, 0x1000),
+                (0x20000, 0x3000),
+                (0x20000, 0x2000),
+                (0x22000, 0x1000),
+                (0x30000, 0x4000),
+            ]
+        );
+
+        internal.calls.lock().unwrap().clear();
+
+        let results = iommu.batch_unmap(&[(0x11000, 0x1000), (0x10000, 0x1000), (0x20000, 0x2000)]);
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(
+            *internal.calls.lock().unwrap(),
+            [(0x10000, 0x2000), (0x20000, 0x2000)]
+        );
+    }
+
+    #[test]
+    fn test_map_guarded() {
+        let internal = RecordingIommu {
+            max_length: 0x1000,
+            calls: Mutex::new(Vec::new()),
+        };
+        let iommu = PciIommu {
+            internal: &internal,
+        };
+
+        let address = 0x50000 as *const u8;
+
+        drop(unsafe { iommu.map_guarded(0x10000, 0x1000, address, Permissions::Read) }.unwrap());
+        unsafe { iommu.map_guarded(0x20000, 0x1000, address, Permissions::Read) }
+            .unwrap()
+            .leak();
+        let mapping = unsafe { iommu.map_guarded(0x30000, 0x1000, address, Permissions::Read) }
+            .unwrap()
+            .into_raw();
+        assert!(unsafe { iommu.map_guarded(0x40000, 0x2000, address, Permissions::Read) }.is_err());
+
+        assert_eq!(mapping.iova, 0x30000);
+        assert_eq!(
+            *internal.calls.lock().unwrap(),
+            [
+                (0x10000, 0x1000),
+                (0x10000, 0x1000),
+                (0x20000, 0x1000),
+                (0x30000, 0x1000),
+                (0x40000, 0x2000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_iova_allocator() {
+        let allocator = IovaAllocator::with_ranges(&[0x800..0x10000, 0x20000..0x24000], 0x1000);
+
+        let a = allocator.allocate(0x1000).unwrap();
+        let b = allocator.allocate(0x1001).unwrap();
+        let c = allocator.allocate_aligned(0x1000, 0x8000).unwrap();
+        let d = allocator.allocate(0x4000).unwrap();
+        let e = allocator.allocate(0x4000).unwrap();
+
+        assert_eq!((a, b, c, d, e), (0x1000, 0x2000, 0x8000, 0x4000, 0x9000));
+        assert!(allocator.allocate(0x8000).is_err());
+
+        allocator.free(b, 0x1001);
+        allocator.free(c, 0x1000);
+        allocator.free(a, 0x1000);
+
+        assert_eq!(allocator.allocate(0x3000).unwrap(), 0x1000);
+        assert_eq!(allocator.allocate(0x4000).unwrap(), 0x20000);
+        assert_eq!(allocator.allocate(0x1000).unwrap(), 0x8000);
+    }
+
+    #[test]
+    fn test_iova_allocator_masked() {
+        let allocator =
+            IovaAllocator::with_ranges(&[0x1000..0x2000, 0xfffe_0000..0x1_0001_0000], 0x1000);
+
+        assert!(allocator
+            .allocate_masked(0x40000, 0x1000, 0xffff_ffff)
+            .is_err());
+        assert_eq!(
+            allocator
+                .allocate_masked(0x10000, 0x1000, 0xffff_ffff)
+                .unwrap(),
+            0xfffe_0000
+        );
+        assert_eq!(
+            allocator
+                .allocate_masked(0x10000, 0x1000, 0xffff_ffff)
+                .unwrap(),
+            0xffff_0000
+        );
+        assert_eq!(
+            allocator
+                .allocate_masked(0x1000, 0x1000, 0xffff_ffff)
+                .unwrap(),
+            0x1000
+        );
+        assert!(allocator
+            .allocate_masked(0x1000, 0x1000, 0xffff_ffff)
+            .is_err());
+        assert_eq!(allocator.allocate(0x1000).unwrap(), 0x1_0000_0000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_iova_allocator_double_free() {
+        let allocator = IovaAllocator::with_ranges(&[0x1000..0x2000, 0x3000..0x4000], 0x1000);
+        let a = allocator.allocate(0x1000).unwrap();
+
+        allocator.free(a, 0x1000);
+        allocator.free(a, 0x1000);
+    }
+
+    #[test]
+    fn test_pasid_allocator() {
+        let allocator = PasidAllocator::new(2);
+
+        assert_eq!(allocator.allocate().unwrap(), 1);
+        assert_eq!(allocator.allocate().unwrap(), 2);
+        assert_eq!(allocator.allocate().unwrap(), 3);
+        assert!(allocator.allocate().is_err());
+
+        allocator.free(2);
+        assert_eq!(allocator.allocate().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_pasid_unsupported() {
+        let internal = RecordingIommu {
+            max_length: 0x1000,
+            calls: Mutex::new(Vec::new()),
+        };
+        let iommu = PciIommu {
+            internal: &internal,
+        };
+
+        assert_eq!(
+            iommu.attach_pasid(1).unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+        assert_eq!(
+            iommu.detach_pasid(1).unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+    }
 }
 
 /* ---------------------------------------------------------------------------------------------- */
@@ -12,13 +12,20 @@
 //! 5. Configure its INTx, MSI, and MSI-X interrupt vectors;
 //! 6. Reset it.
 //!
-//! Implementations of this trait are called _backends_. For now, a single
-//! [`VfioPciDevice`](backends::vfio::VfioPciDevice) backend is provided, which relies on Linux's
-//! VFIO driver framework. The availability of this backend can be controlled through the `vfio`
-//! crate feature. Future backends will each have a corresponding feature. Note that the user cannot
-//! implement additional backends from outside this crate.
+//! Implementations of this trait are called _backends_. The main one is the
+//! [`VfioPciDevice`](backends::vfio::VfioPciDevice) backend, which relies on Linux's VFIO driver
+//! framework. The availability of this backend can be controlled through the `vfio` crate feature.
+//! The `mock` crate feature enables the `backends::mock` module, whose `MockDevice` is a fake,
+//! memory-backed device that you can configure to unit-test your drivers without hardware. Future
+//! backends will each have a corresponding feature. Note that the user cannot implement additional
+//! backends from outside this crate.
 //!
-//! This crate requires Rust 1.47 or above.
+//! The [`enumerate`] module lets you find the devices you want to drive, _e.g._, by vendor and
+//! device ID, and the [`hotplug`] module lets you find out when they go away. The [`claim`] module
+//! keeps several processes using this crate from driving the same function at once, and the
+//! [`identity`] module lets you find a device again after its address changes.
+//!
+//! This crate requires Rust 1.63 or above.
 //!
 //! The following sections showcase [`PciDevice`](device::PciDevice)'s features.
 //!
@@ -213,6 +220,32 @@
 //! # std::io::Result::Ok(())
 //! ```
 //!
+//! Instead of managing process memory, IOVAs, and mappings yourself, you can use
+//! [`DmaBuffer`](dma::DmaBuffer) and [`IovaAllocator`](iommu::IovaAllocator):
+//!
+//! ```no_run
+//! use pci_driver::device::PciDevice;
+//! use pci_driver::dma::DmaBuffer;
+//! use pci_driver::iommu::IovaAllocator;
+//! use pci_driver::regions::{PciRegion, Permissions};
+//!
+//! let device: &dyn PciDevice = unimplemented!();
+//!
+//! let iommu = device.iommu().unwrap();
+//! let allocator = IovaAllocator::new(&iommu);
+//!
+//! let buffer = DmaBuffer::new(iommu, &allocator, 4096, Permissions::ReadWrite)?;
+//! let iova: u64 = buffer.iova(); // tell the device about this
+//! let value: u32 = buffer.region().read_le_u32(0x10)?;
+//!
+//! drop(buffer); // unmaps and frees the memory
+//! # std::io::Result::Ok(())
+//! ```
+//!
+//! With the `vm-memory` crate feature enabled, `PciIommu::map_guest_memory` identity-maps all
+//! regions of a [vm-memory](https://crates.io/crates/vm-memory) guest memory object, so that
+//! devices can DMA to guest RAM using guest physical addresses.
+//!
 //! ## Interrupts
 //!
 //! The [`PciDevice::interrupts`](device::PciDevice::interrupts) method returns a
@@ -243,6 +276,14 @@
 //! # std::io::Result::Ok(())
 //! ```
 //!
+//! With the `async` crate feature enabled, `interrupts::IrqStream` lets you await
+//! interrupts signalled through eventfds from within a tokio runtime.
+//!
+//! With the `pci-ids` crate feature enabled, the
+//! [`PciDeviceInfo`](device::PciDeviceInfo) returned by
+//! [`PciDevice::info`](device::PciDevice::info) has `vendor_name()`, `device_name()`, and
+//! `class_name()` methods that look up human-readable names in the system's pci.ids database.
+//!
 //! ## VFIO backend specificities
 //!
 //! In the following example, devices 0000:00:01.0 and 0000:00:02.0 belong to VFIO group 42, device
@@ -254,7 +295,7 @@
 //! use pci_driver::device::PciDevice;
 //! use pci_driver::regions::Permissions;
 //!
-//! let container: Arc<VfioContainer> = Arc::new(VfioContainer::new(&[42, 123], false)?);
+//! let container: Arc<VfioContainer> = Arc::new(VfioContainer::new(&[42, 123])?);
 //!
 //! let device_a = VfioPciDevice::open_in_container("/sys/bus/pci/devices/0000:00:01.0", Arc::clone(&container))?;
 //! let device_b = VfioPciDevice::open_in_container("/sys/bus/pci/devices/0000:00:02.0", Arc::clone(&container))?;
@@ -277,7 +318,18 @@
 //! // Shorthand for when a device is the only one (that we care about) in its group, and the group
 //! // is the only one in its container
 //!
-//! let device = VfioPciDevice::open("/sys/bus/pci/devices/0000:00:01.0", false)?;
+//! let device = VfioPciDevice::open("/sys/bus/pci/devices/0000:00:01.0")?;
+//!
+//! // On recent kernels, devices can also be opened through their VFIO cdev and bound to iommufd,
+//! // which doesn't involve their group; this falls back to the above when that isn't supported
+//!
+//! let device = VfioPciDevice::open_auto("/sys/bus/pci/devices/0000:00:01.0")?;
+//!
+//! // Devices in no-IOMMU groups can only be opened unsafely, since nothing stops them from doing
+//! // DMA to arbitrary memory
+//!
+//! let device = unsafe { VfioPciDevice::open_noiommu("/sys/bus/pci/devices/0000:00:04.0")? };
+//! assert!(device.is_noiommu() && device.iommu().is_none());
 //!
 //! // Resetting a PCI function, which may not be supported
 //!
@@ -289,6 +341,19 @@
 //! # std::io::Result::Ok(())
 //! ```
 //!
+//! With the `kvm` crate feature enabled, `backends::vfio::VfioKvmDevice` registers containers or
+//! devices with a KVM VM's VFIO pseudo-device, as VMMs assigning them to a guest must do.
+//!
+//! With the `vfio-bindings` crate feature enabled, the `backends::vfio` module re-exports the
+//! [vfio-bindings](https://crates.io/crates/vfio-bindings) crate, and types such as
+//! `VfioDeviceInfo`, `VfioRegionType`, and `VfioHotResetDevice` convert from the corresponding
+//! structs it defines, so they can be used alongside other rust-vmm crates.
+//!
+//! VFIO ioctls that this crate doesn't wrap can be issued with `backends::vfio::vfio_ioctl` on the
+//! file descriptors that `VfioContainer` and `VfioPciDevice` lend out through [`AsFd`].
+//!
+//! [`AsFd`]: std::os::unix::io::AsFd
+//!
 //! ## `pci_struct!` and `pci_bit_field!`
 //!
 //! Many times, your device's BARs or ROM will be structured into registers and bit fields similarly
@@ -431,17 +496,34 @@
 
 #![cfg_attr(feature = "_unsafe-op-in-unsafe-fn", deny(unsafe_op_in_unsafe_fn))]
 #![cfg_attr(not(feature = "_unsafe-op-in-unsafe-fn"), allow(unused_unsafe))]
+// The replacements these lints suggest aren't available in our MSRV.
+#![allow(unknown_lints)]
+#![allow(
+    clippy::io_other_error,
+    clippy::manual_div_ceil,
+    clippy::manual_is_multiple_of,
+    clippy::unnecessary_map_or
+)]
 
 // TODO: enable:
 // #![warn(missing_docs)]
 
 pub mod backends;
+pub mod claim;
 pub mod config;
 pub mod device;
+pub mod dma;
+pub mod enumerate;
+pub mod hotplug;
+pub mod identity;
 pub mod interrupts;
 pub mod iommu;
 #[cfg(feature = "test-mocks")]
 pub mod mocks;
+#[cfg(feature = "pci-ids")]
+mod pci_ids;
+pub mod quirks;
 pub mod regions;
+pub mod sysfs;
 
 /* ---------------------------------------------------------------------------------------------- */
@@ -7,7 +7,10 @@ use std::io;
 use mockall::mock;
 
 use crate::config::PciConfig;
+use crate::device::PciAddress;
 use crate::device::PciDevice;
+use crate::device::PciDeviceInfo;
+use crate::device::PciResetCapabilities;
 use crate::device::Sealed as DeviceSealed;
 use crate::interrupts::PciInterrupts;
 use crate::iommu::PciIommu;
@@ -26,12 +29,16 @@ mock! {
 
     impl PciDevice for PciDevice {
         fn config<'a>(&self) -> PciConfig<'static>;
+        fn info<'a>(&self) -> PciDeviceInfo;
+        fn address<'a>(&self) -> Option<PciAddress>;
+        fn numa_node<'a>(&self) -> io::Result<Option<u32>>;
         fn bar<'a>(&self, index: usize) -> Option<OwningPciRegion>;
         fn bar_region<'a>(&self, index: usize) -> Option<Box<dyn PciRegion>>;
         fn rom<'a>(&self) -> Option<OwningPciRegion>;
         fn iommu<'a>(&self) -> Option<PciIommu<'static>>;
         fn interrupts<'a>(&self) -> PciInterrupts<'static>;
         fn reset<'a>(&self) -> io::Result<()>;
+        fn reset_capabilities<'a>(&self) -> io::Result<PciResetCapabilities>;
     }
 
     impl DeviceSealed for PciDevice {}
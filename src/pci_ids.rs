@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::device::PciDeviceInfo;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Where distributions usually install the pci.ids database, in order of preference.
+const PCI_IDS_PATHS: &[&str] = &[
+    "/usr/share/hwdata/pci.ids",
+    "/usr/share/misc/pci.ids",
+    "/usr/share/pci.ids",
+];
+
+/// The parsed database, loaded on first use. `None` inside the `Some` if it couldn't be loaded.
+static DATABASE: Mutex<Option<Option<Arc<PciIds>>>> = Mutex::new(None);
+
+impl PciDeviceInfo {
+    /// Returns the name of the function's vendor, as found in the system's pci.ids database.
+    ///
+    /// The database is looked for in `/usr/share/hwdata`, `/usr/share/misc`, and `/usr/share`, and
+    /// loaded on first use. Returns `None` if the vendor isn't listed or no database is installed.
+    ///
+    /// Only available with the `pci-ids` crate feature.
+    pub fn vendor_name(&self) -> Option<String> {
+        let database = database()?;
+        let vendor = database.vendors.get(&self.vendor_id)?;
+        Some(vendor.name.clone())
+    }
+
+    /// Returns the name of the function's device, as found in the system's pci.ids database. See
+    /// [`PciDeviceInfo::vendor_name`].
+    ///
+    /// Only available with the `pci-ids` crate feature.
+    pub fn device_name(&self) -> Option<String> {
+        let database = database()?;
+        let vendor = database.vendors.get(&self.vendor_id)?;
+        vendor.devices.get(&self.device_id).cloned()
+    }
+
+    /// Returns the name of the function's class, as found in the system's pci.ids database. See
+    /// [`PciDeviceInfo::vendor_name`].
+    ///
+    /// This is the name of the most specific of the Programming Interface, Sub-Class, and Base
+    /// Class that is listed, _e.g._, "NVM Express" rather than "Non-Volatile memory controller".
+    ///
+    /// Only available with the `pci-ids` crate feature.
+    pub fn class_name(&self) -> Option<String> {
+        database()?.class_name(self.class_code)
+    }
+}
+
+fn database() -> Option<Arc<PciIds>> {
+    let mut database = DATABASE.lock().unwrap();
+
+    database
+        .get_or_insert_with(|| {
+            PCI_IDS_PATHS
+                .iter()
+                .find_map(|path| fs::read_to_string(path).ok())
+                .map(|contents| Arc::new(PciIds::parse(&contents)))
+        })
+        .clone()
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[derive(Debug, Default)]
+struct PciIds {
+    vendors: HashMap<u16, Vendor>,
+    /// Keyed by the full Class Code, with lower bytes set to zero for Base and Sub-Classes.
+    classes: HashMap<u32, String>,
+}
+
+#[derive(Debug)]
+struct Vendor {
+    name: String,
+    devices: HashMap<u16, String>,
+}
+
+impl PciIds {
+    /// Parses the pci.ids format, where vendors are listed as `"vvvv  name"` lines followed by
+    /// `"\tdddd  name"` lines for their devices (subsystems are `"\t\t"` lines, which are ignored),
+    /// and classes are `"C cc  name"` lines followed by `"\tss  name"` and `"\t\tpp  name"` lines.
+    fn parse(contents: &str) -> PciIds {
+        let mut ids = PciIds::default();
+
+        let mut vendor: Option<u16> = None;
+        let mut class: Option<u32> = None;
+
+        for line in contents.lines() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let depth = line.bytes().take_while(|&b| b == b'\t').count();
+            let line = &line[depth..];
+
+            let (id, name) = match line.split_once("  ") {
+                Some((id, name)) => (id, name.to_string()),
+                None => continue,
+            };
+
+            match depth {
+                0 => {
+                    if let Some(id) = id.strip_prefix("C ") {
+                        vendor = None;
+                        class = u32::from_str_radix(id, 16).ok().map(|id| id << 16);
+                        if let Some(class) = class {
+                            ids.classes.insert(class, name);
+                        }
+                    } else {
+                        class = None;
+                        vendor = u16::from_str_radix(id, 16).ok();
+                        if let Some(vendor) = vendor {
+                            let devices = HashMap::new();
+                            ids.vendors.insert(vendor, Vendor { name, devices });
+                        }
+                    }
+                }
+                1 => {
+                    if let (Some(vendor), Ok(device)) = (vendor, u16::from_str_radix(id, 16)) {
+                        let vendor = ids.vendors.get_mut(&vendor).unwrap();
+                        vendor.devices.insert(device, name);
+                    } else if let (Some(current), Ok(sub)) = (class, u32::from_str_radix(id, 16)) {
+                        // Programming Interfaces that follow belong to this Sub-Class
+                        let sub_class = (current & 0xff_0000) | sub << 8;
+                        ids.classes.insert(sub_class, name);
+                        class = Some(sub_class);
+                    }
+                }
+                2 => {
+                    if let (Some(class), Ok(interface)) = (class, u32::from_str_radix(id, 16)) {
+                        ids.classes.insert(class | interface, name);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        ids
+    }
+
+    fn class_name(&self, class_code: u32) -> Option<String> {
+        self.classes
+            .get(&class_code)
+            .or_else(|| self.classes.get(&(class_code & 0xff_ff00)))
+            .or_else(|| self.classes.get(&(class_code & 0xff_0000)))
+            .cloned()
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use crate::pci_ids::PciIds;
+
+    #[test]
+    fn test_parse() {
+        let ids = PciIds::parse(
+            "# comment
+144d  Samsung Electronics Co Ltd
+\ta808  NVMe SSD Controller SM981/PM981/PM983
+\t\t144d a801  SSD 970 EVO Plus 1TB
+8086  Intel Corporation
+\t1237  440FX - 82441FX PMC [Natoma]
+
+C 01  Mass storage controller
+\t08  Non-Volatile memory controller
+\t\t02  NVM Express
+\t80  Mass storage controller
+C 02  Network controller
+",
+        );
+
+        let samsung = &ids.vendors[&0x144d];
+        assert_eq!(samsung.name, "Samsung Electronics Co Ltd");
+        assert_eq!(
+            samsung.devices[&0xa808],
+            "NVMe SSD Controller SM981/PM981/PM983"
+        );
+        assert_eq!(samsung.devices.len(), 1);
+        assert_eq!(ids.vendors[&0x8086].devices.len(), 1);
+
+        assert_eq!(ids.class_name(0x010802).unwrap(), "NVM Express");
+        assert_eq!(
+            ids.class_name(0x010801).unwrap(),
+            "Non-Volatile memory controller"
+        );
+        assert_eq!(ids.class_name(0x018000).unwrap(), "Mass storage controller");
+        assert_eq!(ids.class_name(0x020000).unwrap(), "Network controller");
+        assert_eq!(ids.class_name(0x030000), None);
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
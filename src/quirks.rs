@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Working around misbehaving devices.
+//!
+//! Some devices don't behave as the spec says they should, _e.g._, they hang when reset in a
+//! certain way. [`PciQuirks`] describes how to avoid such problems, and a registry maps devices,
+//! identified by vendor ID, device ID, and revision, to the quirks that apply to them. Backends
+//! look up a device's quirks when it is opened, and
+//! [`PciDevice::quirks`](crate::device::PciDevice::quirks) returns them.
+//!
+//! A few quirks that are known to matter for VFIO are built in, and you can [`register`] your own:
+//!
+//! ```
+//! use pci_driver::quirks::{self, PciQuirks};
+//!
+//! let mut quirks = PciQuirks::default();
+//! quirks.no_rom = true;
+//!
+//! quirks::register(0x1234, Some(0x5678), None, quirks);
+//! ```
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::sync::Mutex;
+
+use crate::device::PciDeviceInfo;
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// How to work around a device's misbehavior. See the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct PciQuirks {
+    /// Don't probe or expose the device's Expansion ROM, _e.g._, because reading it hangs the
+    /// device.
+    pub no_rom: bool,
+    /// Never memory-map the device's BARs, always accessing them through the backend instead.
+    pub no_bar_mmap: bool,
+    /// Don't use Function Level Reset, even if the device claims to support it.
+    pub no_flr: bool,
+    /// Don't reset the device by putting it in D3<sub>hot</sub> and back.
+    ///
+    /// With the VFIO backend, [`PciDevice::reset`](crate::device::PciDevice::reset) fails if the
+    /// kernel would reset the device that way.
+    pub no_pm_reset: bool,
+    /// Don't reset the device by resetting the bus it is on.
+    pub no_hot_reset: bool,
+}
+
+impl PciQuirks {
+    /// Whether no quirks apply.
+    pub fn is_empty(&self) -> bool {
+        *self == PciQuirks::default()
+    }
+
+    fn union(self, other: PciQuirks) -> PciQuirks {
+        PciQuirks {
+            no_rom: self.no_rom || other.no_rom,
+            no_bar_mmap: self.no_bar_mmap || other.no_bar_mmap,
+            no_flr: self.no_flr || other.no_flr,
+            no_pm_reset: self.no_pm_reset || other.no_pm_reset,
+            no_hot_reset: self.no_hot_reset || other.no_hot_reset,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    vendor_id: u16,
+    /// `None` matches any device.
+    device_id: Option<u16>,
+    /// `None` matches any revision.
+    revision_id: Option<u8>,
+    quirks: PciQuirks,
+}
+
+impl Entry {
+    fn matches(&self, info: &PciDeviceInfo) -> bool {
+        self.vendor_id == info.vendor_id
+            && self.device_id.map_or(true, |id| id == info.device_id)
+            && self.revision_id.map_or(true, |id| id == info.revision_id)
+    }
+}
+
+const fn built_in(vendor_id: u16, device_id: u16, quirks: PciQuirks) -> Entry {
+    Entry {
+        vendor_id,
+        device_id: Some(device_id),
+        revision_id: None,
+        quirks,
+    }
+}
+
+const NO_FLR: PciQuirks = PciQuirks {
+    no_rom: false,
+    no_bar_mmap: false,
+    no_flr: true,
+    no_pm_reset: false,
+    no_hot_reset: false,
+};
+
+const NO_HOT_RESET: PciQuirks = PciQuirks {
+    no_rom: false,
+    no_bar_mmap: false,
+    no_flr: false,
+    no_pm_reset: false,
+    no_hot_reset: true,
+};
+
+/// The same as Linux's `quirk_no_flr` and `quirk_no_bus_reset`, which are what make VFIO avoid
+/// these resets.
+const BUILT_IN: &[Entry] = &[
+    // AMD Starship/Matisse USB 3.0 and HD Audio controllers hang on FLR
+    built_in(0x1022, 0x149c, NO_FLR),
+    built_in(0x1022, 0x1487, NO_FLR),
+    built_in(0x1022, 0x148c, NO_FLR),
+    // Intel 82579LM and 82579V advertise FLR but don't implement it
+    built_in(0x8086, 0x1502, NO_FLR),
+    built_in(0x8086, 0x1503, NO_FLR),
+    // Atheros AR93xx and QCA98xx wireless controllers stop responding after a bus reset
+    built_in(0x168c, 0x0030, NO_HOT_RESET),
+    built_in(0x168c, 0x0032, NO_HOT_RESET),
+    built_in(0x168c, 0x0033, NO_HOT_RESET),
+    built_in(0x168c, 0x0034, NO_HOT_RESET),
+    built_in(0x168c, 0x003c, NO_HOT_RESET),
+    built_in(0x168c, 0x003e, NO_HOT_RESET),
+];
+
+static REGISTERED: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+/// Registers quirks for the devices with the given vendor ID, device ID, and revision, in addition
+/// to any that are already known. `None` matches any device ID or revision.
+///
+/// This only affects devices opened afterwards.
+pub fn register(
+    vendor_id: u16,
+    device_id: Option<u16>,
+    revision_id: Option<u8>,
+    quirks: PciQuirks,
+) {
+    REGISTERED.lock().unwrap().push(Entry {
+        vendor_id,
+        device_id,
+        revision_id,
+        quirks,
+    });
+}
+
+/// Returns all quirks, built-in and registered, that apply to the device with the given
+/// information.
+pub fn lookup(info: &PciDeviceInfo) -> PciQuirks {
+    let registered = REGISTERED.lock().unwrap();
+
+    BUILT_IN
+        .iter()
+        .chain(registered.iter())
+        .filter(|entry| entry.matches(info))
+        .fold(PciQuirks::default(), |quirks, entry| {
+            quirks.union(entry.quirks)
+        })
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use crate::backends::mock::MockPciDevice;
+    use crate::device::PciDevice;
+    use crate::quirks::{self, PciQuirks};
+
+    #[test]
+    fn test_lookup() {
+        // a made-up device, so that registering quirks doesn't affect other tests
+        let mut info = MockPciDevice.info();
+        info.vendor_id = 0xfffe;
+        assert!(quirks::lookup(&info).is_empty());
+
+        let no_rom = PciQuirks {
+            no_rom: true,
+            ..PciQuirks::default()
+        };
+        quirks::register(0xfffe, Some(0xa808), Some(0x42), no_rom);
+        assert!(quirks::lookup(&info).is_empty());
+
+        info.revision_id = 0x42;
+        assert_eq!(quirks::lookup(&info), no_rom);
+
+        let no_flr = PciQuirks {
+            no_flr: true,
+            ..PciQuirks::default()
+        };
+        quirks::register(0xfffe, None, None, no_flr);
+        let found = quirks::lookup(&info);
+        assert!(found.no_rom && found.no_flr && !found.no_hot_reset);
+
+        info.vendor_id = 0x8086;
+        info.device_id = 0x1502;
+        assert_eq!(quirks::lookup(&info), no_flr);
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -41,6 +41,11 @@
 //! ## And also
 //!
 //! - [`trait BackedByPciSubregion<'a>`](BackedByPciSubregion).
+//!
+//! - [`struct PciRegionCursor<R>`](PciRegionCursor). Adapts a `PciRegion` to [`std::io::Read`],
+//!   [`std::io::Write`], and [`std::io::Seek`].
+//!
+//! - [`fn verify`](verify). Compares a region against a [`PciRegionSnapshot`].
 
 /* ---------------------------------------------------------------------------------------------- */
 
@@ -48,12 +53,14 @@ mod bit_field_macros;
 mod struct_macros;
 pub mod structured;
 
+use std::cell::UnsafeCell;
 use std::fmt::Debug;
-use std::io::{self, ErrorKind};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::marker::PhantomData;
 use std::mem;
 use std::ops::{Bound, Range, RangeBounds};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use crate::device::PciDeviceInternal;
 
@@ -97,6 +104,19 @@ impl Permissions {
     }
 }
 
+/// The memory type to use when memory-mapping a region. See
+/// [`OwningPciRegion::map_with_cacheability`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Cacheability {
+    /// Accesses are not cached nor combined, and reach the device in program order. This is what
+    /// you want for registers, doorbells, etc.
+    Uncached,
+    /// Writes may be buffered and combined into larger transactions, which is usually much faster
+    /// for large sequential writes, _e.g._, to frame buffers. Only prefetchable Memory Space BARs
+    /// may be mapped this way.
+    WriteCombined,
+}
+
 /* ---------------------------------------------------------------------------------------------- */
 
 pub(crate) use private::Sealed;
@@ -311,7 +331,7 @@ pub trait AsPciSubregion<'a> {
 }
 
 // If a `T` is `AsPciSubregion<'a>`, then any `&T` is also.
-impl<'a, 'b, T> AsPciSubregion<'a> for &'b T
+impl<'a, T> AsPciSubregion<'a> for &T
 where
     T: AsPciSubregion<'a>,
 {
@@ -421,6 +441,9 @@ where
 pub(crate) enum RegionIdentifier {
     Bar(usize),
     Rom,
+    /// A backend-specific region, identified by a backend-specific index.
+    #[allow(dead_code)] // for when pci-driver is built with no backends
+    Other(u32),
 }
 
 /// This is "owning" in the sense that it doesn't borrow the `PciDevice` it came from.
@@ -434,6 +457,9 @@ pub(crate) enum RegionIdentifier {
 /// it, which gives you another type implementing [`PciRegion`], but accesses through it should be
 /// more efficient. You can also obtain a `*const u8` or `*mut u8` from that second [`PciRegion`]
 /// and use that directly.
+///
+/// Alternatively, [`OwningPciRegion::mapped`] maps the whole region once and keeps that mapping
+/// around, after which the `OwningPciRegion`'s own [`PciRegion`] methods go through it too.
 #[derive(Debug)]
 pub struct OwningPciRegion {
     device: Arc<dyn PciDeviceInternal>,
@@ -441,19 +467,26 @@ pub struct OwningPciRegion {
     offset: u64,
     length: u64,
     identifier: RegionIdentifier,
-    is_mappable: bool,
+    /// Relative to the beginning of `region`, sorted and non-overlapping.
+    mappable_ranges: Arc<[Range<u64>]>,
+    mapping: Arc<LazyMapping>,
+    /// The device's [`PciDeviceInternal::region_generation`] when the region was obtained.
+    generation: u64,
 }
 
 impl OwningPciRegion {
+    /// `mappable_ranges` are the parts of `region` that can be memory-mapped, sorted and
+    /// non-overlapping.
     #[allow(dead_code)] // for when pci-driver is built with no backends
     pub(crate) fn new(
         device: Arc<dyn PciDeviceInternal>,
         region: Arc<dyn PciRegion>,
         identifier: RegionIdentifier,
-        is_mappable: bool,
+        mappable_ranges: &[Range<u64>],
     ) -> OwningPciRegion {
         let offset = 0;
         let length = region.len();
+        let generation = device.region_generation();
 
         OwningPciRegion {
             device,
@@ -461,15 +494,35 @@ impl OwningPciRegion {
             offset,
             length,
             identifier,
-            is_mappable,
+            mappable_ranges: mappable_ranges.into(),
+            mapping: Arc::new(LazyMapping::default()),
+            generation,
         }
     }
 
-    /// Whether the region can be memory-mapped.
+    /// Whether at least part of the region can be memory-mapped.
     ///
-    /// If `false`, [`OwningPciRegion::map`] will always fail.
+    /// If `false`, [`OwningPciRegion::map`] will always fail. Otherwise, see
+    /// [`OwningPciRegion::mappable_ranges`] for which parts can.
     pub fn is_mappable(&self) -> bool {
-        self.is_mappable
+        !self.mappable_ranges().is_empty()
+    }
+
+    /// The parts of the region that can be memory-mapped, relative to its beginning, in ascending
+    /// order and without overlaps.
+    ///
+    /// Some regions can only be mapped in part, _e.g._, because other parts are emulated by the
+    /// backend. [`OwningPciRegion::map`] only succeeds for ranges that are entirely contained in
+    /// one of these.
+    pub fn mappable_ranges(&self) -> Vec<Range<u64>> {
+        let end = self.offset + self.length;
+
+        self.mappable_ranges
+            .iter()
+            .map(|r| r.start.max(self.offset)..r.end.min(end))
+            .filter(|r| r.start < r.end)
+            .map(|r| r.start - self.offset..r.end - self.offset)
+            .collect()
     }
 
     /// Like PciSubregion's similar method, but returns an "owning" subregion.
@@ -482,17 +535,79 @@ impl OwningPciRegion {
             offset: self.offset + range.start,
             length: range.end - range.start,
             identifier: self.identifier,
-            is_mappable: self.is_mappable,
+            mappable_ranges: Arc::clone(&self.mappable_ranges),
+            mapping: Arc::clone(&self.mapping),
+            generation: self.generation,
         }
     }
 
     /// Memory-map some range of the region into the current process' address space.
+    ///
+    /// The range must be entirely contained in one of the
+    /// [mappable ranges](OwningPciRegion::mappable_ranges). The mapping is
+    /// [uncached](Cacheability::Uncached).
     pub fn map(
         &self,
         range: impl RangeBounds<u64>,
         permissions: Permissions,
     ) -> io::Result<MappedOwningPciRegion> {
-        let range = clamp_range(range, self.region.len());
+        self.map_with_cacheability(range, permissions, Cacheability::Uncached)
+    }
+
+    /// Like [`OwningPciRegion::map`], but lets you choose the memory type of the mapping.
+    ///
+    /// Not all backends and regions support all memory types. For instance, only BARs can be
+    /// mapped [write-combined](Cacheability::WriteCombined), and only if they are prefetchable.
+    pub fn map_with_cacheability(
+        &self,
+        range: impl RangeBounds<u64>,
+        permissions: Permissions,
+        cacheability: Cacheability,
+    ) -> io::Result<MappedOwningPciRegion> {
+        let range = clamp_range(range, self.length);
+        self.map_underlying(
+            self.offset + range.start..self.offset + range.end,
+            permissions,
+            cacheability,
+        )
+    }
+
+    /// Returns the memory-mapped version of this region, mapping it first if necessary.
+    ///
+    /// The first call maps the _whole_ region this `OwningPciRegion` was derived from (see
+    /// [`OwningPciRegion::owning_subregion`]) with all the permissions it allows. That mapping is
+    /// then shared with every other `OwningPciRegion` derived from the same region, and is only
+    /// unmapped once all of them are dropped. From then on, the [`PciRegion`] methods of all those
+    /// `OwningPciRegion`s access the region through the mapping.
+    ///
+    /// If mapping fails, the error is returned and a later call will try again.
+    ///
+    /// Once the region is invalidated, _e.g._, by
+    /// [`VfioPciDevice::reinitialize`](crate::backends::vfio::VfioPciDevice::reinitialize), this
+    /// fails and the [`PciRegion`] methods stop going through the mapping.
+    pub fn mapped(&self) -> io::Result<PciSubregion<'_>> {
+        self.check_not_invalidated()?;
+
+        let mapping = self.mapping.get_or_try_init(|| {
+            self.map_underlying(
+                0..self.region.len(),
+                self.region.permissions(),
+                Cacheability::Uncached,
+            )
+        })?;
+
+        Ok(mapping.subregion(self.offset..self.offset + self.length))
+    }
+
+    /// Like [`OwningPciRegion::map`], but `range` is relative to the beginning of the underlying
+    /// region, not to `self.offset`.
+    fn map_underlying(
+        &self,
+        range: Range<u64>,
+        permissions: Permissions,
+        cacheability: Cacheability,
+    ) -> io::Result<MappedOwningPciRegion> {
+        self.check_not_invalidated()?;
 
         if range.end - range.start > usize::MAX as u64 {
             return Err(io::Error::new(
@@ -501,6 +616,20 @@ impl OwningPciRegion {
             ));
         }
 
+        if !self
+            .mappable_ranges
+            .iter()
+            .any(|r| r.start <= range.start && range.end <= r.end)
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Range [{:#x}, {:#x}) of the underlying region can't be memory-mapped",
+                    range.start, range.end
+                ),
+            ));
+        }
+
         if (permissions.can_read() && !self.permissions().can_read())
             || (permissions.can_write() && !self.permissions().can_write())
         {
@@ -514,9 +643,10 @@ impl OwningPciRegion {
 
         let ptr = self.device.region_map(
             self.identifier,
-            self.offset + range.start,
+            range.start,
             length,
             permissions,
+            cacheability,
         )?;
 
         let mapped_region = unsafe { PciMemoryRegion::new_raw(ptr, length, permissions) };
@@ -529,13 +659,81 @@ impl OwningPciRegion {
             length,
         })
     }
+
+    fn is_invalidated(&self) -> bool {
+        self.device.region_generation() != self.generation
+    }
+
+    fn check_not_invalidated(&self) -> io::Result<()> {
+        if self.is_invalidated() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "The region was invalidated since it was obtained, get it again",
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 impl_delegating_pci_region! { OwningPciRegion }
 
 impl<'a> AsPciSubregion<'a> for &'a OwningPciRegion {
     fn as_subregion(&self) -> PciSubregion<'a> {
-        (&*self.region).subregion(self.offset..self.offset + self.length)
+        let range = self.offset..self.offset + self.length;
+
+        // once invalidated, let the backend's region fail accesses
+        match self.mapping.get() {
+            Some(mapping) if !self.is_invalidated() => mapping.subregion(range),
+            _ => (&*self.region).subregion(range),
+        }
+    }
+}
+
+/// A [`MappedOwningPciRegion`] that is created on first use and then never changes.
+///
+/// This is a minimal `OnceCell` whose initialization can fail (and be retried).
+#[derive(Debug, Default)]
+struct LazyMapping {
+    init_lock: Mutex<()>,
+    initialized: AtomicBool,
+    mapping: UnsafeCell<Option<MappedOwningPciRegion>>,
+}
+
+// `mapping` is only written once, while holding `init_lock` and before `initialized` is set. After
+// that, it is only ever read.
+unsafe impl Sync for LazyMapping {}
+
+impl LazyMapping {
+    fn get(&self) -> Option<&MappedOwningPciRegion> {
+        if self.initialized.load(Ordering::Acquire) {
+            unsafe { (*self.mapping.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+
+    fn get_or_try_init(
+        &self,
+        init: impl FnOnce() -> io::Result<MappedOwningPciRegion>,
+    ) -> io::Result<&MappedOwningPciRegion> {
+        if let Some(mapping) = self.get() {
+            return Ok(mapping);
+        }
+
+        let _guard = self.init_lock.lock().unwrap();
+
+        // someone else might have initialized it while we waited for the lock
+
+        if let Some(mapping) = self.get() {
+            return Ok(mapping);
+        }
+
+        let mapping = init()?;
+        unsafe { *self.mapping.get() = Some(mapping) };
+        self.initialized.store(true, Ordering::Release);
+
+        Ok(self.get().unwrap())
     }
 }
 
@@ -608,7 +806,7 @@ unsafe impl Send for PciMemoryRegion<'_> {}
 unsafe impl Sync for PciMemoryRegion<'_> {}
 
 impl PciMemoryRegion<'_> {
-    pub fn new(data: &[u8]) -> PciMemoryRegion {
+    pub fn new(data: &[u8]) -> PciMemoryRegion<'_> {
         PciMemoryRegion {
             ptr: data.as_ptr() as *mut _,
             length: data.len(),
@@ -617,7 +815,7 @@ impl PciMemoryRegion<'_> {
         }
     }
 
-    pub fn new_mut(data: &mut [u8]) -> PciMemoryRegion {
+    pub fn new_mut(data: &mut [u8]) -> PciMemoryRegion<'_> {
         PciMemoryRegion {
             ptr: data.as_mut_ptr(),
             length: data.len(),
@@ -792,6 +990,203 @@ impl From<PciRegionSnapshot> for Vec<u8> {
     }
 }
 
+/// A byte of a region that didn't have the expected value. See [`verify`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PciRegionMismatch {
+    /// Offset of the byte from the beginning of the region.
+    pub offset: u64,
+    /// The value the byte was expected to have.
+    pub expected: u8,
+    /// The value that was actually read.
+    pub actual: u8,
+    /// The bits that differ and were not masked out.
+    pub differing_bits: u8,
+}
+
+/// Reads back a region and compares it against an expected snapshot, returning all bytes that
+/// differ, in increasing order of offset.
+///
+/// Bits that are set in `ignore_mask` are not compared. Byte `i` of `ignore_mask` applies to byte
+/// `i` of the region, and bytes past the end of `ignore_mask` are compared in full, so an empty
+/// mask compares everything.
+///
+/// Only the first `expected.len()` bytes of `region` are compared, and this fails if `region` is
+/// shorter than that. This is useful, for instance, to check that firmware was correctly written
+/// to a device, or to compare some state of real hardware against a known-good dump.
+pub fn verify<'a>(
+    region: impl AsPciSubregion<'a>,
+    expected: &PciRegionSnapshot,
+    ignore_mask: &[u8],
+) -> io::Result<Vec<PciRegionMismatch>> {
+    let region = region.as_subregion();
+
+    if region.len() < expected.buffer.len() as u64 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Region is shorter than the snapshot ({:#x} < {:#x} bytes)",
+                region.len(),
+                expected.buffer.len()
+            ),
+        ));
+    }
+
+    let mut actual = vec![0u8; expected.buffer.len()];
+    region.read_bytes(0, &mut actual)?;
+
+    let masks = ignore_mask.iter().copied().chain(std::iter::repeat(0));
+
+    let mismatches = (0..)
+        .zip(expected.buffer.iter().zip(&actual).zip(masks))
+        .filter_map(|(offset, ((&expected, &actual), mask))| {
+            let differing_bits = (expected ^ actual) & !mask;
+            if differing_bits != 0 {
+                Some(PciRegionMismatch {
+                    offset,
+                    expected,
+                    actual,
+                    differing_bits,
+                })
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    Ok(mismatches)
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// Wraps a [`PciRegion`] and keeps track of a current position in it, implementing [`Read`],
+/// [`Write`], and [`Seek`].
+///
+/// This lets code that consumes or produces byte streams, like parsers for Expansion ROM images or
+/// firmware blobs, work directly on device regions. It behaves much like [`std::io::Cursor`]:
+/// seeking past the end of the region is allowed, but reading or writing there then transfers no
+/// bytes.
+///
+/// Writes are performed using the widest naturally aligned accesses possible (up to 4 bytes), so
+/// they also work for regions that require aligned accesses. There is otherwise no guarantee about
+/// the size or atomicity of the accesses made.
+#[derive(Clone, Debug)]
+pub struct PciRegionCursor<R> {
+    region: R,
+    position: u64,
+}
+
+impl<R: PciRegion> PciRegionCursor<R> {
+    /// Creates a cursor over the given region, positioned at its beginning.
+    pub fn new(region: R) -> PciRegionCursor<R> {
+        PciRegionCursor {
+            region,
+            position: 0,
+        }
+    }
+
+    /// Returns the current position, in bytes from the beginning of the region.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Sets the current position, in bytes from the beginning of the region.
+    pub fn set_position(&mut self, position: u64) {
+        self.position = position;
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.region
+    }
+
+    pub fn into_inner(self) -> R {
+        self.region
+    }
+
+    /// How many bytes can be accessed starting at the current position, capped at `max`.
+    fn available(&self, max: usize) -> usize {
+        let remaining = self.region.len().saturating_sub(self.position);
+        remaining.min(max as u64) as usize
+    }
+}
+
+impl<R: PciRegion> Read for PciRegionCursor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.available(buf.len());
+
+        if len > 0 {
+            self.region.read_bytes(self.position, &mut buf[..len])?;
+            self.position += len as u64;
+        }
+
+        Ok(len)
+    }
+}
+
+impl<R: PciRegion> Write for PciRegionCursor<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.available(buf.len());
+        let mut written = 0;
+
+        while written < len {
+            let offset = self.position + written as u64;
+            let rest = &buf[written..len];
+
+            let size = if offset % 4 == 0 && rest.len() >= 4 {
+                let value = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+                self.region.write_le_u32(offset, value)?;
+                4
+            } else if offset % 2 == 0 && rest.len() >= 2 {
+                let value = u16::from_le_bytes([rest[0], rest[1]]);
+                self.region.write_le_u16(offset, value)?;
+                2
+            } else {
+                self.region.write_u8(offset, rest[0])?;
+                1
+            };
+
+            written += size;
+        }
+
+        self.position += len as u64;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<R: PciRegion> Seek for PciRegionCursor<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(delta) => (self.region.len(), delta),
+            SeekFrom::Current(delta) => (self.position, delta),
+        };
+
+        let new_position = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.wrapping_neg() as u64)
+        };
+
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
 /* ---------------------------------------------------------------------------------------------- */
 
 /// Something that is backed by a [`PciSubregion`].
@@ -825,3 +1220,119 @@ fn clamp_range(range: impl RangeBounds<u64>, max_length: u64) -> Range<u64> {
 }
 
 /* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::sync::Arc;
+
+    use crate::backends::mock::{MockDeviceBuilder, MockPciDevice};
+    use crate::device::PciDevice;
+    use crate::regions::{
+        verify, AsPciSubregion, OwningPciRegion, PciMemoryRegion, PciRegion, PciRegionCursor,
+        PciRegionMismatch, PciRegionSnapshot, Permissions, RegionIdentifier,
+    };
+
+    #[test]
+    fn test_cursor() {
+        let mut data = [0u8; 16];
+        let region = PciMemoryRegion::new_mut(&mut data);
+        let mut cursor = PciRegionCursor::new(region);
+
+        cursor.seek(SeekFrom::Start(1)).unwrap();
+        cursor.write_all(&[1, 2, 3, 4, 5, 6, 7]).unwrap();
+        assert_eq!(cursor.position(), 8);
+
+        cursor.seek(SeekFrom::End(-2)).unwrap();
+        assert!(cursor.write_all(&[8, 9, 10]).is_err());
+
+        let mut buffer = Vec::new();
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        cursor.read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, [0, 1, 2, 3, 4, 5, 6, 7, 0, 0, 0, 0, 0, 0, 8, 9]);
+
+        assert!(cursor.seek(SeekFrom::Current(-17)).is_err());
+        assert_eq!(cursor.seek(SeekFrom::Current(4)).unwrap(), 20);
+        assert_eq!(cursor.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_mappable_ranges() {
+        let data = [0u8; 0x4000];
+        let snapshot = PciRegionSnapshot::take(&PciMemoryRegion::new(&data)).unwrap();
+
+        let region = OwningPciRegion::new(
+            Arc::new(MockPciDevice),
+            Arc::new(snapshot),
+            RegionIdentifier::Bar(0),
+            &[0x0000..0x1000, 0x2000..0x4000],
+        );
+
+        assert!(region.is_mappable());
+        assert_eq!(region.mappable_ranges(), [0x0000..0x1000, 0x2000..0x4000]);
+
+        let subregion = region.owning_subregion(0x800..0x2800);
+        assert_eq!(subregion.mappable_ranges(), [0x000..0x800, 0x1800..0x2000]);
+
+        let subregion = region.owning_subregion(0x1000..0x2000);
+        assert!(!subregion.is_mappable());
+        assert!(subregion.map(.., Permissions::Read).is_err());
+        assert!(region.map(0x800..0x2800, Permissions::Read).is_err());
+    }
+
+    #[test]
+    fn test_invalidated_region() {
+        let device = MockDeviceBuilder::new(0x1234, 0x5678)
+            .bar(0, vec![0; 0x1000])
+            .build();
+
+        let region = device.bar(0).unwrap();
+        region.mapped().unwrap();
+
+        // as if the device's regions had been set up anew since
+        let stale = OwningPciRegion {
+            generation: region.generation + 1,
+            ..region.owning_subregion(..)
+        };
+
+        assert!(stale.mapped().is_err());
+        assert!(stale.map(.., Permissions::Read).is_err());
+        assert!(region.mapped().is_ok());
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut data = [0x12, 0x34, 0x56, 0x78];
+        let region = PciMemoryRegion::new_mut(&mut data);
+        let snapshot = PciRegionSnapshot::take(&region).unwrap();
+
+        region.write_u8(1, 0x35).unwrap();
+        region.write_u8(3, 0xf8).unwrap();
+
+        assert_eq!(
+            verify(&region, &snapshot, &[]).unwrap(),
+            vec![
+                PciRegionMismatch {
+                    offset: 1,
+                    expected: 0x34,
+                    actual: 0x35,
+                    differing_bits: 0x01,
+                },
+                PciRegionMismatch {
+                    offset: 3,
+                    expected: 0x78,
+                    actual: 0xf8,
+                    differing_bits: 0x80,
+                },
+            ]
+        );
+
+        assert_eq!(
+            verify(&region, &snapshot, &[0x00, 0x01, 0x00, 0x80]).unwrap(),
+            vec![]
+        );
+        assert!(verify((&region).subregion(1..), &snapshot, &[]).is_err());
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
@@ -319,7 +319,8 @@ where
             return Err(io::Error::new(ErrorKind::InvalidInput, "Value is too big"));
         }
 
-        let to_write = (T::read(self.region, self.offset)? & self.write_mask) | shifted;
+        let to_write =
+            (T::read(self.region, self.offset)? & self.write_mask & !self.mask) | shifted;
         to_write.write(self.region, self.offset)
     }
 }
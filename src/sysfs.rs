@@ -0,0 +1,572 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Accessing the sysfs attributes of a PCI function.
+//!
+//! Some things about a function are only exposed by the kernel through its sysfs directory,
+//! _e.g._, `/sys/bus/pci/devices/0000:00:01.0`, and not through its config space.
+//! [`PciSysfsDevice`] wraps such a directory. Get one from
+//! `VfioPciDevice::sysfs`, or create one from a
+//! [`PciDeviceEntry::sysfs_path`](crate::enumerate::PciDeviceEntry::sysfs_path).
+//!
+//! sysfs also reflects the PCI topology, so [`PciSysfsDevice::parent_bridge`] and
+//! [`PciSysfsDevice::upstream_bridges`] find the bridges above a function, whose config space can
+//! then be inspected through [`PciSysfsDevice::config_space_read_only`].
+
+/* ---------------------------------------------------------------------------------------------- */
+
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::PciLinkSpeed;
+use crate::device::PciAddress;
+use crate::regions::{AsPciSubregion, PciRegion, PciSubregion, Permissions};
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// The sysfs directory of a PCI function.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct PciSysfsDevice {
+    path: PathBuf,
+}
+
+impl PciSysfsDevice {
+    /// Wraps the given sysfs directory, _e.g._, `/sys/bus/pci/devices/0000:00:01.0`. This doesn't
+    /// check that the directory exists.
+    pub fn new<P: AsRef<Path>>(path: P) -> PciSysfsDevice {
+        PciSysfsDevice {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The sysfs directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the kernel may runtime-suspend the function when it is idle, as given by its
+    /// `power/control` attribute being `auto` rather than `on`.
+    ///
+    /// A runtime-suspended function may be put in a low power state, losing its state, which
+    /// user-space drivers usually don't expect.
+    pub fn runtime_pm_allowed(&self) -> io::Result<bool> {
+        match self.read_attribute("power/control")?.as_str() {
+            "auto" => Ok(true),
+            "on" => Ok(false),
+            value => Err(invalid_value("power/control", value)),
+        }
+    }
+
+    /// Allows or prevents the kernel from runtime-suspending the function. See
+    /// [`PciSysfsDevice::runtime_pm_allowed`].
+    ///
+    /// This usually requires root privileges.
+    pub fn set_runtime_pm_allowed(&self, allowed: bool) -> io::Result<()> {
+        let value = if allowed { "auto" } else { "on" };
+        fs::write(self.path.join("power/control"), value)
+    }
+
+    /// The function's runtime PM status, as given by its `power/runtime_status` attribute, _e.g._,
+    /// `active` or `suspended`.
+    pub fn runtime_status(&self) -> io::Result<String> {
+        self.read_attribute("power/runtime_status")
+    }
+
+    /// Whether the function may be put in the D3<sub>cold</sub> power state, _i.e._, have its
+    /// power removed, when it is runtime-suspended, as given by its `d3cold_allowed` attribute.
+    pub fn d3cold_allowed(&self) -> io::Result<bool> {
+        match self.read_attribute("d3cold_allowed")?.as_str() {
+            "1" => Ok(true),
+            "0" => Ok(false),
+            value => Err(invalid_value("d3cold_allowed", value)),
+        }
+    }
+
+    /// Allows or prevents the function from being put in the D3<sub>cold</sub> power state. See
+    /// [`PciSysfsDevice::d3cold_allowed`].
+    ///
+    /// This usually requires root privileges.
+    pub fn set_d3cold_allowed(&self, allowed: bool) -> io::Result<()> {
+        let value = if allowed { "1" } else { "0" };
+        fs::write(self.path.join("d3cold_allowed"), value)
+    }
+
+    /// The NUMA node the function is attached to, as given by its `numa_node` attribute, or `None`
+    /// if the system isn't NUMA or the platform doesn't say.
+    pub fn numa_node(&self) -> io::Result<Option<u32>> {
+        let value = self.read_attribute("numa_node")?;
+
+        match value.parse::<i32>() {
+            Ok(node) if node < 0 => Ok(None),
+            Ok(node) => Ok(Some(node as u32)),
+            Err(_) => Err(invalid_value("numa_node", &value)),
+        }
+    }
+
+    /// The resources assigned to the function, as given by its `resource` attribute, indexed like
+    /// the attribute's lines: BARs 0 to 5, then the Expansion ROM, then (for bridges) the bridge
+    /// windows. Unassigned resources are `None`.
+    pub fn resources(&self) -> io::Result<Vec<Option<PciResource>>> {
+        let contents = self.read_attribute("resource")?;
+
+        contents
+            .lines()
+            .map(|line| {
+                let fields: Vec<u64> = line
+                    .split_whitespace()
+                    .map(parse_hex)
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| invalid_value("resource", line))?;
+
+                match fields[..] {
+                    [0, 0, _] => Ok(None),
+                    [start, end, flags] => Ok(Some(PciResource { start, end, flags })),
+                    _ => Err(invalid_value("resource", line)),
+                }
+            })
+            .collect()
+    }
+
+    /// The Revision ID of the function, as given by its `revision` attribute.
+    pub fn revision(&self) -> io::Result<u8> {
+        let value = self.read_attribute("revision")?;
+        parse_hex(&value)
+            .and_then(|revision| u8::try_from(revision).ok())
+            .ok_or_else(|| invalid_value("revision", &value))
+    }
+
+    /// Whether the function is enabled, as given by its `enable` attribute being non-zero. The
+    /// kernel enables a function when a driver starts using it, _e.g._, when VFIO opens it.
+    pub fn enabled(&self) -> io::Result<bool> {
+        let value = self.read_attribute("enable")?;
+        match value.parse::<u32>() {
+            Ok(count) => Ok(count != 0),
+            Err(_) => Err(invalid_value("enable", &value)),
+        }
+    }
+
+    /// Enables or disables the function. See [`PciSysfsDevice::enabled`].
+    ///
+    /// This usually requires root privileges.
+    pub fn set_enabled(&self, enabled: bool) -> io::Result<()> {
+        let value = if enabled { "1" } else { "0" };
+        fs::write(self.path.join("enable"), value)
+    }
+
+    /// The maximum number of Virtual Functions the function supports, as given by its
+    /// `sriov_totalvfs` attribute, or `None` if it doesn't support SR-IOV.
+    pub fn sriov_totalvfs(&self) -> io::Result<Option<u16>> {
+        let value = match self.read_optional_attribute("sriov_totalvfs")? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        match value.parse() {
+            Ok(total) => Ok(Some(total)),
+            Err(_) => Err(invalid_value("sriov_totalvfs", &value)),
+        }
+    }
+
+    /// The maximum link speed the function supports, as given by its `max_link_speed` attribute,
+    /// or `None` if it isn't a PCI Express function or the speed is unknown.
+    pub fn max_link_speed(&self) -> io::Result<Option<PciLinkSpeed>> {
+        let value = match self.read_optional_attribute("max_link_speed")? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        // like "8.0 GT/s PCIe", or "Unknown"
+        let speed = [
+            PciLinkSpeed::Gt2_5,
+            PciLinkSpeed::Gt5,
+            PciLinkSpeed::Gt8,
+            PciLinkSpeed::Gt16,
+            PciLinkSpeed::Gt32,
+            PciLinkSpeed::Gt64,
+        ]
+        .iter()
+        .copied()
+        .find(|speed| value.starts_with(&speed.to_string()));
+
+        Ok(speed)
+    }
+
+    /// The address of the function, as given by the name of its sysfs directory once resolved.
+    pub fn address(&self) -> io::Result<PciAddress> {
+        let path = fs::canonicalize(&self.path)?;
+
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .parse()
+    }
+
+    /// The sysfs directory of the bridge immediately above the function, or `None` if the function
+    /// is on a root bus, _e.g._, it is a Root Port or a Root Complex Integrated Endpoint.
+    pub fn parent_bridge(&self) -> io::Result<Option<PciSysfsDevice>> {
+        let path = fs::canonicalize(&self.path)?;
+
+        Ok(path
+            .parent()
+            .filter(|parent| is_function_directory(parent))
+            .map(PciSysfsDevice::new))
+    }
+
+    /// The sysfs directories of the bridges above the function, _i.e._, of the Root Port and any
+    /// Switch Ports on the path to it, starting with the one closest to the Root Complex.
+    ///
+    /// These are found by resolving the directory's path, which for PCI functions is like
+    /// `/sys/devices/pci0000:00/0000:00:1c.0/0000:01:00.0`.
+    pub fn upstream_bridges(&self) -> io::Result<Vec<PciSysfsDevice>> {
+        let path = fs::canonicalize(&self.path)?;
+
+        let mut bridges: Vec<PciSysfsDevice> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|ancestor| is_function_directory(ancestor))
+            .map(PciSysfsDevice::new)
+            .collect();
+
+        bridges.reverse();
+        Ok(bridges)
+    }
+
+    /// Opens the function's config space through its `config` attribute.
+    ///
+    /// This doesn't require the function to be bound to any particular driver, which makes it
+    /// useful for looking at bridges. Note however that only root can access more than the first
+    /// 64 bytes, and the returned region is read-only if the attribute can't be opened for writing.
+    pub fn config_space(&self) -> io::Result<PciSysfsConfigSpace> {
+        let path = self.path.join("config");
+
+        match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => PciSysfsConfigSpace::new(file, Permissions::ReadWrite),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                PciSysfsConfigSpace::new(File::open(&path)?, Permissions::Read)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like [`PciSysfsDevice::config_space`], but always opens the attribute read-only, which is
+    /// all that is needed to, _e.g._, inspect the error reporting state of bridges.
+    pub fn config_space_read_only(&self) -> io::Result<PciSysfsConfigSpace> {
+        let file = File::open(self.path.join("config"))?;
+        PciSysfsConfigSpace::new(file, Permissions::Read)
+    }
+
+    /// Reads an attribute, without trailing whitespace.
+    fn read_attribute(&self, name: &str) -> io::Result<String> {
+        let contents = fs::read_to_string(self.path.join(name))?;
+        Ok(contents.trim_end().to_string())
+    }
+
+    /// Like [`PciSysfsDevice::read_attribute`], but returns `None` if the attribute doesn't exist.
+    fn read_optional_attribute(&self, name: &str) -> io::Result<Option<String>> {
+        match self.read_attribute(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl AsRef<Path> for PciSysfsDevice {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// A resource assigned to a PCI function, _e.g._, the address range a BAR is mapped at. See
+/// [`PciSysfsDevice::resources`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct PciResource {
+    /// The first address of the resource.
+    pub start: u64,
+    /// The last address of the resource, inclusive.
+    pub end: u64,
+    /// The kernel's `IORESOURCE_*` flags for the resource.
+    pub flags: u64,
+}
+
+impl PciResource {
+    const IORESOURCE_IO: u64 = 0x0000_0100;
+    const IORESOURCE_MEM: u64 = 0x0000_0200;
+    const IORESOURCE_PREFETCH: u64 = 0x0000_2000;
+    const IORESOURCE_MEM_64: u64 = 0x0010_0000;
+
+    /// The size of the resource, in bytes.
+    pub fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Whether the resource is in I/O space.
+    pub fn is_io(&self) -> bool {
+        self.flags & PciResource::IORESOURCE_IO != 0
+    }
+
+    /// Whether the resource is in memory space.
+    pub fn is_memory(&self) -> bool {
+        self.flags & PciResource::IORESOURCE_MEM != 0
+    }
+
+    /// Whether the resource is prefetchable memory.
+    pub fn is_prefetchable(&self) -> bool {
+        self.flags & PciResource::IORESOURCE_PREFETCH != 0
+    }
+
+    /// Whether the resource is memory that may be placed above 4 GiB.
+    pub fn is_64_bit(&self) -> bool {
+        self.flags & PciResource::IORESOURCE_MEM_64 != 0
+    }
+}
+
+fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value.strip_prefix("0x")?, 16).ok()
+}
+
+fn is_function_directory(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| name.parse::<PciAddress>().is_ok())
+}
+
+fn invalid_value(name: &str, value: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid contents in {}: {:?}", name, value),
+    )
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+/// The config space of a PCI function, accessed through its `config` sysfs attribute. See
+/// [`PciSysfsDevice::config_space`].
+///
+/// Use [`BackedByPciSubregion::backed_by`](crate::regions::BackedByPciSubregion::backed_by) to
+/// get a [`PciConfig`](crate::config::PciConfig) from it.
+#[derive(Debug)]
+pub struct PciSysfsConfigSpace {
+    file: File,
+    length: u64,
+    permissions: Permissions,
+}
+
+impl PciSysfsConfigSpace {
+    fn new(file: File, permissions: Permissions) -> io::Result<PciSysfsConfigSpace> {
+        let length = file.metadata()?.len();
+
+        Ok(PciSysfsConfigSpace {
+            file,
+            length,
+            permissions,
+        })
+    }
+
+    fn validate_access(
+        &self,
+        required_alignment: u64,
+        offset: u64,
+        length: usize,
+    ) -> io::Result<()> {
+        let end = offset + length as u64;
+
+        if end > self.length {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Tried to access region range [{:#x}, {:#x}), must be in [0x0, {:#x})",
+                    offset, end, self.length
+                ),
+            ));
+        }
+
+        if offset % required_alignment != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Access must be {}-byte aligned", required_alignment),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, required_alignment: u64, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.validate_access(required_alignment, offset, buffer.len())?;
+        self.file.read_exact_at(buffer, offset)
+    }
+
+    fn write(&self, required_alignment: u64, offset: u64, buffer: &[u8]) -> io::Result<()> {
+        self.validate_access(required_alignment, offset, buffer.len())?;
+        self.file.write_all_at(buffer, offset)
+    }
+}
+
+impl crate::regions::Sealed for PciSysfsConfigSpace {}
+impl PciRegion for PciSysfsConfigSpace {
+    fn len(&self) -> u64 {
+        self.length
+    }
+
+    fn permissions(&self) -> Permissions {
+        self.permissions
+    }
+
+    fn as_ptr(&self) -> Option<*const u8> {
+        None
+    }
+
+    fn as_mut_ptr(&self) -> Option<*mut u8> {
+        None
+    }
+
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.read(1, offset, buffer)
+    }
+
+    fn read_u8(&self, offset: u64) -> io::Result<u8> {
+        let mut buffer = [0; 1];
+        self.read(1, offset, &mut buffer)?;
+        Ok(buffer[0])
+    }
+
+    fn write_u8(&self, offset: u64, value: u8) -> io::Result<()> {
+        self.write(1, offset, &[value])
+    }
+
+    fn read_le_u16(&self, offset: u64) -> io::Result<u16> {
+        let mut buffer = [0; 2];
+        self.read(2, offset, &mut buffer)?;
+        Ok(u16::from_le_bytes(buffer))
+    }
+
+    fn write_le_u16(&self, offset: u64, value: u16) -> io::Result<()> {
+        self.write(2, offset, &value.to_le_bytes())
+    }
+
+    fn read_le_u32(&self, offset: u64) -> io::Result<u32> {
+        let mut buffer = [0; 4];
+        self.read(4, offset, &mut buffer)?;
+        Ok(u32::from_le_bytes(buffer))
+    }
+
+    fn write_le_u32(&self, offset: u64, value: u32) -> io::Result<()> {
+        self.write(4, offset, &value.to_le_bytes())
+    }
+}
+
+impl<'a> AsPciSubregion<'a> for &'a PciSysfsConfigSpace {
+    fn as_subregion(&self) -> PciSubregion<'a> {
+        let region: &'a dyn PciRegion = *self;
+        <&dyn PciRegion>::as_subregion(&region)
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use crate::config::PciLinkSpeed;
+    use crate::regions::{PciRegion, Permissions};
+    use crate::sysfs::PciSysfsDevice;
+
+    #[test]
+    fn test_attributes() {
+        let root = std::env::temp_dir().join(format!("pci-driver-sysfs-test-{}", process::id()));
+        fs::create_dir_all(root.join("power")).unwrap();
+
+        let device = PciSysfsDevice::new(&root);
+
+        fs::write(root.join("power/control"), "auto\n").unwrap();
+        assert!(device.runtime_pm_allowed().unwrap());
+        device.set_runtime_pm_allowed(false).unwrap();
+        assert!(!device.runtime_pm_allowed().unwrap());
+
+        fs::write(root.join("d3cold_allowed"), "1\n").unwrap();
+        assert!(device.d3cold_allowed().unwrap());
+        device.set_d3cold_allowed(false).unwrap();
+        assert!(!device.d3cold_allowed().unwrap());
+
+        fs::write(root.join("d3cold_allowed"), "yes\n").unwrap();
+        assert!(device.d3cold_allowed().is_err());
+
+        fs::write(root.join("numa_node"), "-1\n").unwrap();
+        assert_eq!(device.numa_node().unwrap(), None);
+        fs::write(root.join("numa_node"), "1\n").unwrap();
+        assert_eq!(device.numa_node().unwrap(), Some(1));
+
+        fs::write(
+            root.join("resource"),
+            "0x00000000f7d00000 0x00000000f7d03fff 0x0000000000140204\n\
+             0x0000000000000000 0x0000000000000000 0x0000000000000000\n\
+             0x000000000000e000 0x000000000000e07f 0x0000000000040101\n",
+        )
+        .unwrap();
+        let resources = device.resources().unwrap();
+        assert_eq!(resources.len(), 3);
+        let bar0 = resources[0].unwrap();
+        assert_eq!(bar0.start, 0xf7d0_0000);
+        assert_eq!(bar0.size(), 0x4000);
+        assert!(bar0.is_memory() && bar0.is_64_bit() && !bar0.is_prefetchable());
+        assert_eq!(resources[1], None);
+        assert!(resources[2].unwrap().is_io());
+
+        fs::write(root.join("revision"), "0x01\n").unwrap();
+        assert_eq!(device.revision().unwrap(), 0x01);
+
+        fs::write(root.join("enable"), "2\n").unwrap();
+        assert!(device.enabled().unwrap());
+
+        assert_eq!(device.sriov_totalvfs().unwrap(), None);
+        fs::write(root.join("sriov_totalvfs"), "64\n").unwrap();
+        assert_eq!(device.sriov_totalvfs().unwrap(), Some(64));
+
+        fs::write(root.join("max_link_speed"), "8.0 GT/s PCIe\n").unwrap();
+        assert_eq!(device.max_link_speed().unwrap(), Some(PciLinkSpeed::Gt8));
+        fs::write(root.join("max_link_speed"), "Unknown\n").unwrap();
+        assert_eq!(device.max_link_speed().unwrap(), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_upstream_bridges() {
+        let root = std::env::temp_dir().join(format!("pci-driver-bridges-test-{}", process::id()));
+        let function = root.join("pci0000:00/0000:00:1c.0/0000:01:00.0/0000:02:00.0/0000:03:00.0");
+        fs::create_dir_all(&function).unwrap();
+
+        let bridges = PciSysfsDevice::new(&function).upstream_bridges().unwrap();
+        let names: Vec<_> = bridges
+            .iter()
+            .map(|bridge| bridge.path().file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["0000:00:1c.0", "0000:01:00.0", "0000:02:00.0"]);
+
+        let device = PciSysfsDevice::new(&function);
+        assert_eq!(device.address().unwrap(), "0000:03:00.0".parse().unwrap());
+        assert_eq!(device.parent_bridge().unwrap().as_ref(), bridges.last());
+        assert_eq!(bridges[0].parent_bridge().unwrap(), None);
+
+        fs::write(function.join("config"), [0x4d, 0x14, 0x08, 0xa8]).unwrap();
+        let config = PciSysfsDevice::new(&function).config_space().unwrap();
+        assert_eq!(config.len(), 4);
+        assert_eq!(config.read_le_u16(0x02).unwrap(), 0xa808);
+        assert!(config.read_le_u16(0x03).is_err());
+        assert!(config.read_le_u32(0x04).is_err());
+
+        let config = PciSysfsDevice::new(&function)
+            .config_space_read_only()
+            .unwrap();
+        assert_eq!(config.permissions(), Permissions::Read);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
+
+/* ---------------------------------------------------------------------------------------------- */